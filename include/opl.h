@@ -0,0 +1,108 @@
+/* opl.h
+ *
+ * C API for embedding opl. Build the library with
+ *
+ *     cargo rustc --lib --release --features cdylib --crate-type cdylib
+ *
+ * and link against target/release/libopl.so (libopl.dylib, opl.dll).
+ *
+ * Engines and values are owned by the caller: free them with
+ * opl_engine_free and opl_value_free. Strings returned by the library are
+ * freed with opl_string_free.
+ */
+
+#ifndef OPL_H
+#define OPL_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct OplEngine OplEngine;
+typedef struct OplValue OplValue;
+
+typedef enum OplKind {
+    OPL_UNIT = 0,
+    OPL_INTEGER = 1,
+    OPL_FLOAT = 2,
+    OPL_BOOLEAN = 3,
+    OPL_STRING = 4,
+    OPL_LIST = 5,
+    OPL_RECORD = 6,
+    OPL_OPTION = 7,
+    OPL_RESULT = 8,
+    OPL_FUNCTION = 9,
+    OPL_OTHER = 10,
+    OPL_ERROR = 11,
+    OPL_MAP = 12,
+    OPL_SET = 13,
+    OPL_DEQUE = 14,
+} OplKind;
+
+/* A host function. arguments are borrowed; the returned value is owned by the
+ * engine. Returning NULL reports a failed call. */
+typedef OplValue *(*OplCallback)(const OplValue *const *arguments, size_t count, void *data);
+
+OplEngine *opl_engine_new(void);
+void opl_engine_free(OplEngine *engine);
+
+/* Returns the value of the last statement, or NULL on failure. When error is
+ * not NULL it is set to the diagnostics of a failure, or NULL on success. */
+OplValue *opl_eval(OplEngine *engine, const char *source, char **error);
+
+/* Binds name to a copy of value. */
+bool opl_engine_set(OplEngine *engine, const char *name, const OplValue *value);
+/* Returns a copy of the global name, or NULL when it is unbound. */
+OplValue *opl_engine_get(const OplEngine *engine, const char *name);
+/* Binds name to callback, which is passed data on every call. */
+bool opl_engine_register(OplEngine *engine, const char *name, OplCallback callback, void *data);
+
+OplValue *opl_value_int(int64_t value);
+OplValue *opl_value_float(double value);
+OplValue *opl_value_bool(bool value);
+OplValue *opl_value_string(const char *value);
+OplValue *opl_value_unit(void);
+OplValue *opl_value_none(void);
+/* The constructors below copy their arguments. */
+OplValue *opl_value_some(const OplValue *value);
+OplValue *opl_value_error(const char *message);
+OplValue *opl_value_list(const OplValue *const *values, size_t count);
+OplValue *opl_value_record(const char *const *names, const OplValue *const *values, size_t count);
+void opl_value_free(OplValue *value);
+
+OplKind opl_value_kind(const OplValue *value);
+/* Each returns false, leaving out untouched, when value has another kind. */
+bool opl_value_as_int(const OplValue *value, int64_t *out);
+bool opl_value_as_float(const OplValue *value, double *out);
+bool opl_value_as_bool(const OplValue *value, bool *out);
+/* NULL when value is not a string. */
+char *opl_value_as_string(const OplValue *value);
+char *opl_value_to_string(const OplValue *value);
+
+/* Elements of a list, set or deque, entries of a map or fields of a record,
+ * otherwise 0. */
+size_t opl_value_len(const OplValue *value);
+OplValue *opl_value_list_get(const OplValue *value, size_t index);
+/* Lists of the elements of a set or deque, and of the keys and values of a
+ * map in the same order, otherwise NULL. */
+OplValue *opl_value_elements(const OplValue *value);
+OplValue *opl_value_keys(const OplValue *value);
+OplValue *opl_value_values(const OplValue *value);
+OplValue *opl_value_field(const OplValue *value, const char *name);
+char *opl_value_field_name(const OplValue *value, size_t index);
+OplValue *opl_value_field_at(const OplValue *value, size_t index);
+/* What a Some, Ok or Err holds, otherwise NULL. */
+OplValue *opl_value_inner(const OplValue *value);
+bool opl_value_is_err(const OplValue *value);
+
+void opl_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OPL_H */
@@ -0,0 +1,86 @@
+// actor.rs
+//
+// Actors: `actor(state, handler)` gives an address, and `send(address,
+// message)` delivers a message, which the actor handles by calling
+// `handler(state, message)` for its next state. An actor handles one message
+// at a time, in the order they were sent. The interpreter runs one thing at a
+// time, so `send` handles the message at once, unless the actor is busy
+// handling another one further up the stack: then the message waits in its
+// mailbox until that one is done, so an actor never sees its own state
+// halfway through an update.
+
+use crate::diagnostics::Code;
+use crate::list::call;
+use crate::object::{Native, Object};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+struct Actor {
+    handler: Object,
+    // None while a message is being handled
+    state: Option<Object>,
+    mailbox: VecDeque<Object>,
+}
+
+/// `actor(state, handler)`, the address of a new actor.
+pub fn actor_builtin(args: Vec<Object>) -> Object {
+    let [state, handler @ (Object::Function(..) | Object::Builtin(_) | Object::Native(_))] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "actor expects an initial state and a handler function".to_string());
+    };
+    let actor = Rc::new(RefCell::new(Actor { handler: handler.clone(), state: Some(state.clone()), mailbox: VecDeque::new() }));
+    let deliver = move |args: Vec<Object>| match args.as_slice() {
+        [message] => deliver(&actor, message.clone()),
+        _ => Object::Error(Code::BuiltinArgument, "an actor takes one message at a time".to_string()),
+    };
+    Object::Native(Native { name: "actor".to_string(), function: Rc::new(deliver) })
+}
+
+/// `send(address, message)` delivers a message to an actor.
+pub fn send_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [Object::Native(address), message] if address.name == "actor" => (address.function)(vec![message.clone()]),
+        _ => Object::Error(Code::BuiltinArgument, "send expects an actor address and a message".to_string()),
+    }
+}
+
+// Handles the mailbox until it is empty. A handler's error stops the actor
+// where it was, keeping its last state and the messages still waiting, and
+// is the error of the `send` that was handling them.
+fn deliver(actor: &RefCell<Actor>, message: Object) -> Object {
+    actor.borrow_mut().mailbox.push_back(message);
+    loop {
+        let (handler, state, message) = {
+            let mut actor = actor.borrow_mut();
+            if actor.state.is_none() || actor.mailbox.is_empty() {
+                return Object::Unit;
+            }
+            let message = actor.mailbox.pop_front().expect("checked above");
+            (actor.handler.clone(), actor.state.take().expect("checked above"), message)
+        };
+        let next = call(&handler, vec![state.clone(), message]);
+        let failed = matches!(next, Object::Error(..));
+        actor.borrow_mut().state = Some(if failed { state } else { next.clone() });
+        if failed {
+            return next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::object::Object;
+
+    #[test]
+    fn test_actor() {
+        let mut eval = crate::evaluator::test_eval();
+
+        // Sending to itself waits for the current message, so 2 is added
+        // before the 100 that the 2 sends
+        eval("let counter = actor(0, fn total n -> if n == 0 { exit(total) } else { if n == 2 { send(counter, 100) } else { () }; total * 10 + n });");
+        assert_eq!(eval("send(counter, 1);\nsend(counter, 2);"), Some(Object::Unit));
+        assert_eq!(eval("send(counter, 0)"), Some(Object::Error(Code::Exit(220), "exited with status 220".to_string())));
+        assert!(matches!(eval("send(1, 2)"), Some(Object::Error(..))));
+    }
+}
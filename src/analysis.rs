@@ -0,0 +1,461 @@
+// analysis.rs
+//
+// Queries about a position in opl source, for editor integrations such as
+// hover. Like completion, they work on source that does not check as a
+// whole: the type checker carries on past a binding that fails.
+
+use crate::ast::*;
+use crate::lexer::{Lexer, Span, Token};
+use crate::parser::Parser;
+use crate::typechecker::{TypeChecker, Ty};
+use std::collections::HashMap;
+
+/// A replacement for the text at `span`, whose offsets count chars.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TextEdit {
+    pub span: Span,
+    pub text: String,
+}
+
+/// The type of what is at byte `offset` in `source`, e.g. `fn int -> int`
+/// over a let binding or a use of one, or `bool` over the `<` of `a < b`.
+/// None elsewhere, including over function parameters, whose uses are not
+/// tracked.
+pub fn type_at(source: &str, offset: usize) -> Option<String> {
+    let offset = char_offset(source, offset)?;
+    let mut lexer = Lexer::new(source);
+    let (token, span) = loop {
+        match lexer.advance() {
+            Token::End => return None,
+            token if lexer.span.start <= offset && offset < lexer.span.end => break (token, lexer.span),
+            _ => {}
+        }
+    };
+
+    let program = Parser::new(Lexer::new(source)).parse_program();
+    let mut checker = TypeChecker::new().with_types();
+    checker.check_program(&program);
+    let types = checker.types();
+    let ty = match token {
+        Token::Identifier(name) => binding(source, &types, &name, span),
+        _ => types.iter().find(|(recorded, _)| recorded.start == span.start).map(|(_, ty)| ty),
+    };
+    ty.map(Ty::to_string)
+}
+
+// The type of the closest let binding `name` at or before `span`
+fn binding<'a>(source: &str, types: &'a [(Span, Ty)], name: &str, span: Span) -> Option<&'a Ty> {
+    let text = |span: &Span| source.chars().skip(span.start).take(span.end - span.start).collect::<String>();
+    types
+        .iter()
+        .filter(|(binding, _)| binding.start <= span.start && text(binding) == name)
+        .max_by_key(|(binding, _)| binding.start)
+        .map(|(_, ty)| ty)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Type,
+    Variant,
+    Field,
+}
+
+/// A name in a program, with where it is defined and where it is used.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    // None for names declared nowhere, e.g. fields of records without a type
+    pub definition: Option<Span>,
+    pub references: Vec<Span>,
+}
+
+/// A use of a variable, see `Table::uses`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Use {
+    pub symbol: usize,
+    // The top level let whose value the use is in
+    pub within: Option<usize>,
+    // Whether the variable is called, as in `f(x)` or `x |> f`
+    pub call: bool,
+}
+
+/// Every name in a program, see `table`.
+pub struct Table {
+    pub symbols: Vec<Symbol>,
+    // Uses of variables, in source order
+    pub uses: Vec<Use>,
+    // The symbol of each of `Program::identifiers`; None for builtins and
+    // unbound names
+    variables: Vec<Option<usize>>,
+}
+
+impl Table {
+    /// The symbol defined or used at char offset `offset`.
+    pub fn at(&self, offset: usize) -> Option<&Symbol> {
+        let contains = |span: &Span| span.start <= offset && offset < span.end;
+        self.symbols.iter().find(|symbol| symbol.definition.iter().chain(&symbol.references).any(contains))
+    }
+}
+
+// A scope's variables, with whether each one's let has run yet. Those that
+// have not are only visible from functions, which run later.
+struct Scope {
+    bindings: Vec<(String, usize, bool)>,
+    // How many function bodies the scope is nested in
+    depth: usize,
+}
+
+// Resolves each of `Program::identifiers` to the variable it names, walking
+// the program in source order with the evaluator's scopes: a program, block
+// or function body has its own, an if branch shares that around it.
+struct Resolver<'a> {
+    identifiers: &'a [(String, Span)],
+    scopes: Vec<Scope>,
+    depth: usize,
+    symbols: Vec<Symbol>,
+    resolved: Vec<Option<usize>>,
+    uses: Vec<Use>,
+    within: Option<usize>,
+    // Set while resolving the callee of a call
+    call: bool,
+}
+
+// Custom operators are bound like variables but are not identifiers
+fn is_variable(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+impl Resolver<'_> {
+    fn bind(&mut self, name: &str, kind: SymbolKind, defined: bool) -> usize {
+        self.symbols.push(Symbol { name: name.to_string(), kind, definition: None, references: Vec::new() });
+        let symbol = self.symbols.len() - 1;
+        self.scopes.last_mut().expect("a scope").bindings.push((name.to_string(), symbol, defined));
+        symbol
+    }
+
+    // The next identifier, which names `symbol`
+    fn occurrence(&mut self, symbol: Option<usize>, definition: bool) {
+        let call = std::mem::take(&mut self.call);
+        if let (Some(symbol), Some((_, span))) = (symbol, self.identifiers.get(self.resolved.len())) {
+            match definition {
+                true => self.symbols[symbol].definition = Some(*span),
+                false => {
+                    self.symbols[symbol].references.push(*span);
+                    self.uses.push(Use { symbol, within: self.within, call });
+                }
+            }
+        }
+        self.resolved.push(symbol);
+    }
+
+    // A scope of `statements`, with their lets declared up front
+    fn scope(&mut self, statements: &[Statement], parameters: &[Identifier]) {
+        self.scopes.push(Scope { bindings: Vec::new(), depth: self.depth });
+        for parameter in parameters {
+            let symbol = self.bind(&parameter.to_string(), SymbolKind::Parameter, true);
+            self.occurrence(Some(symbol), true);
+        }
+        for statement in statements {
+            if let Statement::Let(Token::Identifier(name), _, _) = statement {
+                if is_variable(name) {
+                    self.bind(name, SymbolKind::Variable, false);
+                }
+            }
+        }
+        self.statements(statements);
+        self.scopes.pop();
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        statements.iter().for_each(|statement| self.statement(statement));
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(Token::Identifier(name), expression, _) if is_variable(name) => {
+                let scope = self.scopes.last().expect("a scope");
+                let declared = scope.bindings.iter().position(|(bound, _, defined)| bound == name && !defined);
+                let index = match declared {
+                    Some(index) => index,
+                    None => {
+                        self.bind(name, SymbolKind::Variable, false);
+                        self.scopes.last().expect("a scope").bindings.len() - 1
+                    }
+                };
+                let symbol = self.scopes.last().expect("a scope").bindings[index].1;
+                self.occurrence(Some(symbol), true);
+                let top_level = self.scopes.len() == 1;
+                if top_level {
+                    self.within = Some(symbol);
+                }
+                self.expression(expression);
+                if top_level {
+                    self.within = None;
+                }
+                self.scopes.last_mut().expect("a scope").bindings[index].2 = true;
+            }
+            Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => {
+                self.expression(expression)
+            }
+            Statement::Assert(assertion) => self.expression(&assertion.condition),
+            Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| {
+            scope
+                .bindings
+                .iter()
+                .find(|(bound, _, defined)| bound == name && (*defined || self.depth > scope.depth))
+                .map(|(_, symbol, _)| *symbol)
+        })
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Identifier(identifier) => {
+                let name = identifier.to_string();
+                match is_variable(&name) {
+                    true => self.occurrence(self.lookup(&name), false),
+                    false => self.call = false,
+                }
+            }
+            Expression::OptionSome(inner) | Expression::ResultOk(inner) | Expression::ResultErr(inner) => self.expression(inner),
+            Expression::OptionNone => {}
+            Expression::Literal(Literal::List(elements)) => elements.iter().for_each(|element| self.expression(element)),
+            Expression::Literal(Literal::Record(fields)) => fields.iter().for_each(|(_, value)| self.expression(value)),
+            Expression::Literal(Literal::HashMap(entries)) => entries.iter().for_each(|(key, value)| {
+                self.expression(key);
+                self.expression(value);
+            }),
+            Expression::Literal(_) => {}
+            Expression::Prefix(_, right) => self.expression(right),
+            Expression::Infix(infix, left, right, _) => {
+                self.expression(left);
+                self.call = *infix == Infix::Pipe && matches!(**right, Expression::Identifier(_));
+                self.expression(right);
+                self.call = false;
+            }
+            Expression::Block(statements) => self.scope(statements, &[]),
+            Expression::Function { parameters, body, .. } => {
+                self.depth += 1;
+                self.scope(body, parameters);
+                self.depth -= 1;
+            }
+            Expression::If { condition, consequence, alternative } => {
+                self.expression(condition);
+                self.statements(consequence);
+                if let Some(alternative) = alternative {
+                    self.statements(alternative);
+                }
+            }
+            Expression::Call { function, arguments, .. } => {
+                self.call = matches!(**function, Expression::Identifier(_));
+                self.expression(function);
+                self.call = false;
+                arguments.iter().for_each(|argument| self.expression(argument));
+            }
+            Expression::BuiltIn { arguments, .. } => arguments.iter().for_each(|argument| self.expression(argument)),
+            Expression::Match { expr, arms } => {
+                self.expression(expr);
+                arms.iter().for_each(|(_, body)| self.scope(body, &[]));
+            }
+            Expression::Field { record, .. } => self.expression(record),
+            Expression::Range { start, end } => {
+                self.expression(start);
+                self.expression(end);
+            }
+            Expression::Annotated { expression, .. } => self.expression(expression),
+            // Taken to read the names in scope here, as it most often does
+            Expression::Quote(quoted, _) => self.expression(quoted),
+        }
+    }
+}
+
+/// Resolves every variable, type, variant and record field name of
+/// `program`. Variables follow scopes, the others are global and matched by
+/// name; a variant is also used where it is called like a function. None
+/// when the program has parse errors.
+pub fn table(program: &Program) -> Option<Table> {
+    if !program.errors.is_empty() {
+        return None;
+    }
+    let mut resolver = Resolver {
+        identifiers: &program.identifiers,
+        scopes: Vec::new(),
+        depth: 0,
+        symbols: Vec::new(),
+        resolved: Vec::new(),
+        uses: Vec::new(),
+        within: None,
+        call: false,
+    };
+    resolver.scope(program, &[]);
+    if resolver.resolved.len() != program.identifiers.len() {
+        return None;
+    }
+    let Resolver { mut symbols, resolved: variables, uses, .. } = resolver;
+
+    let mut names: HashMap<(NameKind, &str), usize> = HashMap::new();
+    for name in &program.names {
+        let symbol = *names.entry((name.kind, name.text.as_str())).or_insert_with(|| {
+            let kind = match name.kind {
+                NameKind::Type => SymbolKind::Type,
+                NameKind::Variant => SymbolKind::Variant,
+                NameKind::Field => SymbolKind::Field,
+            };
+            symbols.push(Symbol { name: name.text.clone(), kind, definition: None, references: Vec::new() });
+            symbols.len() - 1
+        });
+        match name.declaration && symbols[symbol].definition.is_none() {
+            true => symbols[symbol].definition = Some(name.span),
+            false => symbols[symbol].references.push(name.span),
+        }
+    }
+    for ((name, span), variable) in program.identifiers.iter().zip(&variables) {
+        if let (None, Some(symbol)) = (variable, names.get(&(NameKind::Variant, name.as_str()))) {
+            symbols[*symbol].references.push(*span);
+        }
+    }
+    symbols.iter_mut().for_each(|symbol| symbol.references.sort_by_key(|span| span.start));
+    Some(Table { symbols, uses, variables })
+}
+
+// Spans count chars
+fn char_offset(source: &str, offset: usize) -> Option<usize> {
+    Some(source.get(..offset)?.chars().count())
+}
+
+fn symbol_at(source: &str, offset: usize) -> Option<Symbol> {
+    let offset = char_offset(source, offset)?;
+    let program = Parser::new(Lexer::new(source)).parse_program();
+    table(&program)?.at(offset).cloned()
+}
+
+/// Where the name at byte `offset` in `source` is defined.
+pub fn definition_at(source: &str, offset: usize) -> Option<Span> {
+    symbol_at(source, offset)?.definition
+}
+
+/// Where the name at byte `offset` in `source` is used, its definition
+/// excluded.
+pub fn references_at(source: &str, offset: usize) -> Vec<Span> {
+    symbol_at(source, offset).map_or(Vec::new(), |symbol| symbol.references)
+}
+
+/// Edits renaming the variable at byte `offset` in `source`, at its binding
+/// and every use, to `new_name`. Empty when there is no variable there, or
+/// when the rename would change what a name refers to, e.g. `new_name` is
+/// bound in the same scope or the variable is used where an inner
+/// `new_name` would shadow it.
+pub fn rename(source: &str, offset: usize, new_name: &str) -> Vec<TextEdit> {
+    let mut lexer = Lexer::new(new_name);
+    if !matches!(lexer.advance(), Token::Identifier(_)) || lexer.advance() != Token::End {
+        return Vec::new();
+    }
+    let resolve = |source: &str| table(&Parser::new(Lexer::new(source)).parse_program());
+    let program = Parser::new(Lexer::new(source)).parse_program();
+    let (Some(offset), Some(table)) = (char_offset(source, offset), table(&program)) else {
+        return Vec::new();
+    };
+    let Some(symbol) = table.at(offset).filter(|symbol| matches!(symbol.kind, SymbolKind::Variable | SymbolKind::Parameter)) else {
+        return Vec::new();
+    };
+    let mut spans: Vec<Span> = symbol.definition.iter().chain(&symbol.references).copied().collect();
+    spans.sort_by_key(|span| span.start);
+    // A punned field, as in `{ name }`, keeps its name
+    let punned = |span: &Span| program.names.iter().any(|name| name.kind == NameKind::Field && name.span == *span);
+    let edits: Vec<TextEdit> = spans
+        .into_iter()
+        .map(|span| TextEdit { span, text: if punned(&span) { format!("{} = {}", symbol.name, new_name) } else { new_name.to_string() } })
+        .collect();
+    // Every name must still refer to the same binding as its neighbours did
+    match resolve(&apply_edits(source, &edits)) {
+        Some(renamed) if same_bindings(&table.variables, &renamed.variables) => edits,
+        _ => Vec::new(),
+    }
+}
+
+fn same_bindings(before: &[Option<usize>], after: &[Option<usize>]) -> bool {
+    let mut forward = HashMap::new();
+    let mut backward = HashMap::new();
+    before.len() == after.len()
+        && before.iter().zip(after).all(|(before, after)| match (before, after) {
+            (Some(before), Some(after)) => *forward.entry(before).or_insert(after) == after && *backward.entry(after).or_insert(before) == before,
+            (None, None) => true,
+            _ => false,
+        })
+}
+
+/// `source` with `edits`, which must be in order and not overlap.
+pub fn apply_edits(source: &str, edits: &[TextEdit]) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut text = String::new();
+    let mut at = 0;
+    for edit in edits {
+        text.extend(&chars[at..edit.span.start]);
+        text.push_str(&edit.text);
+        at = edit.span.end;
+    }
+    text.extend(&chars[at..]);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_at() {
+        let source = "let double = fn x -> x * 2;\nlet big = double(21) > 40;\nlet café = \"é\";\ncafé ++ 1";
+        let at = |needle: &str| type_at(source, source.find(needle).unwrap());
+        assert_eq!(at("double ="), Some("fn int -> int".to_string()));
+        assert_eq!(at("double(21)"), Some("fn int -> int".to_string()));
+        assert_eq!(at("> 40"), Some("bool".to_string()));
+        assert_eq!(at("big"), Some("bool".to_string()));
+        assert_eq!(at("café ++"), Some("string".to_string()));
+        // The failed `++` has no type, and literals are not tracked
+        assert_eq!(at("++ 1"), None);
+        assert_eq!(at("21"), None);
+        assert_eq!(type_at(source, source.len() + 1), None);
+    }
+
+    #[test]
+    fn test_rename() {
+        let source = "let x = 1;\nlet f = fn x -> x + 1;\nlet y = { let x = x * 2; x };\nf(x) + y";
+        let renamed = |needle: &str, name: &str| apply_edits(source, &rename(source, source.find(needle).unwrap(), name));
+        assert_eq!(renamed("x = 1", "one"), "let one = 1;\nlet f = fn x -> x + 1;\nlet y = { let x = one * 2; x };\nf(one) + y");
+        assert_eq!(renamed("x -> x", "n"), "let x = 1;\nlet f = fn n -> n + 1;\nlet y = { let x = x * 2; x };\nf(x) + y");
+        assert_eq!(renamed("x }", "z"), "let x = 1;\nlet f = fn x -> x + 1;\nlet y = { let z = x * 2; z };\nf(x) + y");
+        // Clashes with another binding in the same scope
+        assert!(rename(source, source.find("f =").unwrap(), "y").is_empty());
+        assert!(rename(source, source.find("x = 1").unwrap(), "f").is_empty());
+        assert!(rename(source, source.find("y = {").unwrap(), "x").is_empty());
+        assert!(rename(source, source.find("1;").unwrap(), "z").is_empty());
+        assert!(rename(source, source.find("x = 1").unwrap(), "let").is_empty());
+        // A punned field keeps its name
+        let source = "let x = 1;\n{ x, y = x }";
+        assert_eq!(apply_edits(source, &rename(source, 4, "n")), "let n = 1;\n{ x = n, y = n }");
+    }
+
+    #[test]
+    fn test_definitions_and_references() {
+        let source = "type shape = | circle of int | dot;\ntype point = { x: int, y: int };\nlet origin: point = { x = 0, y = 0 };\nlet f = fn p -> p.x + origin.x;\nlet s = circle(f(origin));";
+        let at = |needle: &str| source.find(needle).unwrap();
+        let text = |span: Span| source.chars().skip(span.start).take(span.end - span.start).collect::<String>();
+        let lines = |spans: Vec<Span>| spans.iter().map(|span| (text(*span), span.line)).collect::<Vec<_>>();
+        assert_eq!(definition_at(source, at("origin)")).map(|span| span.line), Some(3));
+        assert_eq!(lines(references_at(source, at("origin:"))), vec![("origin".to_string(), 4), ("origin".to_string(), 5)]);
+        assert_eq!(lines(references_at(source, at("p ->"))), vec![("p".to_string(), 4)]);
+        assert_eq!(definition_at(source, at("x = 0")).map(|span| span.line), Some(2));
+        assert_eq!(lines(references_at(source, at("x: int"))).len(), 3);
+        assert_eq!(definition_at(source, at("point = { x = 0")).map(|span| span.line), Some(2));
+        assert_eq!(lines(references_at(source, at("circle of"))), vec![("circle".to_string(), 5)]);
+        assert_eq!(definition_at(source, at("int |")), None);
+        assert_eq!(definition_at(source, at("0 }")), None);
+    }
+}
@@ -1,16 +1,149 @@
-use crate::lexer::Token;
-pub type Program = Vec<Statement>;
+use crate::lexer::{Span, Token};
+use crate::parser::ParseErrors;
+use crate::record::FieldCache;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A parsed source file. Derefs to its statements.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+    // One per statement, from its first token to its last
+    pub spans: Vec<Span>,
+    // The file the program was read from, if any
+    pub source_id: Option<String>,
+    // Every comment, nested ones included, in source order
+    pub comments: Vec<(String, Span)>,
+    // Names of variables where they are bound or used, i.e. those of lets,
+    // parameters and identifier expressions, in source order. Like `names`,
+    // cleared once macros are expanded, as expansion moves code around.
+    pub identifiers: Vec<(String, Span)>,
+    // Type, variant and record field names, in source order
+    pub names: Vec<Name>,
+    // Every macro known while parsing, see `macros::expand`
+    pub macros: Vec<Macro>,
+    // Every rewrite rule known while parsing, see `rewrite`
+    pub rules: Vec<Rule>,
+    // The files `include_str` and `include_json` read, or tried to
+    pub includes: Vec<PathBuf>,
+    pub errors: ParseErrors,
+    pub span: Span,
+}
+
+impl Program {
+    pub fn with_source_id(mut self, source_id: &str) -> Self {
+        self.source_id = Some(source_id.to_string());
+        self
+    }
+
+    /// Statements with their spans.
+    pub fn spanned(&self) -> impl Iterator<Item = (&Statement, Span)> {
+        self.statements.iter().zip(self.spans.iter().copied())
+    }
+}
+
+impl Deref for Program {
+    type Target = Vec<Statement>;
+
+    fn deref(&self) -> &Vec<Statement> {
+        &self.statements
+    }
+}
+
+impl DerefMut for Program {
+    fn deref_mut(&mut self) -> &mut Vec<Statement> {
+        &mut self.statements
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = &'a Statement;
+    type IntoIter = std::slice::Iter<'a, Statement>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.statements.iter()
+    }
+}
+
+/// e.g. `macro unless(c, then) -> if $c { () } else { $then };`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub parameters: Vec<String>,
+    // `$name` splices are identifier expressions, or let names, of
+    // `Token::Splice`
+    pub template: Block,
+    pub span: Span,
+}
+
+/// e.g. `rule "map_map": map(f, map(g, xs)) => map(fn x -> f(g(x)), xs);`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: Expression,
+    pub replacement: Expression,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum NameKind {
+    Type,
+    Variant,
+    Field,
+}
+
+/// A name outside of expressions, e.g. `Circle` in
+/// `type Shape = | Circle of float;`, where it is declared.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Name {
+    pub kind: NameKind,
+    pub text: String,
+    pub span: Span,
+    // Whether the name is declared here, by a `type` statement
+    pub declaration: bool,
+}
+
+// The statements of a block, function body or match arm
+pub type Block = Vec<Statement>;
 
 // FIXME: This should simply be a 'String.
 pub type Identifier = Token;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Statement {
-    Let(Identifier, Expression),
+    // Spans here and on `Infix` locate runtime errors; that of a `let` is
+    // the bound name's, that of an infix the operator's
+    Let(Identifier, Expression, Span),
     Return(Expression),
     Comment(Identifier),
     Expression(Expression),
-    Type(Identifier, Type),
+    // With the traits derived for it, e.g. `@derive(enum)`
+    Type(Identifier, Type, Vec<Identifier>),
+    // e.g. @allow(unused_variable)
+    Attribute(Identifier, Vec<Identifier>),
+    // e.g. assert x > 0, "x must be positive";
+    Assert(Assertion),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AssertionKind {
+    Assert,
+    // Contract clauses of a function, only checked in debug mode. They are
+    // kept at the start of the function's body.
+    Requires,
+    Ensures,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Assertion {
+    pub kind: AssertionKind,
+    pub condition: Expression,
+    // Source text of the condition, for the failure message
+    pub source: String,
+    pub message: Option<String>,
+    pub span: Span,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -41,35 +174,148 @@ pub enum Expression {
     Literal(Literal),
     // Expression Variants
     Prefix(Prefix, Box<Expression>),
-    Infix(Infix, Box<Expression>, Box<Expression>),
+    Infix(Infix, Box<Expression>, Box<Expression>, Span),
     // Control Flow
-    Block(Program),
+    Block(Block),
     If {
         condition: Box<Expression>,
-        consequence: Program,
-        alternative: Option<Program>,
+        consequence: Block,
+        alternative: Option<Block>,
     },
     Function {
         parameters: Vec<Identifier>,
-        body: Program,
+        // Shared with every closure created from this expression
+        body: Rc<Block>,
+        // The variables the body uses from around the literal, see
+        // `closure::annotate`; None to close over every scope around it
+        captures: Option<Vec<String>>,
     },
+    // Spans of calls are those of their `(`, of builtins their name's
     Call {
         function: Box<Expression>,
         arguments: Vec<Expression>,
+        span: Span,
     },
     Match {
         expr: Box<Expression>,
-        arms: Vec<(Pattern, Program)>,
+        arms: Vec<(Pattern, Block)>,
     },
     BuiltIn {
         function: Identifier,
         arguments: Vec<Expression>,
+        span: Span,
+    },
+    // e.g. config.name, or config?.name, which is None when config is
+    Field {
+        record: Box<Expression>,
+        field: Identifier,
+        optional: bool,
+        cache: FieldCache,
     },
     // Range expression [start..end]
     Range {
         start: Box<Expression>,
         end: Box<Expression>,
     },
+    // The right hand side of an annotated let, e.g. `let port: int = config.port;`
+    Annotated {
+        expression: Box<Expression>,
+        annotation: Alias,
+    },
+    // `quote expr`, the expression unevaluated as a value, and its source
+    // text, see `quote`
+    Quote(Rc<Expression>, Rc<str>),
+}
+
+impl Expression {
+    /// Location of the operator, call or builtin call, for the expressions
+    /// that keep one.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expression::Infix(_, _, _, span) | Expression::Call { span, .. } | Expression::BuiltIn { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+}
+
+// Taken apart one level at a time, as the tree of a long operator chain is
+// as deep as the chain is long
+impl Drop for Expression {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.detach(&mut pending);
+        while let Some(mut expression) = pending.pop() {
+            expression.detach(&mut pending);
+        }
+    }
+}
+
+impl Expression {
+    /// Moves the expression out, leaving `None` in its place.
+    pub fn take(&mut self) -> Expression {
+        std::mem::replace(self, Expression::OptionNone)
+    }
+
+    // Moves the subexpressions this one owns into `pending`
+    fn detach(&mut self, pending: &mut Vec<Expression>) {
+        let mut take = |expression: &mut Expression| pending.push(expression.take());
+        match self {
+            Expression::Identifier(_) | Expression::OptionNone => {}
+            Expression::OptionSome(inner)
+            | Expression::ResultOk(inner)
+            | Expression::ResultErr(inner)
+            | Expression::Prefix(_, inner)
+            | Expression::Field { record: inner, .. }
+            | Expression::Annotated { expression: inner, .. } => take(inner),
+            Expression::Literal(Literal::List(elements)) => elements.iter_mut().for_each(take),
+            Expression::Literal(Literal::Record(fields)) => fields.iter_mut().for_each(|(_, value)| take(value)),
+            Expression::Literal(Literal::HashMap(entries)) => entries.iter_mut().for_each(|(key, value)| {
+                take(key);
+                take(value);
+            }),
+            Expression::Literal(_) => {}
+            Expression::Infix(_, left, right, _) | Expression::Range { start: left, end: right } => {
+                take(left);
+                take(right);
+            }
+            Expression::Block(block) => block.iter_mut().for_each(|statement| statement.detach(&mut take)),
+            // Bodies and quotes shared with closures or values are left to
+            // their last owner
+            Expression::Function { body, .. } => {
+                if let Some(body) = Rc::get_mut(body) {
+                    body.iter_mut().for_each(|statement| statement.detach(&mut take));
+                }
+            }
+            Expression::Quote(quoted, _) => {
+                if let Some(quoted) = Rc::get_mut(quoted) {
+                    take(quoted);
+                }
+            }
+            Expression::If { condition, consequence, alternative } => {
+                take(condition);
+                consequence.iter_mut().chain(alternative.iter_mut().flatten()).for_each(|statement| statement.detach(&mut take));
+            }
+            Expression::Call { function, arguments, .. } => {
+                take(function);
+                arguments.iter_mut().for_each(take);
+            }
+            Expression::BuiltIn { arguments, .. } => arguments.iter_mut().for_each(take),
+            Expression::Match { expr, arms } => {
+                take(expr);
+                arms.iter_mut().flat_map(|(_, body)| body.iter_mut()).for_each(|statement| statement.detach(&mut take));
+            }
+        }
+    }
+}
+
+impl Statement {
+    fn detach(&mut self, take: &mut impl FnMut(&mut Expression)) {
+        match self {
+            Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => take(expression),
+            Statement::Assert(assertion) => take(&mut assertion.condition),
+            Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -109,6 +355,8 @@ pub enum Constructor {
     Result,
     HashMap,
     Unit,
+    // Checked at runtime instead, see `Expression::Annotated`
+    Dynamic,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -143,9 +391,71 @@ pub enum Infix {
     LessThan,
     GTOrEqual,
     LTOrEqual,
+    ShiftLeft,
+    ShiftRight,
     Caret,
     Modulo,
     Ampersand,
     Cons,
     Pipe,
+    In,
+    Coalesce, // ??
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Prefix::Plus => "+",
+            Prefix::Minus => "-",
+            Prefix::Bang => "!",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for Infix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Infix::Plus => "+",
+            Infix::Minus => "-",
+            Infix::Concat => "++",
+            Infix::Product => "*",
+            Infix::ForwardSlash => "/",
+            Infix::Equal => "==",
+            Infix::DoesNotEqual => "!=",
+            Infix::GreaterThan => ">",
+            Infix::LessThan => "<",
+            Infix::GTOrEqual => ">=",
+            Infix::LTOrEqual => "<=",
+            Infix::ShiftLeft => "<<",
+            Infix::ShiftRight => ">>",
+            Infix::Caret => "^",
+            Infix::Modulo => "%",
+            Infix::Ampersand => "&",
+            Infix::Cons => "::",
+            Infix::Pipe => "|>",
+            Infix::In => "in",
+            Infix::Coalesce => "??",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+// As written in a type annotation, e.g. `result * (int, string)`
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match &self.name {
+            TypeConstructor::BuiltIn(constructor) => format!("{:?}", constructor).to_lowercase(),
+            TypeConstructor::Custom(name) => name.to_string(),
+        };
+        match self.parameters.as_slice() {
+            [] => write!(f, "{}", name),
+            [param] if param.parameters.is_empty() => write!(f, "{} * {}", name, param),
+            [param] => write!(f, "{} * ({})", name, param),
+            params => {
+                let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                write!(f, "{} * ({})", name, params.join(", "))
+            }
+        }
+    }
 }
@@ -0,0 +1,371 @@
+// binary.rs
+//
+// opl-bin, a compact self-describing encoding of data values. A buffer is the
+// magic bytes `OPLB`, a format version, then one encoded value. Every value
+// starts with a tag byte; integers and lengths are LEB128 varints, integers
+// zigzag encoded first, and only the shortest encoding of a varint is
+// accepted. Functions and errors have no encoding.
+
+use std::fmt;
+
+use crate::deque::Deque;
+use crate::map::Map;
+use crate::object::Object;
+use crate::set::Set;
+
+const MAGIC: &[u8; 4] = b"OPLB";
+const VERSION: u8 = 1;
+// Deeper values are rejected rather than risk overflowing the stack
+const MAX_DEPTH: usize = 512;
+
+const UNIT: u8 = 0;
+const INTEGER: u8 = 1;
+const FLOAT: u8 = 2;
+const BOOLEAN: u8 = 3;
+const STRING: u8 = 4;
+const LIST: u8 = 5;
+const SOME: u8 = 6;
+const NONE: u8 = 7;
+const OK: u8 = 8;
+const ERR: u8 = 9;
+const RECORD: u8 = 10;
+const MAP: u8 = 11;
+const SET: u8 = 12;
+const DEQUE: u8 = 13;
+// The union's and variant's names, then whether there is a payload
+const VARIANT: u8 = 14;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum BinaryError {
+    // The `type_name` of a value that cannot be encoded
+    Unserializable(String),
+    TooDeep,
+    BadHeader,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    InvalidVarint,
+    UnknownTag(u8),
+    InvalidUtf8,
+    // A map key or set element that cannot be one, or a repeated one
+    InvalidKey,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryError::Unserializable(ty) => write!(f, "values of type {} cannot be serialized", ty),
+            BinaryError::TooDeep => write!(f, "value is nested more than {} levels deep", MAX_DEPTH),
+            BinaryError::BadHeader => write!(f, "not an opl-bin buffer"),
+            BinaryError::UnsupportedVersion(version) => write!(f, "unsupported opl-bin version {}", version),
+            BinaryError::UnexpectedEnd => write!(f, "buffer ends in the middle of a value"),
+            BinaryError::InvalidVarint => write!(f, "integer is longer than 64 bits or not in its shortest encoding"),
+            BinaryError::UnknownTag(tag) => write!(f, "unknown value tag {}", tag),
+            BinaryError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            BinaryError::InvalidKey => write!(f, "map key or set element is not hashable or is repeated"),
+            BinaryError::TrailingBytes(count) => write!(f, "{} bytes left after the value", count),
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+impl Object {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        encode(self, &mut out, 0)?;
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, BinaryError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(BinaryError::BadHeader);
+        }
+        match reader.byte()? {
+            VERSION => {}
+            version => return Err(BinaryError::UnsupportedVersion(version)),
+        }
+        let value = reader.value(0)?;
+        match bytes.len() - reader.position {
+            0 => Ok(value),
+            left => Err(BinaryError::TrailingBytes(left)),
+        }
+    }
+}
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_str(value: &str, out: &mut Vec<u8>) {
+    write_varint(value.len() as u64, out);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn encode(object: &Object, out: &mut Vec<u8>, depth: usize) -> Result<(), BinaryError> {
+    if depth > MAX_DEPTH {
+        return Err(BinaryError::TooDeep);
+    }
+    match object {
+        Object::Unit => out.push(UNIT),
+        Object::Integer(value) => {
+            out.push(INTEGER);
+            write_varint(((value << 1) ^ (value >> 63)) as u64, out);
+        }
+        Object::Float(value) => {
+            out.push(FLOAT);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        Object::Boolean(value) => out.extend_from_slice(&[BOOLEAN, u8::from(*value)]),
+        Object::String(value) => {
+            out.push(STRING);
+            write_str(value.as_str(), out);
+        }
+        Object::List(elements) => {
+            out.push(LIST);
+            write_varint(elements.len() as u64, out);
+            for element in elements {
+                encode(element, out, depth + 1)?;
+            }
+        }
+        Object::Record(fields) => {
+            out.push(RECORD);
+            write_varint(fields.len() as u64, out);
+            for (name, value) in fields {
+                write_str(name, out);
+                encode(value, out, depth + 1)?;
+            }
+        }
+        Object::OptionSome(inner) | Object::ResultOk(inner) | Object::ResultErr(inner) => {
+            out.push(match object {
+                Object::OptionSome(_) => SOME,
+                Object::ResultOk(_) => OK,
+                _ => ERR,
+            });
+            encode(inner, out, depth + 1)?;
+        }
+        Object::Map(map) => {
+            out.push(MAP);
+            write_varint(map.len() as u64, out);
+            for (key, value) in map.iter() {
+                encode(key, out, depth + 1)?;
+                encode(value, out, depth + 1)?;
+            }
+        }
+        Object::Set(set) => {
+            out.push(SET);
+            write_varint(set.len() as u64, out);
+            for element in set.iter() {
+                encode(element, out, depth + 1)?;
+            }
+        }
+        Object::Deque(deque) => {
+            out.push(DEQUE);
+            let elements = deque.to_vec();
+            write_varint(elements.len() as u64, out);
+            for element in &elements {
+                encode(element, out, depth + 1)?;
+            }
+        }
+        Object::Variant(union, variant, payload) => {
+            out.push(VARIANT);
+            write_str(union, out);
+            write_str(variant, out);
+            out.push(u8::from(payload.is_some()));
+            if let Some(payload) = payload {
+                encode(payload, out, depth + 1)?;
+            }
+        }
+        Object::OptionNone => out.push(NONE),
+        Object::Return(inner) => encode(inner, out, depth)?,
+        Object::Function(..) | Object::Builtin(_) | Object::Native(_) | Object::Handle(_) | Object::Quoted(_) | Object::Error(..) => {
+            return Err(BinaryError::Unserializable(object.type_name()));
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], BinaryError> {
+        let end = self.position.checked_add(count).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or(BinaryError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, BinaryError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            // The tenth byte holds just the top bit, and a last byte of zero
+            // after others would only lengthen the encoding
+            if (shift == 63 && bits > 1) || (shift > 0 && byte == 0) {
+                return Err(BinaryError::InvalidVarint);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BinaryError::InvalidVarint)
+    }
+
+    fn values(&mut self, depth: usize) -> Result<Vec<Object>, BinaryError> {
+        let count = self.length()?;
+        let mut elements = Vec::with_capacity(count);
+        for _ in 0..count {
+            elements.push(self.value(depth + 1)?);
+        }
+        Ok(elements)
+    }
+
+    // A length, checked against what is left so a corrupt buffer cannot
+    // request a huge allocation
+    fn length(&mut self) -> Result<usize, BinaryError> {
+        let length = self.varint()?;
+        usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= self.bytes.len() - self.position)
+            .ok_or(BinaryError::UnexpectedEnd)
+    }
+
+    fn string(&mut self) -> Result<String, BinaryError> {
+        let length = self.length()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BinaryError::InvalidUtf8)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Object, BinaryError> {
+        if depth > MAX_DEPTH {
+            return Err(BinaryError::TooDeep);
+        }
+        Ok(match self.byte()? {
+            UNIT => Object::Unit,
+            INTEGER => {
+                let value = self.varint()?;
+                Object::Integer((value >> 1) as i64 ^ -((value & 1) as i64))
+            }
+            FLOAT => Object::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            BOOLEAN => match self.byte()? {
+                0 => Object::Boolean(false),
+                1 => Object::Boolean(true),
+                _ => return Err(BinaryError::UnknownTag(BOOLEAN)),
+            },
+            STRING => Object::from(self.string()?),
+            LIST => Object::List(self.values(depth)?),
+            RECORD => {
+                let count = self.length()?;
+                let mut fields = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let name = self.string()?;
+                    fields.push((name, self.value(depth + 1)?));
+                }
+                Object::record(fields)
+            }
+            MAP => {
+                let count = self.length()?;
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    entries.push((self.value(depth + 1)?, self.value(depth + 1)?));
+                }
+                let map = Map::from_entries(entries).map_err(|_| BinaryError::InvalidKey)?;
+                if map.len() != count {
+                    return Err(BinaryError::InvalidKey);
+                }
+                Object::Map(map)
+            }
+            SET => {
+                let elements = self.values(depth)?;
+                let count = elements.len();
+                let set = Set::from_elements(elements).map_err(|_| BinaryError::InvalidKey)?;
+                if set.len() != count {
+                    return Err(BinaryError::InvalidKey);
+                }
+                Object::Set(set)
+            }
+            DEQUE => Object::Deque(Deque::from_elements(self.values(depth)?)),
+            VARIANT => {
+                let (union, variant) = (self.string()?, self.string()?);
+                let payload = match self.byte()? {
+                    0 => None,
+                    1 => Some(Box::new(self.value(depth + 1)?)),
+                    _ => return Err(BinaryError::UnknownTag(VARIANT)),
+                };
+                Object::Variant(union, variant, payload)
+            }
+            SOME => Object::OptionSome(Box::new(self.value(depth + 1)?)),
+            NONE => Object::OptionNone,
+            OK => Object::ResultOk(Box::new(self.value(depth + 1)?)),
+            ERR => Object::ResultErr(Box::new(self.value(depth + 1)?)),
+            tag => return Err(BinaryError::UnknownTag(tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let value = Object::record(vec![
+            ("id".to_string(), Object::Integer(-300)),
+            ("ratio".to_string(), Object::Float(0.25)),
+            ("tags".to_string(), Object::List(vec![Object::from("a"), Object::from("é")])),
+            ("parent".to_string(), Object::OptionSome(Box::new(Object::ResultErr(Box::new(Object::Unit))))),
+            ("done".to_string(), Object::Boolean(true)),
+            ("next".to_string(), Object::OptionNone),
+        ]);
+        let bytes = value.to_bytes().unwrap();
+        assert_eq!(Object::from_bytes(&bytes), Ok(value));
+        assert_eq!(Object::Integer(-1).to_bytes().unwrap(), b"OPLB\x01\x01\x01");
+
+        assert_eq!(Object::from_bytes(&bytes[..bytes.len() - 1]), Err(BinaryError::UnexpectedEnd));
+        assert_eq!(Object::from_bytes(b"OPLB\x01\x05\xff\xff\x03"), Err(BinaryError::UnexpectedEnd));
+        let builtin = Object::Builtin(|_| Object::Unit);
+        assert_eq!(builtin.to_bytes(), Err(BinaryError::Unserializable("builtin".to_string())));
+    }
+
+    #[test]
+    fn test_round_trip_collections() {
+        let map = Map::from_entries([(Object::from("a"), Object::Integer(1)), (Object::Integer(2), Object::Boolean(false))]).unwrap();
+        let set = Set::from_elements([Object::Integer(3), Object::from("b")]).unwrap();
+        let deque = Deque::from_elements(vec![Object::Integer(4), Object::Unit]);
+        let leaf = Object::Variant("tree".to_string(), "leaf".to_string(), None);
+        let node = Object::Variant("tree".to_string(), "node".to_string(), Some(Box::new(Object::Integer(5))));
+        let value = Object::List(vec![Object::Map(map), Object::Set(set), Object::Deque(deque), leaf, node]);
+        assert_eq!(Object::from_bytes(&value.to_bytes().unwrap()), Ok(value));
+
+        // The same key twice
+        assert_eq!(Object::from_bytes(b"OPLB\x01\x0c\x02\x01\x02\x01\x02"), Err(BinaryError::InvalidKey));
+    }
+
+    #[test]
+    fn test_varints_are_strict() {
+        // 1 in two bytes
+        assert_eq!(Object::from_bytes(b"OPLB\x01\x01\x82\x00"), Err(BinaryError::InvalidVarint));
+        // Bits past the 64th
+        let too_wide = b"OPLB\x01\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x02";
+        assert_eq!(Object::from_bytes(too_wide), Err(BinaryError::InvalidVarint));
+        for value in [i64::MIN, i64::MAX, 0, -64, 64] {
+            assert_eq!(Object::from_bytes(&Object::Integer(value).to_bytes().unwrap()), Ok(Object::Integer(value)));
+        }
+    }
+}
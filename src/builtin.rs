@@ -1,13 +1,237 @@
+use crate::codec;
+use crate::diagnostics::Code;
+use crate::effects::Effects;
 use crate::object::Object;
+use crate::session;
 use crate::environment::Env;
-use crate::evaluator::Evaluator;
+use crate::ast::Infix;
+use crate::evaluator::{integer_arithmetic, Evaluator};
 use crate::lexer::Token;
+use crate::string::Str;
 use std::rc::Rc;
 use std::cell::RefCell;
 
+pub const BUILTINS: [&str; 48] = [
+    "map", "filter", "fold", "any", "all", "println", "concat_all", "hash",
+    "bit_and", "bit_or", "bit_xor", "bit_not", "popcount", "leading_zeros",
+    "divmod", "div_euclid", "rem_euclid", "show", "format", "dbg",
+    "to_char_code", "from_char_code", "utf8_bytes", "from_utf8",
+    "base64_encode", "base64_decode", "hex_encode", "hex_decode",
+    "sort_by", "sort_by_key", "group_by", "count_by", "sum_by", "min_by", "max_by", "partition",
+    "chunks", "windows", "intersperse", "transpose", "exit",
+    "actor", "send", "sleep", "spawn", "spawn_link", "await", "eval_quoted",
+];
+
+/// Builtins that are records of functions, e.g. `set.union`.
+pub const MODULES: [&str; 13] = [
+    "float", "hashmap", "set", "deque", "table", "stdin", "file", "timer", "template", "csv", "toml", "yaml", "tcp",
+];
+
+/// The members of the module `name`, when it is one this build has.
+pub fn members(name: &str) -> Option<Vec<String>> {
+    match &lookup(name).filter(|_| MODULES.contains(&name))? {
+        Object::Record(members) => Some(members.names().cloned().collect()),
+        _ => None,
+    }
+}
+
+/// Builtins that are ordinary identifiers rather than keywords. They are
+/// found when no binding of the same name is in scope, so scripts may shadow
+/// them.
+pub fn lookup(name: &str) -> Option<Object> {
+    let function: fn(Vec<Object>) -> Object = match name {
+        "hash" => hash_builtin,
+        "bit_and" => |args| binary_bits("bit_and", args, |a, b| a & b),
+        "bit_or" => |args| binary_bits("bit_or", args, |a, b| a | b),
+        "bit_xor" => |args| binary_bits("bit_xor", args, |a, b| a ^ b),
+        "bit_not" => |args| unary_bits("bit_not", args, |a| !a),
+        "popcount" => |args| unary_bits("popcount", args, |a| i64::from(a.count_ones())),
+        "leading_zeros" => |args| unary_bits("leading_zeros", args, |a| i64::from(a.leading_zeros())),
+        "divmod" => divmod_builtin,
+        "div_euclid" => |args| euclid("div_euclid", args, i64::checked_div_euclid),
+        "rem_euclid" => |args| euclid("rem_euclid", args, i64::checked_rem_euclid),
+        "to_char_code" => codec::to_char_code_builtin,
+        "from_char_code" => codec::from_char_code_builtin,
+        "utf8_bytes" => codec::utf8_bytes_builtin,
+        "from_utf8" => codec::from_utf8_builtin,
+        "base64_encode" => codec::base64_encode_builtin,
+        "base64_decode" => codec::base64_decode_builtin,
+        "hex_encode" => codec::hex_encode_builtin,
+        "hex_decode" => codec::hex_decode_builtin,
+        "show" => show_builtin,
+        "format" => crate::format::format_builtin,
+        "exit" => exit_builtin,
+        "actor" => crate::actor::actor_builtin,
+        "send" => crate::actor::send_builtin,
+        "sleep" => crate::timer::sleep_builtin,
+        "spawn" => crate::task::spawn_builtin,
+        "spawn_link" => crate::task::spawn_link_builtin,
+        "await" => crate::task::await_builtin,
+        "sort_by" => crate::list::sort_by_builtin,
+        "sort_by_key" => crate::list::sort_by_key_builtin,
+        "group_by" => crate::list::group_by_builtin,
+        "count_by" => crate::list::count_by_builtin,
+        "sum_by" => crate::list::sum_by_builtin,
+        "min_by" => crate::list::min_by_builtin,
+        "max_by" => crate::list::max_by_builtin,
+        "partition" => crate::list::partition_builtin,
+        "chunks" => crate::list::chunks_builtin,
+        "windows" => crate::list::windows_builtin,
+        "intersperse" => crate::list::intersperse_builtin,
+        "transpose" => crate::list::transpose_builtin,
+        "float" => return Some(float_module()),
+        "hashmap" => return Some(crate::map::module()),
+        "set" => return Some(crate::set::module()),
+        "deque" => return Some(crate::deque::module()),
+        "table" => return Some(crate::table::module()),
+        "stdin" => return Some(crate::stdin::module()),
+        "file" => return Some(crate::file::module()),
+        "timer" => return Some(crate::timer::module()),
+        "template" => return Some(crate::template::module()),
+        #[cfg(feature = "csv")]
+        "csv" => return Some(crate::csv::module()),
+        #[cfg(feature = "toml")]
+        "toml" => return Some(crate::toml::module()),
+        #[cfg(feature = "yaml")]
+        "yaml" => return Some(crate::yaml::module()),
+        #[cfg(feature = "net")]
+        "tcp" => return Some(crate::tcp::module()),
+        _ => return None,
+    };
+    Some(Object::Builtin(function))
+}
+
+/// Effects of calling the builtin `name`; most have none.
+pub fn effects(name: &str) -> Effects {
+    match name {
+        // A module is as effectful as its most effectful function
+        "println" | "dbg" | "table" | "stdin" => Effects::IO,
+        "file" => Effects::FS,
+        "exit" => Effects::PROCESS,
+        "sleep" | "timer" => Effects::TIME,
+        "tcp" => Effects::NET,
+        // Quoted code may do anything
+        "eval_quoted" => Effects::ALL,
+        _ => Effects::NONE,
+    }
+}
+
+pub fn hash_builtin(args: Vec<Object>) -> Object {
+    if args.len() != 1 {
+        return Object::Error(Code::BuiltinArgument, "hash expects exactly one argument".to_string());
+    }
+
+    match args[0].stable_hash() {
+        Some(hash) => Object::Integer(hash as i64),
+        None => Object::Error(Code::Unhashable, format!("Values of type {} cannot be hashed", args[0].type_name())),
+    }
+}
+
+// The int arguments of a bit builtin, or the error to return
+fn integers<const N: usize>(name: &str, args: Vec<Object>) -> Result<[i64; N], Object> {
+    let plural = if N == 1 { "" } else { "s" };
+    let args: [Object; N] = args
+        .try_into()
+        .map_err(|_| Object::Error(Code::BuiltinArgument, format!("{} expects exactly {} int argument{}", name, N, plural)))?;
+    let mut values = [0; N];
+    for (value, arg) in values.iter_mut().zip(args) {
+        *value = i64::try_from(arg).map_err(|e| Object::Error(Code::BuiltinArgument, format!("{}: {}", name, e)))?;
+    }
+    Ok(values)
+}
+
+fn unary_bits(name: &str, args: Vec<Object>, op: fn(i64) -> i64) -> Object {
+    match integers::<1>(name, args) {
+        Ok([a]) => Object::Integer(op(a)),
+        Err(error) => error,
+    }
+}
+
+fn binary_bits(name: &str, args: Vec<Object>, op: fn(i64, i64) -> i64) -> Object {
+    match integers::<2>(name, args) {
+        Ok([a, b]) => Object::Integer(op(a, b)),
+        Err(error) => error,
+    }
+}
+
+// `[a / b, a % b]`, truncating like the operators
+fn divmod_builtin(args: Vec<Object>) -> Object {
+    let [a, b] = match integers::<2>("divmod", args) {
+        Ok(values) => values,
+        Err(error) => return error,
+    };
+    let quotient = integer_arithmetic(&Infix::ForwardSlash, a, b).unwrap();
+    let remainder = integer_arithmetic(&Infix::Modulo, a, b).unwrap();
+    match (quotient, remainder) {
+        (error @ Object::Error(..), _) | (_, error @ Object::Error(..)) => error,
+        (quotient, remainder) => Object::List(vec![quotient, remainder]),
+    }
+}
+
+// Euclidean division, whose remainder is never negative
+fn euclid(name: &str, args: Vec<Object>, op: fn(i64, i64) -> Option<i64>) -> Object {
+    match integers::<2>(name, args) {
+        Ok([_, 0]) => Object::Error(Code::DivisionByZero, format!("{}: cannot divide by zero", name)),
+        Ok([a, b]) => match op(a, b) {
+            Some(value) => Object::Integer(value),
+            None => Object::Error(Code::Overflow, format!("{}: {} by {} overflows an int", name, a, b)),
+        },
+        Err(error) => error,
+    }
+}
+
+// Any value as the REPL prints it
+fn show_builtin(args: Vec<Object>) -> Object {
+    match <[Object; 1]>::try_from(args) {
+        Ok([value]) => Object::String(Str::from(value.to_string())),
+        Err(_) => Object::Error(Code::BuiltinArgument, "show expects exactly one argument".to_string()),
+    }
+}
+
+// Stops the program by unwinding it like an error; whoever runs it decides
+// what the status means, e.g. `opl run` exits the process with it
+fn exit_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [Object::Integer(status @ 0..=255)] => Object::Error(Code::Exit(*status as i32), format!("exited with status {}", status)),
+        [Object::Integer(status)] => Object::Error(Code::BuiltinArgument, format!("exit expects a status from 0 to 255, got {}", status)),
+        _ => Object::Error(Code::BuiltinArgument, "exit expects an int status".to_string()),
+    }
+}
+
+/// `float.parse`, `float.is_nan`, `float.is_finite`, and the special values
+/// `float.nan` and `float.infinity`.
+pub fn float_module() -> Object {
+    let function = |function: fn(Vec<Object>) -> Object| Object::Builtin(function);
+    Object::record(vec![
+        ("parse".to_string(), function(float_parse_builtin)),
+        ("is_nan".to_string(), function(|args| float_test("is_nan", args, f64::is_nan))),
+        ("is_finite".to_string(), function(|args| float_test("is_finite", args, f64::is_finite))),
+        ("nan".to_string(), Object::Float(f64::NAN)),
+        ("infinity".to_string(), Object::Float(f64::INFINITY)),
+    ])
+}
+
+// Reads anything `show` prints for a float, and ints
+fn float_parse_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(text)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "float.parse expects exactly one string argument".to_string());
+    };
+    match text.as_str().trim().parse::<f64>() {
+        Ok(value) => Object::OptionSome(Box::new(Object::Float(value))),
+        Err(_) => Object::OptionNone,
+    }
+}
+
+fn float_test(name: &str, args: Vec<Object>, test: fn(f64) -> bool) -> Object {
+    match args.as_slice() {
+        [Object::Float(value)] => Object::Boolean(test(*value)),
+        _ => Object::Error(Code::BuiltinArgument, format!("float.{} expects exactly one float argument", name)),
+    }
+}
+
 pub fn filter_builtin(args: Vec<Object>) -> Object {
     if args.len() != 2 {
-        return Object::Error("filter expects exactly two arguments: function and list".to_string());
+        return Object::Error(Code::BuiltinArgument, "filter expects exactly two arguments: function and list".to_string());
     }
 
     let function = &args[0];
@@ -16,7 +240,7 @@ pub fn filter_builtin(args: Vec<Object>) -> Object {
     match (function, list) {
         (Object::Function(params, body, env), Object::List(elements)) => {
             if params.len() != 1 {
-                return Object::Error("filter function must take exactly one argument".to_string());
+                return Object::Error(Code::BuiltinArgument, "filter function must take exactly one argument".to_string());
             }
 
             let mut filtered = Vec::new();
@@ -29,24 +253,26 @@ pub fn filter_builtin(args: Vec<Object>) -> Object {
 
                 let mut evaluator = Evaluator::new(Rc::new(RefCell::new(inner_env)));
                 let result = match evaluator.eval_block(body) {
-                    Some(Object::Return(value)) => *value, // Unwrap the Return value
+                    // Errors, and `exit`, stop the iteration
+                    Some(error @ Object::Error(..)) => return error,
+                    Some(Object::Return(ref mut value)) => std::mem::take(&mut **value), // Unwrap the Return value
                     Some(value) => value,        // Use the direct result
-                    None => return Object::Error("Function returned no value".to_string()),
+                    None => return Object::Error(Code::MissingValue, "Function returned no value".to_string()),
                 };
                 
                 // Check if result is a boolean
                 match result {
                     Object::Boolean(true) => filtered.push(element.clone()),
                     Object::Boolean(false) => {}, // Skip this element
-                    _ => return Object::Error(format!("Filter function must return a boolean, got {:?}", result)),
+                    _ => return Object::Error(Code::BuiltinArgument, format!("Filter function must return a boolean, got {:?}", result)),
                 }
             }
 
             Object::List(filtered)
         }
-        (_, Object::List(_)) => Object::Error("First argument must be a function".to_string()),
-        (Object::Function(_, _, _), _) => Object::Error("Second argument must be a list".to_string()),
-        _ => Object::Error("Invalid arguments for filter".to_string()),
+        (_, Object::List(_)) => Object::Error(Code::BuiltinArgument, "First argument must be a function".to_string()),
+        (Object::Function(_, _, _), _) => Object::Error(Code::BuiltinArgument, "Second argument must be a list".to_string()),
+        _ => Object::Error(Code::BuiltinArgument, "Invalid arguments for filter".to_string()),
     }
 }
 
@@ -55,21 +281,39 @@ pub fn filter_builtin(args: Vec<Object>) -> Object {
 
 pub fn println_builtin(args: Vec<Object>) -> Object {
     if args.len() != 1 {
-        return Object::Error("println expects exactly one argument".to_string());
+        return Object::Error(Code::BuiltinArgument, "println expects exactly one argument".to_string());
     }
 
     match &args[0] {
         Object::String(s) => {
-            println!("{}", s);
+            session::print(&format!("{}\n", s));
             Object::Unit
         }
-        _ => Object::Error("println expects a string argument".to_string()),
+        _ => Object::Error(Code::BuiltinArgument, "println expects a string argument".to_string()),
+    }
+}
+
+pub fn concat_all_builtin(args: Vec<Object>) -> Object {
+    if args.len() != 1 {
+        return Object::Error(Code::BuiltinArgument, "concat_all expects exactly one argument: a list of strings".to_string());
+    }
+
+    let Object::List(elements) = &args[0] else {
+        return Object::Error(Code::BuiltinArgument, format!("concat_all expects a list of strings, got {:?}", args[0]));
+    };
+    let mut parts = Vec::with_capacity(elements.len());
+    for element in elements {
+        match element {
+            Object::String(s) => parts.push(s),
+            other => return Object::Error(Code::BuiltinArgument, format!("concat_all expects a list of strings, got {:?}", other)),
+        }
     }
+    Object::String(Str::concat_all(parts.iter().copied()))
 }
 
 pub fn map_builtin(args: Vec<Object>) -> Object {
     if args.len() != 2 {
-        return Object::Error("map expects exactly two arguments: function and list".to_string());
+        return Object::Error(Code::BuiltinArgument, "map expects exactly two arguments: function and list".to_string());
     }
 
     let function = &args[0];
@@ -78,7 +322,7 @@ pub fn map_builtin(args: Vec<Object>) -> Object {
     match (function, list) {
         (Object::Function(params, body, env), Object::List(elements)) => {
             if params.len() != 1 {
-                return Object::Error("map function must take exactly one argument".to_string());
+                return Object::Error(Code::BuiltinArgument, "map function must take exactly one argument".to_string());
             }
 
             let mut mapped = Vec::new();
@@ -91,9 +335,11 @@ pub fn map_builtin(args: Vec<Object>) -> Object {
 
                 let mut evaluator = Evaluator::new(Rc::new(RefCell::new(inner_env)));
                 match evaluator.eval_block(body) {
-                    Some(Object::Return(value)) => mapped.push(*value),
+                    // Errors, and `exit`, stop the iteration
+                    Some(error @ Object::Error(..)) => return error,
+                    Some(Object::Return(ref mut value)) => mapped.push(std::mem::take(&mut **value)),
                     Some(value) => mapped.push(value),
-                    None => return Object::Error("Function returned no value".to_string()),
+                    None => return Object::Error(Code::MissingValue, "Function returned no value".to_string()),
                 }
             }
 
@@ -102,22 +348,22 @@ pub fn map_builtin(args: Vec<Object>) -> Object {
                 let first = &mapped[0];
                 for value in &mapped {
                     if std::mem::discriminant(value) != std::mem::discriminant(first) {
-                        return Object::Error("Map function must return same type for all elements".to_string());
+                        return Object::Error(Code::HeterogeneousList, "Map function must return same type for all elements".to_string());
                     }
                 }
             }
 
             Object::List(mapped)
         }
-        (_, Object::List(_)) => Object::Error("First argument must be a function".to_string()),
-        (Object::Function(_, _, _), _) => Object::Error("Second argument must be a list".to_string()),
-        _ => Object::Error("Invalid arguments for map".to_string()),
+        (_, Object::List(_)) => Object::Error(Code::BuiltinArgument, "First argument must be a function".to_string()),
+        (Object::Function(_, _, _), _) => Object::Error(Code::BuiltinArgument, "Second argument must be a list".to_string()),
+        _ => Object::Error(Code::BuiltinArgument, "Invalid arguments for map".to_string()),
     }
 }
 
 pub fn fold_builtin(args: Vec<Object>) -> Object {
     if args.len() != 3 {
-        return Object::Error("fold expects exactly three arguments: function, initial value, and list".to_string());
+        return Object::Error(Code::BuiltinArgument, "fold expects exactly three arguments: function, initial value, and list".to_string());
     }
 
     let function = &args[0];
@@ -127,7 +373,7 @@ pub fn fold_builtin(args: Vec<Object>) -> Object {
     match (function, initial, list) {
         (Object::Function(params, body, env), initial, Object::List(elements)) => {
             if params.len() != 2 {
-                return Object::Error("fold function must take exactly two arguments: accumulator and element".to_string());
+                return Object::Error(Code::BuiltinArgument, "fold function must take exactly two arguments: accumulator and element".to_string());
             }
 
             let mut accumulator = initial.clone();
@@ -139,33 +385,35 @@ pub fn fold_builtin(args: Vec<Object>) -> Object {
                 if let Token::Identifier(ref name) = params[0] {
                     inner_env.set(name.clone(), accumulator.clone());
                 } else {
-                    return Object::Error("First parameter must be an identifier".to_string());
+                    return Object::Error(Code::BuiltinArgument, "First parameter must be an identifier".to_string());
                 }
                 
                 // Set the current element parameter
                 if let Token::Identifier(ref name) = params[1] {
                     inner_env.set(name.clone(), element.clone());
                 } else {
-                    return Object::Error("Second parameter must be an identifier".to_string());
+                    return Object::Error(Code::BuiltinArgument, "Second parameter must be an identifier".to_string());
                 }
 
                 let mut evaluator = Evaluator::new(Rc::new(RefCell::new(inner_env)));
                 match evaluator.eval_block(body) {
-                    Some(Object::Return(value)) => accumulator = *value,
+                    // Errors, and `exit`, stop the iteration
+                    Some(error @ Object::Error(..)) => return error,
+                    Some(Object::Return(ref mut value)) => accumulator = std::mem::take(&mut **value),
                     Some(value) => accumulator = value,
-                    None => return Object::Error("Function returned no value".to_string()),
+                    None => return Object::Error(Code::MissingValue, "Function returned no value".to_string()),
                 }
             }
 
             accumulator
         }
         (_, _, not_list) if !matches!(not_list, Object::List(_)) => {
-            Object::Error(format!("Third argument to fold must be a list, got {:?}", not_list))
+            Object::Error(Code::BuiltinArgument, format!("Third argument to fold must be a list, got {:?}", not_list))
         }
         (not_fn, _, _) if !matches!(not_fn, Object::Function(_, _, _)) => {
-            Object::Error(format!("First argument to fold must be a function, got {:?}", not_fn))
+            Object::Error(Code::BuiltinArgument, format!("First argument to fold must be a function, got {:?}", not_fn))
         }
-        (_, _, _) => Object::Error("Invalid arguments for fold".to_string()),
+        (_, _, _) => Object::Error(Code::BuiltinArgument, "Invalid arguments for fold".to_string()),
     }
 }
 
@@ -0,0 +1,214 @@
+// closure.rs
+//
+// Free variables of function literals. A closure needs only the variables
+// its function uses from the scopes around it, but holding on to those
+// scopes keeps alive everything bound in them, e.g. every list made by the
+// function that returned the closure. `annotate` records each literal's
+// free variables in its `captures`, and the evaluator copies just their
+// values into the closure, over the program's scope.
+//
+// Copying a value is only right once its binding is settled. A name bound
+// later by a scope around the literal, in a let after it or in an if, would
+// be found by a call of the closure after that binding ran, so such a
+// literal is left with `captures` None and closes over its whole
+// environment, as a local recursive function does.
+
+use crate::ast::*;
+use crate::lexer::Token;
+use crate::macros::{children, Child};
+use std::rc::Rc;
+
+#[derive(Default)]
+struct Scope {
+    // Names whose binding has run
+    bound: Vec<String>,
+    // Names bound by the scope's statements that have not run yet, or
+    // might not, in an if
+    later: Vec<String>,
+}
+
+// A function literal being walked
+struct Function {
+    // The index of the scope of its body
+    scope: usize,
+    free: Vec<String>,
+}
+
+// Names bound by a type declaration: the variants of a union, and the type
+// itself when it derives `enum`
+fn declared(name: &Identifier, declaration: &Type, derives: &[Identifier]) -> Vec<String> {
+    let Type::Union(variants) = declaration else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = variants.iter().map(|(variant, _)| variant.to_string()).collect();
+    if derives.iter().any(|derive| derive.to_string() == "enum") {
+        names.push(name.to_string());
+    }
+    names
+}
+
+// Names the statements bind in their scope, including in the branches of
+// ifs, which share it
+fn bindings(statements: &mut Block, names: &mut Vec<String>) {
+    for statement in statements.iter_mut() {
+        match statement {
+            Statement::Let(name, value, _) => {
+                names.push(name.to_string());
+                expression_bindings(value, names);
+            }
+            Statement::Return(value) | Statement::Expression(value) => expression_bindings(value, names),
+            Statement::Assert(assertion) => expression_bindings(&mut assertion.condition, names),
+            Statement::Type(name, declaration, derives) => names.extend(declared(name, declaration, derives)),
+            Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
+}
+
+fn expression_bindings(expression: &mut Expression, names: &mut Vec<String>) {
+    match expression {
+        // With scopes of their own
+        Expression::Block(_) | Expression::Function { .. } | Expression::Match { .. } => {}
+        Expression::If { condition, consequence, alternative } => {
+            expression_bindings(condition, names);
+            bindings(consequence, names);
+            if let Some(alternative) = alternative {
+                bindings(alternative, names);
+            }
+        }
+        expression => {
+            for child in children(expression) {
+                if let Child::Expression(expression) = child {
+                    expression_bindings(expression, names);
+                }
+            }
+        }
+    }
+}
+
+struct Annotator {
+    scopes: Vec<Scope>,
+    functions: Vec<Function>,
+}
+
+impl Annotator {
+    fn scope(&mut self, statements: &mut Block, parameters: &[Identifier]) {
+        let mut scope = Scope { bound: parameters.iter().map(Identifier::to_string).collect(), later: Vec::new() };
+        bindings(statements, &mut scope.later);
+        self.scopes.push(scope);
+        self.statements(statements, true);
+        self.scopes.pop();
+    }
+
+    // `settled` is false in the branches of an if, whose lets might not run
+    fn statements(&mut self, statements: &mut Block, settled: bool) {
+        for statement in statements.iter_mut() {
+            let names = match statement {
+                Statement::Let(name, value, _) => {
+                    self.expression(value);
+                    vec![name.to_string()]
+                }
+                Statement::Return(value) | Statement::Expression(value) => {
+                    self.expression(value);
+                    continue;
+                }
+                Statement::Assert(assertion) => {
+                    self.expression(&mut assertion.condition);
+                    continue;
+                }
+                Statement::Type(name, declaration, derives) => declared(name, declaration, derives),
+                Statement::Comment(_) | Statement::Attribute(..) => continue,
+            };
+            if settled {
+                self.scopes.last_mut().expect("a scope").bound.extend(names);
+            }
+        }
+    }
+
+    // A use of `name`, free in the functions around it that do not bind it
+    fn reference(&mut self, name: &str) {
+        let bound = self.scopes.iter().rposition(|scope| scope.bound.iter().any(|bound| bound == name));
+        for function in self.functions.iter_mut().rev() {
+            if bound.is_some_and(|scope| scope >= function.scope) {
+                break;
+            }
+            if !function.free.iter().any(|free| free == name) {
+                function.free.push(name.to_string());
+            }
+        }
+    }
+
+    // Whether the bindings of `names` outside the program's scope are settled
+    fn settled(&self, names: &[String]) -> bool {
+        names.iter().all(|name| {
+            for scope in self.scopes.iter().skip(1).rev() {
+                if scope.bound.contains(name) {
+                    return true;
+                }
+                if scope.later.contains(name) {
+                    return false;
+                }
+            }
+            true
+        })
+    }
+
+    fn expression(&mut self, expression: &mut Expression) {
+        match expression {
+            Expression::Identifier(Token::Identifier(name)) => self.reference(name),
+            Expression::Block(statements) => self.scope(statements, &[]),
+            Expression::Function { parameters, body, captures } => {
+                self.functions.push(Function { scope: self.scopes.len(), free: Vec::new() });
+                self.scope(Rc::make_mut(body), parameters);
+                let free = self.functions.pop().expect("a function").free;
+                *captures = self.settled(&free).then_some(free);
+            }
+            Expression::If { condition, consequence, alternative } => {
+                self.expression(condition);
+                self.statements(consequence, false);
+                if let Some(alternative) = alternative {
+                    self.statements(alternative, false);
+                }
+            }
+            expression => {
+                for child in children(expression) {
+                    match child {
+                        Child::Expression(expression) => self.expression(expression),
+                        Child::Block(block) => self.scope(block, &[]),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets the `captures` of every function literal in `program`.
+pub fn annotate(program: &mut Program) {
+    let mut annotator = Annotator { scopes: Vec::new(), functions: Vec::new() };
+    annotator.scope(&mut program.statements, &[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::Env;
+    use crate::object::Object;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_closure_captures() {
+        let env = Rc::new(RefCell::new(Env::new()));
+        let mut eval = crate::evaluator::test_eval_with(Rc::clone(&env));
+
+        eval("let make = fn n -> {\n  let big = 1..1000;\n  fn x -> x + n + offset;\n};\nlet offset = 10;\nlet add = make(1);");
+        let Some(Object::Function(_, _, ref captured)) = env.borrow_mut().get("add".to_string()) else {
+            panic!("add is not a function");
+        };
+        assert_eq!(captured.borrow().store.keys().collect::<Vec<_>>(), vec!["n"]);
+        assert!(Rc::ptr_eq(captured.borrow().outer.as_ref().unwrap(), &env));
+        assert_eq!(eval("add(2)"), Some(Object::Integer(13)));
+
+        // Bound after the literal, so found when the closure is called
+        let source = "let x = 1;\nlet later = fn () -> { let g = fn () -> x; let x = 2; g() };\nlet count = fn n -> { let go = fn i -> if i == 0 { 0 } else { go(i - 1) + 1 }; go(n) };\n[later(), count(3)]";
+        assert_eq!(eval(source), Some(Object::List(vec![Object::Integer(2), Object::Integer(3)])));
+    }
+}
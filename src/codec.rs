@@ -0,0 +1,182 @@
+// codec.rs
+//
+// Builtins converting between strings, char codes and bytes. Bytes are lists
+// of ints from 0 to 255. The encoders take a string (its UTF-8 bytes) or a
+// list of bytes; anything that decodes returns a `Result` with the reason as
+// a string.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::string::Str;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn ok(value: Object) -> Object {
+    Object::ResultOk(Box::new(value))
+}
+
+fn err(message: String) -> Object {
+    Object::ResultErr(Box::new(Object::String(Str::from(message))))
+}
+
+fn byte_list(bytes: impl IntoIterator<Item = u8>) -> Object {
+    Object::List(bytes.into_iter().map(|byte| Object::Integer(i64::from(byte))).collect())
+}
+
+// The bytes an encoder works on: those of a string, or a list of bytes
+fn bytes(name: &str, args: &[Object]) -> Result<Vec<u8>, Object> {
+    match args {
+        [Object::String(s)] => Ok(s.as_str().as_bytes().to_vec()),
+        [Object::List(elements)] => elements
+            .iter()
+            .map(|element| match element {
+                Object::Integer(value) => u8::try_from(*value)
+                    .map_err(|_| Object::Error(Code::BuiltinArgument, format!("{}: {} is not a byte, bytes are between 0 and 255", name, value))),
+                other => Err(Object::Error(Code::BuiltinArgument, format!("{}: expected a list of bytes, got {}", name, other.type_name()))),
+            })
+            .collect(),
+        _ => Err(Object::Error(Code::BuiltinArgument, format!("{} expects a string or a list of bytes", name))),
+    }
+}
+
+fn string_argument<'a>(name: &str, args: &'a [Object]) -> Result<&'a str, Object> {
+    match args {
+        [Object::String(s)] => Ok(s.as_str()),
+        _ => Err(Object::Error(Code::BuiltinArgument, format!("{} expects a string", name))),
+    }
+}
+
+pub fn to_char_code_builtin(args: Vec<Object>) -> Object {
+    let s = match string_argument("to_char_code", &args) {
+        Ok(s) => s,
+        Err(error) => return error,
+    };
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => ok(Object::Integer(i64::from(u32::from(c)))),
+        _ => err(format!("expected a single character, got {:?}", s)),
+    }
+}
+
+pub fn from_char_code_builtin(args: Vec<Object>) -> Object {
+    let [Object::Integer(code)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "from_char_code expects an int".to_string());
+    };
+    match u32::try_from(*code).ok().and_then(char::from_u32) {
+        Some(c) => ok(Object::from(c.to_string())),
+        None => err(format!("{} is not a unicode scalar value", code)),
+    }
+}
+
+pub fn utf8_bytes_builtin(args: Vec<Object>) -> Object {
+    match string_argument("utf8_bytes", &args) {
+        Ok(s) => byte_list(s.bytes()),
+        Err(error) => error,
+    }
+}
+
+pub fn from_utf8_builtin(args: Vec<Object>) -> Object {
+    let bytes = match args.as_slice() {
+        [Object::List(_)] => bytes("from_utf8", &args),
+        _ => return Object::Error(Code::BuiltinArgument, "from_utf8 expects a list of bytes".to_string()),
+    };
+    match bytes.map(String::from_utf8) {
+        Ok(Ok(s)) => ok(Object::from(s)),
+        Ok(Err(e)) => err(format!("invalid UTF-8 at byte {}", e.utf8_error().valid_up_to())),
+        Err(error) => error,
+    }
+}
+
+pub fn base64_encode_builtin(args: Vec<Object>) -> Object {
+    let bytes = match bytes("base64_encode", &args) {
+        Ok(bytes) => bytes,
+        Err(error) => return error,
+    };
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    Object::from(out)
+}
+
+pub fn base64_decode_builtin(args: Vec<Object>) -> Object {
+    let text = match string_argument("base64_decode", &args) {
+        Ok(text) => text,
+        Err(error) => return error,
+    };
+    let trimmed = text.trim_end_matches('=');
+    if text.len() % 4 != 0 || text.len() - trimmed.len() > 2 {
+        return err(format!("invalid base64 length {}", text.len()));
+    }
+    let mut out = Vec::with_capacity(trimmed.len() * 3 / 4);
+    for chunk in trimmed.as_bytes().chunks(4) {
+        let mut group = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let Some(value) = BASE64.iter().position(|b| b == c) else {
+                return err(format!("invalid base64 character {:?}", *c as char));
+            };
+            group |= (value as u32) << (18 - 6 * i);
+        }
+        out.extend(group.to_be_bytes()[1..chunk.len()].iter());
+    }
+    ok(byte_list(out))
+}
+
+pub fn hex_encode_builtin(args: Vec<Object>) -> Object {
+    match bytes("hex_encode", &args) {
+        Ok(bytes) => Object::from(bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+        Err(error) => error,
+    }
+}
+
+pub fn hex_decode_builtin(args: Vec<Object>) -> Object {
+    let text = match string_argument("hex_decode", &args) {
+        Ok(text) => text,
+        Err(error) => return error,
+    };
+    if text.len() % 2 != 0 {
+        return err(format!("hex string has an odd length {}", text.len()));
+    }
+    let mut out = Vec::with_capacity(text.len() / 2);
+    for (i, pair) in text.as_bytes().chunks(2).enumerate() {
+        let digits = std::str::from_utf8(pair).ok();
+        match digits.and_then(|digits| u8::from_str_radix(digits, 16).ok()) {
+            Some(byte) => out.push(byte),
+            None => return err(format!("invalid hex digits at position {}", i * 2)),
+        }
+    }
+    ok(byte_list(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs() {
+        let call = |f: fn(Vec<Object>) -> Object, arg: Object| f(vec![arg]);
+        assert_eq!(call(to_char_code_builtin, Object::from("é")), ok(Object::Integer(233)));
+        assert_eq!(call(to_char_code_builtin, Object::from("ab")), err("expected a single character, got \"ab\"".to_string()));
+        assert_eq!(call(from_char_code_builtin, Object::Integer(0xD800)), err("55296 is not a unicode scalar value".to_string()));
+        assert_eq!(call(utf8_bytes_builtin, Object::from("é")), byte_list([0xc3, 0xa9]));
+        assert_eq!(call(from_utf8_builtin, byte_list([0x68, 0xff])), err("invalid UTF-8 at byte 1".to_string()));
+
+        for (text, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg==")] {
+            assert_eq!(call(base64_encode_builtin, Object::from(text)), Object::from(encoded));
+            assert_eq!(call(base64_decode_builtin, Object::from(encoded)), ok(byte_list(text.bytes())));
+        }
+        assert_eq!(call(base64_decode_builtin, Object::from("Zm9v!A==")), err("invalid base64 character '!'".to_string()));
+
+        assert_eq!(call(hex_encode_builtin, byte_list([0, 171, 255])), Object::from("00abff"));
+        assert_eq!(call(hex_decode_builtin, Object::from("00ABff")), ok(byte_list([0, 171, 255])));
+        assert_eq!(call(hex_decode_builtin, Object::from("0g")), err("invalid hex digits at position 0".to_string()));
+        assert!(matches!(call(hex_encode_builtin, Object::List(vec![Object::Integer(256)])), Object::Error(Code::BuiltinArgument, _)));
+    }
+}
@@ -0,0 +1,224 @@
+// completion.rs
+//
+// Completion candidates for a cursor position in opl source. Shared by the
+// REPL (tab completion) and any editor integration.
+
+use crate::ast::{Expression, Literal, Statement, Type};
+use crate::builtin::{self, BUILTINS, MODULES};
+use crate::environment::Env;
+use crate::lexer::{Lexer, Token};
+use crate::object::Object;
+use crate::parser::Parser;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CompletionKind {
+    Keyword,
+    Builtin,
+    Variable,
+    Function,
+    Field,
+    Variant,
+    Type,
+    Module,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+}
+
+const KEYWORDS: [&str; 10] = ["let", "fn", "return", "if", "else", "type", "match", "with", "of", "use"];
+const VARIANTS: [&str; 4] = ["Some", "None", "Ok", "Err"];
+
+enum Context {
+    // Plain identifier position
+    Identifier,
+    // After `receiver.`
+    Member(String),
+    // After `|`, e.g. inside a match
+    Variant,
+}
+
+// Declarations collected from the surrounding source
+#[derive(Default)]
+struct Declarations {
+    bindings: Vec<Completion>,
+    types: Vec<String>,
+    variants: Vec<String>,
+    // (binding or type name, field names)
+    fields: Vec<(String, Vec<String>)>,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn floor_char_boundary(source: &str, cursor: usize) -> usize {
+    let mut cursor = cursor.min(source.len());
+    while !source.is_char_boundary(cursor) {
+        cursor -= 1;
+    }
+    cursor
+}
+
+/// Returns the byte offset where the word under the cursor starts.
+pub fn word_start(source: &str, cursor: usize) -> usize {
+    let cursor = floor_char_boundary(source, cursor);
+    source[..cursor]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_ident_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(cursor)
+}
+
+fn context(source: &str, start: usize) -> Context {
+    let before = source[..start].trim_end_matches([' ', '\t']);
+    if let Some(receiver) = before.strip_suffix('.') {
+        if receiver.ends_with('.') {
+            return Context::Identifier;
+        }
+        let name_start = word_start(receiver, receiver.len());
+        return Context::Member(receiver[name_start..].to_string());
+    }
+    if before.ends_with('|') && !before.ends_with("||") {
+        return Context::Variant;
+    }
+    Context::Identifier
+}
+
+fn declarations(source: &str) -> Declarations {
+    let mut parser = Parser::new(Lexer::new(source));
+    let program = parser.parse_program();
+    let mut decls = Declarations::default();
+
+    for statement in &program {
+        match statement {
+            Statement::Let(Token::Identifier(name), expression, _) => {
+                let kind = match expression {
+                    Expression::Function { .. } => CompletionKind::Function,
+                    _ => CompletionKind::Variable,
+                };
+                decls.bindings.push(Completion { label: name.clone(), kind });
+                if let Expression::Literal(Literal::Record(fields)) = expression {
+                    decls.fields.push((name.clone(), fields.iter().map(|(f, _)| f.to_string()).collect()));
+                }
+            }
+            Statement::Type(Token::Identifier(name), declaration, _) => {
+                decls.types.push(name.clone());
+                match declaration {
+                    Type::Record(fields) => {
+                        decls.fields.push((name.clone(), fields.iter().map(|(f, _)| f.to_string()).collect()));
+                    }
+                    Type::Union(variants) => {
+                        decls.variants.extend(variants.iter().map(|(v, _)| v.to_string()));
+                    }
+                    Type::Alias(_) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    decls
+}
+
+fn candidates(source: &str, start: usize, env: &Env) -> Vec<Completion> {
+    let decls = declarations(source);
+    let mut items = Vec::new();
+
+    match context(source, start) {
+        Context::Member(receiver) => {
+            let shadowed = decls.bindings.iter().any(|binding| binding.label == receiver) || env.store.contains_key(&receiver);
+            for (name, fields) in decls.fields {
+                if name == receiver {
+                    items.extend(fields.into_iter().map(|label| Completion { label, kind: CompletionKind::Field }));
+                }
+            }
+            if let Some(members) = builtin::members(&receiver).filter(|_| !shadowed) {
+                items.extend(members.into_iter().map(|label| Completion { label, kind: CompletionKind::Function }));
+            }
+        }
+        Context::Variant => {
+            items.extend(VARIANTS.iter().map(|v| Completion { label: v.to_string(), kind: CompletionKind::Variant }));
+            items.extend(decls.variants.into_iter().map(|label| Completion { label, kind: CompletionKind::Variant }));
+        }
+        Context::Identifier => {
+            items.extend(KEYWORDS.iter().map(|k| Completion { label: k.to_string(), kind: CompletionKind::Keyword }));
+            items.extend(BUILTINS.iter().map(|b| Completion { label: b.to_string(), kind: CompletionKind::Builtin }));
+            items.extend(
+                MODULES.iter().filter(|m| builtin::members(m).is_some()).map(|m| Completion { label: m.to_string(), kind: CompletionKind::Module }),
+            );
+            items.extend(VARIANTS.iter().map(|v| Completion { label: v.to_string(), kind: CompletionKind::Variant }));
+            for name in env.names() {
+                let kind = match env.store.get(&name) {
+                    Some(Object::Function(..)) | Some(Object::Builtin(_)) | Some(Object::Native(_)) => CompletionKind::Function,
+                    _ => CompletionKind::Variable,
+                };
+                items.push(Completion { label: name, kind });
+            }
+            items.extend(decls.bindings);
+            items.extend(decls.types.into_iter().map(|label| Completion { label, kind: CompletionKind::Type }));
+        }
+    }
+    items
+}
+
+/// Proposes completions for the word ending at `cursor` (a byte offset into
+/// `source`). Returns the byte offset the completions replace from, and the
+/// candidates sorted by label.
+pub fn complete(source: &str, cursor: usize, env: &Env) -> (usize, Vec<Completion>) {
+    let cursor = floor_char_boundary(source, cursor);
+    let start = word_start(source, cursor);
+    let prefix = &source[start..cursor];
+
+    let mut items: Vec<Completion> = candidates(source, start, env)
+        .into_iter()
+        .filter(|c| c.label.starts_with(prefix))
+        .collect();
+    items.sort_by(|a, b| a.label.cmp(&b.label));
+    items.dedup_by(|a, b| a.label == b.label);
+    (start, items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(source: &str, env: &Env) -> Vec<String> {
+        complete(source, source.len(), env).1.into_iter().map(|c| c.label).collect()
+    }
+
+    #[test]
+    fn test_complete_identifiers() {
+        let mut env = Env::new();
+        env.set("fib".to_string(), Object::Integer(1));
+        let source = "let filtered = [1];\nfi";
+        assert_eq!(labels(source, &env), vec!["fib", "file", "filter", "filtered"]);
+        assert_eq!(complete(source, source.len(), &env).0, source.len() - 2);
+    }
+
+    #[test]
+    fn test_complete_record_fields() {
+        let source = "let f1 = { front = \"a\", back = \"b\" };\nf1.";
+        assert_eq!(labels(source, &Env::new()), vec!["back", "front"]);
+        assert_eq!(labels(&format!("{}fr", source), &Env::new()), vec!["front"]);
+    }
+
+    #[test]
+    fn test_complete_module_members() {
+        assert_eq!(labels("let s = set.un", &Env::new()), vec!["union"]);
+        assert!(labels("deque.", &Env::new()).contains(&"push_back".to_string()));
+        assert!(labels("float.", &Env::new()).contains(&"is_nan".to_string()));
+        assert_eq!(labels("de", &Env::new()), vec!["deque"]);
+        // Not once the name is bound to something else
+        assert_eq!(labels("let set = { size = 1 };\nset.", &Env::new()), vec!["size"]);
+    }
+
+    #[test]
+    fn test_complete_variants() {
+        let source = "type cell = | alive of int | dead;\nmatch c with\n| ";
+        assert_eq!(labels(source, &Env::new()), vec!["Err", "None", "Ok", "Some", "alive", "dead"]);
+    }
+}
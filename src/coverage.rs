@@ -0,0 +1,146 @@
+// coverage.rs
+//
+// Line coverage for `opl test --coverage`. Bindings, operators, calls and
+// assertions are the executable nodes, found by their spans; the evaluator
+// counts how often each one runs (see `Evaluator::with_coverage`), and a
+// line's count is the largest of those of its nodes.
+
+use crate::ast::*;
+use crate::lexer::Span;
+use std::collections::{BTreeMap, HashMap};
+
+// Spans of the executable nodes of a program, function bodies included
+#[derive(Default)]
+struct Nodes(Vec<Span>);
+
+impl Nodes {
+    fn program(&mut self, program: &[Statement]) {
+        program.iter().for_each(|statement| self.statement(statement));
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(_, expression, span) => {
+                self.0.push(*span);
+                self.expression(expression);
+            }
+            Statement::Return(expression) | Statement::Expression(expression) => self.expression(expression),
+            // Contract clauses only run in debug mode
+            Statement::Assert(assertion) => {
+                if assertion.kind == AssertionKind::Assert {
+                    self.0.push(assertion.span);
+                }
+                self.expression(&assertion.condition);
+            }
+            Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Identifier(_) | Expression::OptionNone => {}
+            Expression::OptionSome(inner) | Expression::ResultOk(inner) | Expression::ResultErr(inner) => self.expression(inner),
+            Expression::Literal(Literal::List(elements)) => elements.iter().for_each(|element| self.expression(element)),
+            Expression::Literal(Literal::Record(fields)) => fields.iter().for_each(|(_, value)| self.expression(value)),
+            Expression::Literal(Literal::HashMap(entries)) => entries.iter().for_each(|(key, value)| {
+                self.expression(key);
+                self.expression(value);
+            }),
+            Expression::Literal(_) => {}
+            Expression::Prefix(_, right) => self.expression(right),
+            Expression::Infix(_, left, right, span) => {
+                self.0.push(*span);
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::Block(program) => self.program(program),
+            Expression::Function { body, .. } => self.program(body),
+            Expression::Quote(quoted, _) => self.expression(quoted),
+            Expression::If { condition, consequence, alternative } => {
+                self.expression(condition);
+                self.program(consequence);
+                if let Some(alternative) = alternative {
+                    self.program(alternative);
+                }
+            }
+            Expression::Call { function, arguments, span } => {
+                self.0.push(*span);
+                self.expression(function);
+                arguments.iter().for_each(|argument| self.expression(argument));
+            }
+            Expression::BuiltIn { arguments, span, .. } => {
+                self.0.push(*span);
+                arguments.iter().for_each(|argument| self.expression(argument));
+            }
+            Expression::Match { expr, arms } => {
+                self.expression(expr);
+                arms.iter().for_each(|(_, body)| self.program(body));
+            }
+            Expression::Field { record, .. } => self.expression(record),
+            Expression::Range { start, end } => {
+                self.expression(start);
+                self.expression(end);
+            }
+            Expression::Annotated { expression, .. } => self.expression(expression),
+        }
+    }
+}
+
+/// How often each executable line of `program` ran, by line number.
+pub fn lines(program: &Program, counts: &HashMap<Span, usize>) -> BTreeMap<usize, usize> {
+    let mut nodes = Nodes::default();
+    nodes.program(program);
+    let mut lines = BTreeMap::new();
+    for span in nodes.0 {
+        let count = counts.get(&span).copied().unwrap_or(0);
+        let line = lines.entry(span.line).or_insert(0);
+        *line = count.max(*line);
+    }
+    lines
+}
+
+/// e.g. `main.opl: 8/10 lines (80.0%), missed 4, 9`
+pub fn summary(file: &str, lines: &BTreeMap<usize, usize>) -> String {
+    let missed: Vec<String> = lines.iter().filter(|(_, count)| **count == 0).map(|(line, _)| line.to_string()).collect();
+    let hit = lines.len() - missed.len();
+    let percent = if lines.is_empty() { 100.0 } else { 100.0 * hit as f64 / lines.len() as f64 };
+    let mut summary = format!("{}: {}/{} lines ({:.1}%)", file, hit, lines.len(), percent);
+    if !missed.is_empty() {
+        summary.push_str(&format!(", missed {}", missed.join(", ")));
+    }
+    summary
+}
+
+/// The lcov tracefile record of one file.
+pub fn lcov(file: &str, lines: &BTreeMap<usize, usize>) -> String {
+    let mut record = format!("TN:\nSF:{}\n", file);
+    for (line, count) in lines {
+        record.push_str(&format!("DA:{},{}\n", line, count));
+    }
+    let hit = lines.values().filter(|count| **count > 0).count();
+    record.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_coverage() {
+        let source = "let sign = fn n -> if n > 0 {\n  1\n} else {\n  0 - 1\n};\nsign(5);";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_coverage();
+        evaluator.eval(&program);
+        let lines = lines(&program, evaluator.coverage.as_ref().unwrap());
+        assert_eq!(lines, BTreeMap::from([(1, 1), (4, 0), (6, 1)]));
+        assert_eq!(summary("sign.opl", &lines), "sign.opl: 2/3 lines (66.7%), missed 4");
+        assert_eq!(lcov("sign.opl", &lines), "TN:\nSF:sign.opl\nDA:1,1\nDA:4,0\nDA:6,1\nLF:3\nLH:2\nend_of_record\n");
+    }
+}
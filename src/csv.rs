@@ -0,0 +1,208 @@
+// csv.rs
+//
+// The `csv` module, enabled by the `csv` feature. `csv.parse` reads RFC 4180
+// text into rows: records keyed by the header line with `{ headers = true }`,
+// otherwise lists of strings. `csv.encode` writes either form back out.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::string::Str;
+
+pub fn module() -> Object {
+    Object::record(vec![
+        ("parse".to_string(), Object::Builtin(parse_builtin)),
+        ("encode".to_string(), Object::Builtin(encode_builtin)),
+    ])
+}
+
+fn error(message: String) -> Object {
+    Object::ResultErr(Box::new(Object::String(Str::from(message))))
+}
+
+pub fn parse_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(text), Object::Record(options)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "csv.parse expects a string and an options record, e.g. { headers = true }".to_string());
+    };
+    let headers = match options.get("headers") {
+        Some(Object::Boolean(headers)) => *headers,
+        None => false,
+        Some(other) => {
+            return Object::Error(Code::BuiltinArgument, format!("csv.parse option 'headers' must be a bool, got {}", other.type_name()))
+        }
+    };
+
+    let rows = match read(text.as_str()) {
+        Ok(rows) => rows,
+        Err(message) => return error(message),
+    };
+    if !headers {
+        let rows = rows.into_iter().map(|row| Object::List(row.into_iter().map(Object::from).collect()));
+        return Object::ResultOk(Box::new(Object::List(rows.collect())));
+    }
+
+    let mut rows = rows.into_iter();
+    let Some(names) = rows.next() else {
+        return Object::ResultOk(Box::new(Object::List(Vec::new())));
+    };
+    let mut records = Vec::new();
+    for (i, row) in rows.enumerate() {
+        if row.len() != names.len() {
+            return error(format!("row {} has {} fields, but the header has {}", i + 2, row.len(), names.len()));
+        }
+        records.push(Object::Record(names.iter().cloned().zip(row.into_iter().map(Object::from)).collect()));
+    }
+    Object::ResultOk(Box::new(Object::List(records)))
+}
+
+// Splits text into rows of fields, handling quoted fields that contain
+// commas, doubled quotes or line breaks
+fn read(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    // Whether anything has been read since the last row ended
+    let mut pending = false;
+
+    while let Some(c) = chars.next() {
+        pending = true;
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => return Err(format!("unterminated quoted field starting on line {}", start)),
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\n' | '\r')) {
+                    return Err(format!("unexpected character after a quoted field on line {}", line));
+                }
+            }
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                line += 1;
+                pending = false;
+            }
+            c => field.push(c),
+        }
+    }
+    if pending {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+pub fn encode_builtin(args: Vec<Object>) -> Object {
+    let [Object::List(rows)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "csv.encode expects a list of records or a list of lists".to_string());
+    };
+    let mut out = String::new();
+    if let Some(Object::Record(first)) = rows.first() {
+        let names: Vec<&str> = first.iter().map(|(name, _)| name.as_str()).collect();
+        out.push_str(&names.iter().map(|name| quote(name)).collect::<Vec<String>>().join(","));
+        out.push('\n');
+        for row in rows {
+            let Object::Record(fields) = row else {
+                return Object::Error(Code::BuiltinArgument, format!("csv.encode expects every row to be a record, got {}", row.type_name()));
+            };
+            let cells = names.iter().map(|name| match fields.iter().find(|(field, _)| field == name) {
+                Some((_, value)) => cell(value),
+                None => Err(format!("csv.encode: a row has no field '{}'", name)),
+            });
+            if let Err(message) = write_row(&mut out, cells) {
+                return Object::Error(Code::BuiltinArgument, message);
+            }
+        }
+    } else {
+        for row in rows {
+            let Object::List(cells) = row else {
+                return Object::Error(Code::BuiltinArgument, format!("csv.encode expects every row to be a list, got {}", row.type_name()));
+            };
+            if let Err(message) = write_row(&mut out, cells.iter().map(cell)) {
+                return Object::Error(Code::BuiltinArgument, message);
+            }
+        }
+    }
+    Object::String(Str::from(out))
+}
+
+fn write_row(out: &mut String, cells: impl Iterator<Item = Result<String, String>>) -> Result<(), String> {
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&cell?);
+    }
+    out.push('\n');
+    Ok(())
+}
+
+fn cell(value: &Object) -> Result<String, String> {
+    match value {
+        Object::String(s) => Ok(quote(s.as_str())),
+        Object::Integer(_) | Object::Float(_) | Object::Boolean(_) => Ok(value.to_string()),
+        Object::Unit | Object::OptionNone => Ok(String::new()),
+        Object::OptionSome(inner) => cell(inner),
+        other => Err(format!("csv.encode cannot write a value of type {}", other.type_name())),
+    }
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, headers: bool) -> Object {
+        let options = Object::record(vec![("headers".to_string(), Object::Boolean(headers))]);
+        parse_builtin(vec![Object::from(text), options])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = "name,note\r\nada,\"says \"\"hi\"\", twice\"\nbob,\"two\nlines\"\n";
+        let parsed = parse(text, true);
+        let Object::ResultOk(rows) = &parsed else {
+            panic!("expected Ok");
+        };
+        let Object::List(records) = &**rows else {
+            panic!("expected a list");
+        };
+        assert_eq!(records[0], Object::record(vec![
+            ("name".to_string(), Object::from("ada")),
+            ("note".to_string(), Object::from("says \"hi\", twice")),
+        ]));
+        let encoded = encode_builtin(vec![(**rows).clone()]);
+        assert_eq!(encoded, Object::from("name,note\nada,\"says \"\"hi\"\", twice\"\nbob,\"two\nlines\"\n"));
+
+        assert_eq!(parse("a,b\n1\n", true), error("row 2 has 1 fields, but the header has 2".to_string()));
+        assert_eq!(parse("\"a", false), error("unterminated quoted field starting on line 1".to_string()));
+        assert_eq!(parse("a,,\n", false), Object::ResultOk(Box::new(Object::List(vec![Object::List(vec![
+            Object::from("a"),
+            Object::from(""),
+            Object::from(""),
+        ])]))));
+    }
+}
@@ -0,0 +1,219 @@
+// deque.rs
+//
+// Double ended queues and the `deque` module of builtins over them. Values
+// are immutable, so a push must not copy the queue: a deque is two shared
+// stacks, the front with its first element on top and the back with its
+// last, and pushing or popping at either end is O(1). When the end popped
+// from is empty, the other stack is split in half between them, which keeps
+// a run of pops O(1) amortized.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use std::rc::Rc;
+
+struct Node {
+    value: Object,
+    next: Stack,
+}
+
+type Stack = Option<Rc<Node>>;
+
+#[derive(Clone, Default)]
+pub struct Deque {
+    front: Stack,
+    back: Stack,
+    front_len: usize,
+    back_len: usize,
+}
+
+fn push(stack: &Stack, value: Object) -> Stack {
+    Some(Rc::new(Node { value, next: stack.clone() }))
+}
+
+// The values of a stack, top first
+fn values(stack: &Stack) -> impl Iterator<Item = &Object> {
+    std::iter::successors(stack.as_deref(), |node| node.next.as_deref()).map(|node| &node.value)
+}
+
+impl Deque {
+    pub fn new() -> Self {
+        Deque::default()
+    }
+
+    pub fn from_elements(elements: Vec<Object>) -> Self {
+        let mut deque = Deque::new();
+        deque.balance(elements, true);
+        deque
+    }
+
+    pub fn len(&self) -> usize {
+        self.front_len + self.back_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Elements from front to back.
+    pub fn to_vec(&self) -> Vec<Object> {
+        let mut elements: Vec<Object> = values(&self.front).cloned().collect();
+        let start = elements.len();
+        elements.extend(values(&self.back).cloned());
+        elements[start..].reverse();
+        elements
+    }
+
+    pub fn push_front(&self, value: Object) -> Deque {
+        let mut deque = self.clone();
+        deque.front = push(&self.front, value);
+        deque.front_len += 1;
+        deque
+    }
+
+    pub fn push_back(&self, value: Object) -> Deque {
+        let mut deque = self.clone();
+        deque.back = push(&self.back, value);
+        deque.back_len += 1;
+        deque
+    }
+
+    /// The first element and the rest of the deque.
+    pub fn pop_front(&self) -> Option<(Object, Deque)> {
+        let mut deque = self.clone();
+        if deque.front.is_none() {
+            deque.balance(self.to_vec(), true);
+        }
+        let node = deque.front.take()?;
+        deque.front = node.next.clone();
+        deque.front_len -= 1;
+        Some((node.value.clone(), deque))
+    }
+
+    /// The last element and the rest of the deque.
+    pub fn pop_back(&self) -> Option<(Object, Deque)> {
+        let mut deque = self.clone();
+        if deque.back.is_none() {
+            deque.balance(self.to_vec(), false);
+        }
+        let node = deque.back.take()?;
+        deque.back = node.next.clone();
+        deque.back_len -= 1;
+        Some((node.value.clone(), deque))
+    }
+
+    // Splits `elements`, front to back, between the two stacks, the end
+    // about to be popped taking the extra one of an odd count
+    fn balance(&mut self, elements: Vec<Object>, front: bool) {
+        let middle = if front { elements.len().div_ceil(2) } else { elements.len() / 2 };
+        self.front = elements[..middle].iter().rev().fold(None, |stack, value| push(&stack, value.clone()));
+        self.back = elements[middle..].iter().fold(None, |stack, value| push(&stack, value.clone()));
+        (self.front_len, self.back_len) = (middle, elements.len() - middle);
+    }
+}
+
+impl PartialEq for Deque {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.to_vec() == other.to_vec()
+    }
+}
+
+impl std::fmt::Debug for Deque {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.to_vec()).finish()
+    }
+}
+
+// Dropped iteratively, as a long stack would overflow the call stack
+impl Drop for Deque {
+    fn drop(&mut self) {
+        for stack in [self.front.take(), self.back.take()] {
+            let mut next = stack;
+            while let Some(node) = next {
+                next = match Rc::try_unwrap(node) {
+                    Ok(mut node) => node.next.take(),
+                    Err(_) => None,
+                };
+            }
+        }
+    }
+}
+
+/// `deque.empty`, `deque.from_list`, `deque.to_list`, `deque.size`,
+/// `deque.push_front`, `deque.push_back`, `deque.pop_front` and
+/// `deque.pop_back`. Pops give `Some({ value, rest })`, or None when the
+/// deque is empty.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        ("empty".to_string(), Object::Deque(Deque::new())),
+        function("from_list", |args| match args.as_slice() {
+            [Object::List(elements)] => Object::Deque(Deque::from_elements(elements.clone())),
+            _ => argument_error("from_list", "a list"),
+        }),
+        function("to_list", |args| match args.as_slice() {
+            [Object::Deque(deque)] => Object::List(deque.to_vec()),
+            _ => argument_error("to_list", "a deque"),
+        }),
+        function("size", |args| match args.as_slice() {
+            [Object::Deque(deque)] => Object::Integer(deque.len() as i64),
+            _ => argument_error("size", "a deque"),
+        }),
+        function("push_front", |args| match args.as_slice() {
+            [Object::Deque(deque), value] => Object::Deque(deque.push_front(value.clone())),
+            _ => argument_error("push_front", "a deque and a value"),
+        }),
+        function("push_back", |args| match args.as_slice() {
+            [Object::Deque(deque), value] => Object::Deque(deque.push_back(value.clone())),
+            _ => argument_error("push_back", "a deque and a value"),
+        }),
+        function("pop_front", |args| match args.as_slice() {
+            [Object::Deque(deque)] => popped(deque.pop_front()),
+            _ => argument_error("pop_front", "a deque"),
+        }),
+        function("pop_back", |args| match args.as_slice() {
+            [Object::Deque(deque)] => popped(deque.pop_back()),
+            _ => argument_error("pop_back", "a deque"),
+        }),
+    ])
+}
+
+fn popped(pop: Option<(Object, Deque)>) -> Object {
+    match pop {
+        Some((value, rest)) => {
+            let fields = vec![("value".to_string(), value), ("rest".to_string(), Object::Deque(rest))];
+            Object::OptionSome(Box::new(Object::record(fields)))
+        }
+        None => Object::OptionNone,
+    }
+}
+
+fn argument_error(name: &str, expected: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("deque.{} expects {}", name, expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deque;
+    use crate::object::Object;
+
+    #[test]
+    fn test_deque() {
+        let mut eval = crate::evaluator::test_eval();
+        let ints = |ints: &[i64]| Some(Object::List(ints.iter().map(|&n| Object::Integer(n)).collect()));
+
+        eval("let q = deque.push_front(deque.push_back(deque.from_list([2, 3]), 4), 1);\nlet next = deque.pop_front(q);");
+        assert_eq!(eval("deque.to_list(q)"), ints(&[1, 2, 3, 4]));
+        assert_eq!(eval("[next?.value, deque.pop_back(q)?.value]"), Some(Object::List(vec![Object::OptionSome(Box::new(Object::Integer(1))), Object::OptionSome(Box::new(Object::Integer(4)))])));
+        assert_eq!(eval("show(deque.pop_front(deque.empty))"), Some(Object::from("None")));
+
+        // Popping one end dry moves half of the other end over
+        let mut deque = Deque::from_elements((0..5).map(Object::Integer).collect());
+        for expected in (0..5).rev() {
+            let (value, rest) = deque.pop_back().unwrap();
+            assert_eq!(value, Object::Integer(expected));
+            deque = rest.push_front(Object::Integer(10 + expected));
+        }
+        assert_eq!(deque.to_vec(), (10..15).map(Object::Integer).collect::<Vec<_>>());
+        assert_eq!(deque.pop_front().map(|(value, rest)| (value, rest.len())), Some((Object::Integer(10), 4)));
+    }
+}
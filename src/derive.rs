@@ -0,0 +1,264 @@
+// derive.rs
+//
+// What `@derive(...)` on a type statement generates, when the statement is
+// evaluated. `@derive(enum)` on a union without payloads binds a record of
+// helpers named after the type:
+//
+//   @derive(enum)
+//   type color = | Red | Green | Blue;
+//   color.to_int(Green);   -- 1
+//   color.from_int(2);     -- Some(Blue)
+//   color.values();        -- [Red, Green, Blue]
+//
+// `eq`, `ord` and `show` register the type with the evaluator instead, whose
+// comparisons and `show` then handle its values structurally: variants by
+// declaration order then payload, records field by field in declaration
+// order. Records are structural, so a record is of a record type when it has
+// the type's fields.
+
+use crate::ast::{Identifier, Infix, Type};
+use crate::diagnostics::Code;
+use crate::object::{Native, Object};
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+/// The traits `@derive` accepts.
+pub const DERIVABLE: &[&str] = &["enum", "eq", "ord", "show"];
+
+/// A union or record type with derived traits.
+pub struct Derived {
+    pub name: String,
+    pub traits: Vec<String>,
+    // The variants of a union or the fields of a record type, in
+    // declaration order
+    pub members: Vec<String>,
+    pub union: bool,
+}
+
+/// The types with derived traits defined so far.
+#[derive(Default)]
+pub struct Registry {
+    types: Vec<Derived>,
+}
+
+impl Registry {
+    pub fn register(&mut self, name: &str, declaration: &Type, traits: &[Identifier]) {
+        let (members, union) = match declaration {
+            Type::Union(variants) => (variants.iter().map(|(variant, _)| variant.to_string()).collect(), true),
+            Type::Record(fields) => (fields.iter().map(|(field, _)| field.to_string()).collect(), false),
+            Type::Alias(_) => return,
+        };
+        let traits = traits.iter().map(|t| t.to_string()).collect();
+        self.types.retain(|derived| derived.name != name);
+        self.types.push(Derived { name: name.to_string(), traits, members, union });
+    }
+
+    fn type_of(&self, value: &Object) -> Option<&Derived> {
+        match value {
+            Object::Variant(union, ..) => self.types.iter().find(|derived| derived.union && derived.name == *union),
+            Object::Record(fields) => self.types.iter().find(|derived| {
+                !derived.union && derived.members.len() == fields.len() && fields.iter().all(|(name, _)| derived.members.contains(name))
+            }),
+            _ => None,
+        }
+    }
+
+    fn derives(&self, value: &Object, name: &str) -> Option<&Derived> {
+        self.type_of(value).filter(|derived| derived.traits.iter().any(|t| t == name))
+    }
+
+    /// How two values order structurally, None when they cannot be compared.
+    pub fn compare(&self, left: &Object, right: &Object) -> Option<Ordering> {
+        match (left, right) {
+            (Object::Unit, Object::Unit) => Some(Ordering::Equal),
+            (Object::Integer(a), Object::Integer(b)) => Some(a.cmp(b)),
+            (Object::Float(a), Object::Float(b)) => a.partial_cmp(b),
+            (Object::Boolean(a), Object::Boolean(b)) => Some(a.cmp(b)),
+            (Object::String(a), Object::String(b)) => Some(a.as_str().cmp(b.as_str())),
+            (Object::List(a), Object::List(b)) => self.compare_all(a.iter().zip(b)).map(|o| o.then(a.len().cmp(&b.len()))),
+            (Object::OptionNone, Object::OptionNone) => Some(Ordering::Equal),
+            (Object::OptionNone, Object::OptionSome(_)) | (Object::ResultOk(_), Object::ResultErr(_)) => Some(Ordering::Less),
+            (Object::OptionSome(_), Object::OptionNone) | (Object::ResultErr(_), Object::ResultOk(_)) => Some(Ordering::Greater),
+            (Object::OptionSome(a), Object::OptionSome(b)) | (Object::ResultOk(a), Object::ResultOk(b)) | (Object::ResultErr(a), Object::ResultErr(b)) => {
+                self.compare(a, b)
+            }
+            (Object::Variant(u, v, p), Object::Variant(w, x, q)) if u == w => {
+                let order = match self.type_of(left) {
+                    Some(derived) => {
+                        let index = |variant| derived.members.iter().position(|member| member == variant);
+                        index(v)?.cmp(&index(x)?)
+                    }
+                    None if v == x => Ordering::Equal,
+                    None => return None,
+                };
+                match (p, q) {
+                    (Some(p), Some(q)) if order == Ordering::Equal => self.compare(p, q),
+                    _ => Some(order),
+                }
+            }
+            (Object::Record(a), Object::Record(b)) if a.len() == b.len() => {
+                let names: Vec<&String> = match self.type_of(left) {
+                    Some(derived) => derived.members.iter().collect(),
+                    None => a.iter().map(|(name, _)| name).collect(),
+                };
+                let pairs: Option<Vec<_>> = names.into_iter().map(|name| Some((a.get(name)?, b.get(name)?))).collect();
+                self.compare_all(pairs?.into_iter())
+            }
+            _ => None,
+        }
+    }
+
+    // The first unequal ordering of pairs of values
+    fn compare_all<'a>(&self, mut pairs: impl Iterator<Item = (&'a Object, &'a Object)>) -> Option<Ordering> {
+        pairs.try_fold(Ordering::Equal, |order, (a, b)| match order {
+            Ordering::Equal => self.compare(a, b),
+            _ => Some(order),
+        })
+    }
+
+    /// A comparison of values of which at least one is a variant or record:
+    /// `==` and `!=` for types deriving `eq`, `<` and friends for types
+    /// deriving `ord`.
+    pub fn infix(&self, infix: &Infix, left: &Object, right: &Object) -> Object {
+        let (trait_name, test): (&str, fn(Ordering) -> bool) = match infix {
+            Infix::Equal => ("eq", Ordering::is_eq),
+            Infix::DoesNotEqual => ("eq", Ordering::is_ne),
+            Infix::LessThan => ("ord", Ordering::is_lt),
+            Infix::GreaterThan => ("ord", Ordering::is_gt),
+            Infix::LTOrEqual => ("ord", Ordering::is_le),
+            Infix::GTOrEqual => ("ord", Ordering::is_ge),
+            _ => return Object::Error(Code::InvalidOperator, format!("Invalid infix operator {:?} for given type: {}", infix, left.type_name())),
+        };
+        let name = |value: &Object| self.type_of(value).map_or_else(|| value.type_name(), |derived| derived.name.clone());
+        let (left_name, right_name) = (name(left), name(right));
+        if left_name != right_name {
+            return Object::Error(Code::OperandMismatch, format!("Cannot compare {} and {}", left_name, right_name));
+        }
+        if self.derives(left, trait_name).is_none() {
+            // Any union can derive, but only a declared record type
+            let hint = match (left, self.type_of(left)) {
+                (Object::Variant(..), _) | (_, Some(_)) => format!("; add @derive({}) to type {}", trait_name, left_name),
+                _ => String::new(),
+            };
+            return Object::Error(Code::OperandMismatch, format!("Cannot compare {} and {}{}", left_name, right_name, hint));
+        }
+        match self.compare(left, right) {
+            Some(order) => Object::Boolean(test(order)),
+            // e.g. a NaN in a payload, which is unequal to anything
+            None => Object::Boolean(*infix == Infix::DoesNotEqual),
+        }
+    }
+
+    /// A value as `show` prints it, with records of types deriving `show`
+    /// named by their type, e.g. `point { x = 1, y = 2 }`.
+    pub fn show(&self, value: &Object) -> String {
+        let all = |values: &[Object]| values.iter().map(|v| self.show(v)).collect::<Vec<_>>().join(", ");
+        match value {
+            Object::List(elements) => format!("[{}]", all(elements)),
+            Object::Record(fields) => {
+                let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{} = {}", name, self.show(value))).collect();
+                match self.derives(value, "show") {
+                    Some(derived) => format!("{} {{ {} }}", derived.name, fields.join(", ")),
+                    None => format!("{{ {} }}", fields.join(", ")),
+                }
+            }
+            Object::Map(map) if map.is_empty() => "{:}".to_string(),
+            Object::Map(map) => {
+                let entries: Vec<String> = map.iter().map(|(key, value)| format!("{}: {}", self.show(key), self.show(value))).collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Object::Deque(deque) => format!("deque [{}]", all(&deque.to_vec())),
+            Object::Set(set) => format!("set {{{}}}", set.iter().map(|element| self.show(element)).collect::<Vec<_>>().join(", ")),
+            Object::OptionSome(inner) => format!("Some({})", self.show(inner)),
+            Object::Variant(_, variant, Some(payload)) => format!("{}({})", variant, self.show(payload)),
+            Object::ResultOk(inner) | Object::ResultErr(inner) | Object::Return(inner) => self.show(inner),
+            other => other.to_string(),
+        }
+    }
+}
+
+fn helper(union: &str, name: &str, function: impl Fn(Vec<Object>) -> Object + 'static) -> (String, Object) {
+    let native = Native { name: format!("{}.{}", union, name), function: Rc::new(function) };
+    (name.to_string(), Object::Native(native))
+}
+
+/// The `to_int`, `from_int` and `values` helpers of a union whose variants,
+/// in declaration order, have no payload.
+pub fn enum_helpers(union: &str, variants: &[String]) -> Object {
+    let variants: Rc<[String]> = variants.into();
+    let value = {
+        let union = union.to_string();
+        move |variant: &String| Object::Variant(union.clone(), variant.clone(), None)
+    };
+    let to_int = {
+        let (union, variants) = (union.to_string(), Rc::clone(&variants));
+        move |arguments: Vec<Object>| {
+            let index = match arguments.as_slice() {
+                [Object::Variant(name, variant, None)] if *name == union => variants.iter().position(|v| v == variant),
+                _ => None,
+            };
+            match index {
+                Some(i) => Object::Integer(i as i64),
+                None => Object::Error(Code::BuiltinArgument, format!("{}.to_int expects a {}", union, union)),
+            }
+        }
+    };
+    let from_int = {
+        let (union, variants, value) = (union.to_string(), Rc::clone(&variants), value.clone());
+        move |arguments: Vec<Object>| match arguments.as_slice() {
+            [Object::Integer(i)] => match usize::try_from(*i).ok().and_then(|i| variants.get(i)) {
+                Some(variant) => Object::OptionSome(Box::new(value(variant))),
+                None => Object::OptionNone,
+            },
+            _ => Object::Error(Code::BuiltinArgument, format!("{}.from_int expects an int", union)),
+        }
+    };
+    let values = {
+        let union = union.to_string();
+        move |arguments: Vec<Object>| match arguments.as_slice() {
+            [] => Object::List(variants.iter().map(&value).collect()),
+            _ => Object::Error(Code::BuiltinArgument, format!("{}.values takes no arguments", union)),
+        }
+    };
+    Object::record(vec![helper(union, "to_int", to_int), helper(union, "from_int", from_int), helper(union, "values", values)])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run(source: &str) -> Option<Object> {
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&program)
+    }
+
+    #[test]
+    fn test_derive_enum() {
+        let source = "@derive(enum)\ntype color = | Red | Green | Blue;\n[show(color.to_int(Blue)), show(color.from_int(1)), show(color.from_int(3)), show(color.values())]";
+        let shown = ["2", "Some(Green)", "None", "[Red, Green, Blue]"].map(|s| Object::String(s.into())).to_vec();
+        assert_eq!(run(source), Some(Object::List(shown)));
+
+        let errors = Parser::new(Lexer::new("@derive(enum)\ntype cell = | alive of int | dead;")).parse_program().errors;
+        assert_eq!(errors[0].message(), "@derive(enum) is for unions whose variants have no payload, and cell is not one");
+    }
+
+    #[test]
+    fn test_derive_eq_ord_show() {
+        let types = "@derive(eq, ord, show)\ntype point = { x: int, y: int };\n@derive(eq, ord)\ntype cell = | alive of int | dead;\ntype plain = | A | B;\n";
+        let comparisons = "[{ x = 1, y = 2 } == { y = 2, x = 1 }, { x = 1, y = 2 } < { x = 1, y = 3 }, alive(3) == alive(3), alive(9) < dead, alive(2) > alive(1)]";
+        assert_eq!(run(&format!("{}{}", types, comparisons)), Some(Object::List(vec![Object::Boolean(true); 5])));
+        let shown = run(&format!("{}show([Some({{ x = 1, y = 2 }})]) ++ show([alive(1), dead])", types));
+        assert_eq!(shown, Some(Object::String("[Some(point { x = 1, y = 2 })][alive(1), dead]".into())));
+        let Some(Object::Error(_, ref message)) = run(&format!("{}A == B", types)) else {
+            panic!("comparing values of a type without eq");
+        };
+        assert_eq!(message, "Cannot compare plain and plain; add @derive(eq) to type plain at line 6");
+    }
+}
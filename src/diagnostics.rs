@@ -0,0 +1,877 @@
+// diagnostics.rs
+//
+// Stable error codes for every parse, type and runtime diagnostic, and the
+// extended explanations printed by `opl explain`.
+
+use crate::analysis::TextEdit;
+use crate::ast::Statement;
+use crate::lexer::Span;
+use crate::style::{self, Style};
+use std::fmt;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Code {
+    // Parse errors
+    UnexpectedToken,
+    NoPrefixParse,
+    InvalidNumber,
+    MalformedCall,
+    MalformedFunction,
+    MalformedType,
+    InvalidToken,
+    MalformedBlock,
+    MalformedOperator,
+    ChainedComparison,
+    MalformedCond,
+    MalformedMacro,
+    MacroExpansion,
+    IncludeFailed,
+    NotConstant,
+    MalformedDerive,
+    MalformedRule,
+    NestedTooDeeply,
+    // Type errors
+    TypeMismatch,
+    UnboundType,
+    UnsupportedInference,
+    EffectNotAllowed,
+    InfiniteType,
+    // Runtime errors
+    UndefinedVariable,
+    Redefinition,
+    OperandMismatch,
+    InvalidOperator,
+    NotAFunction,
+    ArityMismatch,
+    BuiltinArgument,
+    HeterogeneousList,
+    InvalidRange,
+    MissingValue,
+    Unsupported,
+    HeapLimit,
+    Unhashable,
+    UnknownField,
+    HostFunction,
+    InvalidShift,
+    AssertionFailed,
+    AnnotationMismatch,
+    DivisionByZero,
+    Overflow,
+    InputFailed,
+    // Not an error: `exit(status)` unwinds the program like one
+    Exit(i32),
+    Interrupted,
+    StepLimit,
+    IoFailed,
+    // Lints
+    UnusedVariable,
+    RewriteLimit,
+    Shadowing,
+    UnusedType,
+}
+
+/// How a lint is reported: suppressed, as a warning, or as an error.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    pub code: Code,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub suggestions: Vec<String>,
+    // Extra context, possibly over several lines, e.g. a type diff
+    pub notes: Vec<String>,
+    // Edits that can be applied as they are, e.g. by an editor
+    pub fixes: Vec<TextEdit>,
+}
+
+pub struct Explanation {
+    pub code: Code,
+    pub title: &'static str,
+    pub description: &'static str,
+    pub broken: &'static str,
+    pub fixed: &'static str,
+}
+
+pub const CATALOG: &[Explanation] = &[
+    Explanation {
+        code: Code::UnexpectedToken,
+        title: "unexpected token",
+        description: "The parser expected a specific token, such as `=` after the name in a `let`, and found something else.",
+        broken: "let x 1;",
+        fixed: "let x = 1;",
+    },
+    Explanation {
+        code: Code::NoPrefixParse,
+        title: "token cannot start an expression",
+        description: "An expression was expected but the token found cannot begin one, usually because of a stray operator or delimiter.",
+        broken: "let x = * 2;",
+        fixed: "let x = 2;",
+    },
+    Explanation {
+        code: Code::InvalidNumber,
+        title: "invalid number literal",
+        description: "A numeric literal could not be read, for example an integer that does not fit in 64 bits, or digits that run into letters as in `12ab`.",
+        broken: "let big = 99999999999999999999;",
+        fixed: "let big = 9999999999;",
+    },
+    Explanation {
+        code: Code::MalformedCall,
+        title: "malformed call",
+        description: "A call's argument list must be wrapped in parentheses and separated by commas.",
+        broken: "map(sq [1, 2]);",
+        fixed: "map(sq, [1, 2]);",
+    },
+    Explanation {
+        code: Code::MalformedFunction,
+        title: "malformed function literal",
+        description: "A function literal needs parameters (or `()`) before `->`, and a block body must end with an expression or `return`.",
+        broken: "let f = fn -> 1;",
+        fixed: "let f = fn () -> 1;",
+    },
+    Explanation {
+        code: Code::MalformedType,
+        title: "malformed type declaration",
+        description: "Type declarations must name their variants, fields and type parameters explicitly; record types cannot be written inline.",
+        broken: "type colony = list * { alive: bool };",
+        fixed: "type cell = { alive: bool };\ntype colony = list * cell;",
+    },
+    Explanation {
+        code: Code::InvalidToken,
+        title: "invalid token",
+        description: "The source contains text that is not part of the language: a character with no meaning outside of strings and comments, or a string that is never closed.",
+        broken: "let greeting = \"hello;",
+        fixed: "let greeting = \"hello\";",
+    },
+    Explanation {
+        code: Code::MalformedBlock,
+        title: "malformed block",
+        description: "A block is an expression whose value is that of its last statement, so it must end with an expression or a return statement.",
+        broken: "let area = { let side = 4; };",
+        fixed: "let area = { let side = 4; side * side };",
+    },
+    Explanation {
+        code: Code::MalformedOperator,
+        title: "malformed operator definition",
+        description: "An operator is defined with `infix`, `infixl` or `infixr`, a level from 1 (binding like `|>`) to 8 (binding like `&`), and a new symbol made of operator characters. Operators that are not associative cannot be chained without parentheses.",
+        broken: "infixl 6 + = fn a, b -> a - b;",
+        fixed: "infixl 6 <-> = fn a, b -> a - b;",
+    },
+    Explanation {
+        code: Code::ChainedComparison,
+        title: "chained comparison",
+        description: "`a < b < c` would compare the bool result of `a < b` with `c`, rather than check that `b` lies between `a` and `c`. Compare each pair on its own; parenthesize to compare the bools on purpose.",
+        broken: "let inside = 0 < x < 10;",
+        fixed: "let inside = if 0 < x { x < 10 } else { false };",
+    },
+    Explanation {
+        code: Code::MalformedCond,
+        title: "malformed cond",
+        description: "A `cond` expression is a list of `| condition -> value` arms, tried in order, optionally ending with an `| else -> value` arm. It needs at least one condition, and nothing can follow its else arm.",
+        broken: "let size = cond { | else -> \"small\" | n > 10 -> \"big\" };",
+        fixed: "let size = cond { | n > 10 -> \"big\" | else -> \"small\" };",
+    },
+    Explanation {
+        code: Code::MalformedMacro,
+        title: "malformed macro",
+        description: "A macro is defined as `macro name(parameters) -> template`, where the template is an expression or a block. Its `$name` splices stand for the arguments of a call, so each must name a parameter, and they cannot appear outside of a template.",
+        broken: "macro twice(x) -> $y * 2;",
+        fixed: "macro twice(x) -> $x * 2;",
+    },
+    Explanation {
+        code: Code::MacroExpansion,
+        title: "macro expansion failed",
+        description: "A call of a macro is replaced by its template, with the call's arguments spliced in. The call must pass one argument per parameter, an argument spliced in as the name of a let must be a name, and expansion stops after 64 nested expansions, as a macro that expands to a call of itself never ends.",
+        broken: "macro twice(x) -> $x * 2;\ntwice(1, 2);",
+        fixed: "macro twice(x) -> $x * 2;\ntwice(1);",
+    },
+    Explanation {
+        code: Code::IncludeFailed,
+        title: "include failed",
+        description: "`include_str(path)` and `include_json(path)` read a file while the program is parsed, relative to the directory of the source file, and stand for its text or for the literal of its JSON. The path must be a string literal, and the file must exist and, for `include_json`, hold valid JSON.",
+        broken: "let config = include_json(\"config\" ++ \".json\");",
+        fixed: "let config = include_json(\"config.json\");",
+    },
+    Explanation {
+        code: Code::NotConstant,
+        title: "not a constant",
+        description: "The value of a `const` is computed when the program is compiled, so it can only be made of literals, operators and constants defined before it. Calls, functions and variables could have effects or change between runs, and the computation must not fail, e.g. by dividing by zero.",
+        broken: "let radius = 2.0;\nconst AREA = 3.14159 * radius * radius;",
+        fixed: "const RADIUS = 2.0;\nconst AREA = 3.14159 * RADIUS * RADIUS;",
+    },
+    Explanation {
+        code: Code::MalformedDerive,
+        title: "malformed derive",
+        description: "`@derive(...)` comes right before a type statement and names traits to generate for the type. `enum` gives a union whose variants have no payload a record of helpers named after the type, with `to_int`, `from_int` and `values`. `eq`, `ord` and `show` are for unions and record types.",
+        broken: "@derive(enum)\ntype shape = | Point | Circle of float;",
+        fixed: "@derive(enum)\ntype color = | Red | Green | Blue;",
+    },
+    Explanation {
+        code: Code::MalformedRule,
+        title: "malformed rewrite rule",
+        description: "A rewrite rule is declared as `rule \"name\": pattern => replacement;`, and the optimizer replaces each expression matching the pattern with the replacement. The names in a pattern stand for any expression, so a pattern that is a single name would match everything.",
+        broken: "rule \"pointless\": x => x;",
+        fixed: "rule \"add_zero\": x + 0 => x;",
+    },
+    Explanation {
+        code: Code::NestedTooDeeply,
+        title: "expression nested too deeply",
+        description: "An expression can nest at most 128 levels of brackets, parentheses, blocks and operands, and a statement's expression can be at most 5000 levels deep, counting each operator of a chain like `1 + 2 + ...` as one. Split the expression with `let`s, or build long lists from smaller ones.",
+        broken: "let xs = [[[[[[[[ ... ]]]]]]]];",
+        fixed: "let inner = [[[[ ... ]]]];\nlet xs = [[[[inner]]]];",
+    },
+    Explanation {
+        code: Code::TypeMismatch,
+        title: "mismatched types",
+        description: "The type checker inferred two different types for values that must agree, such as both operands of `+` or every element of a list.",
+        broken: "let x = 1 + \"one\";",
+        fixed: "let x = 1 + 1;",
+    },
+    Explanation {
+        code: Code::UnboundType,
+        title: "name has no type",
+        description: "The type checker found a name that is not bound by any `let`, parameter or type declaration in scope.",
+        broken: "let y = x + 1;",
+        fixed: "let x = 1;\nlet y = x + 1;",
+    },
+    Explanation {
+        code: Code::UnsupportedInference,
+        title: "type inference not supported",
+        description: "The expression uses a construct the type checker cannot infer yet. The program may still evaluate.",
+        broken: "let x = 1 ^ 2;",
+        fixed: "let x = 1 * 2;",
+    },
+    Explanation {
+        code: Code::EffectNotAllowed,
+        title: "effect not allowed in a pure module",
+        description: "A module marked `@pure` used a builtin or host function with an effect: `io` (console output), `fs`, `net`, `rand`, `time` or `process`. Pure modules can only compute values.",
+        broken: "@pure\nprintln(\"hello\");",
+        fixed: "@pure\nlet greeting = \"hello\";",
+    },
+    Explanation {
+        code: Code::InfiniteType,
+        title: "infinite type",
+        description: "A value would need a type that contains itself, e.g. a function applied to itself would need a type `'a` equal to `fn 'a -> 'b`. No finite type is such, so the expression cannot be typed; the message names the let being checked.",
+        broken: "let apply_self = fn f -> f(f);",
+        fixed: "let apply_twice = fn f, x -> f(f(x));",
+    },
+    Explanation {
+        code: Code::UndefinedVariable,
+        title: "undefined variable",
+        description: "A name was used at runtime that is not bound in the current scope or any enclosing scope.",
+        broken: "println(greeting);",
+        fixed: "let greeting = \"hi\";\nprintln(greeting);",
+    },
+    Explanation {
+        code: Code::Redefinition,
+        title: "variable redefined in the same scope",
+        description: "opl does not allow shadowing: a name can only be bound once per scope. Pick a new name or bind it in an inner function.",
+        broken: "let x = 1;\nlet x = 2;",
+        fixed: "let x = 1;\nlet y = 2;",
+    },
+    Explanation {
+        code: Code::OperandMismatch,
+        title: "operand types do not match",
+        description: "Both operands of an arithmetic or comparison operator must have the same type; opl never converts between int and float implicitly.",
+        broken: "1 + 2.5",
+        fixed: "1.0 + 2.5",
+    },
+    Explanation {
+        code: Code::InvalidOperator,
+        title: "operator not defined for type",
+        description: "The operator exists but is not defined for the type of its operands, e.g. `-` on strings or `<` on booleans.",
+        broken: "\"a\" + \"b\"",
+        fixed: "\"a\" ++ \"b\"",
+    },
+    Explanation {
+        code: Code::NotAFunction,
+        title: "called value is not a function",
+        description: "Only functions and builtins can be called with `(...)`.",
+        broken: "let x = 1;\nx(2);",
+        fixed: "let x = fn y -> y;\nx(2);",
+    },
+    Explanation {
+        code: Code::ArityMismatch,
+        title: "wrong number of arguments",
+        description: "A function was called with a different number of arguments than it declares parameters.",
+        broken: "let add = fn x, y -> x + y;\nadd(1);",
+        fixed: "let add = fn x, y -> x + y;\nadd(1, 2);",
+    },
+    Explanation {
+        code: Code::BuiltinArgument,
+        title: "invalid builtin argument",
+        description: "A builtin such as `map`, `filter` or `println` received an argument of the wrong kind, or its callback returned the wrong kind of value.",
+        broken: "filter(fn x -> x * 2;, [1, 2]);",
+        fixed: "filter(fn x -> x > 1;, [1, 2]);",
+    },
+    Explanation {
+        code: Code::HeterogeneousList,
+        title: "list elements of different types",
+        description: "Every element of a list must have the same type, including elements added with `::` or produced by `map`.",
+        broken: "[1, \"two\"]",
+        fixed: "[1, 2]",
+    },
+    Explanation {
+        code: Code::InvalidRange,
+        title: "invalid range bounds",
+        description: "Both bounds of a range `[start..end]` must be integers.",
+        broken: "[1..2.5]",
+        fixed: "[1..3]",
+    },
+    Explanation {
+        code: Code::MissingValue,
+        title: "expression produced no value",
+        description: "A value was required but the expression evaluated to nothing, such as an `if` without an `else` whose condition was false.",
+        broken: "let x = if false { 1 };",
+        fixed: "let x = if false { 1 } else { 0 };",
+    },
+    Explanation {
+        code: Code::Unsupported,
+        title: "unsupported construct",
+        description: "The construct parses but cannot be evaluated by this version of the interpreter.",
+        broken: "type id = int;",
+        fixed: "-- use int directly",
+    },
+    Explanation {
+        code: Code::HeapLimit,
+        title: "heap limit exceeded",
+        description: "The values a program keeps alive grew past the limit set with `--max-heap`. Build smaller intermediate lists and strings, or raise the limit.",
+        broken: "-- opl run --max-heap 1K\nlet xs = [1..100000];",
+        fixed: "-- opl run --max-heap 16M\nlet xs = [1..100000];",
+    },
+    Explanation {
+        code: Code::Unhashable,
+        title: "value cannot be hashed",
+        description: "Only plain data can be hashed or used as a key: numbers, booleans, strings, and lists, options and results of those. Functions cannot.",
+        broken: "hash(fn x -> x;)",
+        fixed: "hash([1, 2, 3])",
+    },
+    Explanation {
+        code: Code::UnknownField,
+        title: "no such field",
+        description: "A field was read with `.` from a record that does not have it, or from a value that is not a record.",
+        broken: "let config = { name = \"opl\" };\nconfig.nmae;",
+        fixed: "let config = { name = \"opl\" };\nconfig.name;",
+    },
+    Explanation {
+        code: Code::HostFunction,
+        title: "host function failed",
+        description: "A function registered by the program embedding opl, e.g. from Python or C, reported an error.",
+        broken: "-- the host raised an exception\nparse_date(\"yesterday\");",
+        fixed: "parse_date(\"2024-01-01\");",
+    },
+    Explanation {
+        code: Code::InvalidShift,
+        title: "shift out of range",
+        description: "An int was shifted with `<<` or `>>` by a negative amount or by 64 or more bits.",
+        broken: "1 << 64;",
+        fixed: "1 << 63;",
+    },
+    Explanation {
+        code: Code::AssertionFailed,
+        title: "assertion failed",
+        description: "An `assert` condition was false. With `opl run --debug`, the `requires` clauses of a function are also checked when it is called, and its `ensures` clauses, with `result` bound to the return value, when it returns.",
+        broken: "let half = fn n requires n % 2 == 0 -> n / 2;\nhalf(3);",
+        fixed: "let half = fn n requires n % 2 == 0 -> n / 2;\nhalf(4);",
+    },
+    Explanation {
+        code: Code::AnnotationMismatch,
+        title: "value does not match its annotation",
+        description: "The value bound by an annotated `let` is not of the annotated type. The type checker cannot see into `dynamic` values, such as parsed documents, so annotations are checked again when the binding runs.",
+        broken: "let settings: dynamic = { port = \"80\" };\nlet port: int = settings.port;",
+        fixed: "let settings: dynamic = { port = 80 };\nlet port: int = settings.port;",
+    },
+    Explanation {
+        code: Code::DivisionByZero,
+        title: "division by zero",
+        description: "An int was divided by zero with `/`, `%`, `divmod`, `div_euclid` or `rem_euclid`. Check the divisor first; float division by zero is not an error.",
+        broken: "let average = fn total, count -> total / count;\naverage(10, 0);",
+        fixed: "let average = fn total, count -> if count == 0 { 0 } else { total / count };\naverage(10, 0);",
+    },
+    Explanation {
+        code: Code::Overflow,
+        title: "integer overflow",
+        description: "The result of an int operation does not fit in 64 bits. Ints never wrap around silently.",
+        broken: "let big = 9223372036854775807 + 1;",
+        fixed: "let big = 9223372036854775807.0 + 1.0;",
+    },
+    Explanation {
+        code: Code::InputFailed,
+        title: "reading input failed",
+        description: "Reading from `stdin` failed, or the input was not valid UTF-8. Convert binary input to text before piping it in, e.g. with `base64`.",
+        broken: "-- head -c 16 /dev/urandom | opl run count.opl\nstdin.read_all();",
+        fixed: "-- head -c 16 /dev/urandom | base64 | opl run count.opl\nstdin.read_all();",
+    },
+    Explanation {
+        code: Code::Exit(0),
+        title: "exited",
+        description: "The program called `exit(status)`. It stops at once, and `opl run` ends with that status; a host embedding opl gets the status back instead. The status must be from 0 to 255.",
+        broken: "exit(256);",
+        fixed: "exit(1);",
+    },
+    Explanation {
+        code: Code::Interrupted,
+        title: "interrupted",
+        description: "The program was stopped with Ctrl-C. `opl run` then exits with status 130, and the REPL drops the input being evaluated and keeps the session. Press Ctrl-C twice to quit at once, e.g. while waiting for input.",
+        broken: "let spin = fn n -> spin(n + 1);\nspin(0);",
+        fixed: "let count = fn n -> if n == 0 { 0 } else { count(n - 1) };\ncount(10);",
+    },
+    Explanation {
+        code: Code::StepLimit,
+        title: "step limit exceeded",
+        description: "The program ran longer than the host embedding opl allows, e.g. under the `pure` or `scripting` profile of `EngineConfig`. Look for recursion that never ends, or ask the host for a higher limit.",
+        broken: "let spin = fn n -> spin(n + 1);\nspin(0);",
+        fixed: "let count = fn n -> if n == 0 { 0 } else { count(n - 1) };\ncount(10);",
+    },
+    Explanation {
+        code: Code::IoFailed,
+        title: "I/O failed",
+        description: "A file could not be opened, read or written, or a handle was used after it was closed or in a way it was not opened for, such as writing to a file opened with \"r\".",
+        broken: "let log = file.open(\"app.log\", \"r\");\nfile.write(log, \"started\");",
+        fixed: "let log = file.open(\"app.log\", \"a\");\nfile.write(log, \"started\");",
+    },
+    Explanation {
+        code: Code::UnusedVariable,
+        title: "unused variable",
+        description: "A `let` binding inside a function body is never referenced. Remove it, or prefix its name with `_` to mark it as intentionally unused.",
+        broken: "let f = fn x -> {\n    let doubled = x * 2;\n    x\n};",
+        fixed: "let f = fn x -> {\n    let _doubled = x * 2;\n    x\n};",
+    },
+    Explanation {
+        code: Code::RewriteLimit,
+        title: "rewrite limit reached",
+        description: "The optimizer applies rewrite rules to an expression until none matches, and stops after 64 nested rewrites. Reaching the limit means a rule undoes itself, or two rules undo each other, so rewriting would never end. The expression is left as it was when rewriting stopped.",
+        broken: "rule \"swap\": x + y => y + x;\nlet f = fn a, b -> a + b;",
+        fixed: "rule \"add_zero\": x + 0 => x;\nlet f = fn a, b -> a + b;",
+    },
+    Explanation {
+        code: Code::Shadowing,
+        title: "binding shadows a builtin or keyword",
+        description: "A binding is named after a builtin, such as `hash` or `file`, or after a soft keyword: a builtin type name such as `list`, or a word starting a definition such as `macro`. Builtins are only looked up when no binding has their name, so the binding hides the builtin from the rest of its scope, and the errors that follow are about the binding. Rename it.",
+        broken: "let hash = fn s -> 0;\nlet h = hash(\"key\");",
+        fixed: "let hash_of = fn s -> 0;\nlet h = hash(\"key\");",
+    },
+    Explanation {
+        code: Code::UnusedType,
+        title: "unused type",
+        description: "A `type` declaration is never used: no annotation names the type, none of its variants is used, and no record uses its fields. Remove it. The diagnostic carries the removal as a fix, which `--error-format json` gives editors to apply.",
+        broken: "type shape = Circle(float) | Square(float);\nlet area = fn r -> 3.14 * r * r;",
+        fixed: "let area = fn r -> 3.14 * r * r;",
+    },
+];
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::UnexpectedToken => "E0001",
+            Code::NoPrefixParse => "E0002",
+            Code::InvalidNumber => "E0003",
+            Code::MalformedCall => "E0004",
+            Code::MalformedFunction => "E0005",
+            Code::MalformedType => "E0006",
+            Code::InvalidToken => "E0007",
+            Code::MalformedBlock => "E0008",
+            Code::MalformedOperator => "E0009",
+            Code::ChainedComparison => "E0010",
+            Code::MalformedCond => "E0011",
+            Code::MalformedMacro => "E0012",
+            Code::MacroExpansion => "E0013",
+            Code::IncludeFailed => "E0014",
+            Code::NotConstant => "E0015",
+            Code::MalformedDerive => "E0016",
+            Code::MalformedRule => "E0017",
+            Code::NestedTooDeeply => "E0018",
+            Code::TypeMismatch => "E0101",
+            Code::UnboundType => "E0102",
+            Code::UnsupportedInference => "E0103",
+            Code::EffectNotAllowed => "E0104",
+            Code::InfiniteType => "E0105",
+            Code::UndefinedVariable => "E0201",
+            Code::Redefinition => "E0202",
+            Code::OperandMismatch => "E0203",
+            Code::InvalidOperator => "E0204",
+            Code::NotAFunction => "E0205",
+            Code::ArityMismatch => "E0206",
+            Code::BuiltinArgument => "E0207",
+            Code::HeterogeneousList => "E0208",
+            Code::InvalidRange => "E0209",
+            Code::MissingValue => "E0210",
+            Code::Unsupported => "E0211",
+            Code::HeapLimit => "E0212",
+            Code::Unhashable => "E0213",
+            Code::UnknownField => "E0214",
+            Code::HostFunction => "E0215",
+            Code::InvalidShift => "E0216",
+            Code::AssertionFailed => "E0217",
+            Code::AnnotationMismatch => "E0218",
+            Code::DivisionByZero => "E0219",
+            Code::Overflow => "E0220",
+            Code::InputFailed => "E0221",
+            Code::Exit(_) => "E0222",
+            Code::Interrupted => "E0223",
+            Code::StepLimit => "E0224",
+            Code::IoFailed => "E0225",
+            Code::UnusedVariable => "W0001",
+            Code::RewriteLimit => "W0002",
+            Code::Shadowing => "W0003",
+            Code::UnusedType => "W0004",
+        }
+    }
+
+    // Name used by `-A`/`-W`/`-D` and `@allow(...)`; only lints have one
+    pub fn lint_name(&self) -> Option<&'static str> {
+        match self {
+            Code::TypeMismatch => Some("type_mismatch"),
+            Code::UnboundType => Some("unbound_type"),
+            Code::UnsupportedInference => Some("unsupported_inference"),
+            Code::InfiniteType => Some("infinite_type"),
+            Code::UnusedVariable => Some("unused_variable"),
+            Code::RewriteLimit => Some("rewrite_limit"),
+            Code::Shadowing => Some("shadowing"),
+            Code::UnusedType => Some("unused_type"),
+            _ => None,
+        }
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            Code::UnsupportedInference => Level::Allow,
+            Code::TypeMismatch | Code::UnboundType | Code::InfiniteType | Code::UnusedVariable | Code::RewriteLimit | Code::Shadowing | Code::UnusedType => Level::Warn,
+            _ => Level::Deny,
+        }
+    }
+
+    pub fn explanation(&self) -> &'static Explanation {
+        CATALOG.iter().find(|e| e.code.as_str() == self.as_str()).expect("every code has an explanation")
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Diagnostic {
+    pub fn new(code: Code, message: String) -> Self {
+        let severity = match code.default_level() {
+            Level::Deny => Severity::Error,
+            _ => Severity::Warning,
+        };
+        Diagnostic { code, severity, message, span: None, suggestions: Vec::new(), notes: Vec::new(), fixes: Vec::new() }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_suggestion(mut self, suggestion: String) -> Self {
+        self.suggestions.push(suggestion);
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn with_fix(mut self, fix: TextEdit) -> Self {
+        self.fixes.push(fix);
+        self
+    }
+
+    fn severity_str(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    /// Human readable form with the location and any suggestions, e.g.
+    ///
+    /// ```text
+    /// error[E0001]: expected ), got ;
+    ///   --> main.opl:3:14
+    ///   = help: did you mean `x`?
+    /// ```
+    ///
+    /// followed by any notes, indented below the location, painted through
+    /// `style` when color is on.
+    pub fn render(&self, file: &str) -> String {
+        let header = format!("{}[{}]", self.severity_str(), self.code);
+        let severity = match self.severity {
+            Severity::Error => Style::Error,
+            Severity::Warning => Style::Warning,
+        };
+        let mut out = self.to_string().replacen(&header, &style::paint(&header, severity), 1);
+        if let Some(span) = self.span {
+            out.push_str(&format!("\n  {} {}:{}:{}", style::paint("-->", Style::Location), file, span.line, span.column));
+        }
+        for suggestion in &self.suggestions {
+            out.push_str(&format!("\n  {} {}", style::paint("= help:", Style::Note), suggestion));
+        }
+        for note in &self.notes {
+            out.push_str(&format!("\n  {} {}", style::paint("= note:", Style::Note), note.replace('\n', "\n          ")));
+        }
+        out
+    }
+
+    /// A single line JSON object, for editors and CI tooling.
+    pub fn to_json(&self, file: &str) -> String {
+        let lint = match self.code.lint_name() {
+            Some(lint) => format!("\"{}\"", lint),
+            None => "null".to_string(),
+        };
+        let spans = match self.span {
+            Some(span) => format!(
+                "[{{\"file\":\"{}\",\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}]",
+                json_escape(file),
+                span.start,
+                span.end,
+                span.line,
+                span.column
+            ),
+            None => "[]".to_string(),
+        };
+        let suggestions: Vec<String> = self.suggestions.iter().map(|s| format!("\"{}\"", json_escape(s))).collect();
+        let fixes: Vec<String> = self
+            .fixes
+            .iter()
+            .map(|fix| format!("{{\"start\":{},\"end\":{},\"text\":\"{}\"}}", fix.span.start, fix.span.end, json_escape(&fix.text)))
+            .collect();
+        format!(
+            "{{\"code\":\"{}\",\"severity\":\"{}\",\"message\":\"{}\",\"lint\":{},\"spans\":{},\"suggestions\":[{}],\"fixes\":[{}]}}",
+            self.code,
+            self.severity_str(),
+            json_escape(&self.message),
+            lint,
+            spans,
+            suggestions.join(","),
+            fixes.join(",")
+        )
+    }
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// How `opl run` prints diagnostics.
+#[derive(PartialEq, Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity_str(), self.code, self.message)?;
+        match self.code.lint_name() {
+            Some(lint) => write!(f, " [{}]", lint),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lint levels requested on the command line. `warnings` names every lint.
+#[derive(Debug, Clone, Default)]
+pub struct DiagnosticConfig {
+    pub levels: Vec<(String, Level)>,
+}
+
+impl DiagnosticConfig {
+    fn level(&self, code: Code) -> Level {
+        let Some(lint) = code.lint_name() else {
+            return code.default_level();
+        };
+        let mut level = code.default_level();
+        for (name, configured) in &self.levels {
+            if name == lint {
+                level = *configured;
+            }
+        }
+        // `-D warnings` promotes whatever is still a warning
+        for (name, configured) in &self.levels {
+            if name == "warnings" && level == Level::Warn {
+                level = *configured;
+            }
+        }
+        level
+    }
+}
+
+/// Single sink for parser, type checker and linter diagnostics, applying the
+/// configured lint levels before anything is reported.
+pub struct DiagnosticEmitter {
+    config: DiagnosticConfig,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl DiagnosticEmitter {
+    pub fn new(config: DiagnosticConfig) -> Self {
+        DiagnosticEmitter { config, diagnostics: Vec::new() }
+    }
+
+    /// Applies file level `@allow(...)`, `@warn(...)` and `@deny(...)` attributes.
+    /// These take precedence over the command line.
+    pub fn apply_attributes(&mut self, program: &[Statement]) {
+        for statement in program {
+            if let Statement::Attribute(name, arguments) = statement {
+                let level = match name.to_string().as_str() {
+                    "allow" => Level::Allow,
+                    "warn" => Level::Warn,
+                    "deny" => Level::Deny,
+                    _ => continue,
+                };
+                for argument in arguments {
+                    self.config.levels.push((argument.to_string(), level));
+                }
+            }
+        }
+    }
+
+    pub fn emit(&mut self, mut diagnostic: Diagnostic) {
+        diagnostic.severity = match self.config.level(diagnostic.code) {
+            Level::Allow => return,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.severity == Severity::Error)
+    }
+}
+
+/// Edit distance between two names, counted in chars.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate closest to `name`, if one is near enough to be a likely typo.
+/// Ties go to the alphabetically first candidate.
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = name.chars().count().div_ceil(3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Looks up an explanation by its code string, e.g. `E0206`. Case insensitive.
+pub fn explain(code: &str) -> Option<&'static Explanation> {
+    CATALOG.iter().find(|e| e.code.as_str().eq_ignore_ascii_case(code.trim()))
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let indent = |s: &str| s.lines().map(|l| format!("    {}", l)).collect::<Vec<String>>().join("\n");
+        writeln!(f, "{}: {}\n", self.code, self.title)?;
+        writeln!(f, "{}\n", self.description)?;
+        writeln!(f, "Erroneous code:\n\n{}\n", indent(self.broken))?;
+        write!(f, "Fixed:\n\n{}", indent(self.fixed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Token;
+
+    #[test]
+    fn test_catalog_codes_are_unique() {
+        for (i, a) in CATALOG.iter().enumerate() {
+            for b in &CATALOG[i + 1..] {
+                assert_ne!(a.code.as_str(), b.code.as_str());
+            }
+        }
+        assert_eq!(explain("e0206").map(|e| e.code), Some(Code::ArityMismatch));
+        assert!(explain("E9999").is_none());
+    }
+
+    #[test]
+    fn test_emitter_levels() {
+        let unused = || Diagnostic::new(Code::UnusedVariable, "unused variable 'x'".to_string());
+
+        let mut emitter = DiagnosticEmitter::new(DiagnosticConfig::default());
+        emitter.emit(unused());
+        assert_eq!(emitter.diagnostics[0].severity, Severity::Warning);
+        assert!(!emitter.has_errors());
+
+        let config = DiagnosticConfig { levels: vec![("warnings".to_string(), Level::Deny)] };
+        let mut emitter = DiagnosticEmitter::new(config);
+        emitter.emit(unused());
+        assert!(emitter.has_errors());
+
+        let config = DiagnosticConfig { levels: vec![("warnings".to_string(), Level::Deny)] };
+        let mut emitter = DiagnosticEmitter::new(config);
+        emitter.apply_attributes(&[Statement::Attribute(
+            Token::Identifier("allow".to_string()),
+            vec![Token::Identifier("unused_variable".to_string())],
+        )]);
+        emitter.emit(unused());
+        assert!(emitter.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_closest() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(closest("lenght", ["length", "left", "map"]), Some("length"));
+        assert_eq!(closest("fitler", ["filter", "fold"]), Some("filter"));
+        assert_eq!(closest("x", ["y", "z"]), Some("y"));
+        assert_eq!(closest("total", ["map", "count"]), None);
+    }
+
+    #[test]
+    fn test_diagnostic_json() {
+        let span = Span { start: 4, end: 5, line: 1, column: 5, ..Span::default() };
+        let diagnostic = Diagnostic::new(Code::UndefinedVariable, "identifier not found: \"y\"".to_string())
+            .with_span(span)
+            .with_suggestion("did you mean `x`?".to_string());
+        assert_eq!(
+            diagnostic.to_json("a.opl"),
+            r#"{"code":"E0201","severity":"error","message":"identifier not found: \"y\"","lint":null,"spans":[{"file":"a.opl","start":4,"end":5,"line":1,"column":5}],"suggestions":["did you mean `x`?"],"fixes":[]}"#
+        );
+    }
+}
@@ -0,0 +1,238 @@
+// diff.rs
+//
+// Structural comparison of programs for `opl diff --semantic`. Two programs
+// are the same when they parse to the same tree, whatever their layout and
+// comments: spans, comments and the source text kept for assertion messages
+// are ignored.
+
+use crate::ast::*;
+use crate::lexer::Span;
+
+/// Where two programs first differ, located by the innermost node around
+/// the difference that has a span.
+#[derive(PartialEq, Debug)]
+pub struct Difference {
+    pub left: Span,
+    pub right: Span,
+    pub message: String,
+}
+
+type Spans = (Span, Span);
+
+// e.g. "1 entry" or "2 entries"
+fn count(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+// e.g. "a call with 2 arguments"
+fn describe(expression: &Expression) -> String {
+    match expression {
+        Expression::Identifier(name) => format!("`{}`", name),
+        Expression::OptionSome(_) => "a Some".to_string(),
+        Expression::OptionNone => "None".to_string(),
+        Expression::ResultOk(_) => "an Ok".to_string(),
+        Expression::ResultErr(_) => "an Err".to_string(),
+        Expression::Literal(Literal::Integer(n)) => format!("`{}`", n),
+        Expression::Literal(Literal::Float(n)) => format!("`{:?}`", n),
+        Expression::Literal(Literal::String(s)) => format!("the string {:?}", s),
+        Expression::Literal(Literal::Boolean(b)) => format!("`{}`", b),
+        Expression::Literal(Literal::Char(c)) => format!("the char {:?}", c),
+        Expression::Literal(Literal::Unit) => "`()`".to_string(),
+        Expression::Literal(Literal::List(elements)) => format!("a list of {}", count(elements.len(), "element", "elements")),
+        Expression::Literal(Literal::Record(fields)) => {
+            let names: Vec<String> = fields.iter().map(|(name, _)| name.to_string()).collect();
+            format!("a record of {}", names.join(", "))
+        }
+        Expression::Literal(Literal::HashMap(entries)) => format!("a hashmap of {}", count(entries.len(), "entry", "entries")),
+        Expression::Prefix(prefix, _) => format!("a prefix `{}`", prefix),
+        Expression::Infix(infix, ..) => format!("a `{}` operation", infix),
+        Expression::Block(statements) => format!("a block of {}", count(statements.len(), "statement", "statements")),
+        Expression::Function { parameters, .. } => {
+            let names: Vec<String> = parameters.iter().map(|name| name.to_string()).collect();
+            format!("a function of ({})", names.join(", "))
+        }
+        Expression::If { alternative: None, .. } => "an if".to_string(),
+        Expression::If { .. } => "an if with an else".to_string(),
+        Expression::Call { arguments, .. } => format!("a call with {}", count(arguments.len(), "argument", "arguments")),
+        Expression::Match { arms, .. } => format!("a match of {}", count(arms.len(), "arm", "arms")),
+        Expression::BuiltIn { function, .. } => format!("a call of the builtin {}", function),
+        Expression::Field { field, optional, .. } => format!("the field {}{}", if *optional { "?." } else { "." }, field),
+        Expression::Range { .. } => "a range".to_string(),
+        Expression::Annotated { annotation, .. } => format!("a value annotated {}", annotation),
+        Expression::Quote(..) => "a quote".to_string(),
+    }
+}
+
+fn describe_statement(statement: &Statement) -> String {
+    match statement {
+        Statement::Let(name, ..) => format!("let {}", name),
+        Statement::Return(_) => "a return".to_string(),
+        Statement::Comment(_) => "a comment".to_string(),
+        Statement::Expression(expression) => describe(expression),
+        Statement::Type(name, ..) => format!("type {}", name),
+        Statement::Attribute(name, _) => format!("the attribute @{}", name),
+        Statement::Assert(assertion) => format!("{:?}", assertion.kind).to_lowercase(),
+    }
+}
+
+fn differ(left: String, right: String, at: Spans) -> Result<(), Difference> {
+    Err(Difference { left: at.0, right: at.1, message: format!("{} differs from {}", left, right) })
+}
+
+// Statements of a program or block, comments left out, each with its span
+// or else that of the enclosing node
+type Code<'a> = Vec<(&'a Statement, Span)>;
+
+fn code<'a>(statements: impl Iterator<Item = (&'a Statement, Span)>) -> Code<'a> {
+    statements.filter(|(statement, _)| !matches!(statement, Statement::Comment(_))).collect()
+}
+
+fn statements(left: Code, right: Code, at: Spans) -> Result<(), Difference> {
+    for ((l, s), (r, t)) in left.iter().zip(&right) {
+        statement(l, r, (*s, *t))?;
+    }
+    // Located at the extra statement on its side
+    let (side, (extra, _), at) = match left.len().cmp(&right.len()) {
+        std::cmp::Ordering::Equal => return Ok(()),
+        std::cmp::Ordering::Less => ("left", right[left.len()], (at.0, right[left.len()].1)),
+        std::cmp::Ordering::Greater => ("right", left[right.len()], (left[right.len()].1, at.1)),
+    };
+    Err(Difference { left: at.0, right: at.1, message: format!("the {} side has nothing in place of {}", side, describe_statement(extra)) })
+}
+
+fn block(left: &[Statement], right: &[Statement], at: Spans) -> Result<(), Difference> {
+    statements(code(left.iter().map(|s| (s, at.0))), code(right.iter().map(|s| (s, at.1))), at)
+}
+
+fn statement(left: &Statement, right: &Statement, at: Spans) -> Result<(), Difference> {
+    match (left, right) {
+        (Statement::Let(l, a, s), Statement::Let(r, b, t)) if l == r => expression(a, b, (*s, *t)),
+        (Statement::Return(a), Statement::Return(b)) | (Statement::Expression(a), Statement::Expression(b)) => expression(a, b, at),
+        (Statement::Assert(a), Statement::Assert(b)) if a.kind == b.kind && a.message == b.message => {
+            expression(&a.condition, &b.condition, (a.span, b.span))
+        }
+        (Statement::Type(..), Statement::Type(..)) | (Statement::Attribute(..), Statement::Attribute(..)) if left == right => Ok(()),
+        _ => differ(describe_statement(left), describe_statement(right), at),
+    }
+}
+
+fn expressions(left: &[Expression], right: &[Expression], at: Spans) -> Result<(), Difference> {
+    left.iter().zip(right).try_for_each(|(l, r)| expression(l, r, at))
+}
+
+fn expression(left: &Expression, right: &Expression, at: Spans) -> Result<(), Difference> {
+    let differs = || differ(describe(left), describe(right), at);
+    match (left, right) {
+        (Expression::Identifier(l), Expression::Identifier(r)) if l == r => Ok(()),
+        (Expression::OptionSome(a), Expression::OptionSome(b))
+        | (Expression::ResultOk(a), Expression::ResultOk(b))
+        | (Expression::ResultErr(a), Expression::ResultErr(b)) => expression(a, b, at),
+        (Expression::OptionNone, Expression::OptionNone) => Ok(()),
+        (Expression::Literal(Literal::List(a)), Expression::Literal(Literal::List(b))) if a.len() == b.len() => expressions(a, b, at),
+        (Expression::Literal(Literal::Record(a)), Expression::Literal(Literal::Record(b)))
+            if a.iter().map(|(name, _)| name).eq(b.iter().map(|(name, _)| name)) =>
+        {
+            a.iter().zip(b).try_for_each(|((_, l), (_, r))| expression(l, r, at))
+        }
+        (Expression::Literal(Literal::HashMap(a)), Expression::Literal(Literal::HashMap(b))) if a.len() == b.len() => {
+            a.iter().zip(b).try_for_each(|((lk, lv), (rk, rv))| {
+                expression(lk, rk, at)?;
+                expression(lv, rv, at)
+            })
+        }
+        (Expression::Literal(l), Expression::Literal(r)) if l == r => Ok(()),
+        (Expression::Prefix(p, a), Expression::Prefix(q, b)) if p == q => expression(a, b, at),
+        (Expression::Infix(p, la, ra, s), Expression::Infix(q, lb, rb, t)) => {
+            let at = (*s, *t);
+            if p != q {
+                return differ(describe(left), describe(right), at);
+            }
+            expression(la, lb, at)?;
+            expression(ra, rb, at)
+        }
+        (Expression::Block(a), Expression::Block(b)) => block(a, b, at),
+        (Expression::Function { parameters: p, body: a, .. }, Expression::Function { parameters: q, body: b, .. }) if p == q => block(a, b, at),
+        (
+            Expression::If { condition: lc, consequence: la, alternative: lb },
+            Expression::If { condition: rc, consequence: ra, alternative: rb },
+        ) if lb.is_some() == rb.is_some() => {
+            expression(lc, rc, at)?;
+            block(la, ra, at)?;
+            match (lb, rb) {
+                (Some(lb), Some(rb)) => block(lb, rb, at),
+                _ => Ok(()),
+            }
+        }
+        (Expression::Call { function: f, arguments: a, span: s }, Expression::Call { function: g, arguments: b, span: t }) => {
+            let at = (*s, *t);
+            if a.len() != b.len() {
+                return differ(describe(left), describe(right), at);
+            }
+            expression(f, g, at)?;
+            expressions(a, b, at)
+        }
+        (Expression::BuiltIn { function: f, arguments: a, span: s }, Expression::BuiltIn { function: g, arguments: b, span: t }) => {
+            let at = (*s, *t);
+            if f != g || a.len() != b.len() {
+                return differ(describe(left), describe(right), at);
+            }
+            expressions(a, b, at)
+        }
+        (Expression::Match { expr: a, arms: l }, Expression::Match { expr: b, arms: r }) if l.len() == r.len() => {
+            expression(a, b, at)?;
+            for ((lp, lb), (rp, rb)) in l.iter().zip(r) {
+                if lp != rp {
+                    return differ(format!("the pattern {:?}", lp), format!("{:?}", rp), at);
+                }
+                block(lb, rb, at)?;
+            }
+            Ok(())
+        }
+        (Expression::Field { record: a, field: l, optional: p, .. }, Expression::Field { record: b, field: r, optional: q, .. }) if l == r && p == q => {
+            expression(a, b, at)
+        }
+        (Expression::Range { start: la, end: lb }, Expression::Range { start: ra, end: rb }) => {
+            expression(la, ra, at)?;
+            expression(lb, rb, at)
+        }
+        (Expression::Annotated { expression: a, annotation: l }, Expression::Annotated { expression: b, annotation: r }) if l == r => {
+            expression(a, b, at)
+        }
+        (Expression::Quote(a, _), Expression::Quote(b, _)) => expression(a, b, at),
+        _ => differs(),
+    }
+}
+
+/// Whether two expressions have the same structure, as `diff` compares them.
+pub(crate) fn same(left: &Expression, right: &Expression) -> bool {
+    expression(left, right, (Span::default(), Span::default())).is_ok()
+}
+
+/// The first difference between two programs, None when they have the same
+/// structure.
+pub fn diff(left: &Program, right: &Program) -> Option<Difference> {
+    statements(code(left.spanned()), code(right.spanned()), (left.span, right.span)).err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_diff() {
+        let parse = |source| Parser::new(Lexer::new(source)).parse_program();
+        let original = parse("let f = fn x -> {\n  let y = x * 2;\n  y + 1\n};\nf(2)");
+        assert_eq!(diff(&original, &parse("-- doubles, plus one\nlet f = fn x -> { let y = x * 2; y + 1 };\n\nf(2);")), None);
+
+        let changed = parse("let f = fn x -> {\n  let y = x * 2;\n  y - 1\n};\nf(2)");
+        let difference = diff(&original, &changed).unwrap();
+        assert_eq!(difference.message, "a `+` operation differs from a `-` operation");
+        assert_eq!((difference.left.line, difference.left.column), (3, 5));
+
+        let shorter = parse("let f = fn x -> {\n  let y = x * 2;\n  y + 1\n};");
+        assert_eq!(diff(&original, &shorter).unwrap().message, "the right side has nothing in place of a call with 1 argument");
+        assert_eq!(diff(&parse("[1, 2]"), &parse("[1, 3]")).unwrap().message, "`2` differs from `3`");
+    }
+}
@@ -0,0 +1,158 @@
+// doctest.rs
+//
+// Examples in doc comments, run by `opl test --doc`. A doc comment is a run
+// of `---` lines directly before a `let`. Fenced blocks in it are examples,
+// and a `#=> value` line checks that the code above it evaluates to `value`,
+// as the REPL would print it:
+//
+//   --- Doubles a number.
+//   --- ```
+//   --- double(21)
+//   --- #=> 42
+//   --- ```
+//   let double = fn x -> x * 2;
+//
+// Examples run after the whole file, each in its own scope.
+
+use crate::ast::{Program, Statement};
+use crate::environment::Env;
+use crate::evaluator::Evaluator;
+use crate::lexer::{Lexer, Token};
+use crate::object::Object;
+use crate::parser::Parser;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A doc comment and the binding it documents.
+pub struct Doc {
+    pub name: String,
+    pub lines: Vec<String>,
+}
+
+pub struct Example {
+    // The binding whose doc comment holds the example
+    pub name: String,
+    // Counted from 1 within the doc comment
+    pub index: usize,
+    lines: Vec<String>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+}
+
+/// Doc comments with the lets they precede. A comment separated from its let
+/// by anything else is not attached.
+pub fn docs(program: &Program) -> Vec<Doc> {
+    let mut docs = Vec::new();
+    let mut lines = Vec::new();
+    for statement in program {
+        match statement {
+            Statement::Comment(Token::Comment(text)) if text.starts_with('-') => {
+                let line = &text[1..];
+                lines.push(line.strip_prefix(' ').unwrap_or(line).to_string());
+            }
+            Statement::Let(Token::Identifier(name), _, _) if !lines.is_empty() => {
+                docs.push(Doc { name: name.clone(), lines: std::mem::take(&mut lines) });
+            }
+            _ => lines.clear(),
+        }
+    }
+    docs
+}
+
+// Where a doc comment line is relative to its fenced blocks
+enum Fence {
+    Outside,
+    Example(Vec<String>),
+    // In a block fenced as another language, e.g. ```text
+    Skipped,
+}
+
+/// The fenced blocks of every doc comment, except those of another language.
+pub fn examples(program: &Program) -> Vec<Example> {
+    let mut examples = Vec::new();
+    for doc in docs(program) {
+        let mut fence = Fence::Outside;
+        let mut index = 0;
+        for line in doc.lines {
+            let marker = line.trim().strip_prefix("```").map(str::trim);
+            fence = match (fence, marker) {
+                (Fence::Outside, Some("" | "opl")) => Fence::Example(Vec::new()),
+                (Fence::Outside, Some(_)) => Fence::Skipped,
+                (Fence::Example(lines), Some(_)) => {
+                    index += 1;
+                    examples.push(Example { name: doc.name.clone(), index, lines });
+                    Fence::Outside
+                }
+                (Fence::Skipped, Some(_)) => Fence::Outside,
+                (Fence::Example(mut lines), None) => {
+                    lines.push(line);
+                    Fence::Example(lines)
+                }
+                (fence, None) => fence,
+            };
+        }
+    }
+    examples
+}
+
+/// Runs `program`, then every example against its bindings.
+pub fn run(program: &Program, examples: &[Example]) -> Vec<Outcome> {
+    let env = Rc::new(RefCell::new(Env::new()));
+    if let Some(Object::Error(code, ref message)) = Evaluator::new(Rc::clone(&env)).eval(program) {
+        let failure = format!("the file failed before its examples ran: error[{}]: {}", code, message);
+        return examples.iter().map(|_| Outcome::Failed(failure.clone())).collect();
+    }
+    examples.iter().map(|example| run_example(example, &env)).collect()
+}
+
+fn run_example(example: &Example, env: &Rc<RefCell<Env>>) -> Outcome {
+    let scope = Rc::new(RefCell::new(Env::new_with_outer(Rc::clone(env))));
+    let mut evaluator = Evaluator::new(scope);
+    let mut source = String::new();
+    let mut checked = false;
+    for line in &example.lines {
+        let Some(expected) = line.trim().strip_prefix("#=>") else {
+            source.push_str(line);
+            source.push('\n');
+            continue;
+        };
+        let mut parser = Parser::new(Lexer::new(&source));
+        let program = parser.parse_program();
+        if let Some(error) = parser.errors.first() {
+            return Outcome::Failed(format!("cannot parse the example: {}", error));
+        }
+        let actual = match evaluator.eval(&program) {
+            Some(Object::Return(ref mut value)) => std::mem::take(&mut **value),
+            Some(value) => value,
+            None => Object::Unit,
+        };
+        if actual.to_string() != expected.trim() {
+            return Outcome::Failed(format!("expected {}, got {}", expected.trim(), actual));
+        }
+        source.clear();
+        checked = true;
+    }
+    if !checked {
+        return Outcome::Failed("the example has no `#=>` line".to_string());
+    }
+    Outcome::Passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_examples() {
+        let source = "--- ```\n--- double(21)\n--- #=> 42\n--- ```\n--- ```text\n--- ignored\n--- ```\nlet double = fn x -> x * 2;\n\n--- ```\n--- double(1)\n--- #=> 3\n--- ```\nlet twice = double;";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        let examples = examples(&program);
+        let names: Vec<(&str, usize)> = examples.iter().map(|example| (example.name.as_str(), example.index)).collect();
+        assert_eq!(names, vec![("double", 1), ("twice", 1)]);
+        assert_eq!(run(&program, &examples), vec![Outcome::Passed, Outcome::Failed("expected 3, got 2".to_string())]);
+    }
+}
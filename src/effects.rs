@@ -0,0 +1,124 @@
+// effects.rs
+//
+// Effects a call can have beyond returning a value. Builtins and host
+// functions are tagged with theirs; the type checker infers the effects of
+// every function from the calls in its body, and rejects effectful calls in a
+// module declared `@pure`, or that an `Engine` does not allow. `is_pure`
+// tells the optimizer which expressions it may merge or reorder.
+
+use crate::ast::{Expression, Infix, Literal, Statement};
+use crate::builtin;
+use crate::lexer::Token;
+use std::fmt;
+
+/// A set of effects.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Effects(u8);
+
+impl Effects {
+    pub const NONE: Effects = Effects(0);
+    // Console output
+    pub const IO: Effects = Effects(1);
+    pub const FS: Effects = Effects(1 << 1);
+    pub const NET: Effects = Effects(1 << 2);
+    pub const RAND: Effects = Effects(1 << 3);
+    pub const TIME: Effects = Effects(1 << 4);
+    // Ending or starting processes
+    pub const PROCESS: Effects = Effects(1 << 5);
+    pub const ALL: Effects = Effects((1 << 6) - 1);
+
+    const NAMES: [(Effects, &'static str); 6] = [
+        (Effects::IO, "io"),
+        (Effects::FS, "fs"),
+        (Effects::NET, "net"),
+        (Effects::RAND, "rand"),
+        (Effects::TIME, "time"),
+        (Effects::PROCESS, "process"),
+    ];
+
+    /// The effect called `name`, e.g. `fs`.
+    pub fn parse(name: &str) -> Option<Effects> {
+        Effects::NAMES.iter().find(|(_, n)| *n == name).map(|(effects, _)| *effects)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Effects) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Effects) -> Effects {
+        Effects(self.0 | other.0)
+    }
+
+    /// The effects of `self` that are not in `other`.
+    pub fn difference(self, other: Effects) -> Effects {
+        Effects(self.0 & !other.0)
+    }
+}
+
+impl fmt::Display for Effects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "pure");
+        }
+        let names: Vec<&str> = Effects::NAMES
+            .iter()
+            .filter(|(effect, _)| self.contains(*effect))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Whether evaluating `expression` does nothing but compute its value, as far
+/// as can be told without types: it calls no function except builtins with
+/// no effects, and those only with pure function literals. It may still
+/// fail, e.g. dividing by zero.
+pub fn is_pure(expression: &Expression) -> bool {
+    let all = |expressions: &[Expression]| expressions.iter().all(is_pure);
+    match expression {
+        Expression::Identifier(_) | Expression::OptionNone => true,
+        Expression::OptionSome(inner)
+        | Expression::ResultOk(inner)
+        | Expression::ResultErr(inner)
+        | Expression::Prefix(_, inner)
+        | Expression::Field { record: inner, .. }
+        | Expression::Annotated { expression: inner, .. } => is_pure(inner),
+        Expression::Literal(Literal::List(elements)) => all(elements),
+        Expression::Literal(Literal::Record(fields)) => fields.iter().all(|(_, value)| is_pure(value)),
+        Expression::Literal(Literal::HashMap(entries)) => entries.iter().all(|(key, value)| is_pure(key) && is_pure(value)),
+        Expression::Literal(_) => true,
+        // `x |> f` is a call
+        Expression::Infix(infix, left, right, _) => *infix != Infix::Pipe && is_pure(left) && is_pure(right),
+        Expression::Range { start, end } => is_pure(start) && is_pure(end),
+        Expression::Block(block) => block.iter().all(is_pure_statement),
+        Expression::If { condition, consequence, alternative } => {
+            is_pure(condition) && consequence.iter().all(is_pure_statement) && alternative.iter().flatten().all(is_pure_statement)
+        }
+        // Made to be called or evaluated, so only as pure as its body
+        Expression::Function { body, .. } => body.iter().all(is_pure_statement),
+        Expression::Quote(quoted, _) => is_pure(quoted),
+        Expression::BuiltIn { function, arguments, .. } => {
+            let name = match function {
+                Token::Println => "println",
+                Token::Dbg => "dbg",
+                _ => "",
+            };
+            builtin::effects(name).is_empty() && all(arguments)
+        }
+        // What is called is only known at runtime
+        Expression::Call { .. } | Expression::Match { .. } => false,
+    }
+}
+
+// A `return` or an assertion changes how the function it is in ends
+fn is_pure_statement(statement: &Statement) -> bool {
+    match statement {
+        Statement::Let(_, value, _) | Statement::Expression(value) => is_pure(value),
+        Statement::Comment(_) => true,
+        _ => false,
+    }
+}
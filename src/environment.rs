@@ -1,16 +1,26 @@
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::lexer::Span;
 use crate::object::Object;
 
 #[derive(Debug, Clone, PartialEq    )]
 pub struct Env {
     pub store: HashMap<String, Object>,
+    // Where the `let` bindings of this scope were made
+    pub spans: HashMap<String, Span>,
     pub outer: Option<Rc<RefCell<Env>>>,
 }
 
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Env {
     pub fn new() -> Self {
         Env {
             store: HashMap::new(),
+            spans: HashMap::new(),
             outer: None,
         }
     }
@@ -18,6 +28,7 @@ impl Env {
     pub fn from(store: HashMap<String, Object>) -> Self {
         Env {
             store,
+            spans: HashMap::new(),
             outer: None,
         }
     }
@@ -25,6 +36,7 @@ impl Env {
     pub fn new_with_outer(outer: Rc<RefCell<Env>>) -> Self {
         Env {
             store: HashMap::new(),
+            spans: HashMap::new(),
             outer: Some(outer),
         }
     }
@@ -39,6 +51,14 @@ impl Env {
         }
     }
 
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.store.keys().cloned().collect();
+        if let Some(ref outer) = self.outer {
+            names.extend(outer.borrow().names());
+        }
+        names
+    }
+
     pub fn exists_in_current_scope(&self, name: &str) -> bool {
         self.store.contains_key(name)
     }
@@ -46,4 +66,45 @@ impl Env {
     pub fn set(&mut self, key: String, value: Object) {
         self.store.insert(key, value);
     }
+
+    /// Binds `key` like `set`, remembering the span of the `let` binding it.
+    pub fn define(&mut self, key: String, value: Object, span: Span) {
+        self.spans.insert(key.clone(), span);
+        self.store.insert(key, value);
+    }
+
+    /// The scope of a closure made in `env` that uses `names`: their values
+    /// from the scopes inside `globals`, over `globals` itself. None when
+    /// `env` is `globals` or not inside it.
+    pub fn capture(env: &Rc<RefCell<Env>>, globals: &Rc<RefCell<Env>>, names: &[String]) -> Option<Rc<RefCell<Env>>> {
+        let mut scopes = Vec::new();
+        let mut scope = Rc::clone(env);
+        while !Rc::ptr_eq(&scope, globals) {
+            let outer = Rc::clone(scope.borrow().outer.as_ref()?);
+            scopes.push(scope);
+            scope = outer;
+        }
+        if scopes.is_empty() {
+            return None;
+        }
+        let mut captured = Env::new_with_outer(Rc::clone(globals));
+        for name in names {
+            let scopes = scopes.iter().map(|scope| scope.borrow());
+            if let Some(scope) = scopes.into_iter().find(|scope| scope.store.contains_key(name)) {
+                captured.store.insert(name.clone(), scope.store[name].clone());
+                if let Some(span) = scope.spans.get(name) {
+                    captured.spans.insert(name.clone(), *span);
+                }
+            }
+        }
+        Some(Rc::new(RefCell::new(captured)))
+    }
+
+    /// Span of the `let` that bound the innermost `name`, if a `let` did.
+    pub fn origin(&self, name: &str) -> Option<Span> {
+        if self.store.contains_key(name) {
+            return self.spans.get(name).copied();
+        }
+        self.outer.as_ref()?.borrow().origin(name)
+    }
 }
\ No newline at end of file
@@ -1,18 +1,288 @@
 use crate::ast::Identifier;
 use crate::ast::*;
 use crate::environment::Env;
-use crate::lexer::Token;
-use crate::object::Object;
-use crate::builtin::{println_builtin, map_builtin, fold_builtin, filter_builtin};
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::lexer::{Span, Token};
+use crate::diagnostics::{self, Code};
+use crate::derive;
+use crate::profile;
+use crate::session;
+use crate::timer;
+use crate::trace;
+use crate::quote::{self, Quoted};
+use crate::object::{Native, Object};
+use crate::builtin::{self, println_builtin, map_builtin, fold_builtin, filter_builtin, concat_all_builtin, BUILTINS};
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::heap::{self, Heap, Measure};
+use crate::map::Map;
+use crate::record::FieldCache;
+use crate::string::Str;
+use typed_arena::Arena;
+use std::collections::HashMap;
+
 pub struct Evaluator {
     pub env: Rc<RefCell<Env>>,
+    pub heap: Heap,
+    // Whether `requires` and `ensures` clauses are checked
+    pub contracts: bool,
+    // Location of the assertion or infix expression that failed the last
+    // evaluation, if one did
+    pub error_span: Option<Span>,
+    // How often each binding, operator, call and assertion ran, when
+    // measuring coverage
+    pub coverage: Option<HashMap<Span, usize>>,
+    // Name of the file being run, for `dbg` to print
+    source_name: Option<String>,
+    // User types deriving `eq`, `ord` or `show`
+    derived: Rc<RefCell<derive::Registry>>,
+    // The scope the program runs in, which closures keep rather than copy
+    // from, see `closure::annotate`
+    globals: Rc<RefCell<Env>>,
+    // Most frames a run may step through
+    step_limit: Option<u64>,
+    debugger: Option<Debugger>,
+    // The bindings made while there is a debugger, in order, so travelling
+    // back can unmake them
+    journal: Vec<(Weak<RefCell<Env>>, String)>,
+}
+
+/// Called when a run stops for its debugger: at a runtime error where it
+/// happened, before it unwinds, and then at each moment the debugger
+/// travels to. What it gives says how the run goes on.
+pub type Debugger = Rc<dyn Fn(&Stop) -> Resume>;
+
+/// Where a run is stopped for its debugger. The moments of a run are the
+/// operators and calls it reaches, counted from its start.
+pub struct Stop<'a> {
+    /// The error the run stopped for
+    pub error: &'a Object,
+    /// The location of the failure or else of the last operator or call
+    /// reached, or of the moment stopped at
+    pub span: Option<Span>,
+    pub env: Rc<RefCell<Env>>,
+    /// The moment stopped at, before it runs, or None at the error
+    pub moment: Option<usize>,
+    /// Where each moment the run can travel back to was, from `earliest` up
+    /// to where it is stopped
+    pub moments: &'a [Span],
+    pub earliest: usize,
+}
+
+/// How a run goes on from a `Stop`.
+#[derive(Debug, PartialEq)]
+pub enum Resume {
+    /// Let the error stop the run
+    Abort,
+    /// Carry on; at the error, with a value in place of the failed
+    /// expression's
+    Continue(Option<Object>),
+    /// Go back, or on, to a moment and stop there
+    Travel(usize),
+}
+
+// How many moments apart a run with a debugger keeps checkpoints, and how
+// many it keeps, which bounds how far back the debugger can travel
+const CHECKPOINT_EVERY: usize = 1024;
+const CHECKPOINTS: usize = 32;
+
+// The state of a run before one of its moments
+struct Checkpoint<'a> {
+    moment: usize,
+    stack: Vec<Frame<'a>>,
+    value: Option<Object>,
+    here: Option<Span>,
+    env: Rc<RefCell<Env>>,
+    // How far the journal and the input trace had got
+    journal: usize,
+    trace: usize,
+}
+
+// What a run with a debugger keeps to travel through. Travelling back
+// restores the last checkpoint before the moment, unmakes the bindings made
+// since and rewinds the input trace, then runs on to the moment without
+// printing what was printed the first time. What else the program changed,
+// such as timers, files and actors, is not put back.
+#[derive(Default)]
+struct History<'a> {
+    // Where each moment from `earliest` on was
+    moments: Vec<Span>,
+    earliest: usize,
+    checkpoints: Vec<Checkpoint<'a>>,
+    // The moment to stop at, while travelling
+    target: Option<usize>,
+    // The most moments the run has reached
+    reached: usize,
+    // The last error the run stopped for, and where it happened
+    failure: Option<(Object, Option<Span>)>,
+}
+
+impl<'a> History<'a> {
+    // Counts a moment, giving its number
+    fn reach(&mut self, span: Span) -> usize {
+        let moment = self.earliest + self.moments.len();
+        self.moments.push(span);
+        self.reached = self.reached.max(moment + 1);
+        session::mute(moment + 1 < self.reached);
+        moment
+    }
+
+    // Drops the oldest checkpoint once there are too many, and what only
+    // it needed
+    fn keep(&mut self, checkpoint: Checkpoint<'a>, journal: &mut Vec<(Weak<RefCell<Env>>, String)>) {
+        self.checkpoints.push(checkpoint);
+        if self.checkpoints.len() > CHECKPOINTS {
+            self.checkpoints.remove(0);
+            let (moment, dropped) = (self.checkpoints[0].moment, self.checkpoints[0].journal);
+            self.moments.drain(..moment - self.earliest);
+            self.earliest = moment;
+            journal.drain(..dropped);
+            self.checkpoints.iter_mut().for_each(|checkpoint| checkpoint.journal -= dropped);
+        }
+    }
+}
+
+// The evaluator is a CEK style machine. Rather than recursing in Rust for
+// every sub-expression or call, pending work is kept on an explicit stack of
+// frames and the value of the last finished expression is held in a register,
+// so deeply nested expressions and deep recursion cannot overflow the host
+// stack.
+#[derive(Clone)]
+enum Frame<'a> {
+    // Evaluate an expression, leaving its value in the register
+    Eval(&'a Expression),
+    Statement(&'a Statement),
+    // Receives the value of the previous statement, then runs the next one
+    Block { statements: std::slice::Iter<'a, Statement>, scope: Scope },
+    Let(&'a Identifier, Span),
+    // Receives the value of an annotated let and checks it against the annotation
+    Annotation { annotation: &'a Alias, name: &'a Identifier, span: Span },
+    Return,
+    // Receives the condition of an assertion
+    Assert(&'a Assertion),
+    // Receives the value of a function body, then checks the body's
+    // `ensures` clauses with `result` bound to it
+    Ensures { clauses: std::slice::Iter<'a, Statement>, result: Option<Object> },
+    Prefix(&'a Prefix),
+    // Receive the operands of an infix expression, left then right
+    InfixLeft(&'a Expression),
+    InfixRight(&'a Expression, Object),
+    // Receives the result of a stage of a pipeline, naming the stage in
+    // its errors
+    Stage { index: usize, span: Span, input: String },
+    // With the span of the condition, for profiles
+    If { consequence: &'a Block, alternative: Option<&'a Block>, condition: Option<Span> },
+    List { remaining: std::slice::Iter<'a, Expression>, done: Vec<Object> },
+    // Receives the value of the field at `done.len()`
+    Record { fields: &'a [(Identifier, Expression)], done: Vec<(String, Object)> },
+    // Receives the keys and values of the entries in turn
+    Map { entries: &'a [(Expression, Expression)], done: Vec<Object> },
+    // With whether the access is optional, as in `record?.field`
+    Field(&'a Identifier, bool, &'a FieldCache),
+    RangeStart(&'a Expression),
+    RangeEnd(Object),
+    // With the value piped into the call, if any, to pass after the others
+    Arguments { remaining: std::slice::Iter<'a, Expression>, done: Vec<Object>, callee: Callee<'a>, piped: Option<Object> },
+    Apply { callee: Callee<'a>, arguments: Vec<Object> },
+    // Receives the function value and enters its body
+    Call { name: Option<&'a str>, arguments: Vec<Object> },
+    // Restores the caller's environment once a function body finishes
+    Leave(Rc<RefCell<Env>>),
+    // Restores the environment outside a block expression, keeping a
+    // `return` in it pending for the enclosing function
+    Restore(Rc<RefCell<Env>>),
+    Wrap(fn(Box<Object>) -> Object),
+}
+
+#[derive(Clone, Copy)]
+enum Callee<'a> {
+    Function(&'a Expression),
+    Builtin(&'a Token),
+}
+
+// A program keeps running past a top level `return`, a block stops there
+#[derive(PartialEq, Clone, Copy)]
+enum Scope {
+    Program,
+    Block,
+}
+
+// Set from a signal handler, so shared by every evaluator in the process
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the running evaluation to stop, at its next step, with an
+/// `interrupted` error, e.g. on Ctrl-C. Returns whether an earlier request is
+/// still pending.
+pub fn interrupt() -> bool {
+    INTERRUPTED.swap(true, Ordering::Relaxed)
+}
+
+thread_local! {
+    // Steps left of those allowed, and the limit. Shared with the evaluators
+    // that builtins start for callbacks, e.g. the function given to `map`.
+    static STEPS: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+/// Whether an interrupt is waiting to stop the evaluation, for builtins
+/// that wait, such as `sleep`.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Withdraws a pending interrupt that arrived while nothing was running.
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::Relaxed);
 }
 
 impl Evaluator {
     pub fn new(env: Rc<RefCell<Env>>) -> Self {
-        Evaluator { env }
+        let globals = Rc::clone(&env);
+        Evaluator { env, heap: Heap::default(), contracts: false, error_span: None, coverage: None, source_name: None, derived: Rc::default(), globals, step_limit: None, debugger: None, journal: Vec::new() }
+    }
+
+    /// Makes a new top level inside the current one, where `let` may bind
+    /// names bound so far again, as each cell of a `Session` does.
+    pub(crate) fn nest_globals(&mut self) {
+        self.globals = Rc::new(RefCell::new(Env::new_with_outer(Rc::clone(&self.env))));
+        self.env = Rc::clone(&self.globals);
+    }
+
+    /// Checks the `requires` and `ensures` clauses of functions as they are called.
+    pub fn with_contracts(mut self, contracts: bool) -> Self {
+        self.contracts = contracts;
+        self
+    }
+
+    /// Names the file being run in the output of `dbg` and in interrupts.
+    pub fn with_source_name(mut self, name: &str) -> Self {
+        self.source_name = Some(name.to_string());
+        self
+    }
+
+    /// Counts the nodes that run in `self.coverage`, see `coverage::lines`.
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(HashMap::new());
+        self
+    }
+
+    /// Fails evaluation with a runtime error once it has taken `steps` steps,
+    /// counting those of the functions builtins call back into.
+    pub fn with_step_limit(mut self, steps: u64) -> Self {
+        self.step_limit = Some(steps);
+        self
+    }
+
+    /// Hands runtime errors to `debugger` as they happen, see `Debugger`,
+    /// keeping what it needs to travel back through the run.
+    pub fn with_debugger(mut self, debugger: Debugger) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    /// Fails evaluation with a runtime error once live values exceed `bytes`.
+    pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+        self.heap = Heap::new(Some(bytes));
+        self
     }
 
     pub fn is_truthy(&self, object: &Object) -> bool {
@@ -23,382 +293,989 @@ impl Evaluator {
         }
     }
 
-    pub fn eval(&mut self, program: &Program) -> Option<Object> {
-        let mut result: Option<Object> = None;
-        for statement in program {
-            match self.eval_statement(statement) {
-                Some(obj) => {
-                    if let Object::Error(_) = obj {
-                        return Some(obj);
+    pub fn eval(&mut self, program: &[Statement]) -> Option<Object> {
+        let value = self.run(Frame::Block { statements: program.iter(), scope: Scope::Program });
+        // Timers still pending fire once the program is done
+        if matches!(value, Some(Object::Error(..))) {
+            timer::cancel_all();
+            return value;
+        }
+        timer::run_pending().or(value)
+    }
+
+    pub fn eval_block(&mut self, program: &[Statement]) -> Option<Object> {
+        self.run(Frame::Block { statements: program.iter(), scope: Scope::Block })
+    }
+
+    pub fn eval_expression(&mut self, expression: &Expression) -> Option<Object> {
+        self.run(Frame::Eval(expression))
+    }
+
+    fn run(&mut self, frame: Frame) -> Option<Object> {
+        // Function bodies are shared with their closures; the arena keeps each
+        // one alive while frames borrow from it
+        let arena: Arena<Rc<Block>> = Arena::new();
+        let mut bodies: HashMap<*const Block, &Block> = HashMap::new();
+        let mut stack = vec![frame];
+        let mut value: Option<Object> = None;
+        self.error_span = None;
+        // The last operator or call reached, where an interrupt is reported
+        let mut here: Option<Span> = None;
+        let outer_steps = self.step_limit.map(|limit| STEPS.replace(Some((limit, limit))));
+        // Whether the error in the register is already unwinding
+        let mut unwinding = false;
+        let mut history = self.debugger.is_some().then(History::default);
+
+        loop {
+            if let Some(history) = history.as_mut().filter(|_| !unwinding) {
+                if self.debug(history, &mut stack, &mut value, &mut here) {
+                    continue;
+                }
+            }
+            unwinding = matches!(value, Some(Object::Error(..)));
+            let Some(frame) = stack.pop() else { break };
+            // Left pending while an error unwinds, which stops the run anyway
+            if INTERRUPTED.load(Ordering::Relaxed) && !matches!(value, Some(Object::Error(..))) && INTERRUPTED.swap(false, Ordering::Relaxed) {
+                self.error_span = here;
+                value = Some(Object::Error(Code::Interrupted, self.interrupted_at(here)));
+            }
+            if let Some((left, limit)) = STEPS.get().filter(|_| !matches!(value, Some(Object::Error(..)))) {
+                if left == 0 {
+                    self.error_span = here;
+                    value = Some(Object::Error(Code::StepLimit, format!("Evaluation took more than {} steps", limit)));
+                } else {
+                    STEPS.set(Some((left - 1, limit)));
+                }
+            }
+            // Errors unwind every pending frame, restoring environments on the way
+            if matches!(value, Some(Object::Error(..))) && !matches!(frame, Frame::Leave(_) | Frame::Restore(_) | Frame::Stage { .. }) {
+                continue;
+            }
+            // A return inside an expression, e.g. in a block or an if, skips the
+            // rest of the expression up to the body of its function
+            if matches!(value, Some(Object::Return(_)))
+                && !matches!(frame, Frame::Eval(_) | Frame::Statement(_) | Frame::Block { .. } | Frame::Ensures { .. } | Frame::Leave(_) | Frame::Restore(_))
+            {
+                continue;
+            }
+            // Set when the frame produced a newly allocated value
+            let mut allocated = false;
+            match frame {
+                Frame::Eval(expression) => {
+                    if let Some((history, span)) = history.as_mut().zip(expression.span()) {
+                        let moment = history.reach(span);
+                        if moment % CHECKPOINT_EVERY == 0 {
+                            let mut saved = stack.clone();
+                            saved.push(Frame::Eval(expression));
+                            let checkpoint = Checkpoint { moment, stack: saved, value: value.clone(), here, env: Rc::clone(&self.env), journal: self.journal.len(), trace: trace::position() };
+                            history.keep(checkpoint, &mut self.journal);
+                        }
+                        if history.target == Some(moment) {
+                            history.target = None;
+                            match self.stop(history, Some(moment), Some(span)) {
+                                Resume::Travel(target) if target > moment => history.target = Some(target),
+                                Resume::Travel(target) => {
+                                    self.restore(history, target, &mut stack, &mut value, &mut here);
+                                    continue;
+                                }
+                                Resume::Continue(_) => {}
+                                Resume::Abort => {
+                                    (value, self.error_span) = history.failure.clone().map_or((None, None), |(error, span)| (Some(error), span));
+                                    unwinding = true;
+                                    continue;
+                                }
+                            }
+                        }
                     }
-                    result = Some(obj);
+                    here = expression.span().or(here);
+                    value = self.step(expression, &mut stack);
+                    allocated = matches!(expression, Expression::Identifier(_) | Expression::Literal(_));
+                }
+                Frame::Statement(statement) => match statement {
+                    Statement::Let(identifier, expression, span) => {
+                        self.cover(*span);
+                        stack.push(Frame::Let(identifier, *span));
+                        if let Expression::Annotated { annotation, .. } = expression {
+                            stack.push(Frame::Annotation { annotation, name: identifier, span: *span });
+                        }
+                        stack.push(Frame::Eval(expression));
+                    }
+                    Statement::Return(expression) => {
+                        stack.push(Frame::Return);
+                        stack.push(Frame::Eval(expression));
+                    }
+                    Statement::Expression(expression) => stack.push(Frame::Eval(expression)),
+                    Statement::Type(identifier, declaration, derives) => value = self.eval_type(identifier, declaration, derives),
+                    Statement::Assert(assertion) => match assertion.kind {
+                        AssertionKind::Requires if !self.contracts => value = None,
+                        // Checked once the body has a value, see `Frame::Ensures`
+                        AssertionKind::Ensures => value = None,
+                        _ => {
+                            stack.push(Frame::Assert(assertion));
+                            stack.push(Frame::Eval(&assertion.condition));
+                        }
+                    },
+                    Statement::Comment(_) | Statement::Attribute(..) => value = None,
                 },
-                None => {
-                    result = None;
+                Frame::Block { mut statements, scope } => {
+                    if scope == Scope::Block && matches!(value, Some(Object::Return(_))) {
+                        continue;
+                    }
+                    if let Some(statement) = statements.next() {
+                        stack.push(Frame::Block { statements, scope });
+                        stack.push(Frame::Statement(statement));
+                    }
                 }
+                Frame::Let(identifier, span) => value = self.eval_let(identifier, value.take(), span),
+                Frame::Annotation { annotation, name, span } => match &value {
+                    Some(object) if !conforms(object, annotation) => {
+                        self.error_span = Some(span);
+                        value = Some(Object::Error(Code::AnnotationMismatch, format!(
+                            "Expected {} for {}, got {}",
+                            annotation,
+                            name,
+                            object.type_name()
+                        )));
+                    }
+                    _ => {}
+                },
+                Frame::Return => value = value.map(|result| Object::Return(Box::new(result))),
+                Frame::Assert(assertion) => {
+                    self.cover(assertion.span);
+                    value = match value.take() {
+                        Some(Object::Boolean(true)) => None,
+                        Some(Object::Boolean(false)) => {
+                            self.error_span = Some(assertion.span);
+                            Some(Object::Error(Code::AssertionFailed, assertion_failure(assertion)))
+                        }
+                        Some(other) => Some(Object::Error(Code::AssertionFailed, format!(
+                            "Condition of {} must be a bool, got {}",
+                            assertion_name(assertion.kind),
+                            other.type_name()
+                        ))),
+                        None => Some(Object::Error(Code::MissingValue, "Failed to evaluate assertion".to_string())),
+                    };
+                }
+                Frame::Ensures { mut clauses, result } => {
+                    let result = match result {
+                        Some(result) => result,
+                        None => {
+                            let result = match value.take() {
+                                Some(Object::Return(ref mut result)) => std::mem::take(&mut **result),
+                                Some(result) => result,
+                                None => continue,
+                            };
+                            let mut scope = Env::new_with_outer(Rc::clone(&self.env));
+                            scope.set("result".to_string(), result.clone());
+                            self.env = Rc::new(RefCell::new(scope));
+                            result
+                        }
+                    };
+                    let next = clauses.find_map(|statement| match statement {
+                        Statement::Assert(assertion) if assertion.kind == AssertionKind::Ensures => Some(assertion),
+                        _ => None,
+                    });
+                    match next {
+                        Some(assertion) => {
+                            stack.push(Frame::Ensures { clauses, result: Some(result) });
+                            stack.push(Frame::Assert(assertion));
+                            stack.push(Frame::Eval(&assertion.condition));
+                        }
+                        None => value = Some(result),
+                    }
+                }
+                Frame::Prefix(prefix) => value = value.map(|right| self.eval_prefix(prefix, right)),
+                Frame::InfixLeft(expression) => {
+                    if let (Some(mut left), Expression::Infix(infix, left_operand, right, span)) = (value.take(), expression) {
+                        // `x ?? default` evaluates the default only when x has no value
+                        if *infix == Infix::Coalesce {
+                            value = match left {
+                                Object::OptionSome(ref mut inner) | Object::ResultOk(ref mut inner) => Some(std::mem::take(&mut **inner)),
+                                Object::OptionNone | Object::ResultErr(_) => {
+                                    stack.push(Frame::Eval(right));
+                                    None
+                                }
+                                left => {
+                                    self.error_span = Some(*span);
+                                    let message = self.locate(mismatch(infix, &left.type_name(), ""), *span, left_operand, right);
+                                    Some(Object::Error(Code::OperandMismatch, message))
+                                }
+                            };
+                            continue;
+                        }
+                        if *infix == Infix::Pipe {
+                            stack.push(Frame::Stage { index: stage(left_operand), span: *span, input: left.type_name() });
+                            // `x |> f(a)` calls f with a, then x
+                            match &**right {
+                                Expression::Call { function, arguments, span } => {
+                                    self.cover(*span);
+                                    self.arguments(Callee::Function(function), arguments, Some(left), &mut stack);
+                                    continue;
+                                }
+                                Expression::BuiltIn { function, arguments, span } => {
+                                    self.cover(*span);
+                                    self.arguments(Callee::Builtin(function), arguments, Some(left), &mut stack);
+                                    continue;
+                                }
+                                _ => {}
+                            }
+                        }
+                        stack.push(Frame::InfixRight(expression, left));
+                        stack.push(Frame::Eval(right));
+                    }
+                }
+                Frame::InfixRight(expression, left) => {
+                    let Expression::Infix(infix, left_operand, right_operand, span) = expression else {
+                        continue;
+                    };
+                    // `x |> f` calls f, now in the register, with x
+                    if *infix == Infix::Pipe {
+                        let name = match &**right_operand {
+                            Expression::Identifier(Token::Identifier(name)) => Some(name.as_str()),
+                            _ => None,
+                        };
+                        stack.push(Frame::Call { name, arguments: vec![left] });
+                        continue;
+                    }
+                    value = value.map(|right| match self.eval_infix(infix, left, right) {
+                        Object::Error(Code::OperandMismatch, ref mut message) => {
+                            self.error_span = Some(*span);
+                            let message = self.locate(std::mem::take(message), *span, left_operand, right_operand);
+                            Object::Error(Code::OperandMismatch, message)
+                        }
+                        result => result,
+                    });
+                    allocated = true;
+                }
+                Frame::Stage { index, span, input } => {
+                    if let Some(Object::Error(code, message)) = &mut value {
+                        if matches!(code, Code::Exit(_)) {
+                            continue;
+                        }
+                        self.error_span.get_or_insert(span);
+                        message.push_str(&format!("; in stage {} of the pipeline at line {}, column {}, applied to a value of type {}", index, span.line, span.column, input));
+                    }
+                }
+                Frame::If { consequence, alternative, condition: span } => {
+                    let Some(condition) = value.take() else {
+                        continue;
+                    };
+                    let taken = self.is_truthy(&condition);
+                    if let Some(span) = span {
+                        profile::branch(span, taken);
+                    }
+                    let branch = if taken { Some(consequence) } else { alternative };
+                    if let Some(branch) = branch {
+                        stack.push(Frame::Block { statements: branch.iter(), scope: Scope::Block });
+                    }
+                }
+                Frame::List { mut remaining, mut done } => {
+                    let Some(element) = value.take() else {
+                        value = Some(Object::Error(Code::MissingValue, "Failed to evaluate list element".to_string()));
+                        continue;
+                    };
+                    if let Some(first) = done.first() {
+                        if !self.same_type(&element, first) {
+                            value = Some(Object::Error(Code::HeterogeneousList, format!(
+                                "List elements must be of the same type. Expected {}, got {}",
+                                first.type_name(), element.type_name()
+                            )));
+                            continue;
+                        }
+                    }
+                    done.push(element);
+                    match remaining.next() {
+                        Some(next) => {
+                            stack.push(Frame::List { remaining, done });
+                            stack.push(Frame::Eval(next));
+                        }
+                        None => {
+                            value = Some(Object::List(done));
+                            allocated = true;
+                        }
+                    }
+                }
+                Frame::Record { fields, mut done } => {
+                    let Some(field) = value.take() else {
+                        value = Some(Object::Error(Code::MissingValue, "Failed to evaluate record field".to_string()));
+                        continue;
+                    };
+                    done.push((fields[done.len()].0.to_string(), field));
+                    match fields.get(done.len()) {
+                        Some((_, next)) => {
+                            stack.push(Frame::Record { fields, done });
+                            stack.push(Frame::Eval(next));
+                        }
+                        None => {
+                            value = Some(Object::record(done));
+                            allocated = true;
+                        }
+                    }
+                }
+                Frame::Map { entries, mut done } => {
+                    let Some(part) = value.take() else {
+                        value = Some(Object::Error(Code::MissingValue, "Failed to evaluate map entry".to_string()));
+                        continue;
+                    };
+                    done.push(part);
+                    // A key is followed by its value, a value by the next key
+                    let next = match done.len() % 2 {
+                        1 => Some(&entries[done.len() / 2].1),
+                        _ => entries.get(done.len() / 2).map(|(key, _)| key),
+                    };
+                    match next {
+                        Some(next) => {
+                            stack.push(Frame::Map { entries, done });
+                            stack.push(Frame::Eval(next));
+                        }
+                        None => {
+                            let mut parts = done.into_iter();
+                            let pairs = std::iter::from_fn(|| Some((parts.next()?, parts.next()?)));
+                            value = Some(Map::from_entries(pairs).map_or_else(|error| error, Object::Map));
+                            allocated = true;
+                        }
+                    }
+                }
+                Frame::Field(field, false, cache) => value = value.map(|record| self.eval_field(record, field, cache)),
+                Frame::Field(field, true, cache) => value = value.map(|record| self.eval_optional_field(record, field, cache)),
+                Frame::RangeStart(end) => {
+                    let start = value
+                        .take()
+                        .unwrap_or(Object::Error(Code::MissingValue, "Failed to evaluate start".to_string()));
+                    stack.push(Frame::RangeEnd(start));
+                    stack.push(Frame::Eval(end));
+                }
+                Frame::RangeEnd(start) => {
+                    let end = value
+                        .take()
+                        .unwrap_or(Object::Error(Code::MissingValue, "Failed to evaluate end".to_string()));
+                    value = Some(self.eval_range(start, end));
+                    allocated = true;
+                }
+                Frame::Arguments { mut remaining, mut done, callee, piped } => {
+                    let Some(argument) = value.take() else {
+                        value = Some(Object::Error(Code::MissingValue, "Failed to evaluate argument".to_string()));
+                        continue;
+                    };
+                    done.push(argument);
+                    match remaining.next() {
+                        Some(next) => {
+                            stack.push(Frame::Arguments { remaining, done, callee, piped });
+                            stack.push(Frame::Eval(next));
+                        }
+                        None => {
+                            done.extend(piped);
+                            stack.push(Frame::Apply { callee, arguments: done });
+                        }
+                    }
+                }
+                Frame::Apply { callee, arguments } => match callee {
+                    Callee::Function(function) => {
+                        let name = match function {
+                            Expression::Identifier(Token::Identifier(name)) => Some(name.as_str()),
+                            _ => None,
+                        };
+                        stack.push(Frame::Call { name, arguments });
+                        stack.push(Frame::Eval(function));
+                    }
+                    Callee::Builtin(function) => {
+                        value = Some(self.eval_builtin(function, arguments));
+                        allocated = true;
+                    }
+                },
+                Frame::Call { name, arguments } => {
+                    let (parameters, body, env) = match value.take() {
+                        Some(Object::Function(ref mut parameters, ref body, ref env)) => (std::mem::take(parameters), Rc::clone(body), Rc::clone(env)),
+                        Some(Object::Builtin(function)) => {
+                            value = Some(function(arguments));
+                            if self.heap.limit.is_some() {
+                                self.account(&mut value, &stack);
+                            }
+                            continue;
+                        }
+                        Some(Object::Native(ref native)) => {
+                            value = Some((native.function)(arguments));
+                            if self.heap.limit.is_some() {
+                                self.account(&mut value, &stack);
+                            }
+                            continue;
+                        }
+                        _ => {
+                            value = Some(Object::Error(Code::NotAFunction, String::from("Expected function")));
+                            continue;
+                        }
+                    };
+                    if parameters.len() != arguments.len() {
+                        value = Some(arity_error(name, &parameters, arguments.len()));
+                        continue;
+                    }
+                    if let Some(name) = name {
+                        profile::call(name);
+                    }
+
+                    let mut inner_env = Env::new_with_outer(env);
+                    for (ident, arg) in parameters.iter().zip(arguments) {
+                        if let Token::Identifier(name) = ident.clone() {
+                            inner_env.set(name, arg);
+                        } else {
+                            value = Some(Object::Error(Code::Unsupported, format!("Expected identifier, got {:?}", ident)));
+                            break;
+                        }
+                    }
+                    if value.is_some() {
+                        continue;
+                    }
+
+                    let body = *bodies.entry(Rc::as_ptr(&body)).or_insert_with(|| &**arena.alloc(body));
+                    let current_env = std::mem::replace(&mut self.env, Rc::new(RefCell::new(inner_env)));
+                    stack.push(Frame::Leave(current_env));
+                    if self.contracts && body.iter().any(is_ensures) {
+                        stack.push(Frame::Ensures { clauses: body.iter(), result: None });
+                    }
+                    stack.push(Frame::Block { statements: body.iter(), scope: Scope::Block });
+                }
+                Frame::Leave(env) => {
+                    self.env = env;
+                    value = match value.take() {
+                        Some(Object::Return(ref mut value)) => Some(std::mem::take(&mut **value)),
+                        Some(o) => Some(o),
+                        None => Some(Object::Error(Code::MissingValue, String::from("Expected return value"))),
+                    };
+                }
+                Frame::Restore(env) => self.env = env,
+                Frame::Wrap(wrap) => value = value.map(|inner| wrap(Box::new(inner))),
             }
+            if allocated && self.heap.limit.is_some() {
+                self.account(&mut value, &stack);
+            }
+        }
+        if let Some(outer) = outer_steps {
+            STEPS.set(outer);
+        }
+        if history.is_some() {
+            session::mute(false);
         }
-        result
+        value
     }
 
-    fn eval_statement(&mut self, statement: &Statement) -> Option<Object> {
-        match statement {
-            Statement::Let(identifier, expression) => self.eval_let(identifier, expression),
-            Statement::Expression(expression) => self.eval_expression(expression),
-            Statement::Return(expression) => self.eval_return(expression),
-            Statement::Type(identifier, declaration) => self.eval_type(identifier, declaration),
-            Statement::Comment(_) => None,
+    // Stops for the debugger at an error that has just happened, and goes on
+    // as it says; true when it travelled back, replacing the stack
+    fn debug<'a>(&mut self, history: &mut History<'a>, stack: &mut Vec<Frame<'a>>, value: &mut Option<Object>, here: &mut Option<Span>) -> bool {
+        let Some(error @ Object::Error(..)) = value.clone() else {
+            return false;
+        };
+        if matches!(error, Object::Error(Code::Exit(_) | Code::Interrupted, _)) {
+            return false;
+        }
+        let span = self.error_span.or(*here);
+        history.failure = Some((error, span));
+        match self.stop(history, None, span) {
+            Resume::Travel(moment) => return self.restore(history, moment, stack, value, here),
+            Resume::Continue(Some(replacement)) => {
+                *value = Some(replacement);
+                self.error_span = None;
+            }
+            Resume::Continue(None) | Resume::Abort => {}
         }
+        false
     }
 
-    fn eval_type(&mut self, identifier: &Identifier, declaration: &Type) -> Option<Object> {
-        Some(Object::Error(format!("Type evaluation not implemented for {:?}, given identifier: {:?}", declaration, identifier)))
+    // Hands the run to the debugger where it is stopped
+    fn stop(&self, history: &History, moment: Option<usize>, span: Option<Span>) -> Resume {
+        let (Some(debugger), Some((error, _))) = (&self.debugger, &history.failure) else {
+            return Resume::Continue(None);
+        };
+        let muted = session::muted();
+        session::mute(false);
+        let resume = debugger(&Stop { error, span, env: Rc::clone(&self.env), moment, moments: &history.moments, earliest: history.earliest });
+        session::mute(muted);
+        resume
     }
 
-    fn eval_let(&mut self, identifier: &Identifier, expression: &Expression) -> Option<Object> {
-        if let Some(value) = self.eval_expression(expression) {
-            if let Token::Identifier(name) = identifier {
-                if self.env.borrow().exists_in_current_scope(&name) {
-                    return Some(Object::Error(format!(
-                        "Cannot redefine variable '{}' in the same scope. Variable shadowing is not allowed.",
-                        name
-                    )));
-                }
-                self.env.borrow_mut().set(name.clone(), value);
-                None
-            } else {
-                Some(Object::Error(format!(
-                    "Expected identifier, got {:?}",
-                    identifier
-                )))
+    // Puts the run back as it was at the last checkpoint before `moment`,
+    // to run on to it without printing again; false when there is none
+    fn restore<'a>(&mut self, history: &mut History<'a>, moment: usize, stack: &mut Vec<Frame<'a>>, value: &mut Option<Object>, here: &mut Option<Span>) -> bool {
+        let index = history.checkpoints.iter().rposition(|checkpoint| checkpoint.moment <= moment).unwrap_or(0);
+        history.checkpoints.truncate(index + 1);
+        let Some(checkpoint) = history.checkpoints.pop() else {
+            return false;
+        };
+        for (env, name) in self.journal.drain(checkpoint.journal..).rev() {
+            if let Some(env) = env.upgrade() {
+                let mut env = env.borrow_mut();
+                env.store.remove(&name);
+                env.spans.remove(&name);
             }
-        } else {
-            Some(Object::Error(format!(
-                "Expected value, got {:?}",
-                expression
-            )))
         }
+        history.moments.truncate(checkpoint.moment - history.earliest);
+        history.target = Some(moment.max(checkpoint.moment));
+        trace::rewind(checkpoint.trace);
+        session::mute(true);
+        (*stack, *value, *here) = (checkpoint.stack, checkpoint.value, checkpoint.here);
+        self.env = checkpoint.env;
+        self.error_span = None;
+        true
     }
 
-    fn eval_return(&mut self, expression: &Expression) -> Option<Object> {
-        let result = self.eval_expression(expression);
-        match result {
-            Some(result) => Some(Object::Return(Box::new(result))),
-            None => None,
+    // Notes a binding just made in the current scope, for a debugger to
+    // travel back past
+    fn journal(&mut self, name: &str) {
+        if self.debugger.is_some() {
+            self.journal.push((Rc::downgrade(&self.env), name.to_string()));
         }
     }
 
-    fn eval_expression(&mut self, expression: &Expression) -> Option<Object> {
-        match expression {
-            Expression::Identifier(identifier) => self.eval_identifier(identifier),
-            Expression::If {
-                condition,
-                consequence,
-                alternative,
-            } => self.eval_if(condition, consequence, alternative),
-            Expression::Literal(literal) => Some(self.eval_literal(literal)),
-            Expression::Range { start, end } => Some(self.eval_range(start, end)),
-            Expression::OptionNone => Some(Object::OptionNone),
-            Expression::Function { parameters, body } => Some(Object::Function(
-                parameters.clone(),
-                body.clone(),
-                Rc::clone(&self.env),
-            )),
-            Expression::Call {
-                function,
-                arguments,
-            } => Some(self.eval_call(function, arguments)),
-            Expression::Prefix(prefix, expression) => self
-                .eval_expression(&expression)
-                .map(|right| self.eval_prefix(prefix, right)),
-            Expression::Infix(infix, left_expression, right_expression) => {
-                let left = self.eval_expression(left_expression);
-                let right = self.eval_expression(right_expression);
-                if left.is_some() && right.is_some() {
-                    Some(self.eval_infix(infix, left.unwrap(), right.unwrap()))
-                } else {
-                    None
-                }
-            }
-            Expression::BuiltIn { function, arguments } => {
-                let args = arguments.iter()
-                    .map(|arg| self.eval_expression(arg).unwrap_or(Object::Error("Failed to evaluate argument".to_string())))
-                    .collect();
-                
-                match function {
-                    Token::Println => Some(println_builtin(args)),
-                    Token::Map => Some(map_builtin(args)),
-                    Token::Fold => Some(fold_builtin(args)),
-                    Token::Filter => Some(filter_builtin(args)),
-                    _ => Some(Object::Error("Unknown builtin function".to_string())),
+    // Adds a new value to the running total and, when that passes the limit,
+    // measures everything still reachable from the environment and the stack
+    fn account(&mut self, value: &mut Option<Object>, stack: &[Frame]) {
+        let Some(object) = value else {
+            return;
+        };
+        if !self.heap.allocate(heap::size(object)) {
+            return;
+        }
+        let mut measure = Measure::live();
+        measure.env(&self.env);
+        measure.object(object);
+        for frame in stack {
+            match frame {
+                Frame::InfixRight(_, object) | Frame::RangeEnd(object) => measure.object(object),
+                Frame::List { done, .. } => done.iter().for_each(|o| measure.object(o)),
+                Frame::Arguments { done, piped, .. } => done.iter().chain(piped).for_each(|o| measure.object(o)),
+                Frame::Record { done, .. } => done.iter().for_each(|(_, o)| measure.object(o)),
+                Frame::Map { done, .. } => done.iter().for_each(|o| measure.object(o)),
+                Frame::Apply { arguments, .. } | Frame::Call { arguments, .. } => {
+                    arguments.iter().for_each(|o| measure.object(o))
                 }
+                Frame::Leave(env) | Frame::Restore(env) => measure.env(env),
+                _ => {}
             }
-            _ => unreachable!("[ERR] Only literal expression evaluation works."),
+        }
+        self.heap.reset(measure.bytes);
+        if let Some(limit) = self.heap.limit.filter(|limit| measure.bytes > *limit) {
+            *value = Some(Object::Error(Code::HeapLimit, format!(
+                "Heap limit of {} bytes exceeded, {} bytes are live",
+                limit, measure.bytes
+            )));
         }
     }
 
-    fn eval_range(&mut self, start: &Expression, end: &Expression) -> Object {
-        let start_val = self.eval_expression(start).unwrap_or(Object::Error("Failed to evaluate start".to_string()));
-        let end_val = self.eval_expression(end).unwrap_or(Object::Error("Failed to evaluate end".to_string()));
-        
-        match (start_val, end_val) {
-            (Object::Integer(start_int), Object::Integer(end_int)) => {
-                let mut list = Vec::new();
-                for i in start_int..=end_int {
-                    list.push(Object::Integer(i));
+    // Evaluates leaves directly; anything with sub-expressions pushes frames
+    // and produces its value later
+    fn step<'a>(&mut self, expression: &'a Expression, stack: &mut Vec<Frame<'a>>) -> Option<Object> {
+        if let Expression::Infix(.., span) | Expression::Call { span, .. } | Expression::BuiltIn { span, .. } = expression {
+            self.cover(*span);
+        }
+        match expression {
+            Expression::Identifier(identifier) => return self.eval_identifier(identifier),
+            Expression::Quote(expression, source) => {
+                return Some(Object::Quoted(Quoted { expression: Rc::clone(expression), source: Rc::clone(source) }))
+            }
+            Expression::Literal(Literal::List(elements)) => {
+                let mut remaining = elements.iter();
+                match remaining.next() {
+                    Some(first) => {
+                        stack.push(Frame::List { remaining, done: Vec::new() });
+                        stack.push(Frame::Eval(first));
+                    }
+                    None => return Some(Object::List(Vec::new())),
                 }
-                Object::List(list)
-            },
-            (non_int_start, _) if !matches!(non_int_start, Object::Integer(_)) => {
-                Object::Error(format!("Range start must be an integer, got {:?}", non_int_start))
+            }
+            Expression::Literal(Literal::Record(fields)) => match fields.first() {
+                Some((_, first)) => {
+                    stack.push(Frame::Record { fields, done: Vec::new() });
+                    stack.push(Frame::Eval(first));
+                }
+                None => return Some(Object::record(Vec::new())),
             },
-            (_, non_int_end) if !matches!(non_int_end, Object::Integer(_)) => {
-                Object::Error(format!("Range end must be an integer, got {:?}", non_int_end))
+            Expression::Literal(Literal::HashMap(entries)) => match entries.first() {
+                Some((first, _)) => {
+                    stack.push(Frame::Map { entries, done: Vec::new() });
+                    stack.push(Frame::Eval(first));
+                }
+                None => return Some(Object::Map(Map::new())),
             },
-            _ => {
-                Object::Error("Error creating range".to_string())
+            Expression::Literal(literal) => return Some(self.eval_literal(literal)),
+            Expression::OptionNone => return Some(Object::OptionNone),
+            Expression::OptionSome(inner) => {
+                stack.push(Frame::Wrap(Object::OptionSome));
+                stack.push(Frame::Eval(inner));
+            }
+            Expression::ResultOk(inner) => {
+                stack.push(Frame::Wrap(Object::ResultOk));
+                stack.push(Frame::Eval(inner));
+            }
+            Expression::ResultErr(inner) => {
+                stack.push(Frame::Wrap(Object::ResultErr));
+                stack.push(Frame::Eval(inner));
+            }
+            Expression::Function { parameters, body, captures } => {
+                let captured = captures.as_ref().and_then(|names| Env::capture(&self.env, &self.globals, names));
+                return Some(Object::Function(parameters.clone(), Rc::clone(body), captured.unwrap_or_else(|| Rc::clone(&self.env))));
+            }
+            Expression::Prefix(prefix, right) => {
+                stack.push(Frame::Prefix(prefix));
+                stack.push(Frame::Eval(right));
+            }
+            Expression::Infix(_, left, _, _) => {
+                stack.push(Frame::InfixLeft(expression));
+                stack.push(Frame::Eval(left));
+            }
+            Expression::If { condition, consequence, alternative } => {
+                stack.push(Frame::If { consequence, alternative: alternative.as_ref(), condition: condition.span() });
+                stack.push(Frame::Eval(condition));
+            }
+            Expression::Field { record, field, optional, cache } => {
+                stack.push(Frame::Field(field, *optional, cache));
+                stack.push(Frame::Eval(record));
+            }
+            Expression::Range { start, end } => {
+                stack.push(Frame::RangeStart(end));
+                stack.push(Frame::Eval(start));
+            }
+            // Checked by the let binding it, see `Frame::Annotation`
+            Expression::Annotated { expression, .. } => stack.push(Frame::Eval(expression)),
+            Expression::Call { function, arguments, .. } => self.arguments(Callee::Function(function), arguments, None, stack),
+            Expression::BuiltIn { function, arguments, .. } => self.arguments(Callee::Builtin(function), arguments, None, stack),
+            Expression::Block(statements) => {
+                let scope = Env::new_with_outer(Rc::clone(&self.env));
+                let outer = std::mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+                stack.push(Frame::Restore(outer));
+                stack.push(Frame::Block { statements: statements.iter(), scope: Scope::Block });
+            }
+            Expression::Match { .. } => {
+                return Some(Object::Error(Code::Unsupported, format!("Evaluation not implemented for {:?}", expression)));
             }
         }
+        None
     }
 
-    fn eval_call(&mut self, function: &Expression, arguments: &Vec<Expression>) -> Object {
-        let arguments = arguments
-            .iter()
-            .map(|argument| {
-                self.eval_expression(argument)
-                    .unwrap_or(Object::Error(String::from("Expected value")))
-            })
-            .collect::<Vec<Object>>();
+    fn cover(&mut self, span: Span) {
+        if let Some(coverage) = &mut self.coverage {
+            *coverage.entry(span).or_insert(0) += 1;
+        }
+    }
 
-        let (parameters, body, env) = match self.eval_expression(function) {
-            Some(Object::Function(parameters, body, env)) => (parameters, body, env),
-            _ => return Object::Error(String::from("Expected function")),
-        };
+    fn arguments<'a>(&mut self, callee: Callee<'a>, arguments: &'a [Expression], piped: Option<Object>, stack: &mut Vec<Frame<'a>>) {
+        let mut remaining = arguments.iter();
+        match remaining.next() {
+            Some(first) => {
+                stack.push(Frame::Arguments { remaining, done: Vec::new(), callee, piped });
+                stack.push(Frame::Eval(first));
+            }
+            None => stack.push(Frame::Apply { callee, arguments: piped.into_iter().collect() }),
+        }
+    }
+
+    // e.g. `interrupted at main.opl:3`
+    fn interrupted_at(&self, span: Option<Span>) -> String {
+        match (span, &self.source_name) {
+            (Some(span), Some(name)) => format!("interrupted at {}:{}", name, span.line),
+            (Some(span), None) => format!("interrupted at line {}", span.line),
+            (None, _) => "interrupted".to_string(),
+        }
+    }
 
-        if parameters.len() != arguments.len() {
-            return Object::Error(format!(
-                "Expected {} arguments, got {}",
-                parameters.len(),
-                arguments.len()
-            ));
+    // Prints e.g. `main.opl:3: xs ++ [1] = [1]` to stderr
+    fn eval_dbg(&self, args: Vec<Object>) -> Object {
+        let Ok([value, Object::String(ref source), Object::Integer(line)]) = <[Object; 3]>::try_from(args) else {
+            return Object::Error(Code::BuiltinArgument, "dbg expects exactly one argument".to_string());
+        };
+        if !session::muted() {
+            eprintln!("{}:{}: {} = {}", self.source_name.as_deref().unwrap_or("<input>"), line, source, value);
         }
+        value
+    }
 
-        let mut inner_env = Env::new_with_outer(Rc::clone(&env));
+    fn eval_builtin(&mut self, function: &Token, args: Vec<Object>) -> Object {
+        match function {
+            Token::Println => println_builtin(args),
+            Token::Map => map_builtin(args),
+            Token::Fold => fold_builtin(args),
+            Token::Filter => filter_builtin(args),
+            Token::ConcatAll => concat_all_builtin(args),
+            Token::Dbg => self.eval_dbg(args),
+            _ => Object::Error(Code::Unsupported, "Unknown builtin function".to_string()),
+        }
+    }
 
-        for (ident, arg) in parameters.iter().zip(arguments.iter()) {
-            if let Token::Identifier(name) = ident.clone() {
-                inner_env.set(name, arg.clone());
-            } else {
-                return Object::Error(format!("Expected identifier, got {:?}", ident));
+    // Aliases and record types only matter to the type checker. A union binds
+    // its variants, those with a payload as constructors, and the helpers of
+    // the traits derived for it.
+    fn eval_type(&mut self, identifier: &Identifier, declaration: &Type, derives: &[Identifier]) -> Option<Object> {
+        let union = identifier.to_string();
+        if !derives.is_empty() {
+            self.derived.borrow_mut().register(&union, declaration, derives);
+        }
+        let Type::Union(variants) = declaration else {
+            return None;
+        };
+        let mut bindings = Vec::new();
+        for (variant, payload) in variants {
+            let name = variant.to_string();
+            let value = match payload {
+                None => Object::Variant(union.clone(), name.clone(), None),
+                Some(_) => {
+                    let (union, variant) = (union.clone(), name.clone());
+                    let construct = move |arguments: Vec<Object>| match <[Object; 1]>::try_from(arguments) {
+                        Ok([payload]) => Object::Variant(union.clone(), variant.clone(), Some(Box::new(payload))),
+                        Err(arguments) => Object::Error(Code::ArityMismatch, format!("{} takes 1 argument but was given {}", variant, arguments.len())),
+                    };
+                    Object::Native(Native { name: name.clone(), function: Rc::new(construct) })
+                }
+            };
+            bindings.push((name, value));
+        }
+        if derives.iter().any(|derive| derive.to_string() == "enum") {
+            let names: Vec<String> = variants.iter().map(|(variant, _)| variant.to_string()).collect();
+            bindings.push((union.clone(), derive::enum_helpers(&union, &names)));
+        }
+        for (name, value) in bindings {
+            if self.env.borrow().exists_in_current_scope(&name) {
+                return Some(Object::Error(Code::Redefinition, format!(
+                    "Cannot redefine variable '{}' in the same scope. Variable shadowing is not allowed.",
+                    name
+                )));
             }
+            self.env.borrow_mut().set(name.clone(), value);
+            self.journal(&name);
+        }
+        None
+    }
+
+    fn eval_let(&mut self, identifier: &Identifier, value: Option<Object>, span: Span) -> Option<Object> {
+        let Token::Identifier(name) = identifier else {
+            return Some(Object::Error(Code::Unsupported, format!(
+                "Expected identifier, got {:?}",
+                identifier
+            )));
+        };
+        let Some(value) = value else {
+            return Some(Object::Error(Code::MissingValue, format!(
+                "Expected a value for '{}'",
+                name
+            )));
+        };
+        if self.env.borrow().exists_in_current_scope(name) {
+            return Some(Object::Error(Code::Redefinition, format!(
+                "Cannot redefine variable '{}' in the same scope. Variable shadowing is not allowed.",
+                name
+            )));
         }
+        self.env.borrow_mut().define(name.clone(), value, span);
+        self.journal(name);
+        None
+    }
 
-        let current_env = Rc::clone(&self.env);
-        self.env = Rc::new(RefCell::new(inner_env));
-        let object = self.eval_block(&body);
-        self.env = current_env;
+    // Adds where a mismatch happened and where its named operands were bound,
+    // e.g. `... at line 12; left operand from let x (line 3)`
+    fn locate(&self, message: String, span: Span, left: &Expression, right: &Expression) -> String {
+        let mut message = format!("{} at line {}", message, span.line);
+        for (side, operand) in [("left", left), ("right", right)] {
+            let Expression::Identifier(Token::Identifier(name)) = operand else {
+                continue;
+            };
+            if let Some(origin) = self.env.borrow().origin(name) {
+                message.push_str(&format!("; {} operand from let {} (line {})", side, name, origin.line));
+            }
+        }
+        message
+    }
 
-        match object {
-            Some(Object::Return(value)) => *value,
-            Some(o) => o,
-            None => Object::Error(String::from("Expected return value")),
+    fn eval_range(&mut self, start: Object, end: Object) -> Object {
+        match (start, end) {
+            (Object::Integer(start_int), Object::Integer(end_int)) => {
+                let mut list = Vec::new();
+                for i in start_int..=end_int {
+                    list.push(Object::Integer(i));
+                }
+                Object::List(list)
+            },
+            (non_int_start, _) if !matches!(non_int_start, Object::Integer(_)) => {
+                Object::Error(Code::InvalidRange, format!("Range start must be an integer, got {:?}", non_int_start))
+            },
+            (_, non_int_end) if !matches!(non_int_end, Object::Integer(_)) => {
+                Object::Error(Code::InvalidRange, format!("Range end must be an integer, got {:?}", non_int_end))
+            },
+            _ => {
+                Object::Error(Code::InvalidRange, "Error creating range".to_string())
+            }
         }
     }
 
     fn eval_identifier(&mut self, identifier: &Identifier) -> Option<Object> {
         if let Token::Identifier(name) = identifier {
-            match self.env.borrow_mut().get(name.clone()) {
-                Some(value) => Some(value.clone()),
-                None => Some(Object::Error(format!("Undefined variable: {:?}", name))),
+            let value = self.env.borrow_mut().get(name.clone());
+            match value {
+                Some(value) => Some(value),
+                // `show` names the types of records deriving `show`
+                None if name == "show" => {
+                    let derived = Rc::clone(&self.derived);
+                    let show = move |arguments: Vec<Object>| match arguments.as_slice() {
+                        [value] => Object::String(Str::from(derived.borrow().show(value))),
+                        _ => Object::Error(Code::BuiltinArgument, "show expects exactly one argument".to_string()),
+                    };
+                    Some(Object::Native(Native { name: name.clone(), function: Rc::new(show) }))
+                }
+                // Quoted code is evaluated in the scope `eval_quoted` is named in
+                None if name == "eval_quoted" => Some(quote::eval_quoted(Rc::clone(&self.env))),
+                None if builtin::lookup(name).is_some() => builtin::lookup(name),
+                None => {
+                    let names = self.env.borrow().names();
+                    let candidates = names.iter().map(String::as_str).chain(BUILTINS);
+                    let message = match diagnostics::closest(name, candidates) {
+                        Some(candidate) => format!("Undefined variable: {:?}, did you mean `{}`?", name, candidate),
+                        None => format!("Undefined variable: {:?}", name),
+                    };
+                    Some(Object::Error(Code::UndefinedVariable, message))
+                }
             }
         } else {
-            Some(Object::Error(format!(
+            Some(Object::Error(Code::Unsupported, format!(
                 "Expected identifier, got {:?}",
                 identifier
             )))
         }
     }
 
-    fn eval_if(
-        &mut self,
-        condition: &Expression,
-        consequence: &Program,
-        alternative: &Option<Program>,
-    ) -> Option<Object> {
-        let condition = match self.eval_expression(condition) {
-            Some(condition) => condition,
-            None => return None,
+    fn eval_field(&self, record: Object, field: &Identifier, cache: &FieldCache) -> Object {
+        let name = match field {
+            Token::Identifier(name) => name.as_str(),
+            _ => "",
         };
-
-        if self.is_truthy(&condition) {
-            self.eval_block(consequence)
-        } else if let Some(alt) = alternative {
-            self.eval_block(alt)
-        } else {
-            None
+        let Object::Record(fields) = &record else {
+            return Object::Error(Code::UnknownField, format!("Cannot read field '{}' of {}", field, record.type_name()));
+        };
+        if let Some(value) = fields.get_cached(name, cache) {
+            return value.clone();
+        }
+        match diagnostics::closest(name, fields.names().map(String::as_str)) {
+            Some(candidate) => Object::Error(Code::UnknownField, format!("No field '{}' in record, did you mean `{}`?", name, candidate)),
+            None => Object::Error(Code::UnknownField, format!("No field '{}' in record", name)),
         }
     }
 
-    pub fn eval_block(&mut self, program: &Program) -> Option<Object> {
-        let mut result: Option<Object> = None;
-        for statement in program {
-            match self.eval_statement(statement) {
-                Some(Object::Return(value)) => return Some(Object::Return(value)),
-                Some(obj) => {
-                    if let Object::Error(_) = obj {
-                        return Some(obj);
-                    }
-                    result = Some(obj);
-                },
-                None => {
-                    result = None;
-                }
-            }
+    // `record?.field`: None for a None record, and the field of a record, or
+    // of the record in a Some, as an option
+    fn eval_optional_field(&self, mut record: Object, field: &Identifier, cache: &FieldCache) -> Object {
+        match &mut record {
+            Object::OptionNone => return Object::OptionNone,
+            Object::OptionSome(inner) => record = std::mem::take(&mut **inner),
+            _ => {}
+        }
+        // `map?.key` looks the key up as a string
+        if let (Object::Map(map), Token::Identifier(name)) = (&record, field) {
+            return match map.get(&Object::String(Str::from(name.as_str()))) {
+                Some(value) => Object::OptionSome(Box::new(value.clone())),
+                None => Object::OptionNone,
+            };
+        }
+        match self.eval_field(record, field, cache) {
+            value @ (Object::OptionSome(_) | Object::OptionNone | Object::Error(..)) => value,
+            value => Object::OptionSome(Box::new(value)),
         }
-        result
     }
 
     fn eval_literal(&mut self, literal: &Literal) -> Object {
         match literal {
             Literal::Integer(value) => Object::Integer(*value),
             Literal::Float(value) => Object::Float(*value),
-            Literal::String(value) => Object::String(value.clone()),
+            Literal::String(value) => Object::String(Str::from(value.as_str())),
             Literal::Boolean(value) => Object::Boolean(*value),
             Literal::Unit => Object::Unit,
-            Literal::List(elements) => self.eval_list(elements),
-            _ => Object::Error("Unsupported literal type".to_string()),
+            _ => Object::Error(Code::Unsupported, "Unsupported literal type".to_string()),
         }
     }
 
-    fn eval_list(&mut self, elements: &Vec<Expression>) -> Object {
-        let mut evaluated = Vec::new();
-        let mut first_type: Option<Object> = None;
-        
-        for element in elements {
-            match self.eval_expression(element) {
-                Some(value) => {
-                    if let Some(ref first) = first_type {
-                        if !self.assert_list_type(&vec![value.clone()], first) {
-                            return Object::Error(format!(
-                                "List elements must be of the same type. Expected {:?}, got {:?}",
-                                first, value
-                            ));
-                        }
-                    } else {
-                        first_type = Some(value.clone());
-                    }
-                    evaluated.push(value);
-                }
-                None => return Object::Error("Failed to evaluate list element".to_string()),
-            }
-        }
-        
-        Object::List(evaluated)
-    }
-
     fn same_type(&self, a: &Object, b: &Object) -> bool {
-        std::mem::discriminant(a) == std::mem::discriminant(b)
+        match (a, b) {
+            (Object::Variant(a, ..), Object::Variant(b, ..)) => a == b,
+            _ => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
     }
 
 
-    fn assert_list_type(&self, elements: &Vec<Object>, expected_type: &Object) -> bool {
+    fn assert_list_type(&self, elements: &[Object], expected_type: &Object) -> bool {
         elements.iter().all(|v| self.same_type(v, expected_type))
     }
 
     fn eval_cons_infix(&mut self, left_value: Object, right_value: Object) -> Object {
-        if let Object::List(elements) = right_value {
-            if self.assert_list_type(&elements, &left_value) {
+        if let Object::List(elements) = &right_value {
+            if self.assert_list_type(elements, &left_value) {
                 let mut new_list = elements.clone();
                 new_list.insert(0, left_value);
                 Object::List(new_list)
             } else {
-                Object::Error(String::from(format!("Type mismatch, expected type {:?}",std::mem::discriminant(&left_value))))
+                Object::Error(Code::HeterogeneousList, format!("Type mismatch, expected type {:?}",std::mem::discriminant(&left_value)))
             }
         } else {
-            Object::Error(String::from(format!("Invalid cons operation for type {:?}",  std::mem::discriminant(&right_value))))
+            Object::Error(Code::InvalidOperator, format!("Invalid cons operation for type {:?}",  std::mem::discriminant(&right_value)))
         }
     }
 
-    fn eval_infix(&mut self, infix: &Infix, left: Object, right: Object) -> Object {
-        match left {
-            Object::Integer(left_value) => {
-                if let Object::List(right_value) = right {
-                    match infix {
-                        Infix::Cons => self.eval_cons_infix( Object::Integer(left_value), Object::List(right_value)),
-                        _ => Object::Error(String::from(format!("Invalid infix operator {:?} for given type: int", infix)))
-                    }
-                } else if let Object::Integer(right_value) = right {
-                    self.eval_integer_infix(infix, left_value, right_value)
-                } else {
-                    Object::Error(String::from(format!(
-                        "Type Mismatch for infix: int infix {:?} -> int | {:?}",
-                        infix, std::mem::discriminant(&right)
-                    )))
-                }
-            }
-            Object::Float(left_value) => {
-                if let Object::List(right_value) = right {
-                    match infix {
-                        Infix::Cons => self.eval_cons_infix(Object::Float(left_value), Object::List(right_value)),
-                        _ => Object::Error(String::from(format!("Invalid infix operator {:?} for given type: float", infix)))
-                    }
-                } else  if let Object::Float(right_value) = right {
-                    self.eval_float_infix(infix, left_value, right_value)
-                } else {
-                    Object::Error(String::from(format!(
-                        "Type Mismatch for infix: float infix {:?} -> float | {:?}",
-                        infix, std::mem::discriminant(&right)
-                    )))
-                }
-            }
-            Object::Boolean(left_value) => {
-                if let Object::List(right_value) = right {
-                    match infix {
-                        Infix::Cons => self.eval_cons_infix( Object::Boolean(left_value), Object::List(right_value)),
-                        _ => Object::Error(String::from(format!("Invalid infix operator {:?} for given type: bool", infix)))
-                    }
-                } else if let Object::Boolean(right_value) = right {
-                    self.eval_boolean_infix(infix, left_value, right_value)
-                } else {
-                    Object::Error(String::from(format!(
-                        "Type Mismatch for infix: bool infix {:?} -> bool | {:?}",
-                        infix, std::mem::discriminant(&right)
-                    )))
-                }
+    fn eval_infix(&mut self, infix: &Infix, mut left: Object, mut right: Object) -> Object {
+        if *infix == Infix::In {
+            return self.eval_in_infix(left, right);
+        }
+        let equality = |equal: bool| match infix {
+            Infix::Equal => Some(Object::Boolean(equal)),
+            Infix::DoesNotEqual => Some(Object::Boolean(!equal)),
+            _ => None,
+        };
+        let compared = match (&mut left, &mut right) {
+            (Object::Integer(_) | Object::Float(_) | Object::Boolean(_) | Object::String(_) | Object::Variant(..), Object::List(_)) => match infix {
+                Infix::Cons => return self.eval_cons_infix(left, right),
+                _ => None,
+            },
+            (Object::List(a), Object::List(b)) => return self.eval_list_infix(infix, std::mem::take(a), std::mem::take(b)),
+            (Object::Integer(a), Object::Integer(b)) => return self.eval_integer_infix(infix, *a, *b),
+            (Object::Float(a), Object::Float(b)) => return self.eval_float_infix(infix, *a, *b),
+            (Object::Boolean(a), Object::Boolean(b)) => return self.eval_boolean_infix(infix, *a, *b),
+            (Object::String(a), Object::String(b)) => return self.eval_string_infix(infix, std::mem::take(a), std::mem::take(b)),
+            (Object::Map(a), Object::Map(b)) => equality(a == b),
+            (Object::Deque(a), Object::Deque(b)) => equality(a == b),
+            (Object::Set(a), Object::Set(b)) => equality(a == b),
+            (Object::Quoted(a), Object::Quoted(b)) => equality(a == b),
+            (Object::Variant(..) | Object::Record(_), _) | (_, Object::Variant(..) | Object::Record(_)) => {
+                return self.derived.borrow().infix(infix, &left, &right);
             }
-            Object::String(left_value) => {
-                if let Object::List(right_value) = right {
-                    match infix {
-                        Infix::Cons => self.eval_cons_infix(Object::String(left_value), Object::List(right_value)),
-                        _ => Object::Error(String::from(format!("Invalid infix operator {:?} for given type: string", infix)))
-                    }
-                } else if let Object::String(right_value) = right {
-                    self.eval_string_infix(infix, left_value, right_value)
-                } else {
-                    Object::Error(String::from(format!(
-                        "Type Mismatch for infix: string infix {:?} -> string | {:?}",
-                        infix, std::mem::discriminant(&right)
-                    )))
+            _ => None,
+        };
+        compared.unwrap_or_else(|| Object::Error(Code::OperandMismatch, mismatch(infix, &left.type_name(), &right.type_name())))
+    }
+
+    // `x in xs`, `"sub" in s`, `"field" in record`, `key in map` and `x in set`
+    fn eval_in_infix(&mut self, left: Object, right: Object) -> Object {
+        match (&left, &right) {
+            (_, Object::List(elements)) => Object::Boolean(elements.contains(&left)),
+            (Object::String(part), Object::String(text)) => Object::Boolean(text.as_str().contains(part.as_str())),
+            (Object::String(name), Object::Record(fields)) => Object::Boolean(fields.iter().any(|(field, _)| field == name.as_str())),
+            (key, Object::Map(map)) => Object::Boolean(map.contains(key)),
+            (element, Object::Set(set)) => Object::Boolean(set.contains(element)),
+            _ => Object::Error(Code::OperandMismatch, mismatch(&Infix::In, &left.type_name(), &right.type_name())),
+        }
+    }
+
+    fn eval_list_infix(&mut self, infix: &Infix, left: Vec<Object>, right: Vec<Object>) -> Object {
+        match infix {
+            Infix::Cons => self.eval_cons_infix(Object::List(left), Object::List(right)),
+            Infix::Concat => match (left.first(), right.first()) {
+                (Some(a), Some(b)) if !self.same_type(a, b) => {
+                    Object::Error(Code::HeterogeneousList, format!("Cannot concatenate a list of {} and a list of {}", a.type_name(), b.type_name()))
                 }
-            }
-            _ => Object::Error(String::from(format!(
-                "Type Mismatch for infix: {:?} infix {:?} -> {:?}",
-                std::mem::discriminant(&left), infix, std::mem::discriminant(&right)
-            ))),
+                _ => Object::List(left.into_iter().chain(right).collect()),
+            },
+            _ => Object::Error(Code::InvalidOperator, format!("Invalid infix operator {:?} for given type: list", infix)),
         }
     }
 
-    fn eval_string_infix(&mut self, infix: &Infix, left: String, right: String) -> Object {
+    fn eval_string_infix(&mut self, infix: &Infix, left: Str, right: Str) -> Object {
         match infix {
-            Infix::Concat => {
-                let mut concat = left.clone();
-                concat.push_str(right.as_str());
-                Object::String(concat)
-            }
-            _ => Object::Error(String::from(format!(
+            Infix::Concat => Object::String(left.concat(&right)),
+            _ => Object::Error(Code::InvalidOperator, format!(
                 "Invalid infix operator {:?} for given type: string",
                 infix
-            ))),
+            )),
         }
     }
 
@@ -406,10 +1283,10 @@ impl Evaluator {
         match infix {
             Infix::Equal => Object::Boolean(left == right),
             Infix::DoesNotEqual => Object::Boolean(left != right),
-            _ => Object::Error(String::from(format!(
+            _ => Object::Error(Code::InvalidOperator, format!(
                 "Invalid infix operator {:?} for given type: bool",
                 infix
-            ))),
+            )),
         }
     }
 
@@ -426,33 +1303,43 @@ impl Evaluator {
             Infix::LessThan => Object::Boolean(left < right),
             Infix::GTOrEqual => Object::Boolean(left >= right),
             Infix::LTOrEqual => Object::Boolean(left <= right),
-            Infix::Caret | Infix::Cons | Infix::Concat | Infix::Ampersand | Infix::Pipe => {
-                Object::Error(String::from(format!(
+            Infix::Caret | Infix::Cons | Infix::Concat | Infix::Ampersand | Infix::Pipe | Infix::In | Infix::Coalesce | Infix::ShiftLeft | Infix::ShiftRight => {
+                Object::Error(Code::InvalidOperator, format!(
                     "Invalid infix operator {:?} for given type: float",
                     infix
-                )))
+                ))
             }
         }
     }
 
     fn eval_integer_infix(&mut self, infix: &Infix, left: i64, right: i64) -> Object {
+        if let Some(result) = integer_arithmetic(infix, left, right) {
+            return result;
+        }
         match infix {
-            Infix::Plus => Object::Integer(left + right),
-            Infix::Minus => Object::Integer(left - right),
-            Infix::Product => Object::Integer(left * right),
-            Infix::ForwardSlash => Object::Integer(left / right),
-            Infix::Modulo => Object::Integer(left % right),
             Infix::Equal => Object::Boolean(left == right),
             Infix::DoesNotEqual => Object::Boolean(left != right),
             Infix::GreaterThan => Object::Boolean(left > right),
             Infix::LessThan => Object::Boolean(left < right),
             Infix::GTOrEqual => Object::Boolean(left >= right),
             Infix::LTOrEqual => Object::Boolean(left <= right),
-            Infix::Caret | Infix::Cons | Infix::Concat | Infix::Ampersand | Infix::Pipe => {
-                Object::Error(String::from(format!(
+            // `>>` is arithmetic, keeping the sign
+            Infix::ShiftLeft | Infix::ShiftRight => {
+                let shifted = u32::try_from(right).ok().and_then(|amount| match infix {
+                    Infix::ShiftLeft => left.checked_shl(amount),
+                    _ => left.checked_shr(amount),
+                });
+                match shifted {
+                    Some(value) => Object::Integer(value),
+                    None => Object::Error(Code::InvalidShift, format!("Cannot shift by {}, shifts must be between 0 and 63", right)),
+                }
+            }
+            Infix::Plus | Infix::Minus | Infix::Product | Infix::ForwardSlash | Infix::Modulo => unreachable!(),
+            Infix::Caret | Infix::Cons | Infix::Concat | Infix::Ampersand | Infix::Pipe | Infix::In | Infix::Coalesce => {
+                Object::Error(Code::InvalidOperator, format!(
                     "Invalid infix operator {:?} for given type: int",
                     infix
-                )))
+                ))
             }
         }
     }
@@ -469,7 +1356,7 @@ impl Evaluator {
         match object {
             Object::Integer(value) => Object::Integer(value),
             Object::Float(value) => Object::Float(value),
-            _ => Object::Error(String::from(
+            _ => Object::Error(Code::OperandMismatch, String::from(
                 "Type Mismatch for (-): int -> int | float -> float",
             )),
         }
@@ -479,7 +1366,7 @@ impl Evaluator {
         match object {
             Object::Integer(value) => Object::Integer(-value),
             Object::Float(value) => Object::Float(-value),
-            _ => Object::Error(String::from(
+            _ => Object::Error(Code::OperandMismatch, String::from(
                 "Type Mismatch for (-): int -> int | float -> float",
             )),
         }
@@ -489,11 +1376,146 @@ impl Evaluator {
         match object {
             Object::Boolean(true) => Object::Boolean(false),
             Object::Boolean(false) => Object::Boolean(true),
-            _ => Object::Error(String::from("Type Mismatch for (!): bool -> bool")),
+            _ => Object::Error(Code::OperandMismatch, String::from("Type Mismatch for (!): bool -> bool")),
         }
     }
 }
 
+/// `+ - * / %` on ints, or None for other operators. `/` truncates toward
+/// zero and `%` takes the sign of the dividend, so `a / b * b + a % b == a`;
+/// dividing by zero and overflowing are errors.
+pub fn integer_arithmetic(infix: &Infix, left: i64, right: i64) -> Option<Object> {
+    let (result, symbol) = match infix {
+        Infix::Plus => (left.checked_add(right), "+"),
+        Infix::Minus => (left.checked_sub(right), "-"),
+        Infix::Product => (left.checked_mul(right), "*"),
+        Infix::ForwardSlash | Infix::Modulo if right == 0 => {
+            return Some(Object::Error(Code::DivisionByZero, format!("Cannot divide {} by zero", left)));
+        }
+        Infix::ForwardSlash => (left.checked_div(right), "/"),
+        // Only `i64::MIN % -1` overflows, and its remainder is 0
+        Infix::Modulo => (Some(left.wrapping_rem(right)), "%"),
+        _ => return None,
+    };
+    Some(match result {
+        Some(value) => Object::Integer(value),
+        None => Object::Error(Code::Overflow, format!("{} {} {} overflows an int", left, symbol, right)),
+    })
+}
+
+// e.g. Cannot add int and string
+fn mismatch(infix: &Infix, left: &str, right: &str) -> String {
+    match infix {
+        Infix::Plus => format!("Cannot add {} and {}", left, right),
+        Infix::Minus => format!("Cannot subtract {} from {}", right, left),
+        Infix::Product => format!("Cannot multiply {} by {}", left, right),
+        Infix::ForwardSlash => format!("Cannot divide {} by {}", left, right),
+        Infix::Modulo => format!("Cannot take the remainder of {} by {}", left, right),
+        Infix::Equal | Infix::DoesNotEqual | Infix::GreaterThan | Infix::LessThan | Infix::GTOrEqual | Infix::LTOrEqual => {
+            format!("Cannot compare {} and {}", left, right)
+        }
+        Infix::ShiftLeft | Infix::ShiftRight => format!("Cannot shift {} by {}", left, right),
+        Infix::Concat => format!("Cannot concatenate {} and {}", left, right),
+        Infix::Cons => format!("Cannot cons {} onto {}", left, right),
+        Infix::In => format!("Cannot look for {} in {}", left, right),
+        Infix::Coalesce => format!("Cannot default {}, which is not an option or a result", left),
+        Infix::Caret | Infix::Ampersand | Infix::Pipe => format!("Cannot apply {:?} to {} and {}", infix, left, right),
+    }
+}
+
+// The position of `left |> f` in its pipeline, counting from 1
+fn stage(left: &Expression) -> usize {
+    match left {
+        Expression::Infix(Infix::Pipe, inner, _, _) => 1 + stage(inner),
+        _ => 1,
+    }
+}
+
+fn is_ensures(statement: &Statement) -> bool {
+    matches!(statement, Statement::Assert(assertion) if assertion.kind == AssertionKind::Ensures)
+}
+
+fn assertion_name(kind: AssertionKind) -> &'static str {
+    match kind {
+        AssertionKind::Assert => "assertion",
+        AssertionKind::Requires => "requires clause",
+        AssertionKind::Ensures => "ensures clause",
+    }
+}
+
+// e.g. assertion `x > 0` failed: x must be positive
+fn assertion_failure(assertion: &Assertion) -> String {
+    let failure = format!("{} `{}` failed", assertion_name(assertion.kind), assertion.source);
+    match &assertion.message {
+        Some(message) => format!("{}: {}", failure, message),
+        None => failure,
+    }
+}
+
+// Whether a value has the annotated type. Declared types are only checked
+// statically, and `dynamic` accepts anything.
+fn conforms(value: &Object, annotation: &Alias) -> bool {
+    let param = |i: usize| &annotation.parameters[i];
+    let TypeConstructor::BuiltIn(constructor) = &annotation.name else {
+        return true;
+    };
+    match (constructor, value) {
+        (Constructor::Dynamic, _)
+        | (Constructor::Int, Object::Integer(_))
+        | (Constructor::Float, Object::Float(_))
+        | (Constructor::String, Object::String(_))
+        | (Constructor::Bool, Object::Boolean(_))
+        | (Constructor::Unit, Object::Unit)
+        | (Constructor::Option, Object::OptionNone) => true,
+        (Constructor::List, Object::List(elements)) => elements.iter().all(|element| conforms(element, param(0))),
+        (Constructor::Option, Object::OptionSome(inner)) | (Constructor::Result, Object::ResultOk(inner)) => conforms(inner, param(0)),
+        (Constructor::Result, Object::ResultErr(inner)) => conforms(inner, param(1)),
+        (Constructor::HashMap, Object::Map(map)) => map.iter().all(|(key, value)| conforms(key, param(0)) && conforms(value, param(1))),
+        _ => false,
+    }
+}
+
+// Names the callee and its parameter list, and which arguments are missing
+// or extra, e.g. `add` takes 2 arguments (x, y) but 1 was given: missing y
+fn arity_error(name: Option<&str>, parameters: &[Identifier], given: usize) -> Object {
+    let callee = match name {
+        Some(name) => format!("`{}`", name),
+        None => "function".to_string(),
+    };
+    let names: Vec<String> = parameters.iter().map(|parameter| parameter.to_string()).collect();
+    let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+    let detail = if given < parameters.len() {
+        format!("missing {}", names[given..].join(", "))
+    } else {
+        let extra: Vec<String> = (parameters.len() + 1..=given).map(|n| n.to_string()).collect();
+        format!("extra {} {}", plural(extra.len()), extra.join(", "))
+    };
+    Object::Error(Code::ArityMismatch, format!(
+        "{} takes {} {} ({}) but {} {} given: {}",
+        callee,
+        parameters.len(),
+        plural(parameters.len()),
+        names.join(", "),
+        given,
+        if given == 1 { "was" } else { "were" },
+        detail
+    ))
+}
+
+/// For tests: evaluates source in `env`, keeping bindings from one call to
+/// the next.
+#[cfg(test)]
+pub(crate) fn test_eval_with(env: Rc<RefCell<Env>>) -> impl FnMut(&str) -> Option<Object> {
+    let mut evaluator = Evaluator::new(env);
+    move |source| evaluator.eval(&crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program())
+}
+
+/// For tests: `test_eval_with` a new environment.
+#[cfg(test)]
+pub(crate) fn test_eval() -> impl FnMut(&str) -> Option<Object> {
+    test_eval_with(Rc::new(RefCell::new(Env::new())))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -501,7 +1523,7 @@ mod tests {
     #[test]
     fn test_eval_let() {
         let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
-        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::Integer(1))), Statement::Expression(Expression::Identifier(Identifier::Identifier("x".to_string())))];
+        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::Integer(1)), Span::default()), Statement::Expression(Expression::Identifier(Identifier::Identifier("x".to_string())))];
         let result = evaluator.eval(&program);
         assert_eq!(result, Some(Object::Integer(1)));
     }
@@ -527,7 +1549,7 @@ mod tests {
     #[test]
     fn test_eval_list() {
         let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
-        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::List(vec![Expression::Literal(Literal::Integer(1)), Expression::Literal(Literal::Integer(2))]))), Statement::Expression(Expression::Identifier(Identifier::Identifier("x".to_string())))];
+        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::List(vec![Expression::Literal(Literal::Integer(1)), Expression::Literal(Literal::Integer(2))])), Span::default()), Statement::Expression(Expression::Identifier(Identifier::Identifier("x".to_string())))];
         let result = evaluator.eval(&program);
         assert_eq!(result, Some(Object::List(vec![Object::Integer(1), Object::Integer(2)])));
     }
@@ -535,7 +1557,7 @@ mod tests {
     #[test]
     fn test_eval_list_cons() {
         let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
-        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::List(vec![Expression::Literal(Literal::Integer(1)), Expression::Literal(Literal::Integer(2))]))), Statement::Expression(Expression::Infix(Infix::Cons, Box::new(Expression::Literal(Literal::Integer(3))), Box::new(Expression::Identifier(Identifier::Identifier("x".to_string())))))];
+        let program = vec![Statement::Let(Identifier::Identifier("x".to_string()), Expression::Literal(Literal::List(vec![Expression::Literal(Literal::Integer(1)), Expression::Literal(Literal::Integer(2))])), Span::default()), Statement::Expression(Expression::Infix(Infix::Cons, Box::new(Expression::Literal(Literal::Integer(3))), Box::new(Expression::Identifier(Identifier::Identifier("x".to_string()))), Span::default()))];
         let result = evaluator.eval(&program);
         assert_eq!(result, Some(Object::List(vec![Object::Integer(3), Object::Integer(1), Object::Integer(2)])));
     }
@@ -553,7 +1575,7 @@ mod tests {
         
         let result = evaluator.eval_expression(&range_expr).unwrap();
         match result {
-            Object::List(elements) => {
+            Object::List(ref elements) => {
                 assert_eq!(elements.len(), 5);
                 for (i, obj) in elements.iter().enumerate() {
                     assert_eq!(*obj, Object::Integer((i + 1) as i64));
@@ -571,7 +1593,7 @@ mod tests {
         
         let result = evaluator.eval_expression(&range_expr).unwrap();
         match result {
-            Object::Error(msg) => {
+            Object::Error(Code::InvalidRange, ref msg) => {
                 assert!(msg.contains("Range start must be an integer"));
             }
             _ => panic!("Expected error for non-integer start, got {:?}", result),
@@ -586,10 +1608,375 @@ mod tests {
         
         let result = evaluator.eval_expression(&range_expr).unwrap();
         match result {
-            Object::Error(msg) => {
+            Object::Error(Code::InvalidRange, ref msg) => {
                 assert!(msg.contains("Range end must be an integer"));
             }
             _ => panic!("Expected error for non-integer end, got {:?}", result),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_eval_call_arity() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new("let add = fn x, y -> x + y; add(1)"));
+        let result = evaluator.eval(&parser.parse_program());
+        assert_eq!(result, Some(Object::Error(Code::ArityMismatch, "`add` takes 2 arguments (x, y) but 1 was given: missing y".to_string())));
+
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new("add(1, 2, 3, 4)"));
+        let result = evaluator.eval(&parser.parse_program());
+        assert_eq!(result, Some(Object::Error(Code::ArityMismatch, "`add` takes 2 arguments (x, y) but 4 were given: extra arguments 3, 4".to_string())));
+    }
+
+    #[test]
+    fn test_eval_unit() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let nothing = fn () -> (); let id = fn x -> x; [nothing(), id(( )), id(())]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty());
+        assert_eq!(evaluator.eval(&program), Some(Object::List(vec![Object::Unit, Object::Unit, Object::Unit])));
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("fn () -> ()")).parse_program();
+        let ty = crate::typechecker::TypeChecker::new().check_program(&program);
+        assert_eq!(ty.map(|ty| ty.to_string()), Some("fn () -> unit".to_string()));
+    }
+
+    #[test]
+    fn test_eval_block() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let side = 2;\nlet area = { let side = 4; side * side };\nlet sign = fn x -> {\n  let y = { if x < 0 { return 0 - 1; } else { x } };\n  y + 1\n};\n[area, side, sign(1), sign(0 - 5), { x = 7 }.x]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty());
+        let values = [16, 2, 2, -1, 7].map(Object::Integer).to_vec();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(values)));
+    }
+
+    #[test]
+    fn test_eval_lambda() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let add = \\a, b -> a + b;\nfold(add, 0, map(\\x -> x * 2, [1, 2, 3])) + fold(add, 0, map(fn x -> x;, [1]))";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        assert_eq!(evaluator.eval(&program), Some(Object::Integer(13)));
+    }
+
+    #[test]
+    fn test_eval_custom_operators() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "infixl 6 <-> = fn a, b -> a - b;\ninfixr 7 <|> = fn a, b -> a * 10 + b;\ninfix 3 <=> = fn a, b -> if a < b { 0 - 1 } else { if a > b { 1 } else { 0 } };\n[10 <-> 3 <-> 2, 1 <|> 2 <|> 3, 1 + 2 <=> 3]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let expected = [5, 33, 0].map(Object::Integer);
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected.to_vec())));
+
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("infix 3 <=> = fn a, b -> 0;\n1 <=> 2 <=> 3")).parse_program();
+        assert_eq!(program.errors.first().map(|error| error.message()), Some("<=> cannot be chained, add parentheses".to_string()));
+    }
+
+    #[test]
+    fn test_eval_deep_recursion() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let count = fn n -> if n == 0 { 0 } else { 1 + count(n - 1) }; count(20000)";
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(source));
+        assert_eq!(evaluator.eval(&parser.parse_program()), Some(Object::Integer(20000)));
+
+        let mut expression = Expression::Literal(Literal::Integer(0));
+        for _ in 0..5000 {
+            expression = Expression::Infix(Infix::Plus, Box::new(expression), Box::new(Expression::Literal(Literal::Integer(1))), Span::default());
+        }
+        assert_eq!(evaluator.eval_expression(&expression), Some(Object::Integer(5000)));
+
+        let mut eval = test_eval();
+        let ones = Some(Object::List(vec![Object::Integer(1); 1000]));
+        assert_eq!(eval(&format!("{} :: []", vec!["1"; 1000].join(" :: "))), ones);
+        assert_eq!(eval(&vec!["[1]"; 1000].join(" ++ ")), ones);
+    }
+
+    #[test]
+    fn test_eval_deep_values() {
+        // Built here rather than by an opl loop, which copies the value on
+        // every turn
+        let deep = |wrap: fn(Object) -> Object, leaf: i64| (0..10000).fold(Object::Integer(leaf), |value, _| wrap(value));
+        let option = |value| Object::OptionSome(Box::new(value));
+        let list = |value| Object::List(vec![value]);
+        let env = Rc::new(RefCell::new(Env::new()));
+        env.borrow_mut().set("options".to_string(), deep(option, 0));
+        env.borrow_mut().set("lists".to_string(), deep(list, 0));
+        env.borrow_mut().set("other".to_string(), deep(list, 1));
+        let mut eval = test_eval_with(env);
+        // Comparing, printing, measuring, copying and dropping them do not recurse
+        assert_eq!(eval("lists in [other, lists]"), Some(Object::Boolean(true)));
+        assert_eq!(eval("lists in [other]"), Some(Object::Boolean(false)));
+        assert!(matches!(eval("[options, lists]"), Some(Object::Error(Code::HeterogeneousList, _))));
+        assert!(matches!(eval("options == lists"), Some(Object::Error(Code::OperandMismatch, _))));
+        let (Some(options), Some(lists)) = (eval("options"), eval("lists")) else {
+            panic!("expected both values");
+        };
+        assert_eq!(options, options.clone());
+        assert_eq!(options.to_string().len(), "Some()".len() * 10000 + 1);
+        assert_eq!(lists.to_string().len(), "[]".len() * 10000 + 1);
+        assert_eq!(crate::heap::size(&lists), 10000 * std::mem::size_of::<Object>());
+    }
+
+    #[test]
+    fn test_eval_heap_limit() {
+        let eval = |source: &str| {
+            let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_heap_limit(100_000);
+            let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(source));
+            evaluator.eval(&parser.parse_program())
+        };
+        // Temporaries that are no longer reachable do not count
+        assert_eq!(eval(&"[1..1000];".repeat(50)), Some(Object::List((1..=1000).map(Object::Integer).collect())));
+        assert!(matches!(eval("let xs = [1..10000];"), Some(Object::Error(Code::HeapLimit, _))));
+    }
+
+    #[test]
+    fn test_eval_step_limit() {
+        let eval = |source: &str| {
+            let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_step_limit(10_000);
+            let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(source));
+            evaluator.eval(&parser.parse_program())
+        };
+        assert_eq!(eval("let count = fn n -> if n == 0 { 0 } else { count(n - 1) };\ncount(100);"), Some(Object::Integer(0)));
+        assert!(matches!(eval("let spin = fn n -> spin(n + 1);\nspin(0);"), Some(Object::Error(Code::StepLimit, _))));
+        // Callbacks of builtins draw on the same budget
+        assert!(matches!(eval("let spin = fn n -> spin(n + 1);\nmap(fn x -> spin(x), [1]);"), Some(Object::Error(Code::StepLimit, _))));
+    }
+
+    #[test]
+    fn test_eval_operand_mismatch() {
+        let source = "let x = \"three\";\nlet f = fn n -> n * x;\n\nf(4);";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let message = "Cannot multiply int by string at line 2; right operand from let x (line 1)";
+        assert_eq!(evaluator.eval(&program), Some(Object::Error(Code::OperandMismatch, message.to_string())));
+        assert_eq!(evaluator.error_span.map(|span| (span.line, span.column)), Some((2, 19)));
+    }
+
+    #[test]
+    fn test_eval_contracts() {
+        let source = "let half = fn n requires n % 2 == 0 ensures result * 2 == n -> { return n / 2; };\nhalf(3);";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        assert_eq!(evaluator.eval(&program), Some(Object::Integer(1)));
+
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_contracts(true);
+        let failure = Object::Error(Code::AssertionFailed, "requires clause `n % 2 == 0` failed".to_string());
+        assert_eq!(evaluator.eval(&program), Some(failure));
+        assert_eq!(evaluator.error_span.map(|span| (span.line, span.column)), Some((1, 17)));
+
+        let source = "let x = 1;\n  assert x > 1, \"x is too small\";";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let failure = Object::Error(Code::AssertionFailed, "assertion `x > 1` failed: x is too small".to_string());
+        assert_eq!(evaluator.eval(&program), Some(failure));
+        assert_eq!(evaluator.error_span.map(|span| (span.line, span.column)), Some((2, 3)));
+    }
+
+    #[test]
+    fn test_eval_annotation() {
+        let source = "let settings: dynamic = { port = \"80\" };\nlet port: int = settings.port;";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let failure = Object::Error(Code::AnnotationMismatch, "Expected int for port, got string".to_string());
+        assert_eq!(evaluator.eval(&program), Some(failure));
+        assert_eq!(evaluator.error_span.map(|span| span.line), Some(2));
+    }
+
+    #[test]
+    fn test_eval_bits() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "[1 << 4 + 1, -16 >> 2, bit_and(12, 10), bit_xor(12, 10), bit_not(0), popcount(255), leading_zeros(1)];";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let expected = [32, -4, 8, 6, -1, 8, 63].into_iter().map(Object::Integer).collect();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected)));
+
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("1 << 64;")).parse_program();
+        assert!(matches!(evaluator.eval(&program), Some(Object::Error(Code::InvalidShift, _))));
+    }
+
+    #[test]
+    fn test_eval_division() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "[-7 / 2, -7 % 2, 7 % -2, div_euclid(-7, 2), rem_euclid(-7, 2), rem_euclid(7, -2)]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let expected = [-3, -1, 1, -4, 1, 1].into_iter().map(Object::Integer).collect();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected)));
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("divmod(-7, 2)")).parse_program();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(vec![Object::Integer(-3), Object::Integer(-1)])));
+
+        for source in ["1 / 0;", "1 % 0;", "rem_euclid(1, 0);"] {
+            let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+            assert!(matches!(evaluator.eval(&program), Some(Object::Error(Code::DivisionByZero, _))), "{}", source);
+        }
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("let min = 0 - 9223372036854775807 - 1; [min % -1, min / -1]")).parse_program();
+        assert_eq!(evaluator.eval(&program), Some(Object::Error(Code::Overflow, "-9223372036854775808 / -1 overflows an int".to_string())));
+    }
+
+    #[test]
+    fn test_eval_floats() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "[show(2.0), show(0.1 + 0.2), show(1.0 / 0.0), show(-1.0 / 0.0), show(float.nan), show(float.parse(\" 1e300 \")), show(float.parse(\"1.5x\"))]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let expected = ["2.0", "0.30000000000000004", "Infinity", "-Infinity", "NaN", "Some(1e300)", "None"];
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected.map(|s| Object::String(Str::from(s))).to_vec())));
+
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("float.parse(show(0.1 + 0.2))")).parse_program();
+        assert_eq!(evaluator.eval(&program), Some(Object::OptionSome(Box::new(Object::Float(0.1 + 0.2)))));
+
+        let source = "let x = 0.1 + 0.2; [float.nan == float.nan, float.nan < 1.0, float.is_nan(0.0 / 0.0), float.is_finite(float.infinity), float.is_finite(x)]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let expected = [false, false, true, false, true].map(Object::Boolean);
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected.to_vec())));
+    }
+
+    #[test]
+    fn test_eval_concat() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let xs = [1] ++ [2, 3] ++ []; 0 :: 1 + 1 :: xs ++ [4]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let expected = [0, 2, 1, 2, 3, 4].into_iter().map(Object::Integer).collect();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected)));
+
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("\"a\" ++ \"b\" ++ \"c\"")).parse_program();
+        assert_eq!(evaluator.eval(&program), Some(Object::String(Str::from("abc"))));
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("\"a\" ++ [\"b\"]")).parse_program();
+        let failure = Object::Error(Code::OperandMismatch, "Cannot concatenate string and list * string at line 1".to_string());
+        assert_eq!(evaluator.eval(&program), Some(failure));
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("[1] ++ [\"b\"]")).parse_program();
+        assert!(matches!(evaluator.eval(&program), Some(Object::Error(Code::HeterogeneousList, _))));
+    }
+
+    #[test]
+    fn test_eval_in() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let config = { port = 80 }; [2 in [1, 2], 3 in [1, 2], 1 + 1 in [2], \"ell\" in \"hello\", \"port\" in config, \"host\" in config]";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let expected = [true, false, true, true, true, false].map(Object::Boolean);
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected.to_vec())));
+
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new("1 in 2")).parse_program();
+        let failure = Object::Error(Code::OperandMismatch, "Cannot look for int in int at line 1".to_string());
+        assert_eq!(evaluator.eval(&program), Some(failure));
+    }
+
+    #[test]
+    fn test_eval_pipeline() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let inc = fn x -> x + 1;\n1 |> inc |> \\x -> x * 10;";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        assert_eq!(evaluator.eval(&program), Some(Object::Integer(20)));
+
+        let source = "\n1 |> inc |> show |> inc";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let message = "Cannot add string and int at line 1; in stage 3 of the pipeline at line 2, column 18, applied to a value of type string";
+        assert_eq!(evaluator.eval(&program), Some(Object::Error(Code::OperandMismatch, message.to_string())));
+        assert_eq!(evaluator.error_span.map(|span| (span.line, span.column)), Some((1, 21)));
+
+        // A stage that is a call takes the piped value as its last argument
+        let mut eval = test_eval();
+        let source = "let add = fn x, y -> x * 10 + y;\n[1, 2] |> map(\\x -> x * 2) |> fold(add, 0) |> add(3)";
+        assert_eq!(eval(source), Some(Object::Integer(3 * 10 + 24)));
+    }
+
+    #[test]
+    fn test_eval_dbg() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        let source = "let xs = [1];\n(xs ++ [2] |> dbg) ++ dbg( xs )";
+        let program = crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program();
+        let mut calls = Vec::new();
+        let Statement::Expression(Expression::Infix(_, left, right, _)) = &program[1] else {
+            panic!("expected ++, got {:?}", program[1]);
+        };
+        for call in [left, right] {
+            if let Expression::BuiltIn { function: Token::Dbg, arguments, .. } = &**call {
+                calls.push(arguments[1..].to_vec());
+            }
+        }
+        let location = |source: &str| vec![Expression::Literal(Literal::String(source.to_string())), Expression::Literal(Literal::Integer(2))];
+        assert_eq!(calls, vec![location("xs ++ [2]"), location("xs")]);
+        let expected = [1, 2, 1].into_iter().map(Object::Integer).collect();
+        assert_eq!(evaluator.eval(&program), Some(Object::List(expected)));
+    }
+
+    #[test]
+    fn test_eval_hash() {
+        let mut eval = test_eval();
+        let hash = |object: Object| Some(Object::Integer(object.stable_hash().unwrap() as i64));
+
+        let list = Object::List(vec![Object::String("a".into()), Object::String("b".into())]);
+        assert_eq!(eval("hash([\"a\", \"b\"])"), hash(list.clone()));
+        assert_ne!(eval("hash([\"ab\"])"), hash(list));
+        assert_ne!(Object::OptionSome(Box::new(Object::Integer(1))).stable_hash(), Object::ResultOk(Box::new(Object::Integer(1))).stable_hash());
+        assert!(matches!(eval("hash(fn x -> x;)"), Some(Object::Error(Code::Unhashable, _))));
+        // Builtins that are plain identifiers can be shadowed
+        assert_eq!(eval("let hash = fn x -> x; hash(1)"), Some(Object::Integer(1)));
+    }
+
+    #[test]
+    fn test_eval_optional_field() {
+        let mut eval = test_eval();
+        let some = |object| Object::OptionSome(Box::new(object));
+
+        eval("let user = Some({ name = \"ada\", manager = None });\nlet nobody = None;");
+        assert_eq!(eval("user?.name"), Some(some(Object::String("ada".into()))));
+        assert_eq!(eval("nobody?.name"), Some(Object::OptionNone));
+        assert_eq!(eval("user?.manager?.name"), Some(Object::OptionNone));
+        assert_eq!(eval("{ port = 80 }?.port"), Some(some(Object::Integer(80))));
+        assert!(matches!(eval("user?.age"), Some(Object::Error(..))));
+        eval("let m = {\"a\": 1};");
+        assert_eq!(eval("m?.a"), Some(some(Object::Integer(1))));
+        assert_eq!(eval("m?.b"), Some(Object::OptionNone));
+        assert_eq!(eval("let o = Some(m); o?.a ?? 0"), Some(Object::Integer(1)));
+    }
+
+    #[test]
+    fn test_eval_coalesce() {
+        let mut eval = test_eval();
+
+        // The default is not evaluated when there is a value
+        let source = "let config = { port = None, host = Some(\"localhost\") };\nlet ok = Ok(3);\nlet err = Err(\"no\");\n[config?.port ?? 8080, config.port ?? None ?? 80 + 1, ok ?? 1 / 0, err ?? 4]";
+        assert_eq!(eval(source), Some(Object::List([8080, 81, 3, 4].map(Object::Integer).to_vec())));
+        assert_eq!(eval("config.host ?? missing"), Some(Object::String("localhost".into())));
+        let failure = Object::Error(Code::OperandMismatch, "Cannot default int, which is not an option or a result at line 1".to_string());
+        assert_eq!(eval("1 ?? 2"), Some(failure));
+    }
+
+    #[test]
+    fn test_debugger() {
+        // The debugger sees the scope of the failed division, and carries on
+        // with 10 in place of its value
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = Rc::clone(&seen);
+        let debugger: Debugger = Rc::new(move |stop| {
+            record.borrow_mut().push((stop.error.clone(), stop.span.map(|span| span.line), stop.env.borrow_mut().get("n".to_string())));
+            Resume::Continue(Some(Object::Integer(10)))
+        });
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_debugger(debugger);
+        let source = "let f = fn n -> {\n  let half = n / 0;\n  half + 1\n};\nf(4)";
+        assert_eq!(evaluator.eval(&crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program()), Some(Object::Integer(11)));
+        let error = Object::Error(Code::DivisionByZero, "Cannot divide 4 by zero".to_string());
+        assert_eq!(*seen.borrow(), [(error, Some(2), Some(Object::Integer(4)))]);
+    }
+
+    #[test]
+    fn test_time_travel() {
+        // From the error, the debugger goes back to the first moment on line
+        // 3, runs on from there into the error again, and aborts
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = Rc::clone(&seen);
+        let debugger: Debugger = Rc::new(move |stop| {
+            record.borrow_mut().push((stop.moment, stop.env.borrow_mut().get("n".to_string())));
+            match stop.moment {
+                None if record.borrow().len() == 1 => Resume::Travel(stop.moments.iter().position(|span| span.line == 3).unwrap()),
+                None => Resume::Abort,
+                Some(_) => Resume::Continue(None),
+            }
+        });
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new()))).with_debugger(debugger);
+        let source = "let start = 0 + 0;\nlet count = fn n -> {\n  let next = n + 1;\n  if next == 3 { next / 0 } else { count(next) }\n};\ncount(start)";
+        let result = evaluator.eval(&crate::parser::Parser::new(crate::lexer::Lexer::new(source)).parse_program());
+        assert!(matches!(result, Some(Object::Error(Code::DivisionByZero, _))));
+        // The bindings made after the moment were unmade, so the run could
+        // make them again
+        assert_eq!(*seen.borrow(), [(None, Some(Object::Integer(2))), (Some(2), Some(Object::Integer(0))), (None, Some(Object::Integer(2)))]);
+    }
+}
@@ -0,0 +1,534 @@
+// ffi.rs
+//
+// A C ABI over `Engine`, built with `--features cdylib` into a shared
+// library by `cargo rustc --lib --features cdylib --crate-type cdylib`.
+// Engines and values are opaque pointers owned by the caller and released
+// with the matching `_free` function; strings handed out are released with
+// `opl_string_free`. The declarations for C hosts are in include/opl.h.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::ptr;
+
+use crate::interop::Engine;
+use crate::diagnostics::Code;
+use crate::object::Object;
+
+pub type OplEngine = Engine;
+pub type OplValue = Object;
+
+/// A host function. It receives borrowed arguments and the `data` pointer
+/// given to `opl_engine_register`, and returns a new value that the engine
+/// takes ownership of. Returning null is reported as a failed call.
+pub type OplCallback = unsafe extern "C" fn(arguments: *const *const OplValue, count: usize, data: *mut c_void) -> *mut OplValue;
+
+/// The kind of a value, as returned by `opl_value_kind`.
+#[repr(C)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OplKind {
+    Unit = 0,
+    Integer = 1,
+    Float = 2,
+    Boolean = 3,
+    String = 4,
+    List = 5,
+    Record = 6,
+    Option = 7,
+    Result = 8,
+    Function = 9,
+    Other = 10,
+    Error = 11,
+    Map = 12,
+    Set = 13,
+    Deque = 14,
+}
+
+fn into_c_string(string: String) -> *mut c_char {
+    // Interior NUL bytes cannot cross the boundary
+    CString::new(string.replace('\0', "")).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+fn into_value(object: Object) -> *mut OplValue {
+    Box::into_raw(Box::new(object))
+}
+
+unsafe fn to_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+// Copies `count` values out of a C array of value pointers
+unsafe fn copy_values(values: *const *const OplValue, count: usize) -> Option<Vec<Object>> {
+    if count == 0 {
+        return Some(Vec::new());
+    }
+    if values.is_null() {
+        return None;
+    }
+    std::slice::from_raw_parts(values, count).iter().map(|value| value.as_ref().cloned()).collect()
+}
+
+#[no_mangle]
+pub extern "C" fn opl_engine_new() -> *mut OplEngine {
+    Box::into_raw(Box::new(Engine::new()))
+}
+
+/// # Safety
+/// `engine` must come from `opl_engine_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn opl_engine_free(engine: *mut OplEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Evaluates `source`, returning its value or null on failure. When `error`
+/// is not null it receives the diagnostics of a failure, or null on success.
+///
+/// # Safety
+/// `engine` must be a live engine and `source` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_eval(engine: *mut OplEngine, source: *const c_char, error: *mut *mut c_char) -> *mut OplValue {
+    if !error.is_null() {
+        *error = ptr::null_mut();
+    }
+    let (Some(engine), false) = (engine.as_mut(), source.is_null()) else {
+        return ptr::null_mut();
+    };
+    let result = match CStr::from_ptr(source).to_str() {
+        Ok(source) => engine.eval(source).map_err(|diagnostics| {
+            diagnostics.iter().map(ToString::to_string).collect::<Vec<String>>().join("\n")
+        }),
+        Err(e) => Err(format!("source is not valid UTF-8: {}", e)),
+    };
+    match result {
+        Ok(value) => into_value(value),
+        Err(message) => {
+            if !error.is_null() {
+                *error = into_c_string(message);
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Binds `name` to a copy of `value` in the engine's global scope.
+///
+/// # Safety
+/// `engine` and `value` must be live and `name` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_engine_set(engine: *mut OplEngine, name: *const c_char, value: *const OplValue) -> bool {
+    match (engine.as_mut(), name.is_null(), value.as_ref()) {
+        (Some(engine), false, Some(value)) => match CStr::from_ptr(name).to_str() {
+            Ok(name) => {
+                engine.set_global(name, value.clone());
+                true
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Binds `name` to a host function, called with the values it is applied to.
+///
+/// # Safety
+/// `engine` must be live, `name` a NUL terminated string, and `callback` safe
+/// to call with `data` for as long as the engine lives.
+#[no_mangle]
+pub unsafe extern "C" fn opl_engine_register(engine: *mut OplEngine, name: *const c_char, callback: OplCallback, data: *mut c_void) -> bool {
+    let (Some(engine), Some(name)) = (engine.as_mut(), to_str(name)) else {
+        return false;
+    };
+    let function_name = name.to_string();
+    engine.register(name, move |arguments| {
+        let pointers: Vec<*const OplValue> = arguments.iter().map(|argument| argument as *const OplValue).collect();
+        let result = callback(pointers.as_ptr(), pointers.len(), data);
+        if result.is_null() {
+            Object::Error(Code::HostFunction, format!("host function '{}' failed", function_name))
+        } else {
+            *Box::from_raw(result)
+        }
+    });
+    true
+}
+
+/// Returns a copy of the global `name`, or null when it is unbound.
+///
+/// # Safety
+/// `engine` must be live and `name` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_engine_get(engine: *const OplEngine, name: *const c_char) -> *mut OplValue {
+    match (engine.as_ref(), name.is_null()) {
+        (Some(engine), false) => match CStr::from_ptr(name).to_str() {
+            Ok(name) => engine.get_global::<Object>(name).and_then(Result::ok).map_or(ptr::null_mut(), into_value),
+            Err(_) => ptr::null_mut(),
+        },
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn opl_value_int(value: i64) -> *mut OplValue {
+    into_value(Object::Integer(value))
+}
+
+#[no_mangle]
+pub extern "C" fn opl_value_float(value: f64) -> *mut OplValue {
+    into_value(Object::Float(value))
+}
+
+#[no_mangle]
+pub extern "C" fn opl_value_bool(value: bool) -> *mut OplValue {
+    into_value(Object::Boolean(value))
+}
+
+/// # Safety
+/// `value` must be a NUL terminated UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_string(value: *const c_char) -> *mut OplValue {
+    to_str(value).map_or(ptr::null_mut(), |value| into_value(Object::String(value.into())))
+}
+
+#[no_mangle]
+pub extern "C" fn opl_value_unit() -> *mut OplValue {
+    into_value(Object::Unit)
+}
+
+#[no_mangle]
+pub extern "C" fn opl_value_none() -> *mut OplValue {
+    into_value(Object::OptionNone)
+}
+
+/// Wraps a copy of `value` in `Some`.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_some(value: *const OplValue) -> *mut OplValue {
+    value.as_ref().map_or(ptr::null_mut(), |value| into_value(Object::OptionSome(Box::new(value.clone()))))
+}
+
+/// A runtime error, for host functions to fail with.
+///
+/// # Safety
+/// `message` must be a NUL terminated UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_error(message: *const c_char) -> *mut OplValue {
+    to_str(message).map_or(ptr::null_mut(), |message| into_value(Object::Error(Code::HostFunction, message.to_string())))
+}
+
+/// A list of copies of `count` values.
+///
+/// # Safety
+/// `values` must point to `count` live values.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_list(values: *const *const OplValue, count: usize) -> *mut OplValue {
+    copy_values(values, count).map_or(ptr::null_mut(), |elements| into_value(Object::List(elements)))
+}
+
+/// A record with fields `names[i] = values[i]`, values copied.
+///
+/// # Safety
+/// `names` must point to `count` NUL terminated strings and `values` to
+/// `count` live values.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_record(names: *const *const c_char, values: *const *const OplValue, count: usize) -> *mut OplValue {
+    let Some(values) = copy_values(values, count) else {
+        return ptr::null_mut();
+    };
+    if count > 0 && names.is_null() {
+        return ptr::null_mut();
+    }
+    let names = if count == 0 { &[] } else { std::slice::from_raw_parts(names, count) };
+    let names: Option<Vec<String>> = names.iter().map(|name| to_str(*name).map(str::to_string)).collect();
+    names.map_or(ptr::null_mut(), |names| into_value(Object::Record(names.into_iter().zip(values).collect())))
+}
+
+/// # Safety
+/// `value` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_free(value: *mut OplValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+/// # Safety
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_kind(value: *const OplValue) -> OplKind {
+    match value.as_ref() {
+        Some(Object::Unit) => OplKind::Unit,
+        Some(Object::Integer(_)) => OplKind::Integer,
+        Some(Object::Float(_)) => OplKind::Float,
+        Some(Object::Boolean(_)) => OplKind::Boolean,
+        Some(Object::String(_)) => OplKind::String,
+        Some(Object::List(_)) => OplKind::List,
+        Some(Object::Record(_)) => OplKind::Record,
+        Some(Object::OptionSome(_) | Object::OptionNone) => OplKind::Option,
+        Some(Object::ResultOk(_) | Object::ResultErr(_)) => OplKind::Result,
+        Some(Object::Function(..) | Object::Builtin(_) | Object::Native(_)) => OplKind::Function,
+        Some(Object::Error(..)) => OplKind::Error,
+        Some(Object::Map(_)) => OplKind::Map,
+        Some(Object::Set(_)) => OplKind::Set,
+        Some(Object::Deque(_)) => OplKind::Deque,
+        _ => OplKind::Other,
+    }
+}
+
+/// Writes an integer value to `out`, returning false for any other kind.
+///
+/// # Safety
+/// `value` must be live and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_as_int(value: *const OplValue, out: *mut i64) -> bool {
+    match (value.as_ref(), out.is_null()) {
+        (Some(Object::Integer(value)), false) => {
+            *out = *value;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// # Safety
+/// `value` must be live and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_as_float(value: *const OplValue, out: *mut f64) -> bool {
+    match (value.as_ref(), out.is_null()) {
+        (Some(Object::Float(value)), false) => {
+            *out = *value;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// # Safety
+/// `value` must be live and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_as_bool(value: *const OplValue, out: *mut bool) -> bool {
+    match (value.as_ref(), out.is_null()) {
+        (Some(Object::Boolean(value)), false) => {
+            *out = *value;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Copies out the contents of a string value, or null for any other kind.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_as_string(value: *const OplValue) -> *mut c_char {
+    match value.as_ref() {
+        Some(Object::String(value)) => into_c_string(value.as_str().to_string()),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Formats any value the way `println` and the REPL show it.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_to_string(value: *const OplValue) -> *mut c_char {
+    value.as_ref().map_or(ptr::null_mut(), |value| into_c_string(value.to_string()))
+}
+
+/// The number of elements of a list, set or deque, entries of a map or
+/// fields of a record, otherwise 0.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_len(value: *const OplValue) -> usize {
+    match value.as_ref() {
+        Some(Object::List(elements)) => elements.len(),
+        Some(Object::Record(fields)) => fields.len(),
+        Some(Object::Map(map)) => map.len(),
+        Some(Object::Set(set)) => set.len(),
+        Some(Object::Deque(deque)) => deque.len(),
+        _ => 0,
+    }
+}
+
+/// Returns a copy of a list element, or null when out of range.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_list_get(value: *const OplValue, index: usize) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::List(elements)) => elements.get(index).cloned().map_or(ptr::null_mut(), into_value),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Returns a list of copies of the elements of a set, or of a deque from
+/// front to back, otherwise null.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_elements(value: *const OplValue) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::Set(set)) => into_value(Object::List(set.iter().cloned().collect())),
+        Some(Object::Deque(deque)) => into_value(Object::List(deque.to_vec())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Returns a list of copies of the keys of a map, otherwise null.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_keys(value: *const OplValue) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::Map(map)) => into_value(Object::List(map.iter().map(|(key, _)| key.clone()).collect())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Returns a list of copies of the values of a map, in the order of
+/// `opl_value_keys`, otherwise null.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_values(value: *const OplValue) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::Map(map)) => into_value(Object::List(map.iter().map(|(_, value)| value.clone()).collect())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// The name of the record field at `index`, or null when out of range.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_field_name(value: *const OplValue, index: usize) -> *mut c_char {
+    match value.as_ref() {
+        Some(Object::Record(fields)) => fields.get_index(index).map_or(ptr::null_mut(), |(name, _)| into_c_string(name.clone())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Returns a copy of the record field at `index`, or null when out of range.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_field_at(value: *const OplValue, index: usize) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::Record(fields)) => fields.get_index(index).map_or(ptr::null_mut(), |(_, value)| into_value(value.clone())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Returns a copy of what a `Some`, `Ok` or `Err` holds, otherwise null.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_inner(value: *const OplValue) -> *mut OplValue {
+    match value.as_ref() {
+        Some(Object::OptionSome(inner) | Object::ResultOk(inner) | Object::ResultErr(inner)) => into_value((**inner).clone()),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// True for an `Err` result.
+///
+/// # Safety
+/// `value` must be live.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_is_err(value: *const OplValue) -> bool {
+    matches!(value.as_ref(), Some(Object::ResultErr(_)))
+}
+
+/// Returns a copy of a record field, or null when there is no such field.
+///
+/// # Safety
+/// `value` must be live and `name` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn opl_value_field(value: *const OplValue, name: *const c_char) -> *mut OplValue {
+    if name.is_null() {
+        return ptr::null_mut();
+    }
+    match (value.as_ref(), CStr::from_ptr(name).to_str()) {
+        (Some(Object::Record(fields)), Ok(name)) => fields.get(name).map_or(ptr::null_mut(), |value| into_value(value.clone())),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `string` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn opl_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_through_c_abi() {
+        unsafe {
+            let engine = opl_engine_new();
+            let answer = opl_value_int(41);
+            assert!(opl_engine_set(engine, c"answer".as_ptr(), answer));
+            opl_value_free(answer);
+
+            let mut error = ptr::null_mut();
+            let value = opl_eval(engine, c"let r = { xs = [answer + 1] }; r;".as_ptr(), &mut error);
+            assert!(error.is_null());
+            assert_eq!(opl_value_kind(value), OplKind::Record);
+            let xs = opl_value_field(value, c"xs".as_ptr());
+            assert_eq!(opl_value_len(xs), 1);
+            let first = opl_value_list_get(xs, 0);
+            let mut out = 0;
+            assert!(opl_value_as_int(first, &mut out));
+            assert_eq!(out, 42);
+            [value, xs, first].into_iter().for_each(|value| opl_value_free(value));
+
+            let value = opl_eval(engine, c"{ \"a\": 1, \"b\": 2 };".as_ptr(), &mut error);
+            assert_eq!(opl_value_kind(value), OplKind::Map);
+            assert_eq!(opl_value_len(value), 2);
+            let (keys, values) = (opl_value_keys(value), opl_value_values(value));
+            assert_eq!(opl_value_len(keys), 2);
+            let (key, second) = (opl_value_list_get(keys, 1), opl_value_list_get(values, 1));
+            let name = opl_value_as_string(key);
+            assert_eq!(CStr::from_ptr(name).to_str(), Ok("b"));
+            opl_string_free(name);
+            assert!(opl_value_as_int(second, &mut out));
+            assert_eq!(out, 2);
+            [value, keys, values, key, second].into_iter().for_each(|value| opl_value_free(value));
+
+            for (source, kind) in [(c"set.from_list([3, 4]);", OplKind::Set), (c"deque.from_list([3, 4]);", OplKind::Deque)] {
+                let value = opl_eval(engine, source.as_ptr(), &mut error);
+                assert_eq!(opl_value_kind(value), kind);
+                let elements = opl_value_elements(value);
+                let text = opl_value_to_string(elements);
+                assert_eq!(CStr::from_ptr(text).to_str(), Ok("[3, 4]"));
+                opl_string_free(text);
+                [value, elements].into_iter().for_each(|value| opl_value_free(value));
+            }
+
+            let value = opl_eval(engine, c"missing;".as_ptr(), &mut error);
+            assert!(value.is_null());
+            assert!(CStr::from_ptr(error).to_str().unwrap().contains("E0"));
+            opl_string_free(error);
+            opl_engine_free(engine);
+        }
+    }
+}
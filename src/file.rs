@@ -0,0 +1,97 @@
+// file.rs
+//
+// The `file` module: files opened as handles, read a line at a time or
+// whole, and written to. Using it is an `fs` effect.
+
+use crate::diagnostics::Code;
+use crate::handle::{Handle, Resource};
+use crate::object::Object;
+use crate::stdin::{line, read_all, read_line};
+use crate::string::Str;
+use crate::trace;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+
+/// `file.open(path, mode)` opens a file for reading (`"r"`), writing
+/// (`"w"`) or appending (`"a"`). `file.read_line`, `file.read_all`,
+/// `file.write` and `file.close` take the handle first.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("open", |args| match args.as_slice() {
+            [Object::String(path), Object::String(mode)] => open(path.as_str(), mode.as_str()),
+            _ => argument_error("open", "a path and a mode"),
+        }),
+        function("read_line", |args| match args.as_slice() {
+            [Object::Handle(handle)] => trace::read("file", || reader(handle, read_line)).map_or_else(|error| io_error(handle, error), line),
+            _ => argument_error("read_line", "a handle"),
+        }),
+        function("read_all", |args| match args.as_slice() {
+            [Object::Handle(handle)] => trace::read("file", || reader(handle, read_all)).map_or_else(|error| io_error(handle, error), |text| Object::String(Str::from(text))),
+            _ => argument_error("read_all", "a handle"),
+        }),
+        function("write", |args| match args.as_slice() {
+            [Object::Handle(handle), Object::String(text)] => {
+                let written = handle.with(|resource| match resource {
+                    Resource::Writer(writer) => writer.write_all(text.as_str().as_bytes()),
+                    _ => Err(io::Error::other("it was not opened for writing")),
+                });
+                written.map_or_else(|error| io_error(handle, error), |_| Object::Unit)
+            }
+            _ => argument_error("write", "a handle and a string"),
+        }),
+        function("close", |args| match args.as_slice() {
+            [Object::Handle(handle)] => handle.close().map_or_else(|error| io_error(handle, error), |_| Object::Unit),
+            _ => argument_error("close", "a handle"),
+        }),
+    ])
+}
+
+fn open(path: &str, mode: &str) -> Object {
+    let opened = match mode {
+        "r" => trace::open(|| File::open(path).map(|file| Resource::Reader(BufReader::new(file)))),
+        "w" => File::create(path).map(|file| Resource::Writer(BufWriter::new(file))),
+        "a" => OpenOptions::new().append(true).create(true).open(path).map(|file| Resource::Writer(BufWriter::new(file))),
+        _ => return argument_error("open", "a mode of \"r\", \"w\" or \"a\""),
+    };
+    match opened {
+        Ok(resource) => Object::Handle(Handle::open(format!("file {:?}", path), resource)),
+        Err(error) => Object::Error(Code::IoFailed, format!("cannot open {:?}: {}", path, error)),
+    }
+}
+
+fn reader<T>(handle: &Handle, read: impl FnOnce(&mut BufReader<File>) -> io::Result<T>) -> io::Result<T> {
+    handle.with(|resource| match resource {
+        Resource::Reader(reader) => read(reader),
+        _ => Err(io::Error::other("it was not opened for reading")),
+    })
+}
+
+fn argument_error(name: &str, expected: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("file.{} expects {}", name, expected))
+}
+
+fn io_error(handle: &Handle, error: io::Error) -> Object {
+    Object::Error(Code::IoFailed, format!("{} failed: {}", handle.description(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+
+    #[test]
+    fn test_file() {
+        let mut eval = crate::evaluator::test_eval();
+        let path = std::env::temp_dir().join(format!("opl-file-{}.txt", std::process::id()));
+        let path = format!("{:?}", path.to_string_lossy());
+
+        eval(&format!("let out = file.open({}, \"w\");\nfile.write(out, \"one\\ntwo\\n\");\nfile.close(out);", path));
+        eval(&format!("let input = file.open({}, \"r\");", path));
+        assert_eq!(eval("file.read_line(input)"), Some(Object::OptionSome(Box::new(Object::from("one")))));
+        assert_eq!(eval("file.read_all(input)"), Some(Object::from("two\n")));
+        assert!(matches!(eval("file.write(input, \"x\")"), Some(Object::Error(..))));
+        eval("file.close(input);");
+        assert!(matches!(eval("file.read_line(input)"), Some(Object::Error(..))));
+        let _ = std::fs::remove_file(path.trim_matches('"'));
+    }
+}
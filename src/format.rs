@@ -0,0 +1,279 @@
+// format.rs
+//
+// The `format` builtin: `format("x = {:.2}, name = {:>10}", { x, name })`.
+// A format string is text with replacement fields in braces, `{{` and `}}`
+// standing for literal braces. A field names the value it takes, by
+// position or by record field, and may give a spec after a colon:
+//
+//     {[name or index][:[[fill]align][+][0][width][.precision]]}
+//
+// where align is `<`, `>` or `^`. Numbers align right by default and
+// everything else left. Precision is the number of decimals of a float and
+// the most characters of a string. Format strings are parsed once and
+// cached, so a call in a loop only formats.
+
+use crate::diagnostics::Code;
+use crate::object::{format_float, Object};
+use crate::string::Str;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, PartialEq, Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<Align>,
+    sign: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Argument {
+    Next,
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(Argument, Spec),
+}
+
+const MAX_COMPILED: usize = 256;
+
+thread_local! {
+    static COMPILED: RefCell<HashMap<String, Rc<[Piece]>>> = RefCell::new(HashMap::new());
+}
+
+fn compiled(format: &str) -> Result<Rc<[Piece]>, String> {
+    if let Some(pieces) = COMPILED.with(|compiled| compiled.borrow().get(format).cloned()) {
+        return Ok(pieces);
+    }
+    let pieces: Rc<[Piece]> = compile(format)?.into();
+    COMPILED.with(|compiled| {
+        let mut compiled = compiled.borrow_mut();
+        // Format strings built at runtime must not grow the cache forever
+        if compiled.len() >= MAX_COMPILED {
+            compiled.clear();
+        }
+        compiled.insert(format.to_string(), Rc::clone(&pieces))
+    });
+    Ok(pieces)
+}
+
+fn compile(format: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '}' => return Err("unmatched `}`, write `}}` for a literal brace".to_string()),
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c != '{' => field.push(c),
+                        _ => return Err(format!("unclosed field `{{{}`", field)),
+                    }
+                }
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(compile_field(&field)?);
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+fn compile_field(field: &str) -> Result<Piece, String> {
+    let (argument, spec) = field.split_once(':').unwrap_or((field, ""));
+    let argument = match argument.trim() {
+        "" => Argument::Next,
+        name if name.chars().all(|c| c.is_ascii_digit()) => Argument::Index(name.parse().map_err(|_| format!("invalid index `{}`", name))?),
+        name => Argument::Name(name.to_string()),
+    };
+    Ok(Piece::Field(argument, compile_spec(spec).map_err(|error| format!("{} in `{{{}}}`", error, field))?))
+}
+
+fn compile_spec(text: &str) -> Result<Spec, String> {
+    let mut spec = Spec::default();
+    let chars: Vec<char> = text.chars().collect();
+    let align = |c: char| match c {
+        '<' => Some(Align::Left),
+        '>' => Some(Align::Right),
+        '^' => Some(Align::Center),
+        _ => None,
+    };
+    let mut i = 0;
+    if let (Some(&fill), Some(a)) = (chars.first(), chars.get(1).and_then(|&c| align(c))) {
+        (spec.fill, spec.align, i) = (Some(fill), Some(a), 2);
+    } else if let Some(a) = chars.first().and_then(|&c| align(c)) {
+        (spec.align, i) = (Some(a), 1);
+    }
+    if chars.get(i) == Some(&'+') {
+        (spec.sign, i) = (true, i + 1);
+    }
+    if chars.get(i) == Some(&'0') {
+        (spec.zero, i) = (true, i + 1);
+    }
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while chars.get(*i).is_some_and(char::is_ascii_digit) {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+    let width = digits(&mut i);
+    if !width.is_empty() {
+        spec.width = width.parse().map_err(|_| format!("invalid width {}", width))?;
+    }
+    if chars.get(i) == Some(&'.') {
+        i += 1;
+        let precision = digits(&mut i);
+        spec.precision = Some(precision.parse().map_err(|_| "expected a precision after `.`".to_string())?);
+    }
+    match chars.get(i) {
+        None => Ok(spec),
+        Some(c) => Err(format!("unexpected `{}` in the spec", c)),
+    }
+}
+
+// The value a field takes, positional fields counting through `values`
+fn value<'a>(argument: &Argument, values: &'a Object, next: &mut usize) -> Result<&'a Object, String> {
+    let index = match argument {
+        Argument::Next => {
+            *next += 1;
+            *next - 1
+        }
+        Argument::Index(index) => *index,
+        Argument::Name(name) => {
+            let Object::Record(fields) = values else {
+                return Err(format!("`{{{}}}` names a field, but the values are a list", name));
+            };
+            return fields.get(name).ok_or(format!("no field `{}` for `{{{}}}`", name, name));
+        }
+    };
+    let value = match values {
+        Object::List(elements) => elements.get(index),
+        Object::Record(fields) => fields.values().get(index),
+        _ => None,
+    };
+    value.ok_or(format!("no value at position {}, only {} given", index, count(values)))
+}
+
+fn count(values: &Object) -> usize {
+    match values {
+        Object::List(elements) => elements.len(),
+        Object::Record(fields) => fields.len(),
+        _ => 0,
+    }
+}
+
+fn render(value: &Object, spec: &Spec) -> Result<String, String> {
+    let (text, numeric) = match value {
+        Object::Integer(n) => {
+            if spec.precision.is_some() {
+                return Err("a precision applies to floats and strings, not int".to_string());
+            }
+            (n.to_string(), true)
+        }
+        Object::Float(x) => match spec.precision {
+            Some(precision) if x.is_finite() => (format!("{:.*}", precision, x), true),
+            _ => (format_float(*x), true),
+        },
+        Object::String(s) => match spec.precision {
+            Some(precision) => (s.as_str().chars().take(precision).collect(), false),
+            None => (s.to_string(), false),
+        },
+        other => (other.to_string(), false),
+    };
+    let text = if numeric && spec.sign && !text.starts_with('-') { format!("+{}", text) } else { text };
+    let length = text.chars().count();
+    if length >= spec.width {
+        return Ok(text);
+    }
+    let padding = spec.width - length;
+    // Zeros go between the sign and the digits
+    if numeric && spec.zero && spec.align.is_none() {
+        let split = usize::from(text.starts_with(['+', '-']));
+        return Ok(format!("{}{}{}", &text[..split], "0".repeat(padding), &text[split..]));
+    }
+    let fill = spec.fill.unwrap_or(' ').to_string();
+    let (before, after) = match spec.align.unwrap_or(if numeric { Align::Right } else { Align::Left }) {
+        Align::Left => (0, padding),
+        Align::Right => (padding, 0),
+        Align::Center => (padding / 2, padding - padding / 2),
+    };
+    Ok(format!("{}{}{}", fill.repeat(before), text, fill.repeat(after)))
+}
+
+/// `format(string, values)`, the string with each field replaced by its
+/// value from `values`, a list or a record.
+pub fn format_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(format), values @ (Object::List(_) | Object::Record(_))] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "format expects a format string and a list or record of values".to_string());
+    };
+    let result = compiled(format.as_str()).and_then(|pieces| {
+        let mut out = String::new();
+        let mut next = 0;
+        for piece in pieces.iter() {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Field(argument, spec) => out.push_str(&render(value(argument, values, &mut next)?, spec)?),
+            }
+        }
+        Ok(out)
+    });
+    match result {
+        Ok(text) => Object::String(Str::from(text)),
+        Err(message) => Object::Error(Code::BuiltinArgument, format!("format: {}", message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+
+    #[test]
+    fn test_format() {
+        let mut eval = crate::evaluator::test_eval();
+
+        eval("let x = 3.14159;\nlet name = \"ada\";");
+        assert_eq!(eval("format(\"x = {:.2}, name = {:>6}|\", { x, name })"), Some(Object::from("x = 3.14, name =    ada|")));
+        assert_eq!(eval("format(\"{name:*^7} {x:+08.3} {{}}\", { x, name })"), Some(Object::from("**ada** +003.142 {}")));
+        assert_eq!(eval("format(\"{1}-{0}|{:<4}|{:4}|\", [12, 345])"), Some(Object::from("345-12|12  | 345|")));
+
+        let error = |result| match result {
+            Some(Object::Error(_, ref message)) => message.clone(),
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(error(eval("format(\"{} {}\", [1])")), "format: no value at position 1, only 1 given");
+        assert_eq!(error(eval("format(\"{:.2}\", [1])")), "format: a precision applies to floats and strings, not int");
+        assert_eq!(error(eval("format(\"{:x}\", [1])")), "format: unexpected `x` in the spec in `{:x}`");
+        assert_eq!(error(eval("format(\"{:>4\", [1])")), "format: unclosed field `{:>4`");
+    }
+}
@@ -0,0 +1,99 @@
+// graph.rs
+//
+// DOT graphs of a program for `opl graph`, read off the name resolution table
+// of `analysis`: which functions call which, and which top level bindings use
+// which. opl has no modules yet, so dependencies are those between the
+// bindings of a file.
+
+use crate::analysis::{self, Table};
+use crate::ast::{Expression, Program, Statement};
+use crate::lexer::Token;
+
+// Calls and uses outside of any top level let
+const TOP_LEVEL: &str = "(top level)";
+
+// The symbols of the top level lets of `program`, with whether each is bound
+// to a function
+fn bindings(program: &Program, table: &Table) -> Vec<(usize, bool)> {
+    program
+        .iter()
+        .filter_map(|statement| match statement {
+            Statement::Let(Token::Identifier(_), expression, span) => {
+                let symbol = table.symbols.iter().position(|symbol| symbol.definition == Some(*span))?;
+                let function = match expression {
+                    Expression::Annotated { expression, .. } => matches!(**expression, Expression::Function { .. }),
+                    expression => matches!(expression, Expression::Function { .. }),
+                };
+                Some((symbol, function))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn dot(name: &str, table: &Table, nodes: &[usize], edges: &[(Option<usize>, usize)]) -> String {
+    let label = |symbol: Option<usize>| symbol.map_or(TOP_LEVEL, |symbol| table.symbols[symbol].name.as_str());
+    let mut graph = format!("digraph {} {{\n", name);
+    for node in nodes {
+        graph.push_str(&format!("    \"{}\";\n", label(Some(*node))));
+    }
+    let mut seen = Vec::new();
+    for edge in edges {
+        if !seen.contains(edge) {
+            graph.push_str(&format!("    \"{}\" -> \"{}\";\n", label(edge.0), label(Some(edge.1))));
+            seen.push(*edge);
+        }
+    }
+    graph.push_str("}\n");
+    graph
+}
+
+/// Which top level functions call which, calls outside of any function
+/// coming from a `(top level)` node. None when the program has parse errors.
+pub fn calls(program: &Program) -> Option<String> {
+    let table = analysis::table(program)?;
+    let functions: Vec<usize> = bindings(program, &table).into_iter().filter(|(_, function)| *function).map(|(symbol, _)| symbol).collect();
+    let edges: Vec<(Option<usize>, usize)> = table
+        .uses
+        .iter()
+        .filter(|u| u.call && functions.contains(&u.symbol))
+        .map(|u| (u.within.filter(|within| functions.contains(within)), u.symbol))
+        .collect();
+    Some(dot("calls", &table, &functions, &edges))
+}
+
+/// Which top level bindings each one uses, those of expressions outside of
+/// any let coming from a `(top level)` node. None when the program has parse
+/// errors.
+pub fn deps(program: &Program) -> Option<String> {
+    let table = analysis::table(program)?;
+    let bindings: Vec<usize> = bindings(program, &table).into_iter().map(|(symbol, _)| symbol).collect();
+    let edges: Vec<(Option<usize>, usize)> = table
+        .uses
+        .iter()
+        .filter(|u| bindings.contains(&u.symbol) && u.within != Some(u.symbol))
+        .map(|u| (u.within, u.symbol))
+        .collect();
+    Some(dot("deps", &table, &bindings, &edges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_graphs() {
+        let source = "let limit = 10;\nlet small = fn n -> n < limit;\nlet twice = fn f, x -> f(f(x));\nlet fact = fn n -> if small(n) { 1 } else { n * fact(n - 1) };\nlet unused = fn () -> 0;\nprint(5 |> fact);";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        assert_eq!(
+            calls(&program).unwrap(),
+            "digraph calls {\n    \"small\";\n    \"twice\";\n    \"fact\";\n    \"unused\";\n    \"fact\" -> \"small\";\n    \"fact\" -> \"fact\";\n    \"(top level)\" -> \"fact\";\n}\n"
+        );
+        assert_eq!(
+            deps(&program).unwrap(),
+            "digraph deps {\n    \"limit\";\n    \"small\";\n    \"twice\";\n    \"fact\";\n    \"unused\";\n    \"small\" -> \"limit\";\n    \"fact\" -> \"small\";\n    \"(top level)\" -> \"fact\";\n}\n"
+        );
+    }
+}
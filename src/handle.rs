@@ -0,0 +1,177 @@
+// handle.rs
+//
+// Handles to resources outside the interpreter, such as open files, which
+// builtins return as `Object::Handle`. Every copy of a handle value shares
+// one resource, and it is closed by whichever comes first: the script closing
+// it, the last copy going out of scope, or the end of the program. Builtins
+// are plain functions with no evaluator to hand, so the open handles are
+// tracked per thread. In debug mode, handles the script did not close itself
+// are reported as leaks.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+#[cfg(feature = "net")]
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::rc::{Rc, Weak};
+
+/// What a handle holds open.
+pub enum Resource {
+    Reader(BufReader<File>),
+    Writer(BufWriter<File>),
+    // A file read back from a trace by `opl run --replay`
+    Replayed,
+    #[cfg(feature = "net")]
+    Listener(TcpListener),
+    #[cfg(feature = "net")]
+    Connection(BufReader<TcpStream>),
+}
+
+impl Resource {
+    fn close(self) -> io::Result<()> {
+        match self {
+            Resource::Reader(_) | Resource::Replayed => Ok(()),
+            Resource::Writer(mut writer) => writer.flush(),
+            #[cfg(feature = "net")]
+            Resource::Listener(_) => Ok(()),
+            // The peer may have gone already
+            #[cfg(feature = "net")]
+            Resource::Connection(connection) => match connection.get_ref().shutdown(Shutdown::Both) {
+                Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+struct Inner {
+    id: u64,
+    // e.g. `file "out.txt"`
+    description: String,
+    resource: RefCell<Option<Resource>>,
+}
+
+#[derive(Clone)]
+pub struct Handle(Rc<Inner>);
+
+thread_local! {
+    static OPEN: RefCell<BTreeMap<u64, Weak<Inner>>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT: Cell<u64> = const { Cell::new(0) };
+    static REPORT_LEAKS: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Handle {
+    pub fn open(description: String, resource: Resource) -> Handle {
+        let id = NEXT.replace(NEXT.get() + 1);
+        let inner = Rc::new(Inner { id, description, resource: RefCell::new(Some(resource)) });
+        OPEN.with(|open| open.borrow_mut().insert(id, Rc::downgrade(&inner)));
+        Handle(inner)
+    }
+
+    pub fn description(&self) -> &str {
+        &self.0.description
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.0.resource.borrow().is_some()
+    }
+
+    /// Runs `f` on the resource, or fails when the handle is closed.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Resource) -> io::Result<T>) -> io::Result<T> {
+        match &mut *self.0.resource.borrow_mut() {
+            Some(resource) => f(resource),
+            None => Err(io::Error::other(format!("{} is closed", self.0.description))),
+        }
+    }
+
+    /// Closes the resource, flushing what was written to it. Closing a
+    /// closed handle does nothing.
+    pub fn close(&self) -> io::Result<()> {
+        let resource = self.0.resource.borrow_mut().take();
+        OPEN.with(|open| open.borrow_mut().remove(&self.0.id));
+        resource.map_or(Ok(()), Resource::close)
+    }
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}{}>", if self.is_open() { "" } else { "closed " }, self.0.description)
+    }
+}
+
+// Closes what the script left open when the last copy of its handle goes
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = OPEN.try_with(|open| open.borrow_mut().remove(&self.id));
+        if let Some(resource) = self.resource.get_mut().take() {
+            leaked(&self.description);
+            let _ = resource.close();
+        }
+    }
+}
+
+fn leaked(description: &str) {
+    if REPORT_LEAKS.get() {
+        eprintln!("warning: {} was never closed", description);
+    }
+}
+
+/// Whether to report handles the script does not close, on stderr.
+pub fn report_leaks(report: bool) {
+    REPORT_LEAKS.set(report);
+}
+
+/// Closes every open handle, as at the end of a program. Returns how many
+/// were still open.
+pub fn close_all() -> usize {
+    let open: Vec<Rc<Inner>> = OPEN.with(|open| std::mem::take(&mut *open.borrow_mut()).values().filter_map(Weak::upgrade).collect());
+    for inner in &open {
+        if let Some(resource) = inner.resource.borrow_mut().take() {
+            leaked(&inner.description);
+            let _ = resource.close();
+        }
+    }
+    open.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{close_all, Handle, Resource};
+    use std::fs::{self, File};
+    use std::io::{BufWriter, Write};
+
+    #[test]
+    fn test_handle_lifecycle() {
+        let path = std::env::temp_dir().join(format!("opl-handle-{}.txt", std::process::id()));
+        let write = |handle: &Handle, text: &str| {
+            handle.with(|resource| match resource {
+                Resource::Writer(writer) => writer.write_all(text.as_bytes()),
+                _ => unreachable!(),
+            })
+        };
+        let open = || Handle::open("file".to_string(), Resource::Writer(BufWriter::new(File::create(&path).unwrap())));
+
+        // Dropping the last copy flushes and closes
+        let handle = open();
+        write(&handle.clone(), "dropped").unwrap();
+        drop(handle);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dropped");
+
+        let handle = open();
+        write(&handle, "closed").unwrap();
+        assert_eq!(close_all(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "closed");
+        assert!(!handle.is_open());
+        assert!(write(&handle, "more").is_err());
+        assert_eq!(close_all(), 0);
+        let _ = fs::remove_file(&path);
+    }
+}
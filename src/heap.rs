@@ -0,0 +1,167 @@
+// heap.rs
+//
+// Approximate accounting of the memory held by opl values. The evaluator
+// records the size of every value it allocates; once the running total
+// passes the limit it measures what is actually still reachable, and only
+// reports an error if that is over the limit too.
+
+use crate::environment::Env;
+use crate::object::Object;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::rc::Rc;
+
+#[derive(Debug, Default)]
+pub struct Heap {
+    pub limit: Option<usize>,
+    allocated: usize,
+}
+
+impl Heap {
+    pub fn new(limit: Option<usize>) -> Self {
+        Heap { limit, allocated: 0 }
+    }
+
+    /// Records an allocation. Returns true when the total has passed the
+    /// limit and the live size should be measured.
+    pub fn allocate(&mut self, bytes: usize) -> bool {
+        self.allocated += bytes;
+        matches!(self.limit, Some(limit) if self.allocated > limit)
+    }
+
+    /// Restarts the running total from a measured live size.
+    pub fn reset(&mut self, live: usize) {
+        self.allocated = live;
+    }
+}
+
+/// Sums the bytes held by values, following closures into the environments
+/// they capture. Each environment is counted once.
+#[derive(Default)]
+pub struct Measure {
+    seen: HashSet<*const RefCell<Env>>,
+    follow_envs: bool,
+    pub bytes: usize,
+}
+
+impl Measure {
+    /// A measure of live memory, including captured environments.
+    pub fn live() -> Self {
+        Measure { follow_envs: true, ..Measure::default() }
+    }
+
+    pub fn object(&mut self, object: &Object) {
+        let mut later = Later::default();
+        self.walk(object, &mut later);
+        self.finish(later);
+    }
+
+    pub fn env(&mut self, env: &Rc<RefCell<Env>>) {
+        self.finish(Later { copies: Vec::new(), envs: vec![Rc::clone(env)] });
+    }
+
+    // Values nest deeper than Rust can recurse, so `object` walks them with
+    // an explicit stack, leaving what it cannot borrow for `finish`
+    fn walk(&mut self, object: &Object, later: &mut Later) {
+        let mut pending = vec![object];
+        while let Some(object) = pending.pop() {
+            match object {
+                Object::String(value) => self.bytes += value.len(),
+                Object::Error(_, value) => self.bytes += value.capacity(),
+                Object::List(elements) => {
+                    self.bytes += elements.capacity() * size_of::<Object>();
+                    pending.extend(elements);
+                }
+                // Field names are shared by records of one shape
+                Object::Record(fields) => {
+                    self.bytes += fields.len() * size_of::<Object>();
+                    pending.extend(fields.values());
+                }
+                Object::Map(map) => {
+                    self.bytes += map.len() * (size_of::<(Object, Object)>() + size_of::<usize>());
+                    for (key, value) in map.iter() {
+                        pending.push(key);
+                        pending.push(value);
+                    }
+                }
+                Object::Deque(deque) => {
+                    let elements = deque.to_vec();
+                    self.bytes += elements.len() * (size_of::<Object>() + 2 * size_of::<usize>());
+                    later.copies.extend(elements);
+                }
+                Object::Set(set) => {
+                    self.bytes += set.len() * (size_of::<(Object, Object)>() + size_of::<usize>());
+                    pending.extend(set.iter());
+                }
+                Object::OptionSome(inner) | Object::ResultOk(inner) | Object::ResultErr(inner) | Object::Return(inner) => {
+                    self.bytes += size_of::<Object>();
+                    pending.push(inner);
+                }
+                Object::Function(parameters, _, env) => {
+                    self.bytes += parameters.capacity() * size_of::<crate::ast::Identifier>();
+                    if self.follow_envs {
+                        later.envs.push(Rc::clone(env));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn finish(&mut self, mut later: Later) {
+        loop {
+            if let Some(copy) = later.copies.pop() {
+                self.walk(&copy, &mut later);
+            } else if let Some(env) = later.envs.pop() {
+                if !self.seen.insert(Rc::as_ptr(&env)) {
+                    continue;
+                }
+                let env = env.borrow();
+                for (name, value) in &env.store {
+                    self.bytes += name.capacity() + size_of::<Object>();
+                    self.walk(value, &mut later);
+                }
+                if let Some(outer) = &env.outer {
+                    later.envs.push(Rc::clone(outer));
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+// Copies of the elements of deques, and environments, still to measure
+#[derive(Default)]
+struct Later {
+    copies: Vec<Object>,
+    envs: Vec<Rc<RefCell<Env>>>,
+}
+
+/// Approximate bytes owned by a single value, not counting captured
+/// environments.
+pub fn size(object: &Object) -> usize {
+    let mut measure = Measure::default();
+    measure.object(object);
+    measure.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure_counts_environments_once() {
+        let env = Rc::new(RefCell::new(Env::new()));
+        env.borrow_mut().set("s".to_string(), Object::String("x".repeat(100).into()));
+        let closure = Object::Function(Vec::new(), Rc::new(Vec::new()), Rc::clone(&env));
+        env.borrow_mut().set("f".to_string(), closure.clone());
+
+        assert_eq!(size(&closure), 0);
+        let mut measure = Measure::live();
+        measure.object(&closure);
+        measure.object(&closure);
+        assert!(measure.bytes >= 100 && measure.bytes < 300);
+    }
+}
@@ -0,0 +1,532 @@
+// interop.rs
+//
+// Moving values between Rust and opl. Plain Rust values convert through
+// `IntoOpl` and `FromOpl`; structs become records by listing their fields in
+// `opl_record!`. `Engine` runs opl source with globals supplied by the host,
+// within the effects and limits of its `EngineConfig`.
+//
+// Values share their contents through `Rc` and `RefCell`, and builtins keep
+// state such as open handles and timers per thread, so an `Engine` and its
+// values stay on the thread that made them. `EngineHandle` is how other
+// threads use one: the engine lives on a thread of its own and only source
+// and converted results cross over.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::ast::{Program, Statement};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::effects::Effects;
+use crate::environment::Env;
+use crate::evaluator::Evaluator;
+use crate::lexer::{Lexer, Token};
+use crate::object::{ConversionError, Native, Object};
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::typechecker::{TypeChecker, TypeErrorKind};
+
+pub trait IntoOpl {
+    fn into_opl(self) -> Object;
+}
+
+pub trait FromOpl: Sized {
+    fn from_opl(object: &Object) -> Result<Self, ConversionError>;
+}
+
+impl IntoOpl for Object {
+    fn into_opl(self) -> Object {
+        self
+    }
+}
+
+impl FromOpl for Object {
+    fn from_opl(object: &Object) -> Result<Self, ConversionError> {
+        Ok(object.clone())
+    }
+}
+
+// Types with a `From`/`TryFrom` pair on Object
+macro_rules! bridge {
+    ($($rust:ty),*) => {
+        $(
+            impl IntoOpl for $rust {
+                fn into_opl(self) -> Object {
+                    Object::from(self)
+                }
+            }
+
+            impl FromOpl for $rust {
+                fn from_opl(object: &Object) -> Result<Self, ConversionError> {
+                    Self::try_from(object.clone())
+                }
+            }
+        )*
+    };
+}
+
+bridge!(i64, f64, bool, String);
+
+impl IntoOpl for &str {
+    fn into_opl(self) -> Object {
+        Object::from(self)
+    }
+}
+
+impl<T: IntoOpl> IntoOpl for Vec<T> {
+    fn into_opl(self) -> Object {
+        Object::List(self.into_iter().map(IntoOpl::into_opl).collect())
+    }
+}
+
+impl<T: FromOpl> FromOpl for Vec<T> {
+    fn from_opl(object: &Object) -> Result<Self, ConversionError> {
+        match object {
+            Object::List(elements) => elements.iter().map(T::from_opl).collect(),
+            other => Err(ConversionError::new("list", other)),
+        }
+    }
+}
+
+impl<T: IntoOpl> IntoOpl for Option<T> {
+    fn into_opl(self) -> Object {
+        match self {
+            Some(value) => Object::OptionSome(Box::new(value.into_opl())),
+            None => Object::OptionNone,
+        }
+    }
+}
+
+impl<T: FromOpl> FromOpl for Option<T> {
+    fn from_opl(object: &Object) -> Result<Self, ConversionError> {
+        match object {
+            Object::OptionSome(value) => T::from_opl(value).map(Some),
+            Object::OptionNone => Ok(None),
+            other => Err(ConversionError::new("option", other)),
+        }
+    }
+}
+
+/// Builds a record one field at a time, keeping the order fields are given in.
+#[derive(Default)]
+pub struct RecordBuilder {
+    fields: Vec<(String, Object)>,
+}
+
+impl RecordBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(mut self, name: &str, value: impl IntoOpl) -> Self {
+        self.fields.push((name.to_string(), value.into_opl()));
+        self
+    }
+
+    pub fn build(self) -> Object {
+        Object::record(self.fields)
+    }
+}
+
+/// Reads and converts a field of a record.
+pub fn field<T: FromOpl>(object: &Object, name: &str) -> Result<T, ConversionError> {
+    match object {
+        Object::Record(fields) => match fields.get(name) {
+            Some(value) => T::from_opl(value),
+            None => Err(ConversionError::new(format!("a record with field '{}'", name), object)),
+        },
+        other => Err(ConversionError::new("record", other)),
+    }
+}
+
+/// Converts a struct to and from an opl record with the same field names.
+///
+/// ```
+/// struct Point { x: i64, y: i64 }
+/// opl::opl_record!(Point { x, y });
+/// ```
+#[macro_export]
+macro_rules! opl_record {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::interop::IntoOpl for $name {
+            fn into_opl(self) -> $crate::object::Object {
+                $crate::interop::RecordBuilder::new()
+                    $(.field(stringify!($field), self.$field))*
+                    .build()
+            }
+        }
+
+        impl $crate::interop::FromOpl for $name {
+            fn from_opl(object: &$crate::object::Object) -> Result<Self, $crate::object::ConversionError> {
+                Ok($name {
+                    $($field: $crate::interop::field(object, stringify!($field))?,)*
+                })
+            }
+        }
+    };
+}
+
+/// What scripts run by an `Engine` may do. Start from a profile and adjust
+/// it, e.g. `EngineConfig { max_steps: None, ..EngineConfig::pure() }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    /// Effects scripts may have. A script with any other is refused before it
+    /// runs.
+    pub effects: Effects,
+    /// Most evaluation steps of one call to `eval`.
+    pub max_steps: Option<u64>,
+    /// Most bytes of live values.
+    pub max_heap: Option<usize>,
+}
+
+impl EngineConfig {
+    /// For untrusted code that only computes: no effects, ten million steps
+    /// and 64 MiB.
+    pub fn pure() -> Self {
+        EngineConfig { effects: Effects::NONE, max_steps: Some(10_000_000), max_heap: Some(64 << 20) }
+    }
+
+    /// For scripts that print, read the clock and draw random numbers, but
+    /// touch no files, network or processes: a billion steps and 1 GiB.
+    pub fn scripting() -> Self {
+        let effects = Effects::IO.union(Effects::TIME).union(Effects::RAND);
+        EngineConfig { effects, max_steps: Some(1_000_000_000), max_heap: Some(1 << 30) }
+    }
+
+    /// Every effect and no limits, what `Engine::new` gives.
+    pub fn full() -> Self {
+        EngineConfig { effects: Effects::ALL, max_steps: None, max_heap: None }
+    }
+
+    /// The profile called `name`: `pure`, `scripting` or `full`.
+    pub fn profile(name: &str) -> Option<Self> {
+        match name {
+            "pure" => Some(EngineConfig::pure()),
+            "scripting" => Some(EngineConfig::scripting()),
+            "full" => Some(EngineConfig::full()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig::full()
+    }
+}
+
+/// Evaluates opl source on behalf of a Rust host. Bindings persist between
+/// calls to `eval`, like lines in the REPL.
+pub struct Engine {
+    evaluator: Evaluator,
+    // Effects of the host functions registered with them
+    effects: HashMap<String, Effects>,
+    config: EngineConfig,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine::with_config(EngineConfig::full())
+    }
+
+    /// An engine whose scripts are held to `config`, e.g.
+    /// `Engine::with_config(EngineConfig::pure())`.
+    pub fn with_config(config: EngineConfig) -> Self {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        if let Some(steps) = config.max_steps {
+            evaluator = evaluator.with_step_limit(steps);
+        }
+        if let Some(bytes) = config.max_heap {
+            evaluator = evaluator.with_heap_limit(bytes);
+        }
+        Engine { evaluator, effects: HashMap::new(), config }
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl IntoOpl) {
+        self.evaluator.env.borrow_mut().set(name.to_string(), value.into_opl());
+    }
+
+    /// Binds `name` to a host function. Like the builtins it can be shadowed.
+    pub fn register(&mut self, name: &str, function: impl Fn(Vec<Object>) -> Object + 'static) {
+        let native = Native { name: name.to_string(), function: Rc::new(function) };
+        self.evaluator.env.borrow_mut().set(name.to_string(), Object::Native(native));
+    }
+
+    /// Like `register`, for a host function with effects, e.g. one reading
+    /// files. Scripts marked `@pure` cannot use it.
+    pub fn register_with_effects(&mut self, name: &str, effects: Effects, function: impl Fn(Vec<Object>) -> Object + 'static) {
+        self.register(name, function);
+        self.effects.insert(name.to_string(), effects);
+    }
+
+    /// The effects running `source` may have, without running it. Fails on
+    /// parse errors, or when a `@pure` script uses an effectful function.
+    pub fn audit(&self, source: &str) -> Result<Effects, Vec<Diagnostic>> {
+        let program = optimizer::optimize(self.parse(source)?);
+        self.check_effects(&program).map(|checker| checker.performed())
+    }
+
+    fn check_effects(&self, program: &Program) -> Result<TypeChecker, Vec<Diagnostic>> {
+        let mut checker = TypeChecker::new();
+        for name in self.evaluator.env.borrow().names() {
+            checker.declare_host(&name, self.effects.get(&name).copied().unwrap_or_default());
+        }
+        checker.check_program(program);
+        let errors: Vec<Diagnostic> = checker
+            .errors
+            .iter()
+            .filter(|error| matches!(*error.kind, TypeErrorKind::Effect(..)))
+            .map(Diagnostic::from)
+            .collect();
+        if errors.is_empty() {
+            Ok(checker)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Includes read files while parsing, before any effect is checked
+    fn parse(&self, source: &str) -> Result<Program, Vec<Diagnostic>> {
+        let mut parser = Parser::new(Lexer::new(source));
+        if !self.config.effects.contains(Effects::FS) {
+            parser = parser.without_includes();
+        }
+        let program = parser.parse_program();
+        if !parser.errors.is_empty() {
+            return Err(parser.errors.iter().map(Diagnostic::from).collect());
+        }
+        Ok(program)
+    }
+
+    // Lets the next `eval` rebind the globals bound so far
+    pub(crate) fn nest_globals(&mut self) {
+        self.evaluator.nest_globals();
+    }
+
+    // The bindings so far, for completions
+    pub(crate) fn env(&self) -> Rc<RefCell<Env>> {
+        Rc::clone(&self.evaluator.env)
+    }
+
+    /// Reads a global, or `None` when `name` is unbound.
+    pub fn get_global<T: FromOpl>(&self, name: &str) -> Option<Result<T, ConversionError>> {
+        let value = self.evaluator.env.borrow_mut().get(name.to_string());
+        value.as_ref().map(T::from_opl)
+    }
+
+    /// Runs `source` and returns the value of its last statement. A script
+    /// that calls `exit(status)` stops there with one diagnostic, of code
+    /// `Code::Exit(status)`; the host decides what to do with the status.
+    pub fn eval(&mut self, source: &str) -> Result<Object, Vec<Diagnostic>> {
+        // Audited as it will run, after rewrite rules have brought in what
+        // their replacements call
+        let program = optimizer::optimize(self.parse(source)?);
+        let pure = program.iter().any(|statement| matches!(statement, Statement::Attribute(Token::Identifier(name), _) if name == "pure"));
+        if pure || self.config.effects != Effects::ALL {
+            // Any effect the script can reach, as functions passed to builtins
+            // are called without the checker seeing it
+            let denied = self.check_effects(&program)?.mentioned().difference(self.config.effects);
+            if !denied.is_empty() {
+                return Err(vec![Diagnostic::new(Code::EffectNotAllowed, format!("the script performs {}, which this engine does not allow", denied))]);
+            }
+        }
+        match self.evaluator.eval(&program) {
+            Some(Object::Error(code, ref mut message)) => Err(vec![Diagnostic::new(code, std::mem::take(message))]),
+            Some(Object::Return(ref mut value)) => Ok(std::mem::take(&mut **value)),
+            Some(value) => Ok(value),
+            None => Ok(Object::Unit),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&mut Engine) + Send>;
+
+/// An `Engine` on a dedicated thread, which any thread can hand work. It is
+/// `Send` and `Sync`, and clones are handles to the same engine, which takes
+/// requests one at a time in the order they arrive. The thread ends once
+/// every handle is dropped.
+#[derive(Clone)]
+pub struct EngineHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl EngineHandle {
+    /// Starts the thread of an engine held to `config`.
+    pub fn spawn(config: EngineConfig) -> Self {
+        let (jobs, received) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("opl-engine".to_string())
+            .spawn(move || {
+                let mut engine = Engine::with_config(config);
+                for job in received {
+                    job(&mut engine);
+                }
+            })
+            .expect("failed to start the engine thread");
+        EngineHandle { jobs }
+    }
+
+    /// Runs `f` on the engine and waits for its result, e.g. to register a
+    /// host function or read globals. Panics if an earlier `f` panicked,
+    /// which stops the engine.
+    pub fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut Engine) -> R + Send + 'static) -> R {
+        let (reply, result) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move |engine| {
+            let _ = reply.send(f(engine));
+        });
+        self.jobs.send(job).ok().and_then(|_| result.recv().ok()).expect("the engine thread has stopped")
+    }
+
+    /// `Engine::eval`, with the value converted on the engine's thread.
+    pub fn eval<T: FromOpl + Send + 'static>(&self, source: &str) -> Result<T, Vec<Diagnostic>> {
+        let source = source.to_string();
+        self.with(move |engine| {
+            let value = engine.eval(&source)?;
+            T::from_opl(&value).map_err(|error| vec![Diagnostic::new(Code::BuiltinArgument, error.to_string())])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Config {
+        name: String,
+        retries: i64,
+        verbose: bool,
+    }
+
+    opl_record!(Config { name, retries, verbose });
+
+    #[test]
+    fn test_record_round_trip() {
+        let mut engine = Engine::new();
+        engine.set_global("config", Config { name: "opl".to_string(), retries: 3, verbose: false });
+        assert_eq!(engine.eval("config.retries + 1;"), Ok(Object::Integer(4)));
+
+        engine.eval("let updated = { name = config.name, retries = 5, verbose = true };").unwrap();
+        let updated: Config = engine.get_global("updated").unwrap().unwrap();
+        assert_eq!(updated, Config { name: "opl".to_string(), retries: 5, verbose: true });
+        let error = engine.get_global::<i64>("updated").unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "expected int, got { name: string, retries: int, verbose: bool }");
+        assert_eq!(engine.get_global::<i64>("missing"), None);
+    }
+
+    #[test]
+    fn test_register() {
+        let mut engine = Engine::new();
+        engine.register("twice", |arguments| match arguments.as_slice() {
+            [Object::Integer(n)] => Object::Integer(n * 2),
+            _ => Object::Error(crate::diagnostics::Code::BuiltinArgument, "twice expects an int".to_string()),
+        });
+        assert_eq!(engine.eval("let quad = fn x -> twice(twice(x));; quad(3);"), Ok(Object::Integer(12)));
+    }
+
+    #[test]
+    fn test_effects() {
+        let mut engine = Engine::new();
+        engine.register_with_effects("read_file", Effects::FS, |_| Object::from("contents"));
+        engine.register("twice", |arguments| arguments[0].clone());
+
+        let source = "let load = fn path -> read_file(path);\nlet show = fn x -> println(x);\ntwice(1);";
+        assert_eq!(engine.audit(source), Ok(Effects::NONE));
+        assert_eq!(engine.audit("let load = fn path -> read_file(path);\nload(\"a\");"), Ok(Effects::FS));
+        assert_eq!(engine.audit("println(\"hi\");").map(|effects| effects.to_string()), Ok("io".to_string()));
+
+        let errors = engine.eval("@pure\nlet load = read_file;\ntwice(2);").unwrap_err();
+        assert_eq!(errors[0].message, "`read_file` performs fs, which a @pure module cannot do");
+        assert_eq!(engine.eval("@pure\ntwice(2);"), Ok(Object::Integer(2)));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut engine = Engine::with_config(EngineConfig { max_steps: Some(10_000), ..EngineConfig::profile("pure").unwrap() });
+        engine.register_with_effects("read_file", Effects::FS, |_| Object::from("contents"));
+        let denied = |result: Result<Object, Vec<Diagnostic>>| result.unwrap_err()[0].message.clone();
+        assert_eq!(denied(engine.eval("let shout = fn s -> println(s);\nshout(\"hi\");")), "the script performs io, which this engine does not allow");
+        assert_eq!(denied(engine.eval("read_file(\"a\");")), "the script performs fs, which this engine does not allow");
+        assert_eq!(denied(engine.eval("let shout = fn s -> println(s);\nmap(fn s -> shout(s), [\"a\"]);")), "the script performs io, which this engine does not allow");
+        assert_eq!(engine.eval("let spin = fn n -> spin(n + 1);\nspin(0);").map_err(|diagnostics| diagnostics[0].code), Err(Code::StepLimit));
+        assert_eq!(engine.eval("fold(fn acc, x -> acc + x, 0, [1..10]);"), Ok(Object::Integer(55)));
+
+        // A rule's replacement is audited like the rest of the script
+        let rule = "rule \"r\": ident(x) => file.read_all(file.open(x, \"r\"));\nlet ident = fn x -> x;\nident(\"secret.txt\");";
+        assert_eq!(denied(engine.eval(rule)), "the script performs fs, which this engine does not allow");
+
+        let mut engine = Engine::with_config(EngineConfig::scripting());
+        assert_eq!(engine.eval("exit(1);").map_err(|diagnostics| diagnostics[0].code), Err(Code::EffectNotAllowed));
+        assert!(EngineConfig::profile("root").is_none());
+    }
+
+    #[test]
+    fn test_profile_includes() {
+        let path = std::env::temp_dir().join(format!("opl-secret-{}.txt", std::process::id()));
+        std::fs::write(&path, "secret").unwrap();
+        let source = format!("include_str({:?});", path.to_string_lossy());
+        // Includes read while parsing, so only an engine allowed fs may have them
+        for profile in ["pure", "scripting"] {
+            let errors = Engine::with_config(EngineConfig::profile(profile).unwrap()).eval(&source).unwrap_err();
+            assert_eq!((errors[0].code, errors[0].message.as_str()), (Code::EffectNotAllowed, "include_str reads a file, which is the fs effect, and this engine does not allow it"));
+        }
+        assert_eq!(Engine::with_config(EngineConfig::full()).eval(&source), Ok(Object::from("secret")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut engine = Engine::new();
+        let result = engine.eval("let total = fold(fn acc, x -> if x > 2 { exit(3) } else { acc + x }, 0, [1, 2, 3, 4]);\ntotal;");
+        assert_eq!(result.map_err(|diagnostics| diagnostics[0].code), Err(Code::Exit(3)));
+        assert_eq!(engine.eval("1 + 1;"), Ok(Object::Integer(2)));
+        assert!(matches!(engine.eval("exit(256);"), Err(diagnostics) if diagnostics[0].code == Code::BuiltinArgument));
+    }
+
+    #[test]
+    fn test_engine_handle() {
+        fn shared<T: Send + Sync>(value: T) -> T {
+            value
+        }
+        let engine = shared(EngineHandle::spawn(EngineConfig::scripting()));
+        engine.with(|engine| engine.register("twice", |arguments| Object::Integer(i64::try_from(arguments[0].clone()).unwrap_or(0) * 2)));
+        assert_eq!(engine.eval::<i64>("let total = 0;\ntotal;"), Ok(0));
+
+        let workers: Vec<_> = (1..=4)
+            .map(|n| {
+                let engine = engine.clone();
+                thread::spawn(move || engine.eval::<i64>(&format!("twice({});", n)).unwrap())
+            })
+            .collect();
+        let doubled: Vec<i64> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        assert_eq!(doubled, [2, 4, 6, 8]);
+        assert_eq!(engine.eval::<i64>("total + 1;"), Ok(1));
+        assert_eq!(engine.eval::<bool>("1;").unwrap_err()[0].message, "expected bool, got int");
+        assert_eq!(engine.eval::<i64>("exit(1);").unwrap_err()[0].code, Code::EffectNotAllowed);
+    }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(i64::try_from(Object::Integer(3)), Ok(3));
+        assert_eq!(String::try_from(Object::from("opl")), Ok("opl".to_string()));
+        let error = bool::try_from(Object::Float(1.0)).unwrap_err();
+        assert_eq!(error, ConversionError { expected: "bool".to_string(), found: "float".to_string() });
+        assert_eq!(Object::from(error), Object::Error(crate::diagnostics::Code::BuiltinArgument, "expected bool, got float".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let mut engine = Engine::new();
+        engine.set_global("config", RecordBuilder::new().field("name", "opl").build());
+        let errors = engine.eval("config.nmae;").unwrap_err();
+        assert_eq!(errors[0].message, "No field 'nmae' in record, did you mean `name`?");
+    }
+}
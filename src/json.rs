@@ -0,0 +1,233 @@
+// json.rs
+//
+// JSON read at parse time, for `include_json`. A document becomes the
+// literal expression that would be written for it: objects are records,
+// arrays are lists, and null is None.
+
+use crate::ast::{Expression, Literal};
+use crate::lexer::Token;
+
+/// The literal expression for a JSON document, or an error naming its line.
+pub fn parse(text: &str) -> Result<Expression, String> {
+    let mut parser = Parser { chars: text.chars().collect(), position: 0, line: 1 };
+    let value = parser.value()?;
+    parser.skip_space();
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => parser.error(format!("unexpected '{}' after the document", c)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, message.into()))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.next();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            return Ok(());
+        }
+        self.error(format!("expected '{}'", c))
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.next();
+        }
+    }
+
+    fn value(&mut self) -> Result<Expression, String> {
+        self.skip_space();
+        let literal = match self.peek() {
+            Some('{') => self.object()?,
+            Some('[') => {
+                self.next();
+                let elements = self.sequence(']', Parser::value)?;
+                Literal::List(elements)
+            }
+            Some('"') => Literal::String(self.string()?),
+            Some('-' | '0'..='9') => self.number()?,
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.position;
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.next();
+                }
+                match self.chars[start..self.position].iter().collect::<String>().as_str() {
+                    "true" => Literal::Boolean(true),
+                    "false" => Literal::Boolean(false),
+                    "null" => return Ok(Expression::OptionNone),
+                    word => return self.error(format!("unexpected '{}'", word)),
+                }
+            }
+            Some(c) => return self.error(format!("unexpected '{}'", c)),
+            None => return self.error("unexpected end of the document"),
+        };
+        Ok(Expression::Literal(literal))
+    }
+
+    // The comma separated items up to `close`, after the opening bracket
+    fn sequence<T>(&mut self, close: char, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        self.skip_space();
+        if self.eat(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_space();
+            if self.eat(close) {
+                return Ok(items);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn object(&mut self) -> Result<Literal, String> {
+        self.next();
+        let fields = self.sequence('}', |parser| {
+            parser.skip_space();
+            if parser.peek() != Some('"') {
+                return parser.error("expected a key");
+            }
+            let key = parser.string()?;
+            parser.skip_space();
+            parser.expect(':')?;
+            Ok((key, parser.value()?))
+        })?;
+        // Records, unlike JSON objects, cannot repeat a field
+        for (i, (key, _)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == key) {
+                return self.error(format!("the key \"{}\" is repeated", key));
+            }
+        }
+        Ok(Literal::Record(fields.into_iter().map(|(key, value)| (Token::Identifier(key), value)).collect()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => string.push(self.escape()?),
+                    _ => return self.error("invalid escape in a string"),
+                },
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    // `\uXXXX`, after the `u`, with a second escape for a surrogate pair
+    fn escape(&mut self) -> Result<char, String> {
+        let high = self.hex()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).map_or_else(|| self.error("invalid unicode escape"), Ok);
+        }
+        if !(self.eat('\\') && self.eat('u')) {
+            return self.error("unpaired surrogate in a unicode escape");
+        }
+        let low = self.hex()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return self.error("unpaired surrogate in a unicode escape");
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).map_or_else(|| self.error("invalid unicode escape"), Ok)
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            match self.next().and_then(|c| c.to_digit(16)) {
+                Some(digit) => value = value * 16 + digit,
+                None => return self.error("expected 4 hex digits in a unicode escape"),
+            }
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Literal, String> {
+        let start = self.position;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.next();
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        if text.contains(['.', 'e', 'E']) {
+            return text.parse().map(Literal::Float).or_else(|_| self.error(format!("invalid number {}", text)));
+        }
+        text.parse().map(Literal::Integer).or_else(|_| self.error(format!("invalid integer {}", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("opl-include-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("greeting.txt"), "hello\n").unwrap();
+        std::fs::write(directory.join("config.json"), r#"{"name": "opl", "ports": [80, 443], "ratio": 0.5, "debug": false, "owner": null, "motto": "é\n"}"#).unwrap();
+        std::fs::write(directory.join("broken.json"), "{\"ports\": [80,\n 443,]}").unwrap();
+        let parse = |source| Parser::new(Lexer::new(source)).with_directory(&directory).parse_program();
+
+        let program = parse("let config = include_json(\"config.json\");\n[include_str(\"greeting.txt\"), config.name, show(config.ports), config.motto]");
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        assert_eq!(program.includes, [directory.join("config.json"), directory.join("greeting.txt")]);
+        let strings = ["hello\n", "opl", "[80, 443]", "é\n"].map(|s| Object::String(s.into())).to_vec();
+        assert_eq!(Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&program), Some(Object::List(strings)));
+
+        let errors = parse("include_json(\"broken.json\")").errors;
+        assert_eq!(errors[0].code(), Code::IncludeFailed);
+        assert!(errors[0].message().ends_with("broken.json: line 2: unexpected ']'"), "{}", errors[0].message());
+        let missing = parse("include_str(\"missing.txt\")");
+        assert!(missing.errors[0].message().starts_with("cannot include"));
+        // Watched by `opl run --watch` until it appears
+        assert_eq!(missing.includes, [directory.join("missing.txt")]);
+        assert!(parse("include_str(\"a\" ++ \"b\")").errors[0].message().contains("as a string literal"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
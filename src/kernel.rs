@@ -0,0 +1,559 @@
+// kernel.rs
+//
+// The Jupyter kernel run by `opl-kernel` (`jupyter` feature), which gives the
+// cells of a notebook to a `Session`. Jupyter talks to kernels over ZeroMQ;
+// the kernel speaks what it needs of ZMTP 3.0 itself, over plain TCP: the
+// NULL handshake and framed messages, with each request answered on the
+// connection it came in on rather than routed by identity. Messages are
+// signed with HMAC-SHA256 under the key of the connection file.
+//
+// Cells run on the main thread. Every connection is read on a thread of its
+// own, so an `interrupt_request` reaches a cell that is still running, and
+// the heartbeat answers while one does. The kernelspec in
+// bindings/jupyter/opl asks Jupyter to interrupt by message.
+
+use crate::ast::{Expression, Literal};
+use crate::diagnostics::json_escape;
+use crate::evaluator;
+use crate::json;
+use crate::lexer::Token;
+use crate::object::Object;
+use crate::repl::is_incomplete;
+use crate::session::Session;
+use crate::table;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PROTOCOL_VERSION: &str = "5.3";
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+// Flags of a ZMTP frame
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+/// Runs the kernel for the connection file named on the command line, until
+/// Jupyter asks it to shut down.
+pub fn run() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: opl-kernel CONNECTION_FILE");
+        process::exit(2);
+    };
+    if let Err(error) = start(&path) {
+        eprintln!("opl-kernel: {}", error);
+        process::exit(1);
+    }
+}
+
+fn start(path: &str) -> Result<(), String> {
+    let source = std::fs::read_to_string(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+    let connection = json::parse(&source).map_err(|error| format!("{}: {}", path, error))?;
+    if text(member(&connection, "transport")).is_some_and(|transport| transport != "tcp") {
+        return Err("only the tcp transport is supported".to_string());
+    }
+    let key = text(member(&connection, "key")).unwrap_or("");
+    if !key.is_empty() && text(member(&connection, "signature_scheme")).is_some_and(|scheme| scheme != "hmac-sha256") {
+        return Err("only hmac-sha256 signatures are supported".to_string());
+    }
+    let ip = text(member(&connection, "ip")).unwrap_or("127.0.0.1");
+    let bind = |name: &str| {
+        let port = integer(member(&connection, name)).ok_or_else(|| format!("{} has no {}", path, name))?;
+        TcpListener::bind((ip, port as u16)).map_err(|error| format!("cannot listen on {}:{}: {}", ip, port, error))
+    };
+
+    let signer = Signer { key: key.as_bytes().to_vec(), session: message_id() };
+    let iopub = Publisher { subscribers: Arc::default(), signer: signer.clone() };
+    let (requests, incoming) = mpsc::channel();
+    serve(bind("hb_port")?, "REP", |mut stream| loop {
+        let message = read_message(&mut stream)?;
+        write_message(&mut stream, &message)?;
+    });
+    let subscribers = Arc::clone(&iopub.subscribers);
+    serve(bind("iopub_port")?, "PUB", move |mut stream| {
+        subscribers.lock().expect("a publisher panicked").push(stream.try_clone()?);
+        // Everything is published to every subscriber, so subscriptions
+        // are read only to be dropped
+        loop {
+            read_message(&mut stream)?;
+        }
+    });
+    serve(bind("stdin_port")?, "ROUTER", |mut stream| loop {
+        read_message(&mut stream)?;
+    });
+    serve(bind("shell_port")?, "ROUTER", forward(signer.clone(), requests.clone()));
+    serve(bind("control_port")?, "ROUTER", forward(signer.clone(), requests));
+
+    let mut kernel = Kernel { session: Session::new(), signer, iopub };
+    for (request, mut stream) in incoming {
+        let parent = request.header.clone();
+        kernel.iopub.publish(&parent, "status", "{\"execution_state\":\"busy\"}");
+        let running = kernel.handle(&request, &mut stream).map_err(|error| format!("cannot reply: {}", error))?;
+        kernel.iopub.publish(&parent, "status", "{\"execution_state\":\"idle\"}");
+        if !running {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Accepts connections to `listener` as a ZMTP socket of `socket_type`, and
+// gives each one to `handle` on a thread of its own
+fn serve(listener: TcpListener, socket_type: &'static str, handle: impl Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static) {
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let handle = Arc::clone(&handle);
+            thread::spawn(move || {
+                if handshake(&mut stream, socket_type).is_ok() {
+                    let _ = handle(stream);
+                }
+            });
+        }
+    });
+}
+
+// Hands the requests arriving on a connection to the main thread, except
+// interrupts, which cannot wait for the cell they are meant to stop
+fn forward(signer: Signer, requests: Sender<(Message, TcpStream)>) -> impl Fn(TcpStream) -> io::Result<()> + Send + Sync {
+    move |mut stream| loop {
+        let Some(request) = signer.open(read_message(&mut stream)?) else { continue };
+        if request.msg_type == "interrupt_request" {
+            evaluator::interrupt();
+            write_message(&mut stream, &signer.frames(&request.identities, "interrupt_reply", &request.header, "{\"status\":\"ok\"}"))?;
+            continue;
+        }
+        let reply = stream.try_clone()?;
+        requests.send((request, reply)).map_err(|_| io::Error::other("the kernel has stopped"))?;
+    }
+}
+
+struct Kernel {
+    session: Session,
+    signer: Signer,
+    iopub: Publisher,
+}
+
+impl Kernel {
+    // Answers a request, and whether to keep running
+    fn handle(&mut self, request: &Message, stream: &mut TcpStream) -> io::Result<bool> {
+        let content = json::parse(&request.content).unwrap_or(Expression::OptionNone);
+        let code = text(member(&content, "code")).unwrap_or("");
+        let reply = match request.msg_type.as_str() {
+            "kernel_info_request" => format!(
+                "{{\"status\":\"ok\",\"protocol_version\":\"{}\",\"implementation\":\"opl\",\"implementation_version\":\"{}\",\
+                 \"language_info\":{{\"name\":\"opl\",\"version\":\"{}\",\"mimetype\":\"text/x-opl\",\"file_extension\":\".opl\"}},\
+                 \"banner\":\"opl {}\",\"help_links\":[]}}",
+                PROTOCOL_VERSION,
+                env!("CARGO_PKG_VERSION"),
+                env!("CARGO_PKG_VERSION"),
+                env!("CARGO_PKG_VERSION")
+            ),
+            "execute_request" => self.execute(request, &content, code),
+            "complete_request" => {
+                let cursor = integer(member(&content, "cursor_pos")).map_or(code.chars().count(), |cursor| cursor as usize);
+                // Jupyter counts the cursor in characters, completion in bytes
+                let offset = code.char_indices().nth(cursor).map_or(code.len(), |(offset, _)| offset);
+                let (start, completions) = self.session.complete(code, offset);
+                let matches: Vec<String> = completions.iter().map(|completion| string(&completion.label)).collect();
+                format!(
+                    "{{\"status\":\"ok\",\"matches\":[{}],\"cursor_start\":{},\"cursor_end\":{},\"metadata\":{{}}}}",
+                    matches.join(","),
+                    code[..start].chars().count(),
+                    cursor
+                )
+            }
+            "is_complete_request" if is_incomplete(code) => "{\"status\":\"incomplete\",\"indent\":\"\"}".to_string(),
+            "is_complete_request" => "{\"status\":\"complete\"}".to_string(),
+            "inspect_request" => "{\"status\":\"ok\",\"found\":false,\"data\":{},\"metadata\":{}}".to_string(),
+            "history_request" => "{\"status\":\"ok\",\"history\":[]}".to_string(),
+            "comm_info_request" => "{\"status\":\"ok\",\"comms\":{}}".to_string(),
+            "shutdown_request" => {
+                let restart = matches!(member(&content, "restart"), Some(Expression::Literal(Literal::Boolean(true))));
+                self.reply(request, stream, &format!("{{\"status\":\"ok\",\"restart\":{}}}", restart))?;
+                return Ok(false);
+            }
+            _ => return Ok(true),
+        };
+        self.reply(request, stream, &reply)?;
+        Ok(true)
+    }
+
+    fn reply(&self, request: &Message, stream: &mut TcpStream, content: &str) -> io::Result<()> {
+        let msg_type = request.msg_type.replace("_request", "_reply");
+        write_message(stream, &self.signer.frames(&request.identities, &msg_type, &request.header, content))
+    }
+
+    // Runs a cell, publishing its output and value, and gives the reply
+    fn execute(&mut self, request: &Message, content: &Expression, code: &str) -> String {
+        let silent = matches!(member(content, "silent"), Some(Expression::Literal(Literal::Boolean(true))));
+        let parent = &request.header;
+        let result = self.session.submit(code);
+        let count = result.id;
+        if !silent {
+            self.iopub.publish(parent, "execute_input", &format!("{{\"code\":{},\"execution_count\":{}}}", string(code), count));
+            if !result.stdout.is_empty() {
+                self.iopub.publish(parent, "stream", &format!("{{\"name\":\"stdout\",\"text\":{}}}", string(&result.stdout)));
+            }
+        }
+        if let Some(first) = result.diagnostics.first() {
+            let traceback: Vec<String> = result.diagnostics.iter().map(|diagnostic| string(&diagnostic.to_string())).collect();
+            let error = format!(
+                "\"ename\":{},\"evalue\":{},\"traceback\":[{}]",
+                string(&first.code.to_string()),
+                string(&first.message),
+                traceback.join(",")
+            );
+            self.iopub.publish(parent, "error", &format!("{{{}}}", error));
+            return format!("{{\"status\":\"error\",\"execution_count\":{},{}}}", count, error);
+        }
+        if let (Some(value), false) = (&result.value, silent) {
+            let mut data = format!("\"text/plain\":{}", string(&value.to_string()));
+            if let Some(html) = html(value) {
+                data.push_str(&format!(",\"text/html\":{}", string(&html)));
+            }
+            self.iopub.publish(parent, "execute_result", &format!("{{\"execution_count\":{},\"data\":{{{}}},\"metadata\":{{}}}}", count, data));
+        }
+        format!("{{\"status\":\"ok\",\"execution_count\":{},\"user_expressions\":{{}},\"payload\":[]}}", count)
+    }
+}
+
+// HTML for the values that read better laid out: a list of records as a
+// table with a column per field, a record as a table of its fields, and
+// other lists numbered from 0
+fn html(value: &Object) -> Option<String> {
+    let cell = |text: &str, right: bool| {
+        let align = if right { " style=\"text-align:right\"" } else { "" };
+        format!("<td{}>{}</td>", align, escape(text))
+    };
+    match value {
+        Object::List(rows) if !rows.is_empty() && rows.iter().all(|row| matches!(row, Object::Record(_))) => {
+            let (columns, cells) = table::grid(rows).ok()?;
+            let header: String = columns.iter().map(|column| format!("<th>{}</th>", escape(column))).collect();
+            let body: String = cells.iter().map(|row| format!("<tr>{}</tr>", row.iter().map(|(text, right)| cell(text, *right)).collect::<String>())).collect();
+            Some(format!("<table><thead><tr>{}</tr></thead><tbody>{}</tbody></table>", header, body))
+        }
+        Object::Record(_) => {
+            let (fields, cells) = table::grid(std::slice::from_ref(value)).ok()?;
+            let rows: String = fields.iter().zip(&cells[0]).map(|(field, (text, right))| format!("<tr><th>{}</th>{}</tr>", escape(field), cell(text, *right))).collect();
+            Some(format!("<table>{}</table>", rows))
+        }
+        Object::List(elements) if !elements.is_empty() => {
+            let items: String = elements.iter().map(|element| format!("<li>{}</li>", escape(&element.to_string()))).collect();
+            Some(format!("<ol start=\"0\">{}</ol>", items))
+        }
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn string(text: &str) -> String {
+    format!("\"{}\"", json_escape(text))
+}
+
+// A request, with its header and content as JSON
+struct Message {
+    // The frames before the delimiter, which go back with the reply
+    identities: Vec<Vec<u8>>,
+    header: String,
+    msg_type: String,
+    content: String,
+}
+
+// Signs and checks messages, for one session of the kernel
+#[derive(Clone)]
+struct Signer {
+    key: Vec<u8>,
+    session: String,
+}
+
+impl Signer {
+    // The message in `frames`, or None when it is malformed or its
+    // signature is not the key's
+    fn open(&self, mut frames: Vec<Vec<u8>>) -> Option<Message> {
+        let delimiter = frames.iter().position(|frame| frame == DELIMITER)?;
+        let mut parts = frames.split_off(delimiter).into_iter().skip(1);
+        let signature = parts.next()?;
+        let parts: Vec<String> = parts.take(4).map(|part| String::from_utf8(part).ok()).collect::<Option<_>>()?;
+        let [header, parent, metadata, content] = parts.as_slice() else { return None };
+        if !self.key.is_empty() && signature != self.sign(&[header, parent, metadata, content]).as_bytes() {
+            return None;
+        }
+        let msg_type = text(member(&json::parse(header).ok()?, "msg_type"))?.to_string();
+        Some(Message { identities: frames, header: header.clone(), msg_type, content: content.clone() })
+    }
+
+    fn sign(&self, parts: &[&str]) -> String {
+        hmac_sha256(&self.key, parts.concat().as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // The frames of a message of `msg_type` in answer to the one with header
+    // `parent`
+    fn frames(&self, identities: &[Vec<u8>], msg_type: &str, parent: &str, content: &str) -> Vec<Vec<u8>> {
+        let header = format!(
+            "{{\"msg_id\":\"{}\",\"session\":\"{}\",\"username\":\"opl\",\"date\":\"{}\",\"msg_type\":\"{}\",\"version\":\"{}\"}}",
+            message_id(),
+            self.session,
+            now(),
+            msg_type,
+            PROTOCOL_VERSION
+        );
+        let signature = if self.key.is_empty() { String::new() } else { self.sign(&[&header, parent, "{}", content]) };
+        let mut frames = identities.to_vec();
+        frames.push(DELIMITER.to_vec());
+        frames.extend([signature, header, parent.to_string(), "{}".to_string(), content.to_string()].map(String::into_bytes));
+        frames
+    }
+}
+
+// The iopub channel, which every subscriber hears all of
+struct Publisher {
+    subscribers: Arc<Mutex<Vec<TcpStream>>>,
+    signer: Signer,
+}
+
+impl Publisher {
+    fn publish(&self, parent: &str, msg_type: &str, content: &str) {
+        let frames = self.signer.frames(&[msg_type.as_bytes().to_vec()], msg_type, parent, content);
+        let mut subscribers = self.subscribers.lock().expect("a publisher panicked");
+        subscribers.retain_mut(|subscriber| write_message(subscriber, &frames).is_ok());
+    }
+}
+
+// A fresh identifier for a message or session
+fn message_id() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("{:x}-{:x}-{:x}", process::id(), nanos, COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
+// The time now in ISO 8601, in UTC
+fn now() -> String {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = ((since.as_secs() / 86400) as i64, since.as_secs() % 86400);
+    // The civil date of a day number, after Howard Hinnant's days_from_civil
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since.subsec_micros()
+    )
+}
+
+fn member<'a>(value: &'a Expression, key: &str) -> Option<&'a Expression> {
+    let Expression::Literal(Literal::Record(fields)) = value else { return None };
+    fields.iter().find(|(name, _)| matches!(name, Token::Identifier(name) if name == key)).map(|(_, value)| value)
+}
+
+fn text(value: Option<&Expression>) -> Option<&str> {
+    match value? {
+        Expression::Literal(Literal::String(text)) => Some(text),
+        _ => None,
+    }
+}
+
+fn integer(value: Option<&Expression>) -> Option<i64> {
+    match value? {
+        Expression::Literal(Literal::Integer(integer)) => Some(*integer),
+        _ => None,
+    }
+}
+
+// The ZMTP 3.0 greeting with the NULL mechanism, then READY commands both
+// ways, naming the socket type
+fn handshake(stream: &mut TcpStream, socket_type: &str) -> io::Result<()> {
+    let mut greeting = [0; 64];
+    greeting[0] = 0xFF;
+    greeting[9] = 0x7F;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+    let mut peer = [0; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xFF || peer[9] != 0x7F || peer[10] < 3 || &peer[12..17] != b"NULL\0" {
+        return Err(io::Error::other("the peer does not speak ZMTP 3 with the NULL mechanism"));
+    }
+    let mut ready = b"\x05READY\x0bSocket-Type".to_vec();
+    ready.extend((socket_type.len() as u32).to_be_bytes());
+    ready.extend(socket_type.as_bytes());
+    stream.write_all(&frame(&ready, COMMAND))?;
+    match read_frame(stream)? {
+        (flags, body) if flags & COMMAND != 0 && body.starts_with(b"\x05READY") => Ok(()),
+        _ => Err(io::Error::other("the peer did not send READY")),
+    }
+}
+
+fn frame(body: &[u8], flags: u8) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(body.len() + 9);
+    match u8::try_from(body.len()) {
+        Ok(size) => frame.extend([flags, size]),
+        Err(_) => {
+            frame.push(flags | LONG);
+            frame.extend((body.len() as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(body);
+    frame
+}
+
+// A frame's flags and body
+fn read_frame(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0];
+    stream.read_exact(&mut flags)?;
+    let size = if flags[0] & LONG != 0 {
+        let mut size = [0; 8];
+        stream.read_exact(&mut size)?;
+        u64::from_be_bytes(size) as usize
+    } else {
+        let mut size = [0];
+        stream.read_exact(&mut size)?;
+        usize::from(size[0])
+    };
+    let mut body = vec![0; size];
+    stream.read_exact(&mut body)?;
+    Ok((flags[0], body))
+}
+
+// The frames of the next message, skipping commands
+fn read_message(stream: &mut impl Read) -> io::Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & MORE == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+// Writes a message in one piece, so messages from different threads do not
+// interleave
+fn write_message(stream: &mut impl Write, frames: &[Vec<u8>]) -> io::Result<()> {
+    let mut message = Vec::new();
+    for (i, body) in frames.iter().enumerate() {
+        message.extend(frame(body, if i + 1 < frames.len() { MORE } else { 0 }));
+    }
+    stream.write_all(&message)
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).chain(message.iter().copied()).collect();
+    let outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).chain(sha256(&inner)).collect();
+    sha256(&outer)
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in padded.chunks(64) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let (early, late) = (schedule[i - 15], schedule[i - 2]);
+            let s0 = early.rotate_right(7) ^ early.rotate_right(18) ^ (early >> 3);
+            let s1 = late.rotate_right(17) ^ late.rotate_right(19) ^ (late >> 10);
+            schedule[i] = schedule[i - 16].wrapping_add(s0).wrapping_add(schedule[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*constant).wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    let mut digest = [0; 32];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel() {
+        // RFC 4231, test case 2
+        let signer = Signer { key: b"Jefe".to_vec(), session: "s".to_string() };
+        assert_eq!(signer.sign(&["what do ya ", "want for nothing?"]), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        // A signed request survives the wire, and a tampered one is dropped
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            handshake(&mut stream, "DEALER").unwrap();
+            let mut request = signer.frames(&[], "kernel_info_request", "{}", &format!("{{\"code\":\"{}\"}}", "x".repeat(300)));
+            write_message(&mut stream, &request).unwrap();
+            request[2] = b"{\"msg_type\":\"shutdown_request\"}".to_vec();
+            write_message(&mut stream, &request).unwrap();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, "ROUTER").unwrap();
+        let signer = Signer { key: b"Jefe".to_vec(), session: "t".to_string() };
+        let request = signer.open(read_message(&mut stream).unwrap()).unwrap();
+        assert_eq!((request.msg_type.as_str(), text(member(&json::parse(&request.content).unwrap(), "code")).map(str::len)), ("kernel_info_request", Some(300)));
+        assert!(signer.open(read_message(&mut stream).unwrap()).is_none());
+        client.join().unwrap();
+
+        let rows = Object::List(vec![Object::record(vec![("name".to_string(), Object::from("<a>")), ("n".to_string(), Object::Integer(1))])]);
+        assert_eq!(
+            html(&rows).unwrap(),
+            "<table><thead><tr><th>name</th><th>n</th></tr></thead><tbody><tr><td>&lt;a&gt;</td><td style=\"text-align:right\">1</td></tr></tbody></table>"
+        );
+    }
+}
@@ -11,6 +11,11 @@ pub enum Token {
     With,
     Of,
     Raise,
+    Quote,
+    Assert,
+    Requires,
+    Ensures,
+    In,
     Use,
     Std,
 
@@ -34,6 +39,7 @@ pub enum Token {
     CharType,
     BoolType,
     UnitType,
+    DynamicType,
     
     // Literals
     Identifier(String),
@@ -41,11 +47,16 @@ pub enum Token {
     IntegerLiteral(String),
     FloatLiteral(String),
     Comment(String),
+    // $name, in the template of a macro
+    Splice(String),
     Boolean(bool), 
+    // (), the unit value
+    Unit,
 
     // Parsing
     End,
-    Illegal,
+    // Input that is not a token; the parser reports it and skips it
+    Illegal(Span, LexError),
 
     // Operators
     Plus,         // +
@@ -63,9 +74,14 @@ pub enum Token {
     LessThan,     // <
     GTOrEqual,    // >=
     LTOrEqual,    // <=
+    ShiftLeft,    // <<
+    ShiftRight,   // >>
+    // A user defined operator, e.g. <+>, see `Lexer::add_operator`
+    Operator(String),
     Vbar,         // |
     Pipe,         // |>
     Arrow,        // ->
+    FatArrow,     // =>
     LeftArrow,    // <-
     Modulo,       // %
     Ampersand,    // &
@@ -73,6 +89,8 @@ pub enum Token {
     Polymorph,    // 'a
     Cons,         // ::
     Tilde,        // ~
+    Backslash,    // \, starting a lambda
+    At,           // @
 
     // Delimiters
     LeftBrace,    // {
@@ -84,6 +102,8 @@ pub enum Token {
     Colon,        // :
     SemiColon,    // ;
     Period,       // .
+    OptionalPeriod, // ?.
+    Coalesce,     // ??
     Over,         // ..
 
     // Built in functions
@@ -93,16 +113,82 @@ pub enum Token {
     Any, // any : (a -> bool) -> [a] -> bool
     All, // all : (a -> bool) -> [a] -> bool
     Println, // println : [a] -> ()
+    ConcatAll, // concat_all : [string] -> string
+    Dbg, // dbg : a -> a, printing a with its source
 
 
 }
 
+/// Why some input is not a token.
+#[derive(PartialEq, Debug, Clone)]
+pub enum LexError {
+    UnknownCharacter(char),
+    UnterminatedString,
+    // e.g. `12ab`, with the whole of it
+    MalformedNumber(String),
+}
+
+impl LexError {
+    pub fn code(&self) -> Code {
+        match self {
+            LexError::MalformedNumber(_) => Code::InvalidNumber,
+            _ => Code::InvalidToken,
+        }
+    }
+
+    /// A token to parse in place of the illegal input, if one makes sense;
+    /// for a malformed number that of its leading digits.
+    pub fn recovery(&self) -> Option<Token> {
+        let LexError::MalformedNumber(text) = self else {
+            return None;
+        };
+        let end = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+        let number = text[..end].to_string();
+        Some(if number.contains('.') { Token::FloatLiteral(number) } else { Token::IntegerLiteral(number) })
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnknownCharacter(c) => write!(f, "unknown character {:?}", c),
+            LexError::UnterminatedString => write!(f, "unterminated string, expected a closing '\"'"),
+            LexError::MalformedNumber(text) => write!(f, "malformed number '{}'", text),
+        }
+    }
+}
+
+// Location of a token in the source. Offsets count chars; line and column
+// start at 1.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub file: FileId,
+}
+
+impl Span {
+    /// From the start of this span to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end.max(self.end), ..self }
+    }
+}
+
 #[derive(Debug)]
 pub struct Lexer {
     input: Vec<char>,
     cur: usize,
     next_cur: usize,
     ch: char,
+    line: usize,
+    column: usize,
+    // Span of the token most recently returned by `advance`
+    pub span: Span,
+    file: FileId,
+    // Symbols of user defined operators, lexed as `Token::Operator`
+    operators: Vec<Vec<char>>,
 }
 
 fn is_whitespace(c: char) -> bool {
@@ -110,11 +196,11 @@ fn is_whitespace(c: char) -> bool {
 }
 
 fn is_numeric(c: char) -> bool {
-    ('0'..='9').contains(&c)
+    c.is_ascii_digit()
 }
 
 fn is_alphanumeric(c: char) -> bool {
-    ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) || is_numeric(c) || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || is_numeric(c) || c == '_'
 }
 
 impl Lexer {
@@ -124,12 +210,39 @@ impl Lexer {
             cur: 0,
             next_cur: 0,
             ch: '\0',
+            line: 1,
+            column: 0,
+            span: Span::default(),
+            file: FileId::default(),
+            operators: Vec::new(),
         };
         lexer.read();
         lexer
     }
 
+    /// Stamps `file` on the spans of the tokens lexed from here on.
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// Lexes `symbol` as a `Token::Operator` from here on, in preference to
+    /// any shorter operator it starts with.
+    pub fn add_operator(&mut self, symbol: &str) {
+        let symbol: Vec<char> = symbol.chars().collect();
+        if !self.operators.contains(&symbol) {
+            self.operators.push(symbol);
+            self.operators.sort_by_key(|operator| std::cmp::Reverse(operator.len()));
+        }
+    }
+
     pub fn read(&mut self) {
+        if self.ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         if self.next_cur >= self.input.len() {
             self.ch = '\0';
         } else {
@@ -139,8 +252,12 @@ impl Lexer {
         self.next_cur += 1;
     }
 
-    pub fn peek(&mut self) -> char {
-        self.input[self.next_cur]
+    pub fn peek(&self) -> char {
+        if self.next_cur >= self.input.len() {
+            '\0'
+        } else {
+            self.input[self.next_cur]
+        }
     }
 
     pub fn read_string(&mut self) -> Token {
@@ -175,8 +292,7 @@ impl Lexer {
             self.read();
             Token::StringLiteral(result)
         } else {
-            // Unterminated string literal, could also log an error or return a special token.
-            Token::Illegal
+            Token::Illegal(Span::default(), LexError::UnterminatedString)
         }
     }
 
@@ -206,13 +322,27 @@ impl Lexer {
             while is_numeric(self.ch) {
                 self.read();
             }
+            if is_alphanumeric(self.ch) {
+                return self.malformed_number(current);
+            }
             let float_literal = self.input[current..self.cur].iter().collect::<String>();
             return Token::FloatLiteral(float_literal);
         }
+        if is_alphanumeric(self.ch) {
+            return self.malformed_number(current);
+        }
 
         Token::IntegerLiteral(self.input[current..self.cur].iter().collect::<String>())
     }
 
+    // Digits run into letters, e.g. `12ab` or `1.5e3`
+    fn malformed_number(&mut self, start: usize) -> Token {
+        while is_alphanumeric(self.ch) || (self.ch == '.' && is_alphanumeric(self.peek())) {
+            self.read();
+        }
+        Token::Illegal(Span::default(), LexError::MalformedNumber(self.input[start..self.cur].iter().collect()))
+    }
+
     pub fn read_identifier(&mut self) -> Token {
         let current = self.cur;
         loop {
@@ -223,7 +353,7 @@ impl Lexer {
             }
         }
         let literal = self.input[current..self.cur].iter().collect::<String>();
-        return match literal.as_str() {
+        match literal.as_str() {
             "fn" => Token::Fn,
             "let" => Token::Let,
             "return" => Token::Return,
@@ -236,21 +366,15 @@ impl Lexer {
             "with" => Token::With,
             "of" => Token::Of,
             "raise" => Token::Raise,
+            "quote" => Token::Quote,
+            "assert" => Token::Assert,
+            "requires" => Token::Requires,
+            "ensures" => Token::Ensures,
+            "in" => Token::In,
             "true" => Token::Boolean(true),
             "false" => Token::Boolean(false),
-            // Lowercase primitive types
-            "int" => Token::IntType,
-            "float" => Token::FloatType,
-            "string" => Token::StringType,
-            "char" => Token::CharType,
-            "bool" => Token::BoolType,
-            "unit" => Token::UnitType,
-            // Lowercase type constructors
-            "list" => Token::List,
-            "option" => Token::Option,
-            "result" => Token::Result,
-            "hashmap" => Token::HashMap,
-            "tuple" => Token::Tuple,
+            // Builtin type names such as `int` and `list` are soft keywords,
+            // read as identifiers; see `parser::type_keyword`
             // Uppercase type constructors
             //"List" => Token::List,
             // "Option" => Token::Option,
@@ -268,9 +392,25 @@ impl Lexer {
             "any" => Token::Any,
             "all" => Token::All,
             "println" => Token::Println,
+            "dbg" => Token::Dbg,
+            "concat_all" => Token::ConcatAll,
             // TODO: Add the uppercase type constructors only for type module files
             _ => Token::Identifier(literal),
-        };
+        }
+    }
+
+    /// Source text between two char offsets, e.g. those of spans.
+    pub fn source(&self, start: usize, end: usize) -> String {
+        let end = end.min(self.input.len());
+        self.input[start.min(end)..end].iter().collect()
+    }
+
+    /// The token `advance` would return next, without consuming it.
+    pub fn lookahead(&mut self) -> Token {
+        let (cur, next_cur, ch, line, column, span) = (self.cur, self.next_cur, self.ch, self.line, self.column, self.span);
+        let token = self.advance();
+        (self.cur, self.next_cur, self.ch, self.line, self.column, self.span) = (cur, next_cur, ch, line, column, span);
+        token
     }
 
     pub fn advance(&mut self) -> Token {
@@ -281,11 +421,37 @@ impl Lexer {
                 break;
             }
         }
+        let (start, line, column) = (self.cur, self.line, self.column);
+        let mut token = self.next_token();
+        self.span = Span { start, end: self.cur.min(self.input.len()), line, column, file: self.file };
+        if let Token::Illegal(span, _) = &mut token {
+            *span = self.span;
+        }
+        token
+    }
+
+    fn next_token(&mut self) -> Token {
+        let operator = self.operators.iter().find(|symbol| self.input[self.cur.min(self.input.len())..].starts_with(symbol));
+        if let Some(symbol) = operator.cloned() {
+            symbol.iter().for_each(|_| self.read());
+            return Token::Operator(symbol.into_iter().collect());
+        }
+        if self.ch == '$' && self.peek().is_ascii_alphabetic() {
+            self.read();
+            let start = self.cur;
+            while is_alphanumeric(self.ch) {
+                self.read();
+            }
+            return Token::Splice(self.input[start..self.cur].iter().collect());
+        }
         let token: Token = match self.ch {
             '=' => {
                 if self.peek() == '=' {
                     self.read();
                     Token::Equal
+                } else if self.peek() == '>' {
+                    self.read();
+                    Token::FatArrow
                 } else {
                     Token::Assign
                 }
@@ -294,13 +460,15 @@ impl Lexer {
             '(' => {
                 if self.peek() == ')' {
                     self.read();
-                    Token::UnitType
+                    Token::Unit
                 } else {
                     Token::LeftParen
                 }
             }
             ')' => Token::RightParen,
             '~' => Token::Tilde,
+            '\\' => Token::Backslash,
+            '@' => Token::At,
             ',' => Token::Comma,
             '+' => {
                 if self.peek() == '+' {
@@ -362,6 +530,9 @@ impl Lexer {
                 } else if self.peek() == '-' {
                     self.read();
                     Token::LeftArrow
+                } else if self.peek() == '<' {
+                    self.read();
+                    Token::ShiftLeft
                 } else {
                     Token::LessThan
                 }
@@ -377,17 +548,28 @@ impl Lexer {
                 if self.peek() == '=' {
                     self.read();
                     Token::GTOrEqual
+                } else if self.peek() == '>' {
+                    self.read();
+                    Token::ShiftRight
                 } else {
                     Token::GreaterThan
                 }
             }
+            '?' if self.peek() == '.' => {
+                self.read();
+                Token::OptionalPeriod
+            }
+            '?' if self.peek() == '?' => {
+                self.read();
+                Token::Coalesce
+            }
             '{' => Token::LeftBrace,
             '}' => Token::RightBrace,
             '"' => return self.read_string(),
             '0'..='9' => return self.read_number(),
             'a'..='z' | 'A'..='Z' => return self.read_identifier(),
             '\0' => Token::End,
-            _ => Token::Illegal,
+            c => Token::Illegal(Span::default(), LexError::UnknownCharacter(c)),
         };
 
         self.read();
@@ -395,6 +577,8 @@ impl Lexer {
     }
 }
 
+use crate::diagnostics::Code;
+use crate::source::FileId;
 use std::fmt;
 
 impl fmt::Display for Token {
@@ -406,8 +590,45 @@ impl fmt::Display for Token {
             Token::FloatLiteral(fl) => write!(f, "{}", fl),
             Token::Comment(c) => write!(f, "--{}", c),
             Token::Boolean(b) => write!(f, "{}", b),
+            Token::Operator(symbol) => write!(f, "{}", symbol),
+            Token::Splice(name) => write!(f, "${}", name),
             // For other tokens, display their debug representation
             _ => write!(f, "{:?}", self),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn illegal(input: &str) -> Vec<(usize, usize, LexError)> {
+        let mut lexer = Lexer::new(input);
+        let mut errors = Vec::new();
+        loop {
+            match lexer.advance() {
+                Token::End => return errors,
+                Token::Illegal(span, error) => errors.push((span.start, span.end, error)),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_characters() {
+        assert_eq!(illegal("let x = 1 $ 2;"), vec![(10, 11, LexError::UnknownCharacter('$'))]);
+        assert_eq!(illegal("é # ?"), vec![(0, 1, LexError::UnknownCharacter('é')), (2, 3, LexError::UnknownCharacter('#')), (4, 5, LexError::UnknownCharacter('?'))]);
+    }
+
+    #[test]
+    fn test_unterminated_strings() {
+        assert_eq!(illegal("let s = \"abc;\nlet t = 1;"), vec![(8, 24, LexError::UnterminatedString)]);
+        assert_eq!(illegal("\"a\\\""), vec![(0, 4, LexError::UnterminatedString)]);
+    }
+
+    #[test]
+    fn test_malformed_numbers() {
+        assert_eq!(illegal("12ab + 1.5e3"), vec![(0, 4, LexError::MalformedNumber("12ab".to_string())), (7, 12, LexError::MalformedNumber("1.5e3".to_string()))]);
+        assert_eq!(illegal("[1..5] 3.14 x1"), vec![]);
+    }
+}
@@ -0,0 +1,73 @@
+// lib.rs
+//
+// The interpreter as a library, for hosts that embed opl. The `opl` binary is
+// a thin wrapper around `opl::opl::run`, and `opl-kernel` around
+// `opl::kernel::run`.
+
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod macros;
+pub mod closure;
+pub mod json;
+pub mod repl;
+pub mod object;
+pub mod map;
+pub mod set;
+pub mod record;
+pub mod deque;
+pub mod list;
+pub mod format;
+pub mod table;
+pub mod stdin;
+pub mod handle;
+pub mod file;
+pub mod actor;
+pub mod timer;
+pub mod task;
+pub mod evaluator;
+pub mod environment;
+pub mod opl;
+pub mod builtin;
+pub mod diagnostics;
+pub mod style;
+pub mod lint;
+pub mod completion;
+pub mod typechecker;
+pub mod derive;
+pub mod heap;
+pub mod string;
+pub mod optimizer;
+pub mod rewrite;
+pub mod interop;
+pub mod session;
+pub mod binary;
+pub mod template;
+pub mod codec;
+pub mod effects;
+pub mod doctest;
+pub mod coverage;
+pub mod profile;
+pub mod trace;
+pub mod source;
+pub mod analysis;
+pub mod graph;
+pub mod diff;
+pub mod quote;
+#[cfg(feature = "csv")]
+pub mod csv;
+#[cfg(feature = "toml")]
+pub mod toml;
+#[cfg(feature = "yaml")]
+pub mod yaml;
+#[cfg(feature = "net")]
+pub mod tcp;
+#[cfg(feature = "jupyter")]
+pub mod kernel;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
+
+pub use interop::{Engine, EngineHandle, FromOpl, IntoOpl, RecordBuilder};
+pub use session::{CellResult, Session};
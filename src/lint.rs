@@ -0,0 +1,253 @@
+// lint.rs
+//
+// Static checks that do not stop a program from running. Findings are
+// reported through the DiagnosticEmitter so their level can be configured.
+
+use crate::analysis::{self, SymbolKind, TextEdit};
+use crate::ast::*;
+use crate::diagnostics::{Code, Diagnostic, DiagnosticEmitter};
+use crate::builtin::{self, BUILTINS};
+use crate::lexer::{Span, Token};
+use crate::rewrite;
+use crate::typechecker::TypeChecker;
+
+#[derive(Default)]
+struct Usage {
+    // Local bindings, i.e. those inside a function body or block
+    bindings: Vec<String>,
+    uses: Vec<String>,
+    // Every name bound, by a let, with its span, or as a parameter
+    bound: Vec<(String, Option<Span>)>,
+    depth: usize,
+}
+
+impl Usage {
+    fn program(&mut self, program: &[Statement]) {
+        for statement in program {
+            self.statement(statement);
+        }
+    }
+
+    fn nested(&mut self, program: &[Statement]) {
+        self.depth += 1;
+        self.program(program);
+        self.depth -= 1;
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(Token::Identifier(name), expression, span) => {
+                self.bound.push((name.clone(), Some(*span)));
+                if self.depth > 0 && !self.bindings.contains(name) {
+                    self.bindings.push(name.clone());
+                }
+                self.expression(expression);
+            }
+            Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => {
+                self.expression(expression)
+            }
+            Statement::Assert(assertion) => self.expression(&assertion.condition),
+            Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Identifier(identifier) => self.uses.push(identifier.to_string()),
+            Expression::OptionSome(inner) | Expression::ResultOk(inner) | Expression::ResultErr(inner) => {
+                self.expression(inner)
+            }
+            Expression::OptionNone => {}
+            Expression::Literal(literal) => self.literal(literal),
+            Expression::Prefix(_, right) => self.expression(right),
+            Expression::Infix(_, left, right, _) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::Block(program) => self.nested(program),
+            Expression::Function { parameters, body, .. } => {
+                self.bound.extend(parameters.iter().map(|parameter| (parameter.to_string(), None)));
+                self.nested(body)
+            }
+            // Names in quoted code are read where it is evaluated, here or below
+            Expression::Quote(quoted, _) => self.expression(quoted),
+            Expression::If { condition, consequence, alternative } => {
+                self.expression(condition);
+                self.nested(consequence);
+                if let Some(alternative) = alternative {
+                    self.nested(alternative);
+                }
+            }
+            Expression::Call { function, arguments, .. } => {
+                self.expression(function);
+                arguments.iter().for_each(|argument| self.expression(argument));
+            }
+            Expression::BuiltIn { arguments, .. } => arguments.iter().for_each(|argument| self.expression(argument)),
+            Expression::Match { expr, arms } => {
+                self.expression(expr);
+                arms.iter().for_each(|(_, body)| self.nested(body));
+            }
+            Expression::Field { record, .. } => self.expression(record),
+            Expression::Annotated { expression, .. } => self.expression(expression),
+            Expression::Range { start, end } => {
+                self.expression(start);
+                self.expression(end);
+            }
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal) {
+        match literal {
+            Literal::List(elements) => elements.iter().for_each(|element| self.expression(element)),
+            Literal::Record(fields) => fields.iter().for_each(|(_, value)| self.expression(value)),
+            Literal::HashMap(entries) => entries.iter().for_each(|(key, value)| {
+                self.expression(key);
+                self.expression(value);
+            }),
+            _ => {}
+        }
+    }
+}
+
+// Local bindings that are never referenced anywhere in the program. Top level
+// bindings are a script's results, so they are not reported.
+fn unused_variables(program: &Program, emitter: &mut DiagnosticEmitter) {
+    let mut usage = Usage::default();
+    usage.program(program);
+    for name in &usage.bindings {
+        if !name.starts_with('_') && !usage.uses.contains(name) {
+            emitter.emit(Diagnostic::new(Code::UnusedVariable, format!("unused variable '{}'", name)));
+        }
+    }
+}
+
+// Soft keywords, read as names but where they begin something: builtin type
+// names in type position, and the words starting a definition
+const TYPE_NAMES: [&str; 12] = ["int", "float", "string", "char", "bool", "unit", "dynamic", "list", "option", "result", "hashmap", "tuple"];
+const DEFINITIONS: [&str; 6] = ["macro", "rule", "const", "infix", "infixl", "infixr"];
+
+// Bindings named after a builtin, which they hide from the rest of their
+// scope, or after a soft keyword. Builtins are looked up only when no
+// binding has the name, so either is allowed, but the errors it leads to
+// are about the binding and far from it.
+fn shadowing(program: &Program, emitter: &mut DiagnosticEmitter) {
+    let mut usage = Usage::default();
+    usage.program(program);
+    let mut checker = TypeChecker::new();
+    for (name, span) in usage.bound {
+        let diagnostic = if BUILTINS.contains(&name.as_str()) || builtin::lookup(&name).is_some() {
+            let note = match checker.named_builtin_type(&name) {
+                Some(ty) => format!("the builtin is {}: {}", name, ty),
+                None => format!("the builtin is {}", name),
+            };
+            Diagnostic::new(Code::Shadowing, format!("'{}' shadows the builtin of the same name", name)).with_note(note)
+        } else if TYPE_NAMES.contains(&name.as_str()) {
+            Diagnostic::new(Code::Shadowing, format!("'{}' is the name of a builtin type", name))
+                .with_note(format!("in a type, {} always means the builtin type", name))
+        } else if DEFINITIONS.contains(&name.as_str()) {
+            Diagnostic::new(Code::Shadowing, format!("'{}' is a keyword where it starts a definition", name))
+                .with_note(format!("`{} ...` at the start of a statement is read as a definition", name))
+        } else {
+            continue;
+        };
+        // A parameter is located at the first time its name appears
+        let span = span.or_else(|| program.identifiers.iter().find(|(identifier, _)| *identifier == name).map(|(_, span)| *span));
+        emitter.emit(match span {
+            Some(span) => diagnostic.with_span(span),
+            None => diagnostic,
+        });
+    }
+}
+
+// Type declarations nothing refers to: no annotation names the type, no
+// variant of it is used, no field of it is read or written, and it binds no
+// enum helpers that are. Records are typed by their fields, so a field of the
+// same name in another type keeps it too. Imports will be checked the same
+// way once there are modules.
+fn unused_types(program: &Program, emitter: &mut DiagnosticEmitter) {
+    let Some(table) = analysis::table(program) else {
+        return;
+    };
+    let used = |kind: SymbolKind, name: &str, within: Span| {
+        table.symbols.iter().any(|symbol| {
+            symbol.kind == kind && symbol.name == name && symbol.references.iter().any(|span| span.start < within.start || span.end > within.end)
+        })
+    };
+    for (statement, span) in program.spanned() {
+        let Statement::Type(name, declaration, _) = statement else {
+            continue;
+        };
+        let name = name.to_string();
+        let parts: Vec<(SymbolKind, String)> = match declaration {
+            Type::Union(variants) => variants.iter().map(|(variant, _)| (SymbolKind::Variant, variant.to_string())).collect(),
+            Type::Record(fields) => fields.iter().map(|(field, _)| (SymbolKind::Field, field.to_string())).collect(),
+            Type::Alias(_) => Vec::new(),
+        };
+        if used(SymbolKind::Type, &name, span)
+            || parts.iter().any(|(kind, part)| used(*kind, part, span))
+            || program.identifiers.iter().any(|(identifier, _)| *identifier == name)
+        {
+            continue;
+        }
+        emitter.emit(
+            Diagnostic::new(Code::UnusedType, format!("type '{}' is never used", name))
+                .with_span(span)
+                .with_suggestion("remove the declaration".to_string())
+                .with_fix(TextEdit { span, text: String::new() }),
+        );
+    }
+}
+
+// Rewrite rules that keep applying, see `rewrite`
+fn rewrite_limits(program: &Program, emitter: &mut DiagnosticEmitter) {
+    if !program.rules.is_empty() {
+        rewrite::rewrite(&mut program.clone()).into_iter().for_each(|diagnostic| emitter.emit(diagnostic));
+    }
+}
+
+pub fn lint(program: &Program, emitter: &mut DiagnosticEmitter) {
+    unused_variables(program, emitter);
+    shadowing(program, emitter);
+    unused_types(program, emitter);
+    rewrite_limits(program, emitter);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::apply_edits;
+    use crate::diagnostics::{Code, DiagnosticConfig, DiagnosticEmitter};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_shadowing() {
+        let program = Parser::new(Lexer::new("let hash = fn list -> 0;\nlet count = hash([1]);\nlet macro = count;")).parse_program();
+        let mut emitter = DiagnosticEmitter::new(DiagnosticConfig::default());
+        super::lint(&program, &mut emitter);
+        let shadowing: Vec<_> = emitter.diagnostics.iter().filter(|diagnostic| diagnostic.code == Code::Shadowing).collect();
+        let found: Vec<_> = shadowing.iter().map(|diagnostic| (diagnostic.message.as_str(), diagnostic.span.map(|span| span.line))).collect();
+        assert_eq!(found, [
+            ("'hash' shadows the builtin of the same name", Some(1)),
+            ("'list' is the name of a builtin type", Some(1)),
+            ("'macro' is a keyword where it starts a definition", Some(3)),
+        ]);
+        assert_eq!(shadowing[0].notes, ["the builtin is hash: fn 'a -> int"]);
+    }
+
+    #[test]
+    fn test_unused_types() {
+        let source = "type shape = | circle of float | dot;\ntype tree = | leaf | node of tree;\ntype point = { x: int, y: int };\ntype id = int;\n\
+                      @derive(eq)\ntype plain = | A | B;\nlet n: id = 1;\nlet p = { x = n, y = 2 };\ncircle(1.5)";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        let mut emitter = DiagnosticEmitter::new(DiagnosticConfig::default());
+        super::lint(&program, &mut emitter);
+        let unused: Vec<_> = emitter.diagnostics.iter().filter(|diagnostic| diagnostic.code == Code::UnusedType).collect();
+        assert_eq!(unused.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["type 'tree' is never used", "type 'plain' is never used"]);
+        // The fixes remove the declarations, attributes and all
+        let fixes: Vec<_> = unused.iter().flat_map(|diagnostic| diagnostic.fixes.clone()).collect();
+        assert_eq!(
+            apply_edits(source, &fixes),
+            "type shape = | circle of float | dot;\n\ntype point = { x: int, y: int };\ntype id = int;\n\nlet n: id = 1;\nlet p = { x = n, y = 2 };\ncircle(1.5)"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+// list.rs
+//
+// Builtins over lists, which take the list first and then a function or
+// a size, e.g. `sort_by(people, fn a b -> a.age - b.age)`,
+// `group_by(orders, fn order -> order.customer)` or `chunks(rows, 100)`.
+
+use crate::derive::Registry;
+use crate::diagnostics::Code;
+use crate::environment::Env;
+use crate::evaluator::Evaluator;
+use crate::lexer::Token;
+use crate::map::Map;
+use crate::object::Object;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+/// Calls a function value from a builtin, with an evaluator of its own.
+pub fn call(function: &Object, arguments: Vec<Object>) -> Object {
+    match function {
+        Object::Function(parameters, body, env) => {
+            if parameters.len() != arguments.len() {
+                return Object::Error(Code::ArityMismatch, format!(
+                    "function takes {} arguments but was given {}",
+                    parameters.len(),
+                    arguments.len()
+                ));
+            }
+            let mut inner = Env::new_with_outer(Rc::clone(env));
+            for (parameter, argument) in parameters.iter().zip(arguments) {
+                if let Token::Identifier(name) = parameter {
+                    inner.set(name.clone(), argument);
+                }
+            }
+            match Evaluator::new(Rc::new(RefCell::new(inner))).eval_block(body) {
+                Some(Object::Return(ref mut value)) => std::mem::take(&mut **value),
+                Some(value) => value,
+                None => Object::Error(Code::MissingValue, "Function returned no value".to_string()),
+            }
+        }
+        Object::Builtin(function) => function(arguments),
+        Object::Native(native) => (native.function)(arguments),
+        other => Object::Error(Code::BuiltinArgument, format!("Expected a function, got {}", other.type_name())),
+    }
+}
+
+// Sorts stably by `compare`, stopping at its first error
+fn sort<T>(mut items: Vec<T>, mut compare: impl FnMut(&T, &T) -> Result<Ordering, Object>) -> Result<Vec<T>, Object> {
+    let mut error = None;
+    items.sort_by(|a, b| {
+        if error.is_some() {
+            return Ordering::Equal;
+        }
+        compare(a, b).unwrap_or_else(|e| {
+            error = Some(e);
+            Ordering::Equal
+        })
+    });
+    error.map_or(Ok(items), Err)
+}
+
+/// `sort_by(list, fn a b -> ...)`, where the comparator returns a negative
+/// int when `a` goes first, 0 when either order will do, and a positive int
+/// when `b` goes first. Equal elements keep their order.
+pub fn sort_by_builtin(mut args: Vec<Object>) -> Object {
+    let [Object::List(elements), comparator] = args.as_mut_slice() else {
+        return Object::Error(Code::BuiltinArgument, "sort_by expects a list and a comparator function".to_string());
+    };
+    let (elements, comparator) = (std::mem::take(elements), std::mem::take(comparator));
+    let sorted = sort(elements, |a, b| match call(&comparator, vec![a.clone(), b.clone()]) {
+        Object::Integer(order) => Ok(order.cmp(&0)),
+        error @ Object::Error(..) => Err(error),
+        other => Err(Object::Error(Code::BuiltinArgument, format!(
+            "sort_by comparator must return an int, got {} comparing {} and {}",
+            other.type_name(),
+            a,
+            b
+        ))),
+    });
+    sorted.map_or_else(|error| error, Object::List)
+}
+
+/// `sort_by_key(list, fn x -> ...)`, ordering elements by the key each one
+/// gives, stably. Keys are compared structurally, as ints, strings, lists
+/// and records of them.
+pub fn sort_by_key_builtin(mut args: Vec<Object>) -> Object {
+    let [Object::List(elements), key] = args.as_mut_slice() else {
+        return Object::Error(Code::BuiltinArgument, "sort_by_key expects a list and a key function".to_string());
+    };
+    let (elements, key) = (std::mem::take(elements), std::mem::take(key));
+    let mut keyed = Vec::with_capacity(elements.len());
+    for element in elements {
+        match call(&key, vec![element.clone()]) {
+            error @ Object::Error(..) => return error,
+            key => keyed.push((key, element)),
+        }
+    }
+    let registry = Registry::default();
+    let sorted = sort(keyed, |(a, _), (b, _)| {
+        registry.compare(a, b).ok_or_else(|| {
+            Object::Error(Code::BuiltinArgument, format!("sort_by_key cannot order the keys {} and {}", a, b))
+        })
+    });
+    sorted.map_or_else(|error| error, |keyed| Object::List(keyed.into_iter().map(|(_, element)| element).collect()))
+}
+
+// The list and function arguments of the builtin `name`
+fn list_and_function(name: &str, mut args: Vec<Object>) -> Result<(Vec<Object>, Object), Object> {
+    match args.as_mut_slice() {
+        [Object::List(elements), function] => Ok((std::mem::take(elements), std::mem::take(function))),
+        _ => Err(Object::Error(Code::BuiltinArgument, format!("{} expects a list and a function", name))),
+    }
+}
+
+// Each element with the value `function` gives for it
+fn keyed(elements: Vec<Object>, function: &Object) -> Result<Vec<(Object, Object)>, Object> {
+    elements
+        .into_iter()
+        .map(|element| match call(function, vec![element.clone()]) {
+            error @ Object::Error(..) => Err(error),
+            key => Ok((key, element)),
+        })
+        .collect()
+}
+
+/// `group_by(list, fn x -> key)`, a map from each key to the elements with
+/// it, keys in the order they first appear.
+pub fn group_by_builtin(args: Vec<Object>) -> Object {
+    let grouped = list_and_function("group_by", args).and_then(|(elements, key)| {
+        let mut groups: Vec<(Object, Vec<Object>)> = Vec::new();
+        let mut positions = Map::new();
+        for (key, element) in keyed(elements, &key)? {
+            match positions.get(&key) {
+                Some(Object::Integer(i)) => groups[*i as usize].1.push(element),
+                _ => {
+                    positions.insert(key.clone(), Object::Integer(groups.len() as i64))?;
+                    groups.push((key, vec![element]));
+                }
+            }
+        }
+        Map::from_entries(groups.into_iter().map(|(key, group)| (key, Object::List(group))))
+    });
+    grouped.map_or_else(|error| error, Object::Map)
+}
+
+/// `count_by(list, fn x -> key)`, a map from each key to how many elements
+/// have it.
+pub fn count_by_builtin(args: Vec<Object>) -> Object {
+    let counted = list_and_function("count_by", args).and_then(|(elements, key)| {
+        let mut counts = Map::new();
+        for (key, _) in keyed(elements, &key)? {
+            let count = match counts.get(&key) {
+                Some(Object::Integer(count)) => count + 1,
+                _ => 1,
+            };
+            counts.insert(key, Object::Integer(count))?;
+        }
+        Ok(counts)
+    });
+    counted.map_or_else(|error| error, Object::Map)
+}
+
+/// `sum_by(list, fn x -> n)`, the sum of the ints or of the floats the
+/// function gives, 0 for an empty list.
+pub fn sum_by_builtin(args: Vec<Object>) -> Object {
+    let summed = list_and_function("sum_by", args).and_then(|(elements, value)| {
+        let mut sum = Object::Integer(0);
+        for (i, (value, _)) in keyed(elements, &value)?.into_iter().enumerate() {
+            sum = match (sum, value) {
+                (Object::Integer(sum), Object::Integer(value)) => match sum.checked_add(value) {
+                    Some(sum) => Object::Integer(sum),
+                    None => return Err(Object::Error(Code::Overflow, format!("sum_by: {} plus {} overflows an int", sum, value))),
+                },
+                (Object::Integer(0), Object::Float(value)) if i == 0 => Object::Float(value),
+                (Object::Float(sum), Object::Float(value)) => Object::Float(sum + value),
+                (sum, value) => {
+                    return Err(Object::Error(Code::BuiltinArgument, format!(
+                        "sum_by cannot add {} to a sum of {}",
+                        value.type_name(),
+                        sum.type_name()
+                    )))
+                }
+            };
+        }
+        Ok(sum)
+    });
+    summed.unwrap_or_else(|error| error)
+}
+
+// The element with the least key, or the greatest when `greatest`, the
+// first one of those tied
+fn extreme_by(name: &str, args: Vec<Object>, greatest: bool) -> Object {
+    let found = list_and_function(name, args).and_then(|(elements, key)| {
+        let registry = Registry::default();
+        let mut best: Option<(Object, Object)> = None;
+        for (key, element) in keyed(elements, &key)? {
+            let better = match &best {
+                None => true,
+                Some((best, _)) => match registry.compare(&key, best) {
+                    Some(order) => order == if greatest { Ordering::Greater } else { Ordering::Less },
+                    None => {
+                        return Err(Object::Error(Code::BuiltinArgument, format!("{} cannot order the keys {} and {}", name, best, key)))
+                    }
+                },
+            };
+            if better {
+                best = Some((key, element));
+            }
+        }
+        Ok(best.map_or(Object::OptionNone, |(_, element)| Object::OptionSome(Box::new(element))))
+    });
+    found.unwrap_or_else(|error| error)
+}
+
+/// `min_by(list, fn x -> key)`, the first element with the least key, None
+/// for an empty list.
+pub fn min_by_builtin(args: Vec<Object>) -> Object {
+    extreme_by("min_by", args, false)
+}
+
+/// `max_by(list, fn x -> key)`, the first element with the greatest key,
+/// None for an empty list.
+pub fn max_by_builtin(args: Vec<Object>) -> Object {
+    extreme_by("max_by", args, true)
+}
+
+/// `partition(list, fn x -> bool)`, `[matching, rest]`, each in the order
+/// of the list.
+pub fn partition_builtin(args: Vec<Object>) -> Object {
+    let parts = list_and_function("partition", args).and_then(|(elements, predicate)| {
+        let (mut matching, mut rest) = (Vec::new(), Vec::new());
+        for (verdict, element) in keyed(elements, &predicate)? {
+            match verdict {
+                Object::Boolean(true) => matching.push(element),
+                Object::Boolean(false) => rest.push(element),
+                other => {
+                    return Err(Object::Error(Code::BuiltinArgument, format!("partition function must return a bool, got {}", other.type_name())))
+                }
+            }
+        }
+        Ok(Object::List(vec![Object::List(matching), Object::List(rest)]))
+    });
+    parts.unwrap_or_else(|error| error)
+}
+
+// The list and size arguments of the builtin `name`, the size at least 1
+fn list_and_size(name: &str, mut args: Vec<Object>) -> Result<(Vec<Object>, usize), Object> {
+    match args.as_mut_slice() {
+        [Object::List(elements), Object::Integer(size)] if *size > 0 => Ok((std::mem::take(elements), *size as usize)),
+        [Object::List(_), Object::Integer(size)] => {
+            Err(Object::Error(Code::BuiltinArgument, format!("{} expects a size of at least 1, got {}", name, size)))
+        }
+        _ => Err(Object::Error(Code::BuiltinArgument, format!("{} expects a list and a size", name))),
+    }
+}
+
+/// `chunks(list, n)`, the list in runs of `n` elements, the last run
+/// shorter when `n` does not divide the length.
+pub fn chunks_builtin(args: Vec<Object>) -> Object {
+    match list_and_size("chunks", args) {
+        Ok((elements, size)) => Object::List(elements.chunks(size).map(|chunk| Object::List(chunk.to_vec())).collect()),
+        Err(error) => error,
+    }
+}
+
+/// `windows(list, n)`, every run of `n` consecutive elements, none when
+/// the list is shorter than `n`.
+pub fn windows_builtin(args: Vec<Object>) -> Object {
+    match list_and_size("windows", args) {
+        Ok((elements, size)) => Object::List(elements.windows(size).map(|window| Object::List(window.to_vec())).collect()),
+        Err(error) => error,
+    }
+}
+
+/// `intersperse(list, separator)`, the list with `separator` between each
+/// pair of neighbouring elements.
+pub fn intersperse_builtin(mut args: Vec<Object>) -> Object {
+    let [Object::List(elements), separator] = args.as_mut_slice() else {
+        return Object::Error(Code::BuiltinArgument, "intersperse expects a list and a separator".to_string());
+    };
+    let elements = std::mem::take(elements);
+    let mut interspersed = Vec::with_capacity((2 * elements.len()).saturating_sub(1));
+    for (i, element) in elements.into_iter().enumerate() {
+        if i > 0 {
+            interspersed.push(separator.clone());
+        }
+        interspersed.push(element);
+    }
+    Object::List(interspersed)
+}
+
+/// `transpose(rows)`, the columns of a list of lists of the same length.
+pub fn transpose_builtin(mut args: Vec<Object>) -> Object {
+    let [Object::List(rows)] = args.as_mut_slice() else {
+        return Object::Error(Code::BuiltinArgument, "transpose expects a list of lists".to_string());
+    };
+    let mut columns: Vec<Vec<Object>> = Vec::new();
+    for (i, mut row) in std::mem::take(rows).into_iter().enumerate() {
+        let Object::List(row) = &mut row else {
+            return Object::Error(Code::BuiltinArgument, format!("transpose expects a list of lists, got a {} in it", row.type_name()));
+        };
+        if i == 0 {
+            columns = (0..row.len()).map(|_| Vec::new()).collect();
+        } else if row.len() != columns.len() {
+            return Object::Error(Code::BuiltinArgument, format!(
+                "transpose expects rows of the same length, row {} has {} elements but row 0 has {}",
+                i,
+                row.len(),
+                columns.len()
+            ));
+        }
+        columns.iter_mut().zip(std::mem::take(row)).for_each(|(column, element)| column.push(element));
+    }
+    Object::List(columns.into_iter().map(Object::List).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::object::Object;
+
+    #[test]
+    fn test_sort_by() {
+        let mut eval = crate::evaluator::test_eval();
+        let strings = |strings: &[&str]| Some(Object::List(strings.iter().map(|&s| Object::from(s)).collect()));
+
+        eval("let people = [{ name = \"ada\", age = 36 }, { name = \"bob\", age = 25 }, { name = \"cy\", age = 36 }, { name = \"di\", age = 25 }];\nlet names = fn sorted -> map(fn p -> p.name, sorted);");
+        assert_eq!(eval("names(sort_by(people, fn a b -> a.age - b.age))"), strings(&["bob", "di", "ada", "cy"]));
+        assert_eq!(eval("names(sort_by(people, fn a b -> b.age - a.age))"), strings(&["ada", "cy", "bob", "di"]));
+        assert_eq!(eval("names(sort_by_key(people, fn p -> p.age))"), strings(&["bob", "di", "ada", "cy"]));
+
+        let error = eval("sort_by(people, fn a b -> a.age < b.age)");
+        assert!(matches!(&error, Some(Object::Error(Code::BuiltinArgument, message)) if message.starts_with("sort_by comparator must return an int, got bool")), "{:?}", error);
+    }
+
+    #[test]
+    fn test_grouping() {
+        let mut eval = crate::evaluator::test_eval();
+        let shown = |text: &str| Some(Object::from(text));
+
+        eval("let orders = [{ who = \"ada\", total = 5 }, { who = \"bob\", total = 3 }, { who = \"ada\", total = 7 }];\nlet who = fn order -> order.who;");
+        assert_eq!(eval("show(hashmap.keys(group_by(orders, who)))"), shown("[\"ada\", \"bob\"]"));
+        assert_eq!(eval("show(hashmap.get(group_by(orders, who), \"ada\"))"), shown("Some([{ who = \"ada\", total = 5 }, { who = \"ada\", total = 7 }])"));
+        assert_eq!(eval("show(count_by(orders, who))"), shown("{\"ada\": 2, \"bob\": 1}"));
+        assert_eq!(eval("[sum_by(orders, fn order -> order.total), sum_by([], who)]"), Some(Object::List(vec![Object::Integer(15), Object::Integer(0)])));
+        assert_eq!(eval("show([max_by(orders, fn order -> order.total), min_by(orders, who)])"), shown("[Some({ who = \"ada\", total = 7 }), Some({ who = \"ada\", total = 5 })]"));
+        assert_eq!(eval("show(partition([1, 2, 3, 4], fn n -> n > 2))"), shown("[[3, 4], [1, 2]]"));
+        assert!(matches!(eval("sum_by([1, 2], fn n -> show(n))"), Some(Object::Error(Code::BuiltinArgument, _))));
+    }
+
+    #[test]
+    fn test_chunks_and_windows() {
+        let mut eval = crate::evaluator::test_eval();
+        let shown = |text: &str| Some(Object::from(text));
+
+        assert_eq!(eval("show(chunks([1, 2, 3, 4, 5], 2))"), shown("[[1, 2], [3, 4], [5]]"));
+        assert_eq!(eval("show(windows([1, 2, 3, 4], 3))"), shown("[[1, 2, 3], [2, 3, 4]]"));
+        assert_eq!(eval("show([windows([1, 2], 3), chunks([], 2)])"), shown("[[], []]"));
+        assert_eq!(eval("show(intersperse([\"a\", \"b\", \"c\"], \",\"))"), shown("[\"a\", \",\", \"b\", \",\", \"c\"]"));
+        assert_eq!(eval("show([intersperse([1], 0), intersperse([], 0)])"), shown("[[1], []]"));
+        assert_eq!(eval("show(transpose([[1, 2, 3], [4, 5, 6]]))"), shown("[[1, 4], [2, 5], [3, 6]]"));
+
+        let mut error = |source| match eval(source) {
+            Some(Object::Error(Code::BuiltinArgument, ref message)) => message.clone(),
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(error("chunks([1, 2], 0)"), "chunks expects a size of at least 1, got 0");
+        assert!(error("transpose([[1, 2], [3]])").starts_with("transpose expects rows of the same length"));
+    }
+}
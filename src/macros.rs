@@ -0,0 +1,243 @@
+// macros.rs
+//
+// Expansion of macros, after parsing and before anything checks or runs the
+// program. A call of a macro is replaced by its template, with the call's
+// arguments spliced in for its `$name`s, then expanded again:
+//
+//   macro unless(c, then) -> if $c { () } else { $then };
+//   unless(ready, println("waiting"));
+//
+// A call in place of a statement is replaced by the template's statements,
+// so a macro can bind names in the scope of its call; elsewhere it becomes a
+// block, or the template's expression when it has only one. The spliced
+// arguments keep their spans, so errors in them point at the call, and
+// errors in the rest of the template point into the macro.
+
+use crate::ast::*;
+use crate::diagnostics::Code;
+use crate::lexer::{Span, Token};
+use crate::parser::ParseError;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Nested expansions before giving up, e.g. on a macro expanding to a call of
+// itself
+const MAX_DEPTH: usize = 64;
+
+// A direct subexpression or block of an expression
+pub(crate) enum Child<'a> {
+    Expression(&'a mut Expression),
+    Block(&'a mut Block),
+}
+
+pub(crate) fn children(expression: &mut Expression) -> Vec<Child<'_>> {
+    match expression {
+        Expression::Identifier(_) | Expression::OptionNone => Vec::new(),
+        Expression::OptionSome(inner)
+        | Expression::ResultOk(inner)
+        | Expression::ResultErr(inner)
+        | Expression::Prefix(_, inner)
+        | Expression::Field { record: inner, .. }
+        | Expression::Annotated { expression: inner, .. } => vec![Child::Expression(inner)],
+        Expression::Literal(Literal::List(elements)) => elements.iter_mut().map(Child::Expression).collect(),
+        Expression::Literal(Literal::Record(fields)) => fields.iter_mut().map(|(_, value)| Child::Expression(value)).collect(),
+        Expression::Literal(Literal::HashMap(entries)) => {
+            entries.iter_mut().flat_map(|(key, value)| [Child::Expression(key), Child::Expression(value)]).collect()
+        }
+        Expression::Literal(_) => Vec::new(),
+        Expression::Infix(_, left, right, _) | Expression::Range { start: left, end: right } => {
+            vec![Child::Expression(left), Child::Expression(right)]
+        }
+        Expression::Block(block) => vec![Child::Block(block)],
+        Expression::Function { body, .. } => vec![Child::Block(Rc::make_mut(body))],
+        Expression::Quote(quoted, _) => vec![Child::Expression(Rc::make_mut(quoted))],
+        Expression::If { condition, consequence, alternative } => {
+            let mut children = vec![Child::Expression(condition), Child::Block(consequence)];
+            children.extend(alternative.iter_mut().map(Child::Block));
+            children
+        }
+        Expression::Call { function, arguments, .. } => {
+            std::iter::once(Child::Expression(function)).chain(arguments.iter_mut().map(Child::Expression)).collect()
+        }
+        Expression::BuiltIn { arguments, .. } => arguments.iter_mut().map(Child::Expression).collect(),
+        Expression::Match { expr, arms } => {
+            std::iter::once(Child::Expression(expr)).chain(arms.iter_mut().map(|(_, body)| Child::Block(body))).collect()
+        }
+    }
+}
+
+pub(crate) fn statement_expression(statement: &mut Statement) -> Option<&mut Expression> {
+    match statement {
+        Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => Some(expression),
+        Statement::Assert(assertion) => Some(&mut assertion.condition),
+        Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => None,
+    }
+}
+
+// Replaces the splices in `block` with the arguments named by them
+fn substitute_block(block: &mut Block, arguments: &HashMap<&str, &Expression>) -> Result<(), String> {
+    for statement in block.iter_mut() {
+        if let Statement::Let(name, ..) = statement {
+            if let Token::Splice(splice) = name {
+                *name = match arguments.get(splice.as_str()) {
+                    Some(Expression::Identifier(identifier @ Token::Identifier(_))) => identifier.clone(),
+                    _ => return Err(format!("${} names a let, so its argument must be a name", splice)),
+                };
+            }
+        }
+        if let Some(expression) = statement_expression(statement) {
+            substitute(expression, arguments)?;
+        }
+    }
+    Ok(())
+}
+
+fn substitute(expression: &mut Expression, arguments: &HashMap<&str, &Expression>) -> Result<(), String> {
+    if let Expression::Identifier(Token::Splice(name)) = expression {
+        // Splices of unknown names were reported with the macro
+        if let Some(argument) = arguments.get(name.as_str()) {
+            *expression = (*argument).clone();
+        }
+        return Ok(());
+    }
+    for child in children(expression) {
+        match child {
+            Child::Expression(expression) => substitute(expression, arguments)?,
+            Child::Block(block) => substitute_block(block, arguments)?,
+        }
+    }
+    Ok(())
+}
+
+struct Expander<'a> {
+    macros: HashMap<&'a str, &'a Macro>,
+    errors: Vec<ParseError>,
+}
+
+impl Expander<'_> {
+    // The template of the macro called by `expression`, if it calls one,
+    // with the call's arguments spliced in
+    fn instantiate(&mut self, expression: &Expression, depth: usize) -> Option<Block> {
+        let Expression::Call { function, arguments, span } = expression else {
+            return None;
+        };
+        let Expression::Identifier(Token::Identifier(name)) = &**function else {
+            return None;
+        };
+        let definition = *self.macros.get(name.as_str())?;
+        let defined = format!("macro {} is defined at line {}, column {}", name, definition.span.line, definition.span.column);
+        let expansion = if depth >= MAX_DEPTH {
+            Err(format!("expanding {} nested more than {} deep, so it likely expands to a call of itself; {}", name, MAX_DEPTH, defined))
+        } else if arguments.len() != definition.parameters.len() {
+            Err(format!("{} takes {} arguments but was given {}; {}", name, definition.parameters.len(), arguments.len(), defined))
+        } else {
+            let arguments = definition.parameters.iter().map(String::as_str).zip(arguments).collect();
+            let mut template = definition.template.clone();
+            substitute_block(&mut template, &arguments).map(|_| template).map_err(|e| format!("in the expansion of {}: {}; {}", name, e, defined))
+        };
+        match expansion {
+            Ok(template) => Some(template),
+            Err(message) => {
+                self.errors.push(ParseError::Log(Code::MacroExpansion, message, *span));
+                // Left in place, the call would be reported again when run
+                Some(vec![Statement::Expression(Expression::Literal(Literal::Unit))])
+            }
+        }
+    }
+
+    // Expands the statements of a block, keeping `spans` in step with them
+    // when given
+    fn block(&mut self, block: &mut Block, mut spans: Option<&mut Vec<Span>>, depth: usize) {
+        let mut i = 0;
+        while i < block.len() {
+            let expansion = match &block[i] {
+                Statement::Expression(expression) => self.instantiate(expression, depth),
+                _ => None,
+            };
+            let Some(mut statements) = expansion else {
+                self.statement(&mut block[i], depth);
+                i += 1;
+                continue;
+            };
+            self.block(&mut statements, None, depth + 1);
+            let count = statements.len();
+            if let Some(spans) = spans.as_deref_mut() {
+                let span = spans[i];
+                spans.splice(i..=i, std::iter::repeat_n(span, count));
+            }
+            block.splice(i..=i, statements);
+            i += count;
+        }
+    }
+
+    fn statement(&mut self, statement: &mut Statement, depth: usize) {
+        if let Some(expression) = statement_expression(statement) {
+            self.expression(expression, depth);
+        }
+    }
+
+    fn expression(&mut self, expression: &mut Expression, depth: usize) {
+        if let Some(mut template) = self.instantiate(expression, depth) {
+            *expression = match template.as_slice() {
+                [Statement::Expression(_)] => match template.pop() {
+                    Some(Statement::Expression(expression)) => expression,
+                    _ => unreachable!(),
+                },
+                _ => Expression::Block(template),
+            };
+            return self.expression(expression, depth + 1);
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(expression) => self.expression(expression, depth),
+                Child::Block(block) => self.block(block, None, depth),
+            }
+        }
+    }
+}
+
+/// Expands every call of `program.macros` in the program, returning the
+/// calls that could not be expanded.
+pub fn expand(program: &mut Program) -> Vec<ParseError> {
+    let Program { statements, spans, macros, .. } = program;
+    let mut expander = Expander { macros: macros.iter().map(|m| (m.name.as_str(), m)).collect(), errors: Vec::new() };
+    expander.block(statements, Some(spans), 0);
+    let errors = expander.errors;
+    program.identifiers.clear();
+    program.names.clear();
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run(source: &str) -> Result<Option<Object>, Vec<String>> {
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        if !program.errors.is_empty() {
+            return Err(program.errors.iter().map(|error| error.to_string()).collect());
+        }
+        Ok(Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&program))
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "macro unless(c, then) -> if $c { 0 } else { $then };\nmacro define(name, value) -> { let $name = $value; };\nmacro twice(x) -> $x + $x;\ndefine(answer, twice(unless(false, 21)));\nanswer";
+        assert_eq!(run(source), Ok(Some(Object::Integer(42))));
+
+        let errors = run("macro twice(x) -> $x + $y;\ntwice(1)").unwrap_err();
+        assert!(errors[0].contains("$y is not a parameter of macro twice"), "{:?}", errors);
+        let errors = run("macro twice(x) -> $x * 2;\nlet four = twice(2, 3);").unwrap_err();
+        assert!(errors[0].contains("twice takes 1 arguments but was given 2; macro twice is defined at line 1, column 1"), "{:?}", errors);
+        let errors = run("macro forever(x) -> forever($x);\nforever(1)").unwrap_err();
+        assert!(errors[0].contains("nested more than 64 deep"), "{:?}", errors);
+        assert!(run("let x = $y;").unwrap_err()[0].contains(Code::MalformedMacro.as_str()));
+    }
+}
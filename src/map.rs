@@ -0,0 +1,211 @@
+// map.rs
+//
+// Maps from values to values, written `{ "a": 1, "b": 2 }`, and the
+// `hashmap` module of builtins over them. A map iterates in the order its
+// keys were first inserted, so `show`, `hashmap.keys` and the rest give the
+// same output on every run: inserting a key already there replaces its value
+// in place, and removing one keeps the order of the others. Keys are found
+// by their `stable_hash`, so they must be hashable values.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Object, Object)>,
+    // Positions in `entries` by the hash of their key
+    index: HashMap<u64, Vec<usize>>,
+}
+
+fn unhashable(key: &Object) -> Object {
+    Object::Error(Code::Unhashable, format!("Values of type {} cannot be map keys", key.type_name()))
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map::default()
+    }
+
+    /// A map of `entries`, a later entry for a key replacing an earlier one.
+    pub fn from_entries(entries: impl IntoIterator<Item = (Object, Object)>) -> Result<Map, Object> {
+        let mut map = Map::new();
+        for (key, value) in entries {
+            map.insert(key, value)?;
+        }
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position(&self, key: &Object) -> Option<usize> {
+        let positions = self.index.get(&key.stable_hash()?)?;
+        positions.iter().copied().find(|&i| self.entries[i].0 == *key)
+    }
+
+    pub fn get(&self, key: &Object) -> Option<&Object> {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    pub fn contains(&self, key: &Object) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Binds `key` to `value`, in the place of the key if it is already
+    /// there and after every other key if not. Fails for unhashable keys.
+    pub fn insert(&mut self, key: Object, value: Object) -> Result<(), Object> {
+        let Some(hash) = key.stable_hash() else {
+            return Err(unhashable(&key));
+        };
+        match self.position(&key) {
+            Some(i) => self.entries[i].1 = value,
+            None => {
+                self.index.entry(hash).or_default().push(self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &Object) -> Option<Object> {
+        let i = self.position(key)?;
+        let (_, value) = self.entries.remove(i);
+        // Positions after the removed entry move down by one
+        for positions in self.index.values_mut() {
+            positions.retain(|&position| position != i);
+            positions.iter_mut().filter(|position| **position > i).for_each(|position| *position -= 1);
+        }
+        self.index.retain(|_, positions| !positions.is_empty());
+        Some(value)
+    }
+
+    /// Entries in the order their keys were first inserted.
+    pub fn iter(&self) -> impl Iterator<Item = &(Object, Object)> {
+        self.entries.iter()
+    }
+
+    // Empties the map, for dropping nested values one at a time
+    pub(crate) fn take_entries(&mut self) -> Vec<(Object, Object)> {
+        self.index.clear();
+        std::mem::take(&mut self.entries)
+    }
+}
+
+// Maps with the same entries are equal whatever their order
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+/// `hashmap.get`, `hashmap.insert`, `hashmap.remove`, `hashmap.contains`,
+/// `hashmap.size`, `hashmap.keys`, `hashmap.values`, `hashmap.entries` and
+/// `hashmap.from_entries`. The map comes first, and updates return a new map.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("get", |args| match map_and_key("get", &args) {
+            Ok((map, key)) => map.get(key).cloned().map_or(Object::OptionNone, |value| Object::OptionSome(Box::new(value))),
+            Err(error) => error,
+        }),
+        function("insert", |args| match args.as_slice() {
+            [Object::Map(map), key, value] => {
+                let mut map = map.clone();
+                map.insert(key.clone(), value.clone()).map_or_else(|error| error, |_| Object::Map(map))
+            }
+            _ => argument_error("insert", "a map, a key and a value"),
+        }),
+        function("remove", |args| match map_and_key("remove", &args) {
+            Ok((map, key)) => {
+                let mut map = map.clone();
+                map.remove(key);
+                Object::Map(map)
+            }
+            Err(error) => error,
+        }),
+        function("contains", |args| match map_and_key("contains", &args) {
+            Ok((map, key)) => Object::Boolean(map.contains(key)),
+            Err(error) => error,
+        }),
+        function("size", |args| match args.as_slice() {
+            [Object::Map(map)] => Object::Integer(map.len() as i64),
+            _ => argument_error("size", "a map"),
+        }),
+        function("keys", |args| match args.as_slice() {
+            [Object::Map(map)] => Object::List(map.iter().map(|(key, _)| key.clone()).collect()),
+            _ => argument_error("keys", "a map"),
+        }),
+        function("values", |args| match args.as_slice() {
+            [Object::Map(map)] => Object::List(map.iter().map(|(_, value)| value.clone()).collect()),
+            _ => argument_error("values", "a map"),
+        }),
+        function("entries", |args| match args.as_slice() {
+            [Object::Map(map)] => Object::List(map.iter().map(|(key, value)| entry(key.clone(), value.clone())).collect()),
+            _ => argument_error("entries", "a map"),
+        }),
+        function("from_entries", from_entries_builtin),
+    ])
+}
+
+// An entry as `hashmap.entries` gives it, `{ key = ..., value = ... }`
+fn entry(key: Object, value: Object) -> Object {
+    Object::record(vec![("key".to_string(), key), ("value".to_string(), value)])
+}
+
+fn argument_error(name: &str, expected: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("hashmap.{} expects {}", name, expected))
+}
+
+fn map_and_key<'a>(name: &str, args: &'a [Object]) -> Result<(&'a Map, &'a Object), Object> {
+    match args {
+        [Object::Map(map), key] => Ok((map, key)),
+        _ => Err(argument_error(name, "a map and a key")),
+    }
+}
+
+fn from_entries_builtin(args: Vec<Object>) -> Object {
+    let [Object::List(entries)] = args.as_slice() else {
+        return argument_error("from_entries", "a list of { key, value } records");
+    };
+    let mut map = Map::new();
+    for entry in entries {
+        let Object::Record(fields) = entry else {
+            return argument_error("from_entries", "a list of { key, value } records");
+        };
+        let field = |name: &str| fields.get(name).cloned();
+        let (Some(key), Some(value), 2) = (field("key"), field("value"), fields.len()) else {
+            return argument_error("from_entries", "a list of { key, value } records");
+        };
+        if let Err(error) = map.insert(key, value) {
+            return error;
+        }
+    }
+    Object::Map(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+
+    #[test]
+    fn test_map_order() {
+        let mut eval = crate::evaluator::test_eval();
+        let strings = |strings: &[&str]| Some(Object::List(strings.iter().map(|&s| Object::from(s)).collect()));
+
+        eval("let ports = { \"web\": 80, \"mail\": 25, \"ssh\": 22 };\nlet updated = hashmap.insert(hashmap.remove(ports, \"mail\"), \"web\", 8080);");
+        assert_eq!(eval("hashmap.keys(hashmap.insert(updated, \"dns\", 53))"), strings(&["web", "ssh", "dns"]));
+        assert_eq!(eval("show(updated)"), Some(Object::from("{\"web\": 8080, \"ssh\": 22}")));
+        assert_eq!(eval("[hashmap.get(ports, \"ssh\"), hashmap.get(updated, \"mail\") ?? Some(0)]"), Some(Object::List(vec![Object::OptionSome(Box::new(Object::Integer(22))), Object::OptionSome(Box::new(Object::Integer(0)))])));
+        assert_eq!(eval("[\"web\" in ports, updated == { \"ssh\": 22, \"web\": 8080 }]"), Some(Object::List(vec![Object::Boolean(true); 2])));
+        assert_eq!(eval("show({:})"), Some(Object::from("{:}")));
+        let entries = "hashmap.from_entries(hashmap.entries(ports)) == ports";
+        assert_eq!(eval(entries), Some(Object::Boolean(true)));
+        assert!(matches!(eval("hashmap.insert({:}, fn x -> x;, 1)"), Some(Object::Error(crate::diagnostics::Code::Unhashable, _))));
+    }
+}
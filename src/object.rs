@@ -1,19 +1,36 @@
-use crate::ast::{Identifier, Statement};
+use crate::ast::{Block, Identifier};
+use crate::diagnostics::Code;
 use crate::environment::Env;
+use crate::deque::Deque;
+use crate::handle::Handle;
+use crate::map::Map;
+use crate::quote::Quoted;
+use crate::record::Record;
+use crate::set::Set;
+use crate::string::Str;
 use std::cell::RefCell;
 use std::fmt;
+use std::hash::Hasher;
 use std::rc::Rc;
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Default)]
 pub enum Object {
+    #[default]
     Unit,
     Integer(i64),
     Float(f64),
     Boolean(bool),
-    String(String),
+    String(Str),
     List(Vec<Object>),
+    // Fields in declaration order
+    Record(Record),
+    Map(Map),
+    Set(Set),
+    Deque(Deque),
+    // A value of a union type: the type, the variant, and its payload if any
+    Variant(String, String, Option<Box<Object>>),
 
-    Function(Vec<Identifier>, Vec<Statement>, Rc<RefCell<Env>>),
+    Function(Vec<Identifier>, Rc<Block>, Rc<RefCell<Env>>),
 
     Return(Box<Object>),
 
@@ -25,31 +42,601 @@ pub enum Object {
     ResultErr(Box<Object>),
 
     // Type Errors
-    Error(String),
+    Error(Code, String),
 
     // Builtin
     Builtin(fn(Vec<Object>) -> Object),
+    // Function registered by an embedding host
+    Native(Native),
+    // An open file or other resource, see `handle`
+    Handle(Handle),
+    // Code as a value, see `quote`
+    Quoted(Quoted),
+}
+
+// Values nest as deep as a program makes them, e.g. an option wrapped in a
+// loop, so dropping, comparing and printing them walk an explicit stack
+// rather than recursing, like `Str` does for ropes
+
+impl Object {
+    pub fn record(fields: Vec<(String, Object)>) -> Object {
+        Object::Record(Record::new(fields))
+    }
+
+    // Whether dropping the value drops other values it holds
+    fn nests(&self) -> bool {
+        match self {
+            Object::List(elements) => !elements.is_empty(),
+            Object::Record(fields) => !fields.is_empty(),
+            Object::Map(map) => !map.is_empty(),
+            Object::Set(set) => !set.is_empty(),
+            Object::Variant(_, _, payload) => payload.is_some(),
+            Object::OptionSome(_) | Object::ResultOk(_) | Object::ResultErr(_) | Object::Return(_) => true,
+            _ => false,
+        }
+    }
+
+    // Moves the values this one holds that hold others themselves into
+    // `pending`, leaving it shallow to drop
+    fn detach(&mut self, pending: &mut Vec<Object>) {
+        let mut keep = |object: &mut Object| {
+            if object.nests() {
+                pending.push(std::mem::take(object));
+            }
+        };
+        match self {
+            Object::List(elements) => elements.iter_mut().for_each(&mut keep),
+            Object::Record(fields) => fields.iter_mut().for_each(|(_, value)| keep(value)),
+            Object::Map(map) => map.take_entries().iter_mut().for_each(|(key, value)| {
+                keep(key);
+                keep(value);
+            }),
+            Object::Set(set) => set.take_elements().iter_mut().for_each(keep),
+            Object::Variant(_, _, Some(payload))
+            | Object::OptionSome(payload)
+            | Object::ResultOk(payload)
+            | Object::ResultErr(payload)
+            | Object::Return(payload) => keep(payload),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        if !self.nests() {
+            return;
+        }
+        let mut pending = Vec::new();
+        self.detach(&mut pending);
+        while let Some(mut object) = pending.pop() {
+            object.detach(&mut pending);
+        }
+    }
+}
+
+// A value still to copy, or one whose parts are copied and wait to be put
+// back together
+enum Copying<'a> {
+    Visit(&'a Object),
+    Build(&'a Object),
+}
+
+impl Clone for Object {
+    fn clone(&self) -> Self {
+        let mut work = vec![Copying::Visit(self)];
+        let mut done: Vec<Object> = Vec::new();
+        while let Some(copy) = work.pop() {
+            match copy {
+                Copying::Visit(object) => match object {
+                    Object::List(elements) => {
+                        work.push(Copying::Build(object));
+                        work.extend(elements.iter().rev().map(Copying::Visit));
+                    }
+                    Object::Record(fields) => {
+                        work.push(Copying::Build(object));
+                        work.extend(fields.iter().rev().map(|(_, value)| Copying::Visit(value)));
+                    }
+                    Object::Variant(_, _, Some(payload))
+                    | Object::OptionSome(payload)
+                    | Object::ResultOk(payload)
+                    | Object::ResultErr(payload)
+                    | Object::Return(payload) => {
+                        work.push(Copying::Build(object));
+                        work.push(Copying::Visit(payload));
+                    }
+                    _ => done.push(object.clone_flat()),
+                },
+                Copying::Build(object) => {
+                    let object = match object {
+                        Object::List(elements) => Object::List(done.split_off(done.len() - elements.len())),
+                        Object::Record(fields) => {
+                            Object::Record(fields.with_values(done.split_off(done.len() - fields.len())))
+                        }
+                        _ => {
+                            let payload = Box::new(done.pop().unwrap_or_default());
+                            match object {
+                                Object::Variant(union, variant, _) => {
+                                    Object::Variant(union.clone(), variant.clone(), Some(payload))
+                                }
+                                Object::OptionSome(_) => Object::OptionSome(payload),
+                                Object::ResultOk(_) => Object::ResultOk(payload),
+                                Object::ResultErr(_) => Object::ResultErr(payload),
+                                _ => Object::Return(payload),
+                            }
+                        }
+                    };
+                    done.push(object);
+                }
+            }
+        }
+        done.pop().unwrap_or_default()
+    }
+}
+
+impl Object {
+    // Copy of a value that holds no others directly; maps and sets copy
+    // their own entries
+    fn clone_flat(&self) -> Object {
+        match self {
+            Object::Unit => Object::Unit,
+            Object::Integer(value) => Object::Integer(*value),
+            Object::Float(value) => Object::Float(*value),
+            Object::Boolean(value) => Object::Boolean(*value),
+            Object::String(value) => Object::String(value.clone()),
+            Object::Map(map) => Object::Map(map.clone()),
+            Object::Set(set) => Object::Set(set.clone()),
+            Object::Deque(deque) => Object::Deque(deque.clone()),
+            Object::Variant(union, variant, _) => Object::Variant(union.clone(), variant.clone(), None),
+            Object::Function(parameters, body, env) => {
+                Object::Function(parameters.clone(), Rc::clone(body), Rc::clone(env))
+            }
+            Object::OptionNone => Object::OptionNone,
+            Object::Error(code, message) => Object::Error(*code, message.clone()),
+            Object::Builtin(function) => Object::Builtin(*function),
+            Object::Native(native) => Object::Native(native.clone()),
+            Object::Handle(handle) => Object::Handle(handle.clone()),
+            Object::Quoted(quoted) => Object::Quoted(quoted.clone()),
+            Object::List(_)
+            | Object::Record(_)
+            | Object::Return(_)
+            | Object::OptionSome(_)
+            | Object::ResultOk(_)
+            | Object::ResultErr(_) => unreachable!("{} holds other values", self.type_name()),
+        }
+    }
+}
+
+#[allow(unpredictable_function_pointer_comparisons)]
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pairs = vec![(self, other)];
+        while let Some(pair) = pairs.pop() {
+            let equal = match pair {
+                (Object::List(left), Object::List(right)) => {
+                    pairs.extend(left.iter().zip(right));
+                    left.len() == right.len()
+                }
+                (Object::Record(left), Object::Record(right)) => {
+                    pairs.extend(left.values().iter().zip(right.values()));
+                    left.same_shape(right) || left.names().eq(right.names())
+                }
+                (Object::Variant(union, variant, left), Object::Variant(other_union, other_variant, right)) => {
+                    match (left, right) {
+                        (Some(left), Some(right)) => pairs.push((left, right)),
+                        (None, None) => {}
+                        _ => return false,
+                    }
+                    union == other_union && variant == other_variant
+                }
+                (Object::OptionSome(left), Object::OptionSome(right))
+                | (Object::ResultOk(left), Object::ResultOk(right))
+                | (Object::ResultErr(left), Object::ResultErr(right))
+                | (Object::Return(left), Object::Return(right)) => {
+                    pairs.push((left, right));
+                    true
+                }
+                (Object::Unit, Object::Unit) | (Object::OptionNone, Object::OptionNone) => true,
+                (Object::Integer(left), Object::Integer(right)) => left == right,
+                (Object::Float(left), Object::Float(right)) => left == right,
+                (Object::Boolean(left), Object::Boolean(right)) => left == right,
+                (Object::String(left), Object::String(right)) => left == right,
+                (Object::Map(left), Object::Map(right)) => left == right,
+                (Object::Set(left), Object::Set(right)) => left == right,
+                (Object::Deque(left), Object::Deque(right)) => left == right,
+                (Object::Function(parameters, body, env), Object::Function(other_parameters, other_body, other_env)) => {
+                    parameters == other_parameters && body == other_body && env == other_env
+                }
+                (Object::Error(code, message), Object::Error(other_code, other_message)) => code == other_code && message == other_message,
+                (Object::Builtin(left), Object::Builtin(right)) => left == right,
+                (Object::Native(left), Object::Native(right)) => left == right,
+                (Object::Handle(left), Object::Handle(right)) => left == right,
+                (Object::Quoted(left), Object::Quoted(right)) => left == right,
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A host function callable from opl, see `Engine::register`.
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub function: Rc<dyn Fn(Vec<Object>) -> Object>,
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.function, &other.function)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+impl Object {
+    // Type of a value written in opl's type syntax, e.g. `list * int`
+    pub fn type_name(&self) -> String {
+        let mut name = String::new();
+        let mut pending = vec![Piece::Value(self)];
+        while let Some(piece) = pending.pop() {
+            let value = match piece {
+                Piece::Text(text) => {
+                    name.push_str(text);
+                    continue;
+                }
+                Piece::Value(value) => value,
+            };
+            // Like `Display`, a value holding others names them in its place
+            let pieces = match value {
+                Object::List(elements) => match elements.first() {
+                    Some(first) => vec![Piece::Text("list * "), Piece::Value(first)],
+                    None => vec![Piece::Text("list * 'a")],
+                },
+                Object::Record(fields) => {
+                    enclosed("{ ", fields.iter().map(|(name, value)| vec![Piece::Text(name), Piece::Text(": "), Piece::Value(value)]), " }")
+                }
+                Object::Map(map) => match map.iter().next() {
+                    Some((key, value)) => vec![
+                        Piece::Text("hashmap * ("),
+                        Piece::Value(key),
+                        Piece::Text(", "),
+                        Piece::Value(value),
+                        Piece::Text(")"),
+                    ],
+                    None => vec![Piece::Text("hashmap * ('a, 'b)")],
+                },
+                Object::Set(set) => match set.iter().next() {
+                    Some(element) => vec![Piece::Text("set * "), Piece::Value(element)],
+                    None => vec![Piece::Text("set * 'a")],
+                },
+                Object::Return(value) => vec![Piece::Value(value)],
+                Object::OptionSome(value) => vec![Piece::Text("option * "), Piece::Value(value)],
+                Object::ResultOk(value) => vec![Piece::Text("result * ("), Piece::Value(value), Piece::Text(", 'b)")],
+                Object::ResultErr(value) => vec![Piece::Text("result * ('a, "), Piece::Value(value), Piece::Text(")")],
+                value => {
+                    name.push_str(&value.type_name_flat());
+                    continue;
+                }
+            };
+            pending.extend(pieces.into_iter().rev());
+        }
+        name
+    }
+
+    // Types named without naming others, see `type_name`
+    fn type_name_flat(&self) -> String {
+        match self {
+            Object::Unit => "unit".to_string(),
+            Object::Integer(_) => "int".to_string(),
+            Object::Float(_) => "float".to_string(),
+            Object::Boolean(_) => "bool".to_string(),
+            Object::String(_) => "string".to_string(),
+            Object::Deque(deque) => match deque.to_vec().first() {
+                Some(first) => format!("deque * {}", first.type_name()),
+                None => "deque * 'a".to_string(),
+            },
+            Object::Variant(union, ..) => union.clone(),
+            Object::Function(parameters, _, _) => format!("fn({})", parameters.len()),
+            Object::OptionNone => "option * 'a".to_string(),
+            Object::Error(..) => "error".to_string(),
+            Object::Builtin(_) | Object::Native(_) => "builtin".to_string(),
+            Object::Handle(_) => "handle".to_string(),
+            Object::Quoted(_) => "quoted".to_string(),
+            Object::List(_)
+            | Object::Record(_)
+            | Object::Map(_)
+            | Object::Set(_)
+            | Object::Return(_)
+            | Object::OptionSome(_)
+            | Object::ResultOk(_)
+            | Object::ResultErr(_) => unreachable!("{} holds other values", self.type_name()),
+        }
+    }
+}
+
+/// FNV-1a. Unlike the std hasher its output is fixed, so hashes of opl values
+/// are the same across runs, platforms and Rust versions.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+impl Object {
+    /// Structural hash of a value: equal values hash equally. Returns None for
+    /// values that cannot be keys, such as functions.
+    pub fn stable_hash(&self) -> Option<u64> {
+        let mut hasher = StableHasher::default();
+        self.hash_into(&mut hasher).then(|| hasher.finish())
+    }
+
+    fn hash_into(&self, hasher: &mut StableHasher) -> bool {
+        // A tag per variant keeps e.g. `Some(1)` and `Ok(1)` apart
+        match self {
+            Object::Unit => hasher.write_u8(0),
+            Object::Integer(value) => {
+                hasher.write_u8(1);
+                hasher.write(&value.to_le_bytes());
+            }
+            Object::Float(value) => {
+                hasher.write_u8(2);
+                // 0.0 and -0.0 are equal, so they must hash the same
+                let value = if *value == 0.0 { 0.0 } else { *value };
+                hasher.write(&value.to_bits().to_le_bytes());
+            }
+            Object::Boolean(value) => hasher.write(&[3, u8::from(*value)]),
+            Object::String(value) => {
+                hasher.write_u8(4);
+                hasher.write(&(value.len() as u64).to_le_bytes());
+                hasher.write(value.as_str().as_bytes());
+            }
+            Object::List(elements) => {
+                hasher.write_u8(5);
+                hasher.write(&(elements.len() as u64).to_le_bytes());
+                return elements.iter().all(|element| element.hash_into(hasher));
+            }
+            Object::Record(fields) => {
+                hasher.write_u8(10);
+                hasher.write(&(fields.len() as u64).to_le_bytes());
+                return fields.iter().all(|(name, value)| {
+                    hasher.write(&(name.len() as u64).to_le_bytes());
+                    hasher.write(name.as_bytes());
+                    value.hash_into(hasher)
+                });
+            }
+            // Equal maps may differ in order, so their entries' hashes are summed
+            Object::Map(map) => {
+                hasher.write_u8(12);
+                hasher.write(&(map.len() as u64).to_le_bytes());
+                let mut sum: u64 = 0;
+                for (key, value) in map.iter() {
+                    let mut entry = StableHasher::default();
+                    if !(key.hash_into(&mut entry) && value.hash_into(&mut entry)) {
+                        return false;
+                    }
+                    sum = sum.wrapping_add(entry.finish());
+                }
+                hasher.write(&sum.to_le_bytes());
+            }
+            Object::Set(set) => {
+                hasher.write_u8(13);
+                hasher.write(&(set.len() as u64).to_le_bytes());
+                // Elements of a set are hashable
+                let sum = set.iter().filter_map(Object::stable_hash).fold(0u64, u64::wrapping_add);
+                hasher.write(&sum.to_le_bytes());
+            }
+            Object::Deque(deque) => {
+                hasher.write_u8(14);
+                hasher.write(&(deque.len() as u64).to_le_bytes());
+                return deque.to_vec().iter().all(|element| element.hash_into(hasher));
+            }
+            Object::Variant(union, variant, payload) => {
+                hasher.write_u8(11);
+                for name in [union, variant] {
+                    hasher.write(&(name.len() as u64).to_le_bytes());
+                    hasher.write(name.as_bytes());
+                }
+                return payload.as_ref().is_none_or(|payload| payload.hash_into(hasher));
+            }
+            Object::OptionSome(inner) => {
+                hasher.write_u8(6);
+                return inner.hash_into(hasher);
+            }
+            Object::OptionNone => hasher.write_u8(7),
+            Object::ResultOk(inner) => {
+                hasher.write_u8(8);
+                return inner.hash_into(hasher);
+            }
+            Object::ResultErr(inner) => {
+                hasher.write_u8(9);
+                return inner.hash_into(hasher);
+            }
+            Object::Return(inner) => return inner.hash_into(hasher),
+            Object::Function(..) | Object::Builtin(_) | Object::Native(_) | Object::Handle(_) | Object::Quoted(_) | Object::Error(..) => return false,
+        }
+        true
+    }
+}
+
+/// The shortest text `float.parse` reads back as the same float, always with
+/// a `.` or an exponent, e.g. `1.0`, `0.1`, `1e300`, `NaN`, `-Infinity`.
+pub fn format_float(value: f64) -> String {
+    match value {
+        _ if value.is_nan() => "NaN".to_string(),
+        f64::INFINITY => "Infinity".to_string(),
+        f64::NEG_INFINITY => "-Infinity".to_string(),
+        _ => format!("{:?}", value),
+    }
+}
+
+// Text still to write, and values still to print
+enum Piece<'a> {
+    Text(&'a str),
+    Value(&'a Object),
+}
+
+// `parts` with `, ` between them, inside `open` and `close`
+fn enclosed<'a>(open: &'a str, parts: impl Iterator<Item = Vec<Piece<'a>>>, close: &'a str) -> Vec<Piece<'a>> {
+    let mut pieces = vec![Piece::Text(open)];
+    for (i, part) in parts.enumerate() {
+        if i > 0 {
+            pieces.push(Piece::Text(", "));
+        }
+        pieces.extend(part);
+    }
+    pieces.push(Piece::Text(close));
+    pieces
 }
 
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pending = vec![Piece::Value(self)];
+        while let Some(piece) = pending.pop() {
+            let value = match piece {
+                Piece::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Piece::Value(value) => value,
+            };
+            // A value holding others gives the pieces to print in its place
+            let pieces = match value {
+                Object::OptionSome(value) => vec![Piece::Text("Some("), Piece::Value(value), Piece::Text(")")],
+                Object::List(elements) => enclosed("[", elements.iter().map(|element| vec![Piece::Value(element)]), "]"),
+                Object::Record(fields) => {
+                    enclosed("{ ", fields.iter().map(|(name, value)| vec![Piece::Text(name), Piece::Text(" = "), Piece::Value(value)]), " }")
+                }
+                Object::Map(map) if !map.is_empty() => {
+                    enclosed("{", map.iter().map(|(key, value)| vec![Piece::Value(key), Piece::Text(": "), Piece::Value(value)]), "}")
+                }
+                Object::Set(set) => enclosed("set {", set.iter().map(|element| vec![Piece::Value(element)]), "}"),
+                Object::Variant(_, variant, Some(payload)) => vec![Piece::Text(variant), Piece::Text("("), Piece::Value(payload), Piece::Text(")")],
+                Object::Return(value) | Object::ResultOk(value) | Object::ResultErr(value) => vec![Piece::Value(value)],
+                value => {
+                    value.fmt_flat(f)?;
+                    continue;
+                }
+            };
+            pending.extend(pieces.into_iter().rev());
+        }
+        Ok(())
+    }
+}
+
+impl Object {
+    // Values printed without printing others, see `Display`
+    fn fmt_flat(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Object::Integer(value) => write!(f, "{}", value),
-            Object::Float(ref value) => write!(f, "{}", value),
-            Object::Boolean(ref value) => write!(f, "{}", value),
-            Object::String(ref value) => write!(f, "\"{}\"", value),
+            Object::Float(value) => write!(f, "{}", format_float(*value)),
+            Object::Boolean(value) => write!(f, "{}", value),
+            Object::String(value) => write!(f, "\"{}\"", value),
             Object::Unit => write!(f, "()"),
-            Object::OptionSome(ref value) => write!(f, "Some({})", value),
             Object::OptionNone => write!(f, "None"),
-            Object::Function(ref parameters, _, _) => {
+            Object::Function(parameters, _, _) => {
                 write!(f, "fn {} -> {{ ... }}", parameters.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "))
             }
-            Object::List(ref value) => write!(f, "[{}]", value.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(", ")),
-            Object::Return(ref value) => write!(f, "{}", value),
-            Object::ResultOk(ref value) => write!(f, "{}", value),
-            Object::ResultErr(ref value) => write!(f, "{}", value),
-            Object::Error(ref value) => write!(f, "{}", value),
-            Object::Builtin(ref value) => write!(f, "{:?}", value),
+            Object::Map(_) => write!(f, "{{:}}"),
+            Object::Deque(deque) => write!(
+                f,
+                "deque [{}]",
+                deque.to_vec().iter().map(|element| element.to_string()).collect::<Vec<String>>().join(", ")
+            ),
+            Object::Variant(_, variant, _) => write!(f, "{}", variant),
+            Object::Error(code, value) => write!(f, "error[{}]: {}", code, value),
+            Object::Builtin(value) => write!(f, "{:?}", value),
+            Object::Native(native) => write!(f, "{:?}", native),
+            Object::Handle(handle) => write!(f, "{:?}", handle),
+            Object::Quoted(quoted) => write!(f, "{}", quoted),
+            Object::OptionSome(_) | Object::List(_) | Object::Record(_) | Object::Set(_) | Object::Return(_) | Object::ResultOk(_) | Object::ResultErr(_) => {
+                unreachable!("{} holds other values", self.type_name())
+            }
+        }
+    }
+}
+
+/// A value that does not have the type a Rust conversion asked for.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ConversionError {
+    pub expected: String,
+    // The `type_name` of the value that was given
+    pub found: String,
+}
+
+impl ConversionError {
+    pub fn new(expected: impl Into<String>, found: &Object) -> Self {
+        ConversionError { expected: expected.into(), found: found.type_name() }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}, got {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+// Lets a builtin return a failed conversion as its result
+impl From<ConversionError> for Object {
+    fn from(error: ConversionError) -> Self {
+        Object::Error(Code::BuiltinArgument, error.to_string())
+    }
+}
+
+// Conversions between a Rust type and the variant holding it
+macro_rules! convert {
+    ($rust:ty, $variant:ident, $name:literal, $into:expr, $from:expr) => {
+        impl From<$rust> for Object {
+            fn from(value: $rust) -> Self {
+                Object::$variant($into(value))
+            }
         }
+
+        impl TryFrom<Object> for $rust {
+            type Error = ConversionError;
+
+            fn try_from(mut object: Object) -> Result<Self, Self::Error> {
+                match object {
+                    Object::$variant(ref mut value) => Ok($from(std::mem::take(value))),
+                    other => Err(ConversionError::new($name, &other)),
+                }
+            }
+        }
+    };
+}
+
+convert!(i64, Integer, "int", |value| value, |value| value);
+convert!(f64, Float, "float", |value| value, |value| value);
+convert!(bool, Boolean, "bool", |value| value, |value| value);
+convert!(String, String, "string", Str::from, |value: Str| value.as_str().to_string());
+convert!(Vec<Object>, List, "list", |value| value, |value| value);
+
+impl From<&str> for Object {
+    fn from(value: &str) -> Self {
+        Object::String(Str::from(value))
     }
 }
@@ -1,11 +1,23 @@
 use clap::{Parser, Subcommand};
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use crate::{lexer, parser, evaluator, environment, repl};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::{analysis, ast, diff, graph, evaluator, environment, repl, diagnostics, lint, optimizer, doctest, coverage, handle, profile, trace};
+use crate::doctest::Outcome;
+use crate::diagnostics::{Code, Diagnostic, DiagnosticConfig, DiagnosticEmitter, ErrorFormat, Level};
+use crate::lexer::Span;
+use crate::object::Object;
+use crate::source::SourceMap;
+use crate::style::{self, ColorChoice, Style};
+use crate::typechecker::TypeChecker;
 
 const VERSION: &str = "0.4.2.ec9839e-rc";
 const ABOUT: &str = "opl is a general purpose functional language.";
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 const ZEN: &str = "\n* Strive to be pure.\n* Simplicity over complexity.\n* Elegance over verbosity.\n";
 
 #[derive(Parser)]
@@ -18,6 +30,8 @@ const ZEN: &str = "\n* Strive to be pure.\n* Simplicity over complexity.\n* Eleg
 pub struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto, help = "When to color diagnostics and REPL output; NO_COLOR turns auto off")]
+    color: ColorChoice,
 }
 
 #[derive(Subcommand)]
@@ -27,56 +41,512 @@ enum Commands {
         #[arg(short, long)]
         parse: bool,
     },
-    #[command(about = "Execute a .opl file. Optional --eval flag to evaluate the input.")]
+    #[command(about = "Execute a .opl file. Optional --watch flag to re-run it whenever it or a file it includes changes.")]
     Run {
         #[arg(name = "FILE")]
         file: String,
+        #[arg(short, long)]
+        watch: bool,
+        #[arg(long, requires = "watch", help = "Keep bindings from the previous run when re-running")]
+        keep_env: bool,
+        #[arg(short = 'A', long = "allow", value_name = "LINT", help = "Suppress a lint")]
+        allow: Vec<String>,
+        #[arg(short = 'W', long = "warn", value_name = "LINT", help = "Report a lint as a warning")]
+        warn: Vec<String>,
+        #[arg(short = 'D', long = "deny", value_name = "LINT", help = "Report a lint as an error; -D warnings denies every warning")]
+        deny: Vec<String>,
+        #[arg(long, value_enum, default_value_t = ErrorFormat::Human, help = "How diagnostics are printed")]
+        error_format: ErrorFormat,
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, help = "Stop once live values exceed SIZE bytes; accepts K, M and G suffixes")]
+        max_heap: Option<usize>,
+        #[arg(long, help = "Debug mode: check the requires and ensures clauses of functions, and report files and other handles left open")]
+        debug: bool,
+        #[arg(long, help = "On a runtime error, open a REPL in the scope where it happened, to inspect it and then abort or carry on")]
+        debug_on_error: bool,
+        #[arg(long, value_name = "FILE", help = "Write how often each function was called and each if went either way to FILE, as JSON")]
+        profile_out: Option<String>,
+        #[arg(long, value_name = "FILE", help = "Optimize with a profile written by --profile-out, inlining the small functions it called most and trying the tests it saw pass most first")]
+        profile_in: Option<String>,
+        #[arg(long, value_name = "FILE", help = "Record what the program reads from stdin and files, and the time it goes by, to FILE")]
+        record: Option<String>,
+        #[arg(long, value_name = "FILE", conflicts_with = "record", help = "Run the program again on the inputs recorded in FILE, without reading them")]
+        replay: Option<String>,
+    },
+    #[command(about = "Run .opl files as tests; each passes when it runs without an error, e.g. a failed assert. .repl files are replayed as REPL transcripts.")]
+    Test {
+        #[arg(name = "FILES", required = true)]
+        files: Vec<String>,
+        #[arg(long, help = "Run the examples in the files' doc comments instead")]
+        doc: bool,
+        #[arg(long, conflicts_with = "doc", help = "Print the share of each file's lines that ran")]
+        coverage: bool,
+        #[arg(long, value_name = "FILE", conflicts_with = "doc", help = "Write line coverage to FILE in lcov format")]
+        lcov: Option<String>,
+    },
+    #[command(about = "Rename the variable at LINE:COLUMN of a file, at its binding and every use, printing the result.")]
+    Rename {
+        #[arg(name = "FILE")]
+        file: String,
+        #[arg(name = "LINE:COLUMN", value_parser = parse_position)]
+        position: (usize, usize),
+        #[arg(name = "NEW_NAME")]
+        new_name: String,
+        #[arg(short, long, help = "Rewrite the file instead of printing it")]
+        write: bool,
+    },
+    #[command(about = "Print a DOT graph of which functions call which (--calls) or which top level bindings use which (--deps).")]
+    Graph {
+        #[arg(name = "FILE")]
+        file: String,
+        #[arg(long, conflicts_with = "deps", required_unless_present = "deps")]
+        calls: bool,
+        #[arg(long)]
+        deps: bool,
+    },
+    #[command(about = "Print where two .opl files first differ; exits with 1 when they do.")]
+    Diff {
+        #[arg(name = "LEFT")]
+        left: String,
+        #[arg(name = "RIGHT")]
+        right: String,
+        #[arg(long, help = "Compare the parsed programs, ignoring layout and comments")]
+        semantic: bool,
     },
     #[command(about = "Print our zen and exit.")]
     Zen,
+    #[command(about = "Explain an error code, e.g. E0206. Lists every code when none is given.")]
+    Explain {
+        #[arg(name = "CODE")]
+        code: Option<String>,
+    },
+}
+
+// Settings shared by every run of a file, including re-runs under --watch
+struct RunOptions {
+    config: DiagnosticConfig,
+    format: ErrorFormat,
+    max_heap: Option<usize>,
+    contracts: bool,
+    debug_on_error: bool,
+    profile_out: Option<String>,
+    profile_in: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+// Branches are read for the files of `sources`, so it is read once they are loaded
+fn read_profile(path: &str, sources: &SourceMap) -> Result<profile::Profile, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| profile::Profile::from_json(&text, sources))
+        .map_err(|e| format!("Error reading profile '{}': {}", path, e))
+}
+
+fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&size[..i], c.to_ascii_uppercase()),
+        _ => (size, 'B'),
+    };
+    let scale: usize = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("unknown size unit '{}'", unit)),
+    };
+    digits
+        .parse::<usize>()
+        .map_err(|e| format!("invalid size '{}': {}", size, e))
+        .and_then(|n| n.checked_mul(scale).ok_or(format!("size '{}' is too large", size)))
+}
+
+fn parse_position(position: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid position '{}', expected LINE:COLUMN", position);
+    let (line, column) = position.split_once(':').ok_or_else(invalid)?;
+    match (line.parse(), column.parse()) {
+        (Ok(line), Ok(column)) if line > 0 && column > 0 => Ok((line, column)),
+        _ => Err(invalid()),
+    }
+}
+
+// The byte offset of a line and column, both counted from 1 like those of spans
+fn offset(source: &str, (line, column): (usize, usize)) -> Option<usize> {
+    let start: usize = source.split_inclusive('\n').take(line - 1).map(str::len).sum();
+    let text = source[start..].lines().next()?;
+    text.char_indices().map(|(i, _)| start + i).nth(column - 1)
+}
+
+fn rename_file(file: &str, position: (usize, usize), new_name: &str, write: bool) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("cannot read '{}': {}", file, e))?;
+    let edits = offset(&source, position).map_or(Vec::new(), |offset| analysis::rename(&source, offset, new_name));
+    if edits.is_empty() {
+        return Err(format!(
+            "cannot rename at {}:{}:{}: there is no variable there, the file does not parse, or renaming it to '{}' would change what a name refers to",
+            file, position.0, position.1, new_name
+        ));
+    }
+    let renamed = analysis::apply_edits(&source, &edits);
+    if write {
+        fs::write(file, renamed).map_err(|e| format!("cannot write '{}': {}", file, e))?;
+        eprintln!("Renamed {} occurrences in '{}'", edits.len(), file);
+    } else {
+        print!("{}", renamed);
+    }
+    Ok(())
+}
+
+// e.g. `a.opl:3:5 and b.opl:3:5 differ: a `+` operation differs from a `-` operation`
+fn diff_files(left: &str, right: &str, semantic: bool) -> Result<Option<String>, String> {
+    let mut sources = SourceMap::new();
+    if semantic {
+        let (a, b) = (parse_file(&mut sources, left)?, parse_file(&mut sources, right)?);
+        return Ok(diff::diff(&a, &b).map(|difference| {
+            format!("{} and {} differ: {}", sources.location(difference.left), sources.location(difference.right), difference.message)
+        }));
+    }
+    let read = |file: &str| fs::read_to_string(file).map_err(|e| format!("cannot read '{}': {}", file, e));
+    let (a, b) = (read(left)?, read(right)?);
+    let (mut a_lines, mut b_lines) = (a.lines(), b.lines());
+    let mut line = 1;
+    loop {
+        match (a_lines.next(), b_lines.next()) {
+            (None, None) => return Ok(None),
+            (l, r) if l == r => line += 1,
+            _ => return Ok(Some(format!("{}:{} and {}:{} differ", left, line, right, line))),
+        }
+    }
+}
+
+fn print_diagnostic(diagnostic: &Diagnostic, sources: &SourceMap, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => eprintln!("{}", sources.render(diagnostic)),
+        ErrorFormat::Json => eprintln!("{}", sources.to_json(diagnostic)),
+    }
+}
+
+// Returns the status to exit with: that given to `exit`, 130 when the
+// program was interrupted, and 1 on an error, including warnings denied by
+// -D. Sets `includes` to the files the program included.
+fn run_file(file: &str, env: Rc<RefCell<environment::Env>>, options: &RunOptions, includes: &mut Vec<PathBuf>) -> Option<i32> {
+    let format = options.format;
+    let mut sources = SourceMap::new();
+    let program = match sources.load(file) {
+        Ok(id) => sources.parse(id),
+        Err(e) => {
+            eprintln!("Error reading file '{}': {}", file, e);
+            return Some(1);
+        }
+    };
+    includes.clone_from(&program.includes);
+
+    let mut evaluator = evaluator::Evaluator::new(env).with_contracts(options.contracts).with_source_name(file);
+    if let Some(limit) = options.max_heap {
+        evaluator = evaluator.with_heap_limit(limit);
+    }
+    if options.debug_on_error {
+        let file = file.to_string();
+        let location = move |span: Span| format!("{}:{}:{}", file, span.line, span.column);
+        let breakpoints = RefCell::new(BTreeSet::new());
+        evaluator = evaluator.with_debugger(Rc::new(move |stop| repl::debug(stop, &location, &breakpoints)));
+    }
+
+    let mut emitter = DiagnosticEmitter::new(options.config.clone());
+    emitter.apply_attributes(&program);
+    for error in &program.errors {
+        emitter.emit(error.into());
+    }
+    if program.errors.is_empty() {
+        let mut checker = TypeChecker::new();
+        checker.check_program(&program);
+        for error in &checker.errors {
+            emitter.emit(error.into());
+        }
+        lint::lint(&program, &mut emitter);
+    }
+
+    for diagnostic in &emitter.diagnostics {
+        print_diagnostic(diagnostic, &sources, format);
+    }
+    if emitter.has_errors() {
+        return Some(1);
+    }
+
+    let profile_in = match options.profile_in.as_deref().map(|path| read_profile(path, &sources)).transpose() {
+        Ok(profile) => profile,
+        Err(message) => {
+            eprintln!("{}", message);
+            return Some(1);
+        }
+    };
+    let program = optimizer::optimize_with(program, profile_in.as_ref());
+    handle::report_leaks(options.contracts);
+    if options.profile_out.is_some() {
+        profile::start();
+    }
+    // The debugger travels back through a run on the inputs it recorded
+    if options.record.is_some() || (options.debug_on_error && options.replay.is_none()) {
+        trace::record();
+    }
+    if let Some(path) = &options.replay {
+        if let Err(e) = std::fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| trace::replay(&bytes)) {
+            eprintln!("Error reading trace '{}': {}", path, e);
+            return Some(1);
+        }
+    }
+    let result = evaluator.eval(&program);
+    handle::close_all();
+    match (&options.record, trace::finish()) {
+        (Some(path), Ok(Some(trace))) => {
+            if let Err(e) = std::fs::write(path, trace) {
+                eprintln!("Error writing trace '{}': {}", path, e);
+            }
+        }
+        (_, Err(e)) => eprintln!("{}", style::paint(&format!("warning: {}", e), Style::Warning)),
+        _ => {}
+    }
+    if let (Some(path), Some(profile)) = (&options.profile_out, profile::finish()) {
+        if let Err(e) = std::fs::write(path, profile.to_json(&sources) + "\n") {
+            eprintln!("Error writing profile '{}': {}", path, e);
+        }
+    }
+    match result {
+        Some(Object::Error(Code::Exit(status), _)) => return Some(status),
+        Some(Object::Error(Code::Interrupted, ref message)) => {
+            let mut diagnostic = Diagnostic::new(Code::Interrupted, message.clone());
+            diagnostic.span = evaluator.error_span;
+            print_diagnostic(&diagnostic, &sources, format);
+            return Some(130);
+        }
+        // Reported like any other diagnostic, where it happened if known
+        Some(Object::Error(code, ref message)) => {
+            let mut diagnostic = Diagnostic::new(code, message.clone());
+            diagnostic.span = evaluator.error_span;
+            print_diagnostic(&diagnostic, &sources, format);
+            return Some(1);
+        }
+        Some(result) => println!("{}", result),
+        None => {}
+    }
+    None
+}
+
+fn parse_file(sources: &mut SourceMap, file: &str) -> Result<ast::Program, String> {
+    let id = sources.load(file).map_err(|e| format!("cannot read the file: {}", e))?;
+    let program = sources.parse(id);
+    match program.errors.first() {
+        Some(error) => Err(format!("cannot parse the file: {}", error)),
+        None => Ok(program),
+    }
+}
+
+// How `opl test` runs
+struct TestOptions {
+    doc: bool,
+    coverage: bool,
+    lcov: Option<String>,
+}
+
+// Runs the files, or the examples in their doc comments, printing a line per
+// test and then any coverage. Returns whether every test passed.
+fn test_files(files: &[String], options: &TestOptions) -> bool {
+    let mut results: Vec<(String, Outcome)> = Vec::new();
+    let mut covered = Vec::new();
+    let mut sources = SourceMap::new();
+    for file in files {
+        // Transcripts of REPL sessions, see `repl::replay`
+        if file.ends_with(".repl") && !options.doc {
+            let outcome = match fs::read_to_string(file).map_err(|e| format!("cannot read the file: {}", e)).and_then(|t| repl::replay(&t)) {
+                Ok(()) => Outcome::Passed,
+                Err(message) => Outcome::Failed(message),
+            };
+            results.push((file.clone(), outcome));
+            continue;
+        }
+        let program = match parse_file(&mut sources, file) {
+            Ok(program) => program,
+            Err(message) => {
+                results.push((file.clone(), Outcome::Failed(message)));
+                continue;
+            }
+        };
+        if options.doc {
+            let examples = doctest::examples(&program);
+            let outcomes = doctest::run(&program, &examples);
+            for (example, outcome) in examples.iter().zip(outcomes) {
+                results.push((format!("{} {} (example {})", file, example.name, example.index), outcome));
+            }
+            continue;
+        }
+        let env = Rc::new(RefCell::new(environment::Env::new()));
+        let mut evaluator = evaluator::Evaluator::new(env).with_source_name(file);
+        if options.coverage || options.lcov.is_some() {
+            evaluator = evaluator.with_coverage();
+        }
+        let outcome = match evaluator.eval(&program) {
+            Some(Object::Error(Code::Exit(0), _)) => Outcome::Passed,
+            Some(error @ Object::Error(..)) => Outcome::Failed(error.to_string()),
+            _ => Outcome::Passed,
+        };
+        results.push((file.clone(), outcome));
+        if let Some(counts) = &evaluator.coverage {
+            covered.push((file, coverage::lines(&program, counts)));
+        }
+    }
+
+    let mut failed = 0;
+    for (name, outcome) in &results {
+        match outcome {
+            Outcome::Passed => println!("test {} ... ok", name),
+            Outcome::Failed(message) => {
+                failed += 1;
+                println!("test {} ... FAILED: {}", name, message);
+            }
+        }
+    }
+    println!("\ntest result: {} passed, {} failed", results.len() - failed, failed);
+
+    if options.coverage {
+        println!("\ncoverage:");
+        for (file, lines) in &covered {
+            println!("  {}", coverage::summary(file, lines));
+        }
+    }
+    if let Some(path) = &options.lcov {
+        let tracefile: String = covered.iter().map(|(file, lines)| coverage::lcov(file, lines)).collect();
+        if let Err(e) = fs::write(path, tracefile) {
+            eprintln!("Error writing '{}': {}", path, e);
+        }
+    }
+    failed == 0
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Polls the file and those it includes, and re-runs it on every change of
+// any. With `keep_env` each run is evaluated in a scope nested inside the
+// previous one, so earlier bindings stay visible while the re-run can still
+// define them again.
+fn watch_file(file: &str, mut env: Rc<RefCell<environment::Env>>, keep_env: bool, options: &RunOptions, mut includes: Vec<PathBuf>) {
+    let paths = |includes: &[PathBuf]| -> Vec<PathBuf> { std::iter::once(PathBuf::from(file)).chain(includes.iter().cloned()).collect() };
+    let mut watched = paths(&includes);
+    let mut last: Vec<Option<SystemTime>> = watched.iter().map(|path| modified(path)).collect();
+    eprintln!("Watching '{}' for changes...", file);
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+        let current: Vec<Option<SystemTime>> = watched.iter().map(|path| modified(path)).collect();
+        let Some(changed) = current.iter().zip(&last).position(|(current, last)| current != last) else {
+            continue;
+        };
+        last = current;
+
+        env = if keep_env {
+            Rc::new(RefCell::new(environment::Env::new_with_outer(env)))
+        } else {
+            Rc::new(RefCell::new(environment::Env::new()))
+        };
+        eprintln!("--- '{}' changed, re-running ---", watched[changed].display());
+        run_file(file, Rc::clone(&env), options, &mut includes);
+        // The program may now include other files
+        if paths(&includes) != watched {
+            watched = paths(&includes);
+            last = watched.iter().map(|path| modified(path)).collect();
+        }
+    }
 }
 
 pub fn run() {
     let cli = Cli::parse();
+    style::set_color(cli.color);
+    // Ctrl-C stops the running evaluation; a second one before it has
+    // stopped, e.g. while a script waits for input, quits
+    let _ = ctrlc::set_handler(|| {
+        if evaluator::interrupt() {
+            std::process::exit(130);
+        }
+    });
 
     match cli.command {
         None => {
-            let _ = Cli::parse_from(&["opl", "--help"]);
+            let _ = Cli::parse_from(["opl", "--help"]);
         },
         Some(command) => match command {
             Commands::Repl { parse } => {
                 println!("Starting OPL REPL (parse only: {})", parse);
                 repl::start(parse);
             },
-            Commands::Run { file } => {
-                let input = match fs::read_to_string(&file) {
-                    Ok(content) => content,
-                    Err(e) => {
-                        eprintln!("Error reading file '{}': {}", file, e);
-                        return;
-                    }
-                };
-
-                let lexer = lexer::Lexer::new(&input);
-                let mut parser = parser::Parser::new(lexer);
-                let mut evaluator = evaluator::Evaluator::new(Rc::new(RefCell::new(environment::Env::new())));
-                let program = parser.parse_program();
-                
-                if !parser.errors.is_empty() {
-                    eprintln!("Parser errors:");
-                    for error in &parser.errors {
-                        eprintln!("  {:#?}", error);
+            Commands::Run { file, watch, keep_env, allow, warn, deny, error_format, max_heap, debug, debug_on_error, profile_out, profile_in, record, replay } => {
+                let mut config = DiagnosticConfig::default();
+                config.levels.extend(allow.into_iter().map(|lint| (lint, Level::Allow)));
+                config.levels.extend(warn.into_iter().map(|lint| (lint, Level::Warn)));
+                config.levels.extend(deny.into_iter().map(|lint| (lint, Level::Deny)));
+
+                let options = RunOptions { config, format: error_format, max_heap, contracts: debug, debug_on_error, profile_out, profile_in, record, replay };
+                let env = Rc::new(RefCell::new(environment::Env::new()));
+                let mut includes = Vec::new();
+                let status = run_file(&file, Rc::clone(&env), &options, &mut includes);
+                if watch {
+                    watch_file(&file, env, keep_env, &options, includes);
+                }
+                if let Some(status) = status {
+                    std::process::exit(status);
+                }
+            },
+            Commands::Test { files, doc, coverage, lcov } => {
+                if !test_files(&files, &TestOptions { doc, coverage, lcov }) {
+                    std::process::exit(1);
+                }
+            },
+            Commands::Rename { file, position, new_name, write } => {
+                if let Err(message) = rename_file(&file, position, &new_name, write) {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            },
+            Commands::Graph { file, calls, deps: _ } => {
+                let mut sources = SourceMap::new();
+                let graph = parse_file(&mut sources, &file).and_then(|program| {
+                    let graph = if calls { graph::calls(&program) } else { graph::deps(&program) };
+                    graph.ok_or("cannot resolve the names in the file".to_string())
+                });
+                match graph {
+                    Ok(graph) => print!("{}", graph),
+                    Err(message) => {
+                        eprintln!("Error graphing '{}': {}", file, message);
+                        std::process::exit(1);
                     }
-                    return;
                 }
-
-                if let Some(result) = evaluator.eval(&program) {
-                    println!("{}", result);
+            },
+            Commands::Diff { left, right, semantic } => match diff_files(&left, &right, semantic) {
+                Ok(None) => {}
+                Ok(Some(difference)) => {
+                    println!("{}", difference);
+                    std::process::exit(1);
+                }
+                Err(message) => {
+                    eprintln!("Error comparing '{}' and '{}': {}", left, right, message);
+                    std::process::exit(2);
                 }
             },
             Commands::Zen => {
                 println!("{}", ZEN);
             },
+            Commands::Explain { code } => match code {
+                Some(code) => match diagnostics::explain(&code) {
+                    Some(explanation) => println!("{}", explanation),
+                    None => {
+                        eprintln!("Unknown error code '{}'", code);
+                        std::process::exit(1);
+                    }
+                },
+                None => {
+                    for explanation in diagnostics::CATALOG {
+                        println!("{}  {}", explanation.code, explanation.title);
+                    }
+                },
+            },
         },
     }
 } 
\ No newline at end of file
@@ -0,0 +1,816 @@
+// optimizer.rs
+//
+// Rewrites applied to a checked program before it is evaluated, after the
+// program's own rewrite rules, see `rewrite`. Every
+// rewrite keeps results for well typed programs, and evaluation order but
+// for pure expressions, which `effects::is_pure` tells apart: of those,
+// repeats are computed once and pipelines of callbacks are fused. When two
+// of them would both fail, the error reported may then be the other one.
+// Given a profile of an earlier run, small functions it called often are
+// also inlined, see `inline`, and the tests of `if` chains that it saw pass
+// most are tried first, see `order_tests`.
+
+use crate::ast::*;
+use crate::effects::is_pure;
+use crate::environment::Env;
+use crate::evaluator::{integer_arithmetic, Evaluator};
+use crate::lexer::{Span, Token};
+use crate::closure;
+use crate::macros::{children, statement_expression, Child};
+use crate::object::Object;
+use crate::profile::Profile;
+use crate::rewrite;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub fn optimize(program: Program) -> Program {
+    optimize_with(program, None)
+}
+
+/// `optimize`, ordering tests and inlining the hot functions by `profile`,
+/// if any.
+pub fn optimize_with(mut program: Program, profile: Option<&Profile>) -> Program {
+    // Where rewriting was stopped is reported by the lint
+    rewrite::rewrite(&mut program);
+    if let Some(profile) = profile {
+        order_tests(&mut program.statements, profile);
+        inline(&mut program.statements, profile);
+        closure::annotate(&mut program);
+    }
+    Program { statements: block(program.statements), ..program }
+}
+
+fn block(statements: Block) -> Block {
+    statements.into_iter().map(statement).collect()
+}
+
+fn statement(statement: Statement) -> Statement {
+    match statement {
+        Statement::Let(identifier, value, span) => Statement::Let(identifier, expression(value), span),
+        Statement::Return(value) => Statement::Return(expression(value)),
+        Statement::Expression(value) => Statement::Expression(expression(value)),
+        Statement::Assert(assertion) => Statement::Assert(Assertion { condition: expression(assertion.condition), ..assertion }),
+        other => other,
+    }
+}
+
+fn boxed(value: Expression) -> Box<Expression> {
+    Box::new(expression(value))
+}
+
+fn expressions(values: Vec<Expression>) -> Vec<Expression> {
+    values.into_iter().map(expression).collect()
+}
+
+fn expression(mut value: Expression) -> Expression {
+    match &mut value {
+        Expression::Infix(Infix::Concat, left, right, span) => concat_chain(left.take(), right.take(), *span),
+        Expression::Infix(Infix::Cons, left, right, span) => right_chain(Infix::Cons, left.take(), right.take(), *span),
+        Expression::OptionSome(inner) => Expression::OptionSome(boxed(inner.take())),
+        Expression::ResultOk(inner) => Expression::ResultOk(boxed(inner.take())),
+        Expression::ResultErr(inner) => Expression::ResultErr(boxed(inner.take())),
+        Expression::Literal(literal) => Expression::Literal(match std::mem::replace(literal, Literal::Unit) {
+            Literal::List(elements) => Literal::List(expressions(elements)),
+            Literal::Record(fields) => Literal::Record(fields.into_iter().map(|(k, v)| (k, expression(v))).collect()),
+            Literal::HashMap(entries) => {
+                Literal::HashMap(entries.into_iter().map(|(k, v)| (expression(k), expression(v))).collect())
+            }
+            other => other,
+        }),
+        Expression::Prefix(prefix, right) => Expression::Prefix(prefix.clone(), boxed(right.take())),
+        Expression::Infix(infix, left, right, span) => infix_chain(infix.clone(), left.take(), right.take(), *span),
+        Expression::Block(program) => Expression::Block(block(std::mem::take(program))),
+        Expression::If { condition, consequence, alternative } => Expression::If {
+            condition: boxed(condition.take()),
+            consequence: block(std::mem::take(consequence)),
+            alternative: alternative.take().map(block),
+        },
+        Expression::Function { parameters, body, captures } => Expression::Function {
+            parameters: std::mem::take(parameters),
+            body: Rc::new(common_subexpressions(block(Rc::unwrap_or_clone(std::mem::take(body))))),
+            captures: captures.take(),
+        },
+        Expression::Call { function, arguments, span } => Expression::Call {
+            function: boxed(function.take()),
+            arguments: expressions(std::mem::take(arguments)),
+            span: *span,
+        },
+        Expression::Match { expr, arms } => Expression::Match {
+            expr: boxed(expr.take()),
+            arms: std::mem::take(arms).into_iter().map(|(pattern, body)| (pattern, block(body))).collect(),
+        },
+        Expression::BuiltIn { function, arguments, span } => fuse(function.clone(), expressions(std::mem::take(arguments)), *span),
+        Expression::Range { start, end } => Expression::Range { start: boxed(start.take()), end: boxed(end.take()) },
+        Expression::Field { record, field, optional, cache } => {
+            Expression::Field { record: boxed(record.take()), field: field.clone(), optional: *optional, cache: std::mem::take(cache) }
+        }
+        Expression::Annotated { expression: inner, annotation } => {
+            Expression::Annotated { expression: boxed(inner.take()), annotation: annotation.clone() }
+        }
+        // Quoted code is a value, left as it was written
+        Expression::Identifier(_) | Expression::OptionNone | Expression::Quote(..) => value,
+    }
+}
+
+// A long chain like `1 + 1 + ...` nests down its left side, which is walked
+// in a loop rather than recursion
+fn infix_chain(infix: Infix, left: Expression, right: Expression, span: Span) -> Expression {
+    let mut chain = vec![(infix, right, span)];
+    let mut leftmost = left;
+    while let Expression::Infix(infix, left, right, span) = &mut leftmost {
+        if *infix == Infix::Concat {
+            break;
+        }
+        chain.push((infix.clone(), right.take(), *span));
+        leftmost = left.take();
+    }
+    let mut left = expression(leftmost);
+    while let Some((infix, right, span)) = chain.pop() {
+        left = fold(infix, left, expression(right), span);
+    }
+    left
+}
+
+// `::` and `++` group to the right, so their chains nest down the right side
+fn right_chain(infix: Infix, left: Expression, right: Expression, span: Span) -> Expression {
+    let mut chain = vec![(left, span)];
+    let mut rightmost = right;
+    while let Expression::Infix(next, left, right, span) = &mut rightmost {
+        if *next != infix {
+            break;
+        }
+        chain.push((left.take(), *span));
+        rightmost = right.take();
+    }
+    let mut right = expression(rightmost);
+    while let Some((left, span)) = chain.pop() {
+        right = Expression::Infix(infix.clone(), Box::new(expression(left)), Box::new(right), span);
+    }
+    right
+}
+
+// `60 * 60` becomes `3600`, by the evaluator's own arithmetic. Operations
+// that fail, such as `1 / 0`, are left for the evaluator to report.
+fn fold(infix: Infix, left: Expression, right: Expression, span: Span) -> Expression {
+    if let (Expression::Literal(Literal::Integer(a)), Expression::Literal(Literal::Integer(b))) = (&left, &right) {
+        if let Some(Object::Integer(value)) = integer_arithmetic(&infix, *a, *b) {
+            return Expression::Literal(Literal::Integer(value));
+        }
+    }
+    Expression::Infix(infix, Box::new(left), Box::new(right), span)
+}
+
+/// The literal value of the initializer of a `const`, folded from literals,
+/// operators and the `constants` defined before it. Anything else, such as a
+/// call, could have effects, and is an error.
+pub fn constant(value: &Expression, constants: &HashMap<String, Expression>) -> Result<Expression, String> {
+    let folded = match value {
+        Expression::Literal(Literal::List(elements)) => {
+            Expression::Literal(Literal::List(elements.iter().map(|e| constant(e, constants)).collect::<Result<_, _>>()?))
+        }
+        Expression::Literal(Literal::Record(fields)) => Expression::Literal(Literal::Record(
+            fields.iter().map(|(name, e)| Ok((name.clone(), constant(e, constants)?))).collect::<Result<_, String>>()?,
+        )),
+        Expression::Literal(Literal::HashMap(_)) => return Err("a hashmap is not a constant".to_string()),
+        Expression::Literal(_) | Expression::OptionNone => value.clone(),
+        Expression::OptionSome(inner) => Expression::OptionSome(Box::new(constant(inner, constants)?)),
+        Expression::ResultOk(inner) => Expression::ResultOk(Box::new(constant(inner, constants)?)),
+        Expression::ResultErr(inner) => Expression::ResultErr(Box::new(constant(inner, constants)?)),
+        Expression::Identifier(name) => match constants.get(&name.to_string()) {
+            Some(value) => value.clone(),
+            None => return Err(format!("{} is not a constant", name)),
+        },
+        Expression::Prefix(prefix, right) => evaluate(Expression::Prefix(prefix.clone(), Box::new(constant(right, constants)?)))?,
+        Expression::Infix(infix, left, right, span) => {
+            let (left, right) = (constant(left, constants)?, constant(right, constants)?);
+            evaluate(Expression::Infix(infix.clone(), Box::new(left), Box::new(right), *span))?
+        }
+        _ => return Err("a constant can only be made of literals, operators and other constants".to_string()),
+    };
+    Ok(folded)
+}
+
+// An operation on literals, by the evaluator
+fn evaluate(operation: Expression) -> Result<Expression, String> {
+    let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+    match evaluator.eval(&[Statement::Expression(operation)]) {
+        Some(Object::Error(_, ref mut message)) => Err(std::mem::take(message)),
+        Some(object) => literal(object).ok_or_else(|| "the value is not a constant".to_string()),
+        None => Err("the value is not a constant".to_string()),
+    }
+}
+
+fn literal(mut object: Object) -> Option<Expression> {
+    let literal = match &mut object {
+        Object::Unit => Literal::Unit,
+        Object::Integer(n) => Literal::Integer(*n),
+        Object::Float(n) => Literal::Float(*n),
+        Object::Boolean(b) => Literal::Boolean(*b),
+        Object::String(s) => Literal::String(s.to_string()),
+        Object::List(elements) => Literal::List(std::mem::take(elements).into_iter().map(literal).collect::<Option<_>>()?),
+        Object::Record(fields) => Literal::Record(
+            std::mem::take(fields).into_iter().map(|(name, value)| Some((Token::Identifier(name), literal(value)?))).collect::<Option<_>>()?,
+        ),
+        Object::OptionSome(inner) => return Some(Expression::OptionSome(Box::new(literal(std::mem::take(inner))?))),
+        Object::OptionNone => return Some(Expression::OptionNone),
+        Object::ResultOk(inner) => return Some(Expression::ResultOk(Box::new(literal(std::mem::take(inner))?))),
+        Object::ResultErr(inner) => return Some(Expression::ResultErr(Box::new(literal(std::mem::take(inner))?))),
+        _ => return None,
+    };
+    Some(Expression::Literal(literal))
+}
+
+// A callback of one parameter that is pure, its parameter and body
+fn stage(callback: &Expression) -> Option<(&Identifier, &Block)> {
+    match callback {
+        Expression::Function { parameters, body, .. } if parameters.len() == 1 && is_pure(callback) => Some((&parameters[0], body)),
+        _ => None,
+    }
+}
+
+// `map(f, map(g, xs))` becomes `map(fn x -> f(g(x)), xs)`, and
+// `filter(p, filter(q, xs))` becomes `filter(fn x -> q(x) && p(x), xs)`,
+// one pass over the list instead of two, when the callbacks are pure
+// function literals. Each body runs in a block binding its own parameter.
+fn fuse(function: Token, arguments: Vec<Expression>, span: Span) -> Expression {
+    let fused = match (&function, arguments.as_slice()) {
+        (Token::Map | Token::Filter, [outer, Expression::BuiltIn { function: inner, arguments: inner_arguments, .. }]) if *inner == function => {
+            match (stage(outer), inner_arguments.as_slice()) {
+                (Some(second), [first, list]) => stage(first).map(|first| (second, first, list)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    let Some(((second, second_body), (first, first_body), list)) = fused else {
+        return Expression::BuiltIn { function, arguments, span };
+    };
+    // Not a name a script can write, so it shadows nothing
+    let element = Token::Identifier("element#".to_string());
+    let applied = |parameter: &Identifier, body: &Block| {
+        let bind = Statement::Let(parameter.clone(), Expression::Identifier(element.clone()), Span::default());
+        Expression::Block(std::iter::once(bind).chain(body.iter().cloned()).collect())
+    };
+    let body = if function == Token::Map {
+        let bind = Statement::Let(second.clone(), applied(first, first_body), Span::default());
+        std::iter::once(bind).chain(second_body.iter().cloned()).collect()
+    } else {
+        vec![Statement::Expression(Expression::If {
+            condition: Box::new(applied(first, first_body)),
+            consequence: vec![Statement::Expression(applied(second, second_body))],
+            alternative: Some(vec![Statement::Expression(Expression::Literal(Literal::Boolean(false)))]),
+        })]
+    };
+    let callback = Expression::Function { parameters: vec![element], body: Rc::new(body), captures: None };
+    Expression::BuiltIn { function, arguments: vec![callback, list.clone()], span }
+}
+
+// Calls `f` on `expression` and, unless it returns true, on each
+// subexpression evaluated whenever `expression` is: not those in blocks,
+// branches or function bodies, nor quoted code or the default of a `??`
+fn always_evaluated(expression: &mut Expression, f: &mut impl FnMut(&mut Expression) -> bool) {
+    if f(expression) || matches!(expression, Expression::Quote(..)) {
+        return;
+    }
+    if let Expression::Infix(Infix::Coalesce, left, _, _) = expression {
+        return always_evaluated(left, f);
+    }
+    for child in children(expression) {
+        if let Child::Expression(child) = child {
+            always_evaluated(child, f);
+        }
+    }
+}
+
+// An operation on names, scalars and fields, so pure, and worth computing
+// once when it repeats
+fn shareable(expression: &Expression) -> bool {
+    fn simple(expression: &Expression) -> bool {
+        match expression {
+            Expression::Identifier(_) | Expression::OptionNone => true,
+            Expression::Literal(literal) => !matches!(literal, Literal::List(_) | Literal::Record(_) | Literal::HashMap(_)),
+            Expression::Infix(infix, left, right, _) => !matches!(infix, Infix::Pipe | Infix::Coalesce) && simple(left) && simple(right),
+            Expression::Prefix(_, inner) | Expression::Field { record: inner, .. } => simple(inner),
+            _ => false,
+        }
+    }
+    matches!(expression, Expression::Infix(..) | Expression::Prefix(..)) && simple(expression)
+}
+
+// `expression` without the spans of its operators, to compare repeats by
+fn unspanned(expression: &Expression) -> Expression {
+    let mut expression = expression.clone();
+    always_evaluated(&mut expression, &mut |e| {
+        if let Expression::Infix(_, _, _, span) = e {
+            *span = Span::default();
+        }
+        false
+    });
+    expression
+}
+
+fn size(expression: &Expression) -> usize {
+    let mut size = 0;
+    always_evaluated(&mut expression.clone(), &mut |_| {
+        size += 1;
+        false
+    });
+    size
+}
+
+// Names bound by `statement` in the scope it is in, or in blocks within it
+fn bound(statement: &mut Statement, names: &mut Vec<String>) {
+    fn walk(expression: &mut Expression, names: &mut Vec<String>) {
+        if matches!(expression, Expression::Function { .. }) {
+            return;
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(child) => walk(child, names),
+                Child::Block(block) => block.iter_mut().for_each(|statement| bound(statement, names)),
+            }
+        }
+    }
+    if let Statement::Let(name, ..) = statement {
+        names.push(name.to_string());
+    }
+    if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+        walk(value, names);
+    }
+}
+
+// Repeats of a pure expression in a function's body, e.g. `p.x * p.x` in
+// `(p.x * p.x + 1) / (p.x * p.x)`, are computed once into a local before
+// the first statement using it. Only the parts of statements that always
+// run are shared, so nothing is computed that would not have been, and only
+// while the names the expression reads keep their bindings. The branches of
+// an if bind into the function's scope, so locals are numbered through the
+// whole body.
+fn common_subexpressions(body: Block) -> Block {
+    let mut count = 0;
+    share(body, &mut count)
+}
+
+fn share(mut block: Block, count: &mut usize) -> Block {
+    for statement in block.iter_mut() {
+        if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+            share_within(value, count);
+        }
+    }
+    loop {
+        // Each distinct candidate, with its first occurrence and the
+        // statements it occurs in
+        let mut repeats: Vec<(Expression, Expression, Vec<usize>)> = Vec::new();
+        for (index, statement) in block.iter_mut().enumerate() {
+            if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+                always_evaluated(value, &mut |e| {
+                    if shareable(e) {
+                        let key = unspanned(e);
+                        match repeats.iter_mut().find(|(seen, ..)| *seen == key) {
+                            Some((_, _, statements)) => statements.push(index),
+                            None => repeats.push((key, e.clone(), vec![index])),
+                        }
+                    }
+                    false
+                });
+            }
+        }
+        let mut best: Option<(usize, usize)> = None;
+        for (candidate, (key, _, statements)) in repeats.iter().enumerate() {
+            let (first, last) = (statements[0], statements[statements.len() - 1]);
+            if statements.len() < 2 || best.is_some_and(|(_, size_of_best)| size_of_best >= size(key)) {
+                continue;
+            }
+            let mut rebound = Vec::new();
+            block[first..last].iter_mut().for_each(|statement| bound(statement, &mut rebound));
+            let mut reads = Vec::new();
+            always_evaluated(&mut key.clone(), &mut |e| {
+                if let Expression::Identifier(name) = e {
+                    reads.push(name.to_string());
+                }
+                false
+            });
+            if !reads.iter().any(|name| rebound.contains(name)) {
+                best = Some((candidate, size(key)));
+            }
+        }
+        let Some((candidate, _)) = best else {
+            return block;
+        };
+        let (key, first_occurrence, statements) = repeats.swap_remove(candidate);
+        let name = Token::Identifier(format!("shared#{}", count));
+        *count += 1;
+        for statement in &mut block[statements[0]..=statements[statements.len() - 1]] {
+            if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+                always_evaluated(value, &mut |e| {
+                    let repeat = shareable(e) && unspanned(e) == key;
+                    if repeat {
+                        *e = Expression::Identifier(name.clone());
+                    }
+                    repeat
+                });
+            }
+        }
+        block.insert(statements[0], Statement::Let(name, first_occurrence, Span::default()));
+    }
+}
+
+// Shares within the blocks and branches of `expression`, but not the bodies
+// of function literals, which are shared on their own
+fn share_within(expression: &mut Expression, count: &mut usize) {
+    if matches!(expression, Expression::Function { .. } | Expression::Quote(..)) {
+        return;
+    }
+    for child in children(expression) {
+        match child {
+            Child::Expression(child) => share_within(child, count),
+            Child::Block(block) => *block = share(std::mem::take(block), count),
+        }
+    }
+}
+
+// `if x == 1 { a } else if x == 2 { b } else { c }`, as `cond` also writes,
+// tests one name against distinct literals, so at most one test passes and
+// the tests can be tried in any order: those the profile saw pass most go
+// first.
+fn order_tests(statements: &mut Block, profile: &Profile) {
+    fn walk(expression: &mut Expression, profile: &Profile) {
+        if matches!(expression, Expression::If { .. }) {
+            order_chain(expression, profile);
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(child) => walk(child, profile),
+                Child::Block(block) => order_tests(block, profile),
+            }
+        }
+    }
+    for statement in statements.iter_mut() {
+        if let Some(expression) = statement_expression(statement) {
+            walk(expression, profile);
+        }
+    }
+}
+
+// The name and literal a condition compares, and its operator's span
+fn equality_test(condition: &Expression) -> Option<(&Identifier, &Literal, Span)> {
+    let Expression::Infix(Infix::Equal, left, right, span) = condition else {
+        return None;
+    };
+    match (&**left, &**right) {
+        (Expression::Identifier(name), Expression::Literal(literal)) | (Expression::Literal(literal), Expression::Identifier(name)) => {
+            let scalar = matches!(literal, Literal::Integer(_) | Literal::Float(_) | Literal::String(_) | Literal::Boolean(_) | Literal::Char(_));
+            scalar.then_some((name, literal, *span))
+        }
+        _ => None,
+    }
+}
+
+fn order_chain(expression: &mut Expression, profile: &Profile) {
+    // The tests of the chain, with what each compares, its branch and how
+    // often it passed, then what runs when none does
+    let mut tests: Vec<(Identifier, Literal, Expression, Block, u64)> = Vec::new();
+    let mut otherwise = Some(vec![Statement::Expression(expression.take())]);
+    while let Some([Statement::Expression(Expression::If { condition, consequence, alternative })]) = otherwise.as_deref_mut() {
+        let Some((name, literal, span)) = equality_test(condition) else {
+            break;
+        };
+        if tests.iter().any(|(subject, seen, ..)| subject != name || seen == literal) {
+            break;
+        }
+        let taken = profile.branches.get(&(span.file, span.line, span.column)).map_or(0, |(taken, _)| *taken);
+        tests.push((name.clone(), literal.clone(), condition.take(), std::mem::take(consequence), taken));
+        otherwise = alternative.take();
+    }
+    if tests.len() > 1 {
+        tests.sort_by_key(|test| std::cmp::Reverse(test.4));
+    }
+    let mut chain = otherwise;
+    while let Some((_, _, condition, consequence, _)) = tests.pop() {
+        chain = Some(vec![Statement::Expression(Expression::If { condition: Box::new(condition), consequence, alternative: chain })]);
+    }
+    if let Some(Statement::Expression(ordered)) = chain.and_then(|mut block| block.pop()) {
+        *expression = ordered;
+    }
+}
+
+// A function the profile counted at least this many calls of is hot
+const HOT_CALLS: u64 = 100;
+// The most nodes the body of an inlined function has, see `nodes`
+const INLINE_NODES: usize = 24;
+
+// Calls of a hot function become a block binding its parameters to the
+// arguments, then its body: with `let twice = fn x -> x * 2;`, `twice(n)`
+// becomes `{ let x = n; x * 2 }`. Only small functions bound by a top level
+// let are inlined, those whose name and free variables are bound nowhere
+// else, and whose body is one expression without a `return`, so it means
+// the same wherever it is put. Calls after the let are inlined.
+fn inline(statements: &mut Block, profile: &Profile) {
+    let mut bindings = HashMap::new();
+    statements.iter_mut().for_each(|statement| count_bindings(statement, &mut bindings));
+    let mut inlined = HashMap::new();
+    for statement in statements.iter_mut() {
+        if !inlined.is_empty() {
+            inline_calls(statement, &inlined);
+        }
+        let Statement::Let(name, Expression::Function { parameters, body, captures: Some(free) }, _) = statement else {
+            continue;
+        };
+        let name = name.to_string();
+        let hot = profile.calls.get(&name).is_some_and(|calls| *calls >= HOT_CALLS);
+        let settled = bindings.get(&name) == Some(&1) && free.iter().all(|free| !bindings.contains_key(free));
+        if let (true, [Statement::Expression(value)]) = (hot && settled, body.as_slice()) {
+            let mut value = value.clone();
+            if nodes(&mut value) <= INLINE_NODES && !returns(&mut value) {
+                inlined.insert(name, (parameters.clone(), value));
+            }
+        }
+    }
+}
+
+// How many times each name is bound, by lets and parameters, in
+// `statement` and the blocks and functions within it
+fn count_bindings(statement: &mut Statement, bindings: &mut HashMap<String, usize>) {
+    fn walk(expression: &mut Expression, bindings: &mut HashMap<String, usize>) {
+        if let Expression::Function { parameters, .. } = expression {
+            for parameter in parameters.iter() {
+                *bindings.entry(parameter.to_string()).or_insert(0) += 1;
+            }
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(child) => walk(child, bindings),
+                Child::Block(block) => block.iter_mut().for_each(|statement| count_bindings(statement, bindings)),
+            }
+        }
+    }
+    if let Statement::Let(name, ..) = statement {
+        *bindings.entry(name.to_string()).or_insert(0) += 1;
+    }
+    if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+        walk(value, bindings);
+    }
+    if let Statement::Assert(assertion) = statement {
+        walk(&mut assertion.condition, bindings);
+    }
+}
+
+fn nodes(expression: &mut Expression) -> usize {
+    let mut count = 1;
+    for child in children(expression) {
+        match child {
+            Child::Expression(child) => count += nodes(child),
+            Child::Block(block) => {
+                for statement in block.iter_mut() {
+                    if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+                        count += nodes(value);
+                    }
+                    if let Statement::Assert(assertion) = statement {
+                        count += nodes(&mut assertion.condition);
+                    }
+                }
+            }
+        }
+    }
+    count
+}
+
+// Whether `expression` returns from the function it is in
+fn returns(expression: &mut Expression) -> bool {
+    if matches!(expression, Expression::Function { .. }) {
+        return false;
+    }
+    children(expression).into_iter().any(|child| match child {
+        Child::Expression(child) => returns(child),
+        Child::Block(block) => block.iter_mut().any(|statement| match statement {
+            Statement::Return(_) => true,
+            Statement::Let(_, value, _) | Statement::Expression(value) => returns(value),
+            _ => false,
+        }),
+    })
+}
+
+// Inlines the calls in `statement` of the functions in `inlined`, with
+// their parameters and bodies
+fn inline_calls(statement: &mut Statement, inlined: &HashMap<String, (Vec<Identifier>, Expression)>) {
+    fn walk(expression: &mut Expression, inlined: &HashMap<String, (Vec<Identifier>, Expression)>) {
+        if matches!(expression, Expression::Quote(..)) {
+            return;
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(child) => walk(child, inlined),
+                Child::Block(block) => block.iter_mut().for_each(|statement| inline_calls(statement, inlined)),
+            }
+        }
+        let Expression::Call { function, arguments, .. } = expression else {
+            return;
+        };
+        let Expression::Identifier(name) = &**function else {
+            return;
+        };
+        if let Some((parameters, body)) = inlined.get(&name.to_string()).filter(|(parameters, _)| parameters.len() == arguments.len()) {
+            *expression = Expression::Block(inlined_call(parameters, std::mem::take(arguments), body.clone()));
+        }
+    }
+    if let Statement::Let(_, value, _) | Statement::Expression(value) | Statement::Return(value) = statement {
+        walk(value, inlined);
+    }
+    if let Statement::Assert(assertion) = statement {
+        walk(&mut assertion.condition, inlined);
+    }
+}
+
+// The arguments of a call of more than one parameter are bound to locals
+// first, so that none is evaluated with a parameter in scope: `f(y, x)` of
+// `fn x, y -> ...` is `{ let argument#0 = y; let argument#1 = x; let x =
+// argument#0; let y = argument#1; ... }`
+fn inlined_call(parameters: &[Identifier], arguments: Vec<Expression>, body: Expression) -> Block {
+    let mut block = Vec::new();
+    if parameters.len() == 1 {
+        block.extend(arguments.into_iter().map(|argument| Statement::Let(parameters[0].clone(), argument, Span::default())));
+    } else {
+        let locals: Vec<Identifier> = (0..parameters.len()).map(|index| Token::Identifier(format!("argument#{}", index))).collect();
+        block.extend(locals.iter().zip(arguments).map(|(local, argument)| Statement::Let(local.clone(), argument, Span::default())));
+        block.extend(parameters.iter().zip(&locals).map(|(parameter, local)| {
+            Statement::Let(parameter.clone(), Expression::Identifier(local.clone()), Span::default())
+        }));
+    }
+    block.push(Statement::Expression(body));
+    block
+}
+
+// The operands of a chain of `++`, left to right
+fn concat_operands(left: &Expression, right: &Expression) -> Vec<Expression> {
+    let mut operands = Vec::new();
+    let mut pending = vec![right, left];
+    while let Some(operand) = pending.pop() {
+        match operand {
+            Expression::Infix(Infix::Concat, left, right, _) => {
+                pending.push(right);
+                pending.push(left);
+            }
+            other => operands.push(other.clone()),
+        }
+    }
+    operands
+}
+
+// `a ++ b ++ c` becomes `concat_all([a, b, c])`, copying each piece once
+// instead of building a rope node per `++`. A string literal among the
+// operands is what shows the chain joins strings, so chains without one are
+// left alone.
+fn concat_chain(left: Expression, right: Expression, span: Span) -> Expression {
+    let operands = concat_operands(&left, &right);
+    let has_string = operands.iter().any(|o| matches!(o, Expression::Literal(Literal::String(_))));
+    if operands.len() < 3 || !has_string {
+        return right_chain(Infix::Concat, left, right, span);
+    }
+    Expression::BuiltIn {
+        function: Token::ConcatAll,
+        arguments: vec![Expression::Literal(Literal::List(expressions(operands)))],
+        span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn optimized(source: &str) -> Program {
+        optimize(Parser::new(Lexer::new(source)).parse_program())
+    }
+
+    #[test]
+    fn test_concat_chain_becomes_concat_all() {
+        let program = optimized("\"<\" ++ tag ++ (\">\" ++ body)");
+        let Statement::Expression(Expression::BuiltIn { function: Token::ConcatAll, arguments, .. }) = &program[0] else {
+            panic!("expected concat_all, got {:?}", program);
+        };
+        let Expression::Literal(Literal::List(operands)) = &arguments[0] else {
+            panic!("expected a list argument, got {:?}", arguments);
+        };
+        assert_eq!(operands.len(), 4);
+
+        // Two operands, or no string literal to show these are strings
+        assert!(matches!(optimized("a ++ \"b\"")[0], Statement::Expression(Expression::Infix(..))));
+        assert!(matches!(optimized("a ++ b ++ c")[0], Statement::Expression(Expression::Infix(..))));
+    }
+
+    #[test]
+    fn test_folds_integer_arithmetic() {
+        assert_eq!(optimized("(0 - 7) / 2 * 10 + (0 - 7) % 2")[0], Statement::Expression(Expression::Literal(Literal::Integer(-31))));
+        assert!(matches!(optimized("1 + 1 / 0")[0], Statement::Expression(Expression::Infix(Infix::Plus, ..))));
+    }
+
+    #[test]
+    fn test_folds_long_chain() {
+        let chain = vec!["1"; 3000].join(" + ");
+        assert_eq!(optimized(&chain)[0], Statement::Expression(Expression::Literal(Literal::Integer(3000))));
+        let chain = vec!["1 + 1"; 3000].join(" :: ");
+        let Statement::Expression(Expression::Infix(Infix::Cons, head, _, _)) = &optimized(&format!("{} :: []", chain))[0] else {
+            panic!("expected a cons");
+        };
+        assert_eq!(**head, Expression::Literal(Literal::Integer(2)));
+    }
+
+    #[test]
+    fn test_pure_repeats_are_shared() {
+        let run = |source: &str| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&optimized(source));
+        let program = optimized("let f = fn p -> { let d = p.x * p.x; (p.x * p.x + d) / (p.x * p.x) };");
+        let Statement::Let(_, Expression::Function { body, .. }, _) = &program[0] else { panic!("expected a function, got {:?}", program) };
+        assert!(matches!(&body[0], Statement::Let(Token::Identifier(name), Expression::Infix(Infix::Product, ..), _) if name == "shared#0"));
+        assert_eq!(body.len(), 3);
+        assert_eq!(run("let f = fn p -> { let d = p.x * p.x; (p.x * p.x + d) / (p.x * p.x) };\nf({ x = 3 })"), Some(Object::Integer(2)));
+
+        // Not out of a branch, nor past a new binding of what it reads
+        assert_eq!(optimized("let f = fn x -> if x > 0 { 10 / x } else { 10 / x };"), Parser::new(Lexer::new("let f = fn x -> if x > 0 { 10 / x } else { 10 / x };")).parse_program());
+        assert_eq!(run("let x = 2;\nlet f = fn y -> { let a = x * y; let x = 5; a + x * y };\nf(3)"), Some(Object::Integer(21)));
+    }
+
+    #[test]
+    fn test_pipelines_are_fused() {
+        let run = |source: &str| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&optimized(source));
+        let fused = |source: &str| match &optimized(source)[0] {
+            Statement::Expression(Expression::BuiltIn { arguments, .. }) => !matches!(arguments[1], Expression::BuiltIn { .. }),
+            other => panic!("expected a builtin call, got {:?}", other),
+        };
+        let maps = "map(fn x -> x * 10, map(fn x -> x + 1, map(fn y -> y * y, [1, 2, 3])))";
+        assert!(fused(maps));
+        assert_eq!(run(maps), Some(Object::List(vec![Object::Integer(20), Object::Integer(50), Object::Integer(100)])));
+        let filters = "filter(fn x -> x % 2 == 0, filter(fn x -> x > 2, [1, 2, 3, 4, 5, 6]))";
+        assert!(fused(filters));
+        assert_eq!(run(filters), Some(Object::List(vec![Object::Integer(4), Object::Integer(6)])));
+
+        // Effects stay in the order they were written
+        assert!(!fused("map(fn x -> x + 1, map(fn x -> dbg(x), [1]))"));
+        assert!(!fused("map(fn x -> f(x), map(fn x -> x, [1]))"));
+    }
+
+    #[test]
+    fn test_hot_functions_are_inlined() {
+        let profile = Profile { calls: [("sub", 500), ("twice", 500), ("count", 500), ("cold", 1)].map(|(name, calls)| (name.to_string(), calls)).into(), ..Profile::default() };
+        let optimized = |source: &str| optimize_with(Parser::new(Lexer::new(source)).parse_program(), Some(&profile));
+        let run = |source: &str| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&optimized(source));
+        let source = "let sub = fn x, y -> x - y;\nlet x = 10;\nlet y = 3;\nsub(y, x) + sub(x, y)";
+        let program = optimized(source);
+        let Statement::Expression(Expression::Infix(_, left, _, _)) = &program[3] else { panic!("expected +, got {:?}", program[3]) };
+        assert!(matches!(**left, Expression::Block(_)));
+        assert_eq!(run(source), Some(Object::Integer(0)));
+        assert_eq!(run("let twice = fn x -> x * 2;\nmap(fn x -> twice(twice(x)), [1, 2])"), Some(Object::List(vec![Object::Integer(4), Object::Integer(8)])));
+
+        // Not cold functions, nor those using names bound elsewhere, nor
+        // calls before the function is bound
+        let unchanged = |source: &str| optimized(source) == optimize(Parser::new(Lexer::new(source)).parse_program());
+        assert!(unchanged("let cold = fn x -> x;\ncold(1)"));
+        assert!(unchanged("let n = 1;\nlet twice = fn x -> x * n;\ntwice(1)"));
+        assert!(unchanged("let count = fn xs -> if xs == [] { 0 } else { 1 + count(tail(xs)) };\ncount([1])"));
+        assert!(unchanged("let g = fn -> twice(1);\nlet twice = fn x -> x * 2;"));
+    }
+
+    #[test]
+    fn test_tests_are_ordered_by_profile() {
+        let run = |program: &Program| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(program);
+        // The program optimized by a profile of its own run, and its value
+        let profiled = |source: &str| {
+            crate::profile::start();
+            let value = run(&Parser::new(Lexer::new(source)).parse_program());
+            let profile = crate::profile::finish().unwrap();
+            (optimize_with(Parser::new(Lexer::new(source)).parse_program(), Some(&profile)), value)
+        };
+        let source = "let name = fn n -> cond {\n  | n == 1 -> \"one\"\n  | 2 == n -> \"two\"\n  | n == 3 -> \"three\"\n  | else -> \"many\"\n};\nmap(name, [3, 3, 2, 3, 5])";
+        let (program, value) = profiled(source);
+        let Statement::Let(_, Expression::Function { body, .. }, _) = &program[0] else { panic!("expected a function, got {:?}", program[0]) };
+        let mut tested = Vec::new();
+        let mut branch = body.as_slice();
+        while let [Statement::Expression(Expression::If { condition, alternative, .. })] = branch {
+            tested.push(equality_test(condition).map(|(_, literal, _)| literal.clone()));
+            branch = alternative.as_deref().unwrap_or_default();
+        }
+        assert_eq!(tested, [3, 2, 1].map(|n| Some(Literal::Integer(n))));
+        assert_eq!(run(&program), value);
+
+        // Tests of different names, or of other than equality with a literal,
+        // keep their order
+        let unchanged = |source: &str| profiled(source).0 == optimize(Parser::new(Lexer::new(source)).parse_program());
+        assert!(unchanged("let f = fn n, m -> if n == 1 { 1 } else if m == 3 { 3 } else { 0 };\nf(2, 3) + f(2, 3)"));
+        assert!(unchanged("let f = fn n -> if n == 1 { 1 } else if n > 2 { 2 } else { 0 };\nf(3) + f(3)"));
+    }
+
+    #[test]
+    fn test_const_is_folded() {
+        let program = optimized("const PI = 3.5;\nconst TAU = 2.0 * PI;\nconst LIMITS = { low = -(1), high = [1 << 10] };");
+        assert!(matches!(&program[1], Statement::Let(_, Expression::Literal(Literal::Float(tau)), _) if *tau == 7.0));
+        let limits = optimized("{ low = 0 - 1, high = [1024] }");
+        assert!(matches!((&program[2], &limits[0]), (Statement::Let(_, value, _), Statement::Expression(literal)) if value == literal));
+
+        let errors = Parser::new(Lexer::new("let r = 2;\nconst A = r * 2;\nconst B = 1 / 0;\nconst C = abs(2);")).parse_program().errors;
+        let messages: Vec<String> = errors.iter().map(|error| error.message()).collect();
+        assert_eq!(messages[0], "const A is not known at compile time: r is not a constant");
+        assert_eq!(messages[1], "const B is not known at compile time: Cannot divide 1 by zero");
+        assert!(messages[2].starts_with("const C is not known at compile time: a constant can only be made of"));
+    }
+}
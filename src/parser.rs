@@ -1,52 +1,219 @@
 use crate::ast::*;
-use crate::lexer::{Lexer, Token};
+use crate::closure;
+use crate::derive;
+use crate::json;
+use crate::macros;
+use crate::optimizer;
+use crate::record::FieldCache;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::lexer::{Lexer, Span, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::io::Write;
 
-#[derive(Debug, PartialEq, Clone, PartialOrd)]
+#[derive(Debug, PartialEq, Clone, Copy, PartialOrd)]
 pub enum Precedence {
     Lowest,
     Pipe,        // |>
+    Coalesce,    // ??
     Equals,      // == =/=
-    LessGreater, // < >
-    Sum,         // + - ++
+    LessGreater, // < > in
+    Shift,       // << >>
+    Cons,        // :: ++, grouping to the right
+    Sum,         // + -
     Product,     // * / %
-    Cons,        // ::
     Prefix,      // - ! ~
     BitwiseOp,   // & ^
     Call,        // lambda x
                  // way to index into a list
 }
 
+// How deep the expressions of `statement` go, walked in a loop
+fn depth(statement: &mut Statement) -> usize {
+    let mut deepest = 0;
+    let mut pending: Vec<_> = macros::statement_expression(statement).map(|expression| (expression, 1)).into_iter().collect();
+    while let Some((expression, depth)) = pending.pop() {
+        deepest = deepest.max(depth);
+        for child in macros::children(expression) {
+            match child {
+                macros::Child::Expression(child) => pending.push((child, depth + 1)),
+                macros::Child::Block(block) => {
+                    pending.extend(block.iter_mut().filter_map(macros::statement_expression).map(|child| (child, depth + 1)));
+                }
+            }
+        }
+    }
+    deepest
+}
+
+// The source text and line of a `dbg` call are passed on to it after the
+// value, for it to print
+fn dbg(value: Expression, source: String, span: Span) -> Expression {
+    let location = [Literal::String(source), Literal::Integer(span.line as i64)].map(Expression::Literal);
+    let mut arguments = vec![value];
+    arguments.extend(location);
+    Expression::BuiltIn { function: Token::Dbg, arguments, span }
+}
+
 fn token_to_precedence(token: &Token) -> Precedence {
     match token {
         Token::Pipe => Precedence::Pipe,
+        Token::Coalesce => Precedence::Coalesce,
         Token::Equal | Token::DoesNotEqual => Precedence::Equals,
-        Token::LessThan | Token::GreaterThan | Token::GTOrEqual | Token::LTOrEqual => {
+        Token::LessThan | Token::GreaterThan | Token::GTOrEqual | Token::LTOrEqual | Token::In => {
             Precedence::LessGreater
         }
+        Token::ShiftLeft | Token::ShiftRight => Precedence::Shift,
         Token::Plus | Token::Minus => Precedence::Sum,
-        Token::Product | Token::ForwardSlash | Token::Period | Token::Modulo => Precedence::Product,
+        Token::Product | Token::ForwardSlash | Token::Modulo => Precedence::Product,
         Token::Cons | Token::Concat => Precedence::Cons,
         Token::Ampersand | Token::Caret => Precedence::BitwiseOp, // New precedence level needed
-        Token::LeftParen => Precedence::Call,
+        Token::LeftParen | Token::Unit | Token::Period | Token::OptionalPeriod => Precedence::Call,
         _ => Precedence::Lowest,
     }
 }
 
+// Precedences of user defined operators, by the level in their definition,
+// e.g. level 6 binds like `+`
+const OPERATOR_LEVELS: [Precedence; 8] = [
+    Precedence::Pipe,
+    Precedence::Equals,
+    Precedence::LessGreater,
+    Precedence::Shift,
+    Precedence::Cons,
+    Precedence::Sum,
+    Precedence::Product,
+    Precedence::BitwiseOp,
+];
+
+// Chars a user defined operator can be made of
+const OPERATOR_CHARS: &str = "!%&*+-./:<=>|~";
+
+// Levels of brackets, parentheses, blocks and operands an expression can
+// nest, as the parser recurses into each
+const MAX_NESTING: usize = 128;
+// Levels a statement's expression can be deep, each operator of a chain
+// like `1 + 2 + ...` counting as one, as later passes recurse into each
+const MAX_DEPTH: usize = 5000;
+
+/// How a chain of one operator groups, e.g. `a <+> b <+> c`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Associativity {
+    Left,
+    Right,
+    // Chaining is an error
+    None,
+}
+
+/// User defined operators by symbol, with their level and associativity.
+pub type Operators = HashMap<String, (usize, Associativity)>;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ParseError {
-    UnexpectedToken { want: Option<Token>, got: Token },
-    Log(String),
+    UnexpectedToken { want: Option<Token>, got: Token, span: Span },
+    Log(Code, String, Span),
+}
+
+impl ParseError {
+    pub fn code(&self) -> Code {
+        match self {
+            ParseError::UnexpectedToken { .. } => Code::UnexpectedToken,
+            ParseError::Log(code, _, _) => *code,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. } | ParseError::Log(_, _, span) => *span,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::UnexpectedToken { want: Some(want), got, .. } => format!("expected {}, got {}", want, got),
+            ParseError::UnexpectedToken { want: None, got, .. } => format!("unexpected {}", got),
+            ParseError::Log(_, message, _) => message.clone(),
+        }
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(error: &ParseError) -> Self {
+        Diagnostic::new(error.code(), error.message()).with_span(error.span())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code(), self.message())
+    }
 }
 
 pub type ParseErrors = Vec<ParseError>;
 
+// Builtin type names are soft keywords: the lexer reads them as identifiers,
+// so they can name bindings, fields and functions, and in type position they
+// always mean the builtin type, as no type can be declared with their names.
+fn type_keyword(token: &Token) -> Token {
+    let Token::Identifier(name) = token else {
+        return token.clone();
+    };
+    match name.as_str() {
+        "int" => Token::IntType,
+        "float" => Token::FloatType,
+        "string" => Token::StringType,
+        "char" => Token::CharType,
+        "bool" => Token::BoolType,
+        "unit" => Token::UnitType,
+        "dynamic" => Token::DynamicType,
+        "list" => Token::List,
+        "option" => Token::Option,
+        "result" => Token::Result,
+        "hashmap" => Token::HashMap,
+        "tuple" => Token::Tuple,
+        _ => token.clone(),
+    }
+}
+
 pub struct Parser {
     lexer: Lexer,
     pub curr: Token,
     pub peek: Token,
+    pub curr_span: Span,
+    pub peek_span: Span,
     pub errors: ParseErrors,
     pub log_file: Option<std::fs::File>,
+    // Collected into `Program::comments`
+    comments: Vec<(String, Span)>,
+    // Collected into `Program::identifiers`
+    identifiers: Vec<(String, Span)>,
+    // Collected into `Program::names`
+    names: Vec<Name>,
+    // Macros defined so far, including any from earlier input
+    pub macros: Vec<Macro>,
+    // Rewrite rules declared so far, including any from earlier input
+    pub rules: Vec<Rule>,
+    // Values of the constants defined so far, including any from earlier
+    // input
+    pub constants: HashMap<String, Expression>,
+    // The splices of the macro template being parsed, if any
+    splices: Option<Vec<(String, Span)>>,
+    // What `include_str` and `include_json` paths are relative to, if not
+    // the working directory
+    directory: Option<PathBuf>,
+    // Whether `include_str` and `include_json` may read files, which an
+    // engine without the fs effect forbids
+    includes: bool,
+    // The files included so far
+    included: Vec<PathBuf>,
+    // Calls of `parse_expression` in progress
+    nesting: usize,
+    // Once an expression nests too deeply, the number of errors up to the
+    // one reporting it, as the levels it unwinds through fail too
+    too_deep: Option<usize>,
+    pub operators: Operators,
 }
 
 impl Parser {
@@ -55,14 +222,50 @@ impl Parser {
             lexer,
             curr: Token::End,
             peek: Token::End,
+            curr_span: Span::default(),
+            peek_span: Span::default(),
             errors: Vec::new(),
             log_file: None,
+            comments: Vec::new(),
+            identifiers: Vec::new(),
+            names: Vec::new(),
+            macros: Vec::new(),
+            rules: Vec::new(),
+            constants: HashMap::new(),
+            splices: None,
+            directory: None,
+            includes: true,
+            included: Vec::new(),
+            nesting: 0,
+            too_deep: None,
+            operators: Operators::new(),
         };
         parser.next_token();
         parser.next_token();
         parser
     }
 
+    /// Starts with operators defined elsewhere, e.g. in earlier REPL input.
+    pub fn with_operators(lexer: Lexer, operators: &Operators) -> Self {
+        let mut lexer = lexer;
+        operators.keys().for_each(|symbol| lexer.add_operator(symbol));
+        let mut parser = Parser::new(lexer);
+        parser.operators = operators.clone();
+        parser
+    }
+
+    /// Includes files relative to `directory`, e.g. that of the source file.
+    pub fn with_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.directory = Some(directory.into());
+        self
+    }
+
+    /// Rejects `include_str` and `include_json` rather than read the file.
+    pub fn without_includes(mut self) -> Self {
+        self.includes = false;
+        self
+    }
+
     pub fn set_log_file(&mut self, file: std::fs::File) {
         self.log_file = Some(file);
     }
@@ -77,18 +280,49 @@ impl Parser {
 
     fn next_token(&mut self) {
         self.curr = self.peek.clone();
+        self.curr_span = self.peek_span;
         self.peek = self.lexer.advance();
+        // Illegal input is reported, then parsed as its recovery token or skipped
+        while let Token::Illegal(span, error) = &self.peek {
+            self.errors.push(ParseError::Log(error.code(), error.to_string(), *span));
+            self.peek = match error.recovery() {
+                Some(token) => token,
+                None => self.lexer.advance(),
+            };
+        }
+        self.peek_span = self.lexer.span;
     }
 
     pub fn parse_program(&mut self) -> Program {
-        let mut program = vec![];
+        let mut program = Program { span: self.curr_span, ..Program::default() };
         while self.curr != Token::End {
-            if let Some(statement) = self.parse_statement() {
-                program.push(statement);
-            } else if !self.errors.is_empty() {
+            let start = self.curr_span;
+            if let Some(mut statement) = self.parse_statement() {
+                if depth(&mut statement) > MAX_DEPTH {
+                    self.errors.push(ParseError::Log(Code::NestedTooDeeply, format!(
+                        "expression is more than {} levels deep; split it with lets",
+                        MAX_DEPTH
+                    ), start.to(self.curr_span)));
+                } else {
+                    program.statements.push(statement);
+                    program.spans.push(start.to(self.curr_span));
+                }
             }
+            program.span = program.span.to(self.curr_span);
             self.next_token();
         }
+        program.comments = std::mem::take(&mut self.comments);
+        program.identifiers = std::mem::take(&mut self.identifiers);
+        program.names = std::mem::take(&mut self.names);
+        program.macros = self.macros.clone();
+        if !program.macros.is_empty() {
+            let errors = macros::expand(&mut program);
+            self.errors.extend(errors);
+        }
+        program.rules = self.rules.clone();
+        program.includes = std::mem::take(&mut self.included);
+        closure::annotate(&mut program);
+        program.errors = self.errors.clone();
         program
     }
 
@@ -96,19 +330,162 @@ impl Parser {
         match self.curr {
             Token::Let => self.parse_let_statement(),
             Token::Return => self.parse_return_statement(),
-            Token::Comment(_) => Some(Statement::Comment(self.curr.clone())),
+            Token::Comment(ref text) => {
+                self.comments.push((text.clone(), self.curr_span));
+                Some(Statement::Comment(self.curr.clone()))
+            }
             Token::Type => self.parse_type_statement(),
+            Token::At => self.parse_attribute(),
+            Token::Assert => self.parse_assert_statement(),
+            Token::Identifier(ref name)
+                if matches!(name.as_str(), "infix" | "infixl" | "infixr") && matches!(self.peek, Token::IntegerLiteral(_)) =>
+            {
+                self.parse_operator_definition()
+            }
+            Token::Identifier(ref name)
+                if name == "macro" && matches!(self.peek, Token::Identifier(_)) && matches!(self.lexer.lookahead(), Token::LeftParen | Token::Unit) =>
+            {
+                self.parse_macro_definition()
+            }
+            Token::Identifier(ref name) if name == "rule" && matches!(self.peek, Token::StringLiteral(_)) => self.parse_rule_definition(),
+            Token::Identifier(ref name) if name == "const" && matches!(self.peek, Token::Identifier(_)) && self.lexer.lookahead() == Token::Assign => {
+                self.parse_const_statement()
+            }
             // TODO: Match
             _ => self.parse_expression_statement(),
         }
     }
 
-    fn parse_return_statement(&mut self) -> Option<Statement> {
+    // `const NAME = value;`, a let of the value folded at compile time
+    fn parse_const_statement(&mut self) -> Option<Statement> {
         self.next_token();
-        let expr = match self.parse_expression(Precedence::Lowest) {
-            Some(expr) => expr,
-            None => return None,
+        let span = self.curr_span;
+        let name = self.curr.to_string();
+        self.identifiers.push((name.clone(), span));
+        self.next_token();
+        self.next_token();
+        let start = self.curr_span;
+        let value = self.parse_expression(Precedence::Lowest)?;
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+        match optimizer::constant(&value, &self.constants) {
+            Ok(value) => {
+                self.constants.insert(name.clone(), value.clone());
+                Some(Statement::Let(Token::Identifier(name), value, span))
+            }
+            Err(message) => {
+                self.errors.push(ParseError::Log(Code::NotConstant, format!("const {} is not known at compile time: {}", name, message), start));
+                None
+            }
+        }
+    }
+
+    fn parse_attribute(&mut self) -> Option<Statement> {
+        let start = self.curr_span;
+        match &self.peek {
+            Token::Identifier(_) => self.next_token(),
+            _ => {
+                self.peek_error(Token::Identifier(String::new()));
+                return None;
+            }
+        }
+        let name = self.curr.clone();
+        let mut arguments = Vec::new();
+
+        if self.peek_token_is(Token::LeftParen) {
+            self.next_token();
+            while !self.peek_token_is(Token::RightParen) {
+                self.next_token();
+                match &self.curr {
+                    Token::Identifier(_) => arguments.push(self.curr.clone()),
+                    _ => {
+                        self.errors.push(ParseError::Log(Code::UnexpectedToken, format!(
+                            "Expected attribute argument, got {:?}",
+                            self.curr
+                        ), self.curr_span));
+                        return None;
+                    }
+                }
+                if self.peek_token_is(Token::Comma) {
+                    self.next_token();
+                }
+            }
+            self.next_token();
+        }
+
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+        if name == Token::Identifier("derive".to_string()) {
+            return self.parse_derive(arguments, start);
+        }
+        Some(Statement::Attribute(name, arguments))
+    }
+
+    // `@derive(...)`, given its arguments, and the type statement it is for
+    fn parse_derive(&mut self, derives: Vec<Identifier>, span: Span) -> Option<Statement> {
+        if !self.peek_token_is(Token::Type) {
+            self.errors.push(ParseError::Log(Code::MalformedDerive, "@derive must come right before a type statement".to_string(), span));
+            return None;
+        }
+        self.next_token();
+        let Statement::Type(name, declaration, _) = self.parse_type_statement()? else {
+            return None;
         };
+        for derive in &derives {
+            let error = match derive.to_string().as_str() {
+                other if !derive::DERIVABLE.contains(&other) => {
+                    format!("cannot derive {}; the derivable traits are: {}", other, derive::DERIVABLE.join(", "))
+                }
+                "enum" => match &declaration {
+                    Type::Union(variants) if variants.iter().all(|(_, payload)| payload.is_none()) => continue,
+                    _ => format!("@derive(enum) is for unions whose variants have no payload, and {} is not one", name),
+                },
+                other => match &declaration {
+                    Type::Alias(_) => format!("@derive({}) is for unions and record types, and {} is an alias", other, name),
+                    _ => continue,
+                },
+            };
+            self.errors.push(ParseError::Log(Code::MalformedDerive, error, span));
+        }
+        Some(Statement::Type(name, declaration, derives))
+    }
+
+    fn parse_assert_statement(&mut self) -> Option<Statement> {
+        let assertion = self.parse_assertion(AssertionKind::Assert)?;
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+        Some(Statement::Assert(assertion))
+    }
+
+    // The condition and optional message following `assert`, `requires` or
+    // `ensures`, e.g. `x > 0, "x must be positive"`
+    fn parse_assertion(&mut self, kind: AssertionKind) -> Option<Assertion> {
+        let keyword = self.curr_span;
+        self.next_token();
+        let start = self.curr_span.start;
+        let condition = self.parse_expression(Precedence::Lowest)?;
+        let source = self.lexer.source(start, self.curr_span.end);
+
+        let mut message = None;
+        if self.peek_token_is(Token::Comma) {
+            self.next_token();
+            let Token::StringLiteral(text) = self.peek.clone() else {
+                self.peek_error(Token::StringLiteral(String::new()));
+                return None;
+            };
+            self.next_token();
+            message = Some(text);
+        }
+        let span = Span { end: self.curr_span.end, ..keyword };
+        Some(Assertion { kind, condition, source, message, span })
+    }
+
+    fn parse_return_statement(&mut self) -> Option<Statement> {
+        self.next_token();
+        let expr = self.parse_expression(Precedence::Lowest)?;
 
         if self.peek_token_is(Token::SemiColon) {
             self.next_token();
@@ -120,11 +497,25 @@ impl Parser {
     fn parse_let_statement(&mut self) -> Option<Statement> {
         match &self.peek {
             Token::Identifier(_) => self.next_token(),
+            // e.g. `let $name = ...` in a macro template
+            Token::Splice(_) => self.next_token(),
             _ => return None,
         }
-        let ident = match self.parse_identifier() {
-            Some(ident) => ident,
-            None => return None,
+        let span = self.curr_span;
+        let ident = match self.curr {
+            Token::Splice(_) => self.parse_splice()?,
+            _ => {
+                self.identifiers.push((self.curr.to_string(), span));
+                self.parse_identifier()?
+            }
+        };
+
+        let annotation = if self.peek_token_is(Token::Colon) {
+            self.next_token();
+            self.next_token();
+            Some(self.parse_type_annotation()?)
+        } else {
+            None
         };
 
         if !self.expect_peek(Token::Assign) {
@@ -132,16 +523,269 @@ impl Parser {
         }
         self.next_token();
 
-        let expr = match self.parse_expression(Precedence::Lowest) {
-            Some(expr) => expr,
-            None => return None,
+        let mut expr = self.parse_expression(Precedence::Lowest)?;
+        if let Some(annotation) = annotation {
+            expr = Expression::Annotated { expression: Box::new(expr), annotation };
+        }
+
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+
+        Some(Statement::Let(ident, expr, span))
+    }
+
+    // `infixl 6 <+> = fn a, b -> ...;` binds the function to `<+>`, and
+    // registers the symbol so that the rest of the input lexes it as one
+    // operator. `infixr` groups chains to the right, `infix` rejects them.
+    fn parse_operator_definition(&mut self) -> Option<Statement> {
+        let associativity = match &self.curr {
+            Token::Identifier(name) if name == "infixl" => Associativity::Left,
+            Token::Identifier(name) if name == "infixr" => Associativity::Right,
+            _ => Associativity::None,
+        };
+        self.next_token();
+        let level = match &self.curr {
+            Token::IntegerLiteral(level) => level.parse::<usize>().ok().filter(|level| (1..=OPERATOR_LEVELS.len()).contains(level)),
+            _ => None,
         };
+        let Some(level) = level else {
+            self.errors.push(ParseError::Log(Code::MalformedOperator, format!("operator levels are 1 to {}, got {}", OPERATOR_LEVELS.len(), self.curr), self.curr_span));
+            self.skip_statement();
+            return None;
+        };
+
+        // The symbol is lexed as builtin operators with nothing between them
+        self.next_token();
+        let start = self.curr_span;
+        while self.peek != Token::End && self.peek_span.start == self.curr_span.end {
+            self.next_token();
+        }
+        let span = start.to(self.curr_span);
+        let symbol = self.lexer.source(span.start, span.end);
+        let builtin = {
+            let mut lexer = Lexer::new(&symbol);
+            !matches!(lexer.advance(), Token::Operator(_)) && lexer.advance() == Token::End
+        };
+        if symbol.is_empty() || !symbol.chars().all(|c| OPERATOR_CHARS.contains(c)) || symbol.contains("--") {
+            self.errors.push(ParseError::Log(Code::MalformedOperator, format!("'{}' is not an operator symbol, which is made of {}", symbol, OPERATOR_CHARS), span));
+            self.skip_statement();
+            return None;
+        }
+        if builtin {
+            self.errors.push(ParseError::Log(Code::MalformedOperator, format!("cannot redefine the builtin operator {}", symbol), span));
+            self.skip_statement();
+            return None;
+        }
+        self.lexer.add_operator(&symbol);
+        self.operators.insert(symbol.clone(), (level, associativity));
 
+        if !self.expect_peek(Token::Assign) {
+            return None;
+        }
+        self.next_token();
+        let function = self.parse_expression(Precedence::Lowest)?;
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+        Some(Statement::Let(Token::Identifier(symbol), function, span))
+    }
+
+    // Skips the rest of a statement that cannot be parsed, to its `;`
+    fn skip_statement(&mut self) {
+        while !matches!(self.peek, Token::SemiColon | Token::End) {
+            self.next_token();
+        }
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+    }
+
+    // `a <+> b` is the call `<+>(a, b)`
+    // e.g. `macro twice(x) -> $x * 2;`, kept for `macros::expand`
+    fn parse_macro_definition(&mut self) -> Option<Statement> {
+        let span = self.curr_span;
+        self.next_token();
+        let name = self.curr.to_string();
+        self.next_token();
+        let mut parameters = Vec::new();
+        while self.curr != Token::Unit && self.curr != Token::RightParen {
+            self.next_token();
+            let Token::Identifier(parameter) = &self.curr else {
+                self.errors.push(ParseError::Log(Code::MalformedMacro, format!("expected a parameter name of macro {}, got {}", name, self.curr), self.curr_span));
+                return None;
+            };
+            parameters.push(parameter.clone());
+            if !matches!(self.peek, Token::Comma | Token::RightParen) {
+                self.errors.push(ParseError::Log(Code::MalformedMacro, format!("expected , or ) after a parameter of macro {}, got {}", name, self.peek), self.peek_span));
+                return None;
+            }
+            self.next_token();
+        }
+        if !self.expect_peek(Token::Arrow) {
+            return None;
+        }
+        self.next_token();
+
+        // Names in the template are not the program's until it is expanded
+        let (identifiers, names) = (self.identifiers.len(), self.names.len());
+        self.splices = Some(Vec::new());
+        // A block template need not end with an expression, so it can bind
+        // names where the macro is called
+        let template = if self.curr_token_is(Token::LeftBrace) && self.starts_block() {
+            Some(self.parse_block_statement())
+        } else {
+            self.parse_expression(Precedence::Lowest).map(|expression| vec![Statement::Expression(expression)])
+        };
+        let splices = self.splices.take().unwrap_or_default();
+        self.identifiers.truncate(identifiers);
+        self.names.truncate(names);
+        let template = template?;
+        if self.peek_token_is(Token::SemiColon) {
+            self.next_token();
+        }
+
+        for (splice, span) in splices.into_iter().filter(|(splice, _)| !parameters.contains(splice)) {
+            self.errors.push(ParseError::Log(Code::MalformedMacro, format!(
+                "${} is not a parameter of macro {}, whose parameters are ({})",
+                splice, name, parameters.join(", ")
+            ), span));
+        }
+        self.macros.push(Macro { name, parameters, template, span });
+        None
+    }
+
+    // e.g. `rule "double": x + x => 2 * x;`, kept for `rewrite::rewrite`
+    fn parse_rule_definition(&mut self) -> Option<Statement> {
+        let span = self.curr_span;
+        self.next_token();
+        let name = match &self.curr {
+            Token::StringLiteral(name) => name.clone(),
+            _ => unreachable!("a rule name is a string"),
+        };
+        if !self.expect_peek(Token::Colon) {
+            return None;
+        }
+        self.next_token();
+
+        // Names in a rule are not the program's
+        let (identifiers, names) = (self.identifiers.len(), self.names.len());
+        let pattern = self.parse_expression(Precedence::Lowest);
+        let replacement = match pattern.is_some() && self.expect_peek(Token::FatArrow) {
+            true => {
+                self.next_token();
+                self.parse_expression(Precedence::Lowest)
+            }
+            false => None,
+        };
+        self.identifiers.truncate(identifiers);
+        self.names.truncate(names);
+        let (pattern, replacement) = (pattern?, replacement?);
         if self.peek_token_is(Token::SemiColon) {
             self.next_token();
         }
 
-        Some(Statement::Let(ident, expr))
+        if let Expression::Identifier(_) = pattern {
+            self.errors.push(ParseError::Log(Code::MalformedRule, format!(
+                "the pattern of rule {:?} is a single name, which would match every expression", name
+            ), span));
+            return None;
+        }
+        self.rules.push(Rule { name, pattern, replacement, span });
+        None
+    }
+
+    fn parse_splice(&mut self) -> Option<Token> {
+        let Token::Splice(name) = &self.curr else {
+            return None;
+        };
+        match &mut self.splices {
+            Some(splices) => splices.push((name.clone(), self.curr_span)),
+            None => {
+                self.errors.push(ParseError::Log(Code::MalformedMacro, format!(
+                    "${} splices an argument of a macro, so it can only appear in a macro template", name
+                ), self.curr_span));
+                return None;
+            }
+        }
+        Some(self.curr.clone())
+    }
+
+    fn parse_operator_expression(&mut self, left: Expression) -> Option<Expression> {
+        let Token::Operator(symbol) = self.curr.clone() else {
+            return None;
+        };
+        let span = self.curr_span;
+        let (level, associativity) = self.operators[&symbol];
+        // The right operand of a right associative operator takes in the rest
+        // of the chain
+        let precedence = match associativity {
+            Associativity::Right if level > 1 => OPERATOR_LEVELS[level - 2],
+            Associativity::Right => Precedence::Lowest,
+            _ => OPERATOR_LEVELS[level - 1],
+        };
+        self.next_token();
+        let right = self.parse_expression(precedence)?;
+        if associativity == Associativity::None && self.peek == Token::Operator(symbol.clone()) {
+            self.errors.push(ParseError::Log(Code::MalformedOperator, format!("{} cannot be chained, add parentheses", symbol), self.peek_span));
+            return None;
+        }
+        let function = Box::new(Expression::Identifier(Token::Identifier(symbol)));
+        Some(Expression::Call { function, arguments: vec![left, right], span })
+    }
+
+    // Records the current token as a name of `kind`
+    fn name(&mut self, kind: NameKind, declaration: bool) {
+        self.names.push(Name { kind, text: self.curr.to_string(), span: self.curr_span, declaration });
+    }
+
+    // `include_str("path")` or `include_json("path")`, replaced by the text
+    // of the file or the literal of its JSON
+    fn parse_include(&mut self) -> Option<Expression> {
+        let json = self.curr == Token::Identifier("include_json".to_string());
+        self.next_token();
+        self.next_token();
+        let span = self.curr_span;
+        let path = match &self.curr {
+            Token::StringLiteral(path) if self.peek_token_is(Token::RightParen) => Some(path.clone()),
+            _ => {
+                self.parse_expression(Precedence::Lowest);
+                None
+            }
+        };
+        if !self.expect_peek(Token::RightParen) {
+            return None;
+        }
+        let name = if json { "include_json" } else { "include_str" };
+        let Some(path) = path else {
+            let message = format!("{} takes the path of a file as a string literal", name);
+            self.errors.push(ParseError::Log(Code::IncludeFailed, message, span));
+            return None;
+        };
+        if !self.includes {
+            let message = format!("{} reads a file, which is the fs effect, and this engine does not allow it", name);
+            self.errors.push(ParseError::Log(Code::EffectNotAllowed, message, span));
+            return None;
+        }
+        let file = match &self.directory {
+            Some(directory) => directory.join(&path),
+            None => PathBuf::from(&path),
+        };
+        self.included.push(file.clone());
+        let included = std::fs::read_to_string(&file).map_err(|e| e.to_string()).and_then(|text| {
+            if json {
+                json::parse(&text)
+            } else {
+                Ok(Expression::Literal(Literal::String(text)))
+            }
+        });
+        match included {
+            Ok(expression) => Some(expression),
+            Err(e) => {
+                self.errors.push(ParseError::Log(Code::IncludeFailed, format!("cannot include {}: {}", file.display(), e), span));
+                None
+            }
+        }
     }
 
     fn parse_identifier(&self) -> Option<Identifier> {
@@ -159,6 +803,7 @@ impl Parser {
         self.errors.push(ParseError::UnexpectedToken {
             want: Some(token),
             got: self.peek.clone(),
+            span: self.peek_span,
         });
     }
 
@@ -176,46 +821,90 @@ impl Parser {
         }
     }
 
-    // fn peek_precedence(&mut self) -> Precedence {
-    //     token_to_precedence(&self.peek)
-    // }
+    fn peek_precedence(&self) -> Precedence {
+        self.precedence(&self.peek)
+    }
 
     fn curr_precedence(&mut self) -> Precedence {
-        token_to_precedence(&self.curr)
+        self.precedence(&self.curr)
+    }
+
+    fn precedence(&self, token: &Token) -> Precedence {
+        match token {
+            Token::Operator(symbol) => self.operators.get(symbol).map_or(Precedence::Lowest, |(level, _)| OPERATOR_LEVELS[level - 1]),
+            token => token_to_precedence(token),
+        }
     }
 
     fn no_prefix_parse_fn_error(&mut self, t: Token) {
-        self.errors.push(ParseError::Log(format!(
+        self.errors.push(ParseError::Log(Code::NoPrefixParse, format!(
             "No prefix parse function for {:?} found",
             t
-        )));
+        ), self.curr_span));
     }
 
     fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        if self.nesting == MAX_NESTING {
+            self.errors.push(ParseError::Log(Code::NestedTooDeeply, format!(
+                "expression nests more than {} levels deep; split it with lets",
+                MAX_NESTING
+            ), self.curr_span));
+            self.too_deep = Some(self.errors.len());
+            self.skip_statement();
+            return None;
+        }
+        self.nesting += 1;
+        let expression = self.parse_nested_expression(precedence);
+        self.nesting -= 1;
+        if let Some(errors) = self.too_deep {
+            if self.nesting == 0 {
+                self.errors.truncate(errors);
+                self.too_deep = None;
+            }
+            return None;
+        }
+        expression
+    }
+
+    fn parse_nested_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        let start = self.curr_span.start;
+        let block = self.curr == Token::LeftBrace && self.starts_block();
         let mut left = match &self.curr {
-            Token::Identifier(_) => match self.parse_identifier() {
-                Some(ident) => Some(Expression::Identifier(ident)),
-                None => None,
-            },
+            // `cond` is only a keyword before a brace
+            Token::Identifier(name) if name == "cond" && self.peek_token_is(Token::LeftBrace) => self.parse_cond_expression(),
+            // and `include_str` and `include_json` before a paren
+            Token::Identifier(name) if matches!(name.as_str(), "include_str" | "include_json") && self.peek_token_is(Token::LeftParen) => {
+                self.parse_include()
+            }
+            Token::Identifier(name) => {
+                self.identifiers.push((name.clone(), self.curr_span));
+                self.parse_identifier().map(Expression::Identifier)
+            }
+            Token::Splice(_) => self.parse_splice().map(Expression::Identifier),
             Token::StringLiteral(s) => Some(Expression::Literal(Literal::String(s.clone()))),
             Token::IntegerLiteral(s) => match s.parse::<i64>() {
                 Ok(d) => Some(Expression::Literal(Literal::Integer(d))),
                 Err(_) => {
-                    self.errors.push(ParseError::Log(format!("Could not parse {} as integer", s)));
+                    self.errors.push(ParseError::Log(Code::InvalidNumber, format!("Could not parse {} as integer", s), self.curr_span));
                     return None;
                 }
             },
             Token::FloatLiteral(s) => match s.parse::<f64>() {
                 Ok(d) => Some(Expression::Literal(Literal::Float(d))),
                 Err(_) => {
-                    self.errors.push(ParseError::Log(format!("Could not parse {} as float", s)));
+                    self.errors.push(ParseError::Log(Code::InvalidNumber, format!("Could not parse {} as float", s), self.curr_span));
                     return None;
                 }
             },
             Token::Boolean(b) => Some(Expression::Literal(Literal::Boolean(*b))),
-            Token::UnitType => Some(Expression::Literal(Literal::Unit)),
+            Token::Unit => Some(Expression::Literal(Literal::Unit)),
             Token::LeftBracket => self.parse_list_expression(),
             Token::Bang | Token::Minus | Token::Plus => self.parse_prefix_expression(),
+            // `( )`, spaced out
+            Token::LeftParen if self.peek_token_is(Token::RightParen) => {
+                self.next_token();
+                Some(Expression::Literal(Literal::Unit))
+            }
             Token::LeftParen => {
                 self.next_token();
                 let expr = self.parse_expression(Precedence::Lowest);
@@ -224,17 +913,21 @@ impl Parser {
                 }
                 expr
             }
+            Token::LeftBrace if block => self.parse_block("block", Code::MalformedBlock).map(Expression::Block),
+            Token::LeftBrace if self.peek_token_is(Token::Colon) || self.lexer.lookahead() == Token::Colon => self.parse_map_expression(),
             Token::LeftBrace => self.parse_record_expression(),
             Token::If => self.parse_if_expression(),
-            Token::Fn => self.parse_function_literal(),
+            // `\x -> x + 1` is shorthand for `fn x -> x + 1`
+            Token::Fn | Token::Backslash => self.parse_function_literal(),
             Token::Some => self.parse_some_expression(),
             Token::None => Some(Expression::OptionNone),
             Token::Ok => self.parse_ok_expression(),
             Token::Err => self.parse_err_expression(),
-            Token::Map | Token::Filter | Token::Fold | Token::Any | Token::All | Token::Println => self.parse_builtin_function(self.curr.clone()),
-            Token::StringType | Token::IntType | Token::FloatType | Token::CharType | Token::BoolType |  Token::List | Token::Option | Token::Result | Token::HashMap => {
-                Some(Expression::Identifier(self.curr.clone()))
-            },
+            Token::Dbg => self.parse_dbg_expression(),
+            Token::Quote => self.parse_quote_expression(),
+            Token::Map | Token::Filter | Token::Fold | Token::Any | Token::All | Token::Println | Token::ConcatAll => {
+                self.parse_builtin_function(self.curr.clone())
+            }
             _ => {
                 self.no_prefix_parse_fn_error(self.curr.clone());
                 return None;
@@ -244,9 +937,16 @@ impl Parser {
     
 
         // Infix expressions
-        while !self.peek_token_is(Token::SemiColon) && precedence < token_to_precedence(&self.peek)
+        while !self.peek_token_is(Token::SemiColon) && precedence < self.peek_precedence()
         {
             match self.peek {
+                // `x |> dbg` is `dbg(x)`
+                Token::Pipe if self.lexer.lookahead() == Token::Dbg => {
+                    let source = self.lexer.source(start, self.curr_span.end);
+                    self.next_token();
+                    self.next_token();
+                    left = Some(dbg(left?, source, self.curr_span));
+                }
                 Token::Plus
                 | Token::Minus
                 | Token::Modulo
@@ -256,15 +956,34 @@ impl Parser {
                 | Token::DoesNotEqual
                 | Token::LessThan
                 | Token::GreaterThan
+                | Token::GTOrEqual
+                | Token::LTOrEqual
+                | Token::In
+                | Token::ShiftLeft
+                | Token::ShiftRight
                 | Token::Pipe
                 | Token::Cons
-                | Token::Concat => {
+                | Token::Concat
+                | Token::Coalesce => {
+                    self.next_token();
+                    left = self.parse_infix_expression(left?);
+                }
+                Token::Operator(_) => {
                     self.next_token();
-                    left = self.parse_infix_expression(left.unwrap());
+                    left = self.parse_operator_expression(left?);
                 }
                 Token::LeftParen => {
                     self.next_token();
-                    left = self.parse_call_expression(left.unwrap());
+                    left = self.parse_call_expression(left?);
+                }
+                // `f()` calls f with no arguments
+                Token::Unit => {
+                    self.next_token();
+                    left = Some(Expression::Call { function: Box::new(left?), arguments: Vec::new(), span: self.curr_span });
+                }
+                Token::Period | Token::OptionalPeriod => {
+                    self.next_token();
+                    left = self.parse_field_expression(left?);
                 }
                 _ => return left,
             }
@@ -273,12 +992,34 @@ impl Parser {
         left
     }
 
+    // `dbg(x)` prints the source of x with its value, and evaluates to x
+    fn parse_dbg_expression(&mut self) -> Option<Expression> {
+        let span = self.curr_span;
+        if !self.expect_peek(Token::LeftParen) {
+            return None;
+        }
+        self.next_token();
+        let start = self.curr_span.start;
+        let value = self.parse_expression(Precedence::Lowest)?;
+        let source = self.lexer.source(start, self.curr_span.end);
+        if !self.peek_token_is(Token::RightParen) {
+            self.errors.push(ParseError::Log(Code::MalformedCall, "dbg expects exactly one argument".to_string(), self.peek_span));
+            return None;
+        }
+        self.next_token();
+        Some(dbg(value, source, span))
+    }
+
     fn parse_builtin_function(&mut self, function: Token) -> Option<Expression> {
+        let span = self.curr_span;
         self.next_token(); // Move to the token after the function name
         
+        if self.curr_token_is(Token::Unit) {
+            return Some(Expression::BuiltIn { function, arguments: Vec::new(), span });
+        }
         // Parse the arguments (should start with left paren)
         if !self.curr_token_is(Token::LeftParen) {
-            self.errors.push(ParseError::Log(format!("Expected '(' after builtin function, got {:?}", self.curr)));
+            self.errors.push(ParseError::Log(Code::MalformedCall, format!("Expected '(' after builtin function, got {:?}", self.curr), self.curr_span));
             return None;
         }
         
@@ -287,6 +1028,7 @@ impl Parser {
         Some(Expression::BuiltIn {
             function,
             arguments: args,
+            span,
         })
     }
 
@@ -320,7 +1062,7 @@ impl Parser {
         
         // Check for closing parenthesis
         if !self.peek_token_is(Token::RightParen) {
-            self.errors.push(ParseError::Log(format!("Expected ')' after arguments, got {:?}", self.peek)));
+            self.errors.push(ParseError::Log(Code::MalformedCall, format!("Expected ')' after arguments, got {:?}", self.peek), self.curr_span));
             return None;
         }
         
@@ -339,10 +1081,7 @@ impl Parser {
         self.next_token();
         
         // Parse the first element
-        let first_element = match self.parse_expression(Precedence::Lowest) {
-            Some(expr) => expr,
-            None => return None,
-        };
+        let first_element = self.parse_expression(Precedence::Lowest)?;
         
         // Check if this is a range expression [elem1..elem2]
         if self.peek_token_is(Token::Over) {
@@ -350,10 +1089,7 @@ impl Parser {
             self.next_token(); // move to the second expression
             
             // Parse the end of the range
-            let end_element = match self.parse_expression(Precedence::Lowest) {
-                Some(expr) => expr,
-                None => return None,
-            };
+            let end_element = self.parse_expression(Precedence::Lowest)?;
             
             if !self.expect_peek(Token::RightBracket) {
                 return None;
@@ -394,7 +1130,18 @@ impl Parser {
         while !self.peek_token_is(Token::RightBrace) {
             self.next_token(); // move to field name
             let field_name = self.parse_identifier()?;
-            
+            self.name(NameKind::Field, false);
+
+            // `{ name, age }` is short for `{ name = name, age = age }`
+            if matches!(self.peek, Token::Comma | Token::RightBrace) {
+                self.identifiers.push((field_name.to_string(), self.curr_span));
+                fields.push((field_name.clone(), Expression::Identifier(field_name)));
+                if self.peek_token_is(Token::Comma) {
+                    self.next_token();
+                }
+                continue;
+            }
+
             // Expect = for assignment
             if !self.expect_peek(Token::Assign) {
                 return None;
@@ -421,30 +1168,72 @@ impl Parser {
         Some(Expression::Literal(Literal::Record(fields)))
     }
 
+    // `{ key: value, ... }`, or `{:}` for the empty map
+    fn parse_map_expression(&mut self) -> Option<Expression> {
+        let mut entries = Vec::new();
+        if self.peek_token_is(Token::Colon) {
+            self.next_token();
+            if !self.expect_peek(Token::RightBrace) {
+                return None;
+            }
+            return Some(Expression::Literal(Literal::HashMap(entries)));
+        }
+
+        while !self.peek_token_is(Token::RightBrace) {
+            self.next_token(); // move to the key
+            let key = self.parse_expression(Precedence::Lowest)?;
+            if !self.expect_peek(Token::Colon) {
+                return None;
+            }
+            self.next_token(); // move to the value
+            let value = self.parse_expression(Precedence::Lowest)?;
+            entries.push((key, value));
+            if self.peek_token_is(Token::Comma) {
+                self.next_token();
+            } else {
+                break;
+            }
+        }
+
+        if !self.expect_peek(Token::RightBrace) {
+            return None;
+        }
+        Some(Expression::Literal(Literal::HashMap(entries)))
+    }
+
+    fn parse_field_expression(&mut self, record: Expression) -> Option<Expression> {
+        let optional = self.curr == Token::OptionalPeriod;
+        self.next_token(); // move past the period
+        let field = self.parse_identifier()?;
+        self.name(NameKind::Field, false);
+        Some(Expression::Field { record: Box::new(record), field, optional, cache: FieldCache::default() })
+    }
+
     fn parse_function_literal(&mut self) -> Option<Expression> {
         let params = {
             let mut params = Vec::new();
             
             // Check if we have at least one parameter or empty parentheses
-            if self.peek == Token::Arrow {
-                self.errors.push(ParseError::Log(
-                    "Function definition requires parameters or empty parentheses '()' before ->".to_string()
+            if matches!(self.peek, Token::Arrow | Token::Requires | Token::Ensures) {
+                self.errors.push(ParseError::Log(Code::MalformedFunction, 
+                    "Function definition requires parameters or empty parentheses '()' before ->".to_string(), self.curr_span
                 ));
                 return None;
             }
             
-            while self.peek != Token::Arrow {
+            while !matches!(self.peek, Token::Arrow | Token::Requires | Token::Ensures) {
                 self.next_token();
                 if let Token::Identifier(s) = &self.curr {
+                    self.identifiers.push((s.clone(), self.curr_span));
                     params.push(Token::Identifier(s.clone()));
-                } else if let Token::UnitType = &self.curr {
-                    params.push(Token::UnitType);
+                } else if self.curr == Token::Unit && params.is_empty() {
+                    // `fn () -> ...` takes no arguments, and is called as `f()`
                     break;
                 } else {
-                    self.errors.push(ParseError::Log(format!(
+                    self.errors.push(ParseError::Log(Code::MalformedFunction, format!(
                         "expected identifier in function parameters, got {:?}",
                         self.curr
-                    )));
+                    ), self.curr_span));
                     return None;
                 }
                 if self.peek == Token::Comma {
@@ -454,6 +1243,14 @@ impl Parser {
             params
         };
 
+        // e.g. fn a, b requires b != 0 ensures result * b <= a -> a / b;
+        let mut contracts = Vec::new();
+        while matches!(self.peek, Token::Requires | Token::Ensures) {
+            self.next_token();
+            let kind = if self.curr == Token::Requires { AssertionKind::Requires } else { AssertionKind::Ensures };
+            contracts.push(Statement::Assert(self.parse_assertion(kind)?));
+        }
+
         if !self.expect_peek(Token::Arrow) {
             return None;
         }
@@ -461,36 +1258,21 @@ impl Parser {
 
         // Handle both block and single-line expressions
         let body = if self.curr_token_is(Token::LeftBrace) {
-            let block = self.parse_block_statement();
-            
-            // Validate block return semantics
-            if let Some(last) = block.last() {
-                match last {
-                    Statement::Return(_) => (), 
-                    Statement::Expression(_) => (),
-                    _ => {
-                        self.errors.push(ParseError::Log(
-                            "Function block must end with expression or return statement".to_string()
-                        ));
-                        return None;
-                    }
-                }
-            } else {
-                self.errors.push(ParseError::Log(
-                    "Empty function body".to_string()
-                ));
-                return None;
-            }
-            block
+            self.parse_block("function body", Code::MalformedFunction)?
         } else {
             let expr = self.parse_expression(Precedence::Lowest)?;
-            self.next_token(); // consume semicolon
+            // A trailing semicolon is optional, so `map(\x -> x * 2, xs)` works
+            if self.peek_token_is(Token::SemiColon) {
+                self.next_token();
+            }
             vec![Statement::Expression(expr)]
         };
 
+        contracts.extend(body);
         Some(Expression::Function {
             parameters: params,
-            body,
+            body: Rc::new(contracts),
+            captures: None,
         })
     }
 
@@ -514,12 +1296,14 @@ impl Parser {
     }
 
     fn parse_call_expression(&mut self, function: Expression) -> Option<Expression> {
+        let span = self.curr_span;
         // Parse the opening parenthesis
         let args = self.parse_call_arguments()?;
         
         Some(Expression::Call {
             function: Box::new(function),
             arguments: args,
+            span,
         })
     }
 
@@ -563,24 +1347,68 @@ impl Parser {
             Token::GreaterThan => Infix::GreaterThan,
             Token::GTOrEqual => Infix::GTOrEqual,
             Token::LTOrEqual => Infix::LTOrEqual,
+            Token::ShiftLeft => Infix::ShiftLeft,
+            Token::ShiftRight => Infix::ShiftRight,
             Token::Pipe => Infix::Pipe,
+            Token::In => Infix::In,
             Token::Cons => Infix::Cons,
             Token::Concat => Infix::Concat,
+            Token::Coalesce => Infix::Coalesce,
             _ => return None,
         };
 
+        let span = self.curr_span;
         let precedence = self.curr_precedence();
+        if matches!(infix, Infix::Cons | Infix::Concat | Infix::Coalesce) {
+            return self.parse_right_chain(infix, left, span, precedence);
+        }
         self.next_token();
-        self.parse_expression(precedence)
-            .map(|expr| Expression::Infix(infix, Box::new(left), Box::new(expr)))
+        let right = self.parse_expression(precedence)?;
+        // `a < b < c` would compare the bool `a < b` with c
+        if precedence == Precedence::LessGreater && token_to_precedence(&self.peek) == Precedence::LessGreater {
+            let (first, second) = (self.lexer.source(span.start, span.end), self.lexer.source(self.peek_span.start, self.peek_span.end));
+            self.errors.push(ParseError::Log(Code::ChainedComparison, format!(
+                "comparisons cannot be chained, `a {} b {} c` would compare a bool with c; compare each pair on its own",
+                first, second
+            ), self.peek_span));
+        }
+        Some(Expression::Infix(infix, Box::new(left), Box::new(right), span))
+    }
+
+    // `1 :: 2 :: []` is `1 :: (2 :: [])`, and `a ++ b ++ c` and `a ?? b ?? c`
+    // likewise. The operands of a chain are read in a loop, and grouped from
+    // the right once it ends.
+    fn parse_right_chain(&mut self, infix: Infix, left: Expression, span: Span, precedence: Precedence) -> Option<Expression> {
+        let mut operands = vec![left];
+        let mut operators = vec![(infix, span)];
+        loop {
+            self.next_token();
+            let mut operand = self.parse_expression(precedence)?;
+            // An operator defined at the level of `::` binds its operands first
+            while precedence == Precedence::Cons && matches!(self.peek, Token::Operator(_)) && self.peek_precedence() == precedence {
+                self.next_token();
+                operand = self.parse_operator_expression(operand)?;
+            }
+            operands.push(operand);
+            let infix = match (&self.peek, precedence) {
+                (Token::Cons, Precedence::Cons) => Infix::Cons,
+                (Token::Concat, Precedence::Cons) => Infix::Concat,
+                (Token::Coalesce, Precedence::Coalesce) => Infix::Coalesce,
+                _ => break,
+            };
+            self.next_token();
+            operators.push((infix, self.curr_span));
+        }
+        let mut right = operands.pop()?;
+        while let (Some((infix, span)), Some(left)) = (operators.pop(), operands.pop()) {
+            right = Expression::Infix(infix, Box::new(left), Box::new(right), span);
+        }
+        Some(right)
     }
 
     fn parse_if_expression(&mut self) -> Option<Expression> {
         self.next_token();
-        let condition = match self.parse_expression(Precedence::Lowest) {
-            Some(expr) => expr,
-            None => return None,
-        };
+        let condition = self.parse_expression(Precedence::Lowest)?;
 
         if !self.expect_peek(Token::LeftBrace) {
             return None;
@@ -601,7 +1429,79 @@ impl Parser {
         })
     }
 
-    fn parse_block_statement(&mut self) -> Program {
+    // `cond { | x > 10 -> "big" | x > 5 -> "mid" | else -> "small" }` is the
+    // ladder `if x > 10 { "big" } else { if x > 5 { "mid" } else { "small" } }`
+    fn parse_cond_expression(&mut self) -> Option<Expression> {
+        let start = self.curr_span;
+        self.next_token();
+        let mut arms = Vec::new();
+        let mut otherwise = None;
+        while self.peek_token_is(Token::Vbar) {
+            self.next_token();
+            self.next_token();
+            if otherwise.is_some() {
+                self.errors.push(ParseError::Log(Code::MalformedCond, "the else arm must be the last arm of cond".to_string(), self.curr_span));
+                return None;
+            }
+            let condition = match self.curr {
+                Token::Else => None,
+                _ => Some(self.parse_expression(Precedence::Lowest)?),
+            };
+            if !self.expect_peek(Token::Arrow) {
+                return None;
+            }
+            self.next_token();
+            let body = vec![Statement::Expression(self.parse_expression(Precedence::Lowest)?)];
+            match condition {
+                Some(condition) => arms.push((condition, body)),
+                None => otherwise = Some(body),
+            }
+        }
+        if !self.expect_peek(Token::RightBrace) {
+            return None;
+        }
+
+        let Some((condition, consequence)) = arms.pop() else {
+            self.errors.push(ParseError::Log(Code::MalformedCond, "cond needs at least one `| condition -> value` arm".to_string(), start.to(self.curr_span)));
+            return None;
+        };
+        let mut ladder = Expression::If { condition: Box::new(condition), consequence, alternative: otherwise };
+        while let Some((condition, consequence)) = arms.pop() {
+            let alternative = Some(vec![Statement::Expression(ladder)]);
+            ladder = Expression::If { condition: Box::new(condition), consequence, alternative };
+        }
+        Some(ladder)
+    }
+
+    // `{` opens a block unless it is a record literal: `{}`, or a first
+    // field of the form `name =`
+    fn starts_block(&mut self) -> bool {
+        match self.peek {
+            Token::RightBrace => false,
+            // `{:}` and `{ key: ...` start maps
+            Token::Colon => false,
+            // `{ name = ...` and `{ name, ...` start records
+            Token::Identifier(_) => !matches!(self.lexer.lookahead(), Token::Assign | Token::Comma | Token::Colon),
+            _ => self.lexer.lookahead() != Token::Colon,
+        }
+    }
+
+    // A block whose value is that of its last statement, which must be an
+    // expression or a return, e.g. `{ let x = 1; x + 1 }`. A block breaking
+    // that rule is still returned, so parsing carries on after it.
+    fn parse_block(&mut self, what: &str, code: Code) -> Option<Block> {
+        let start = self.curr_span;
+        let block = self.parse_block_statement();
+        let error = match block.last() {
+            Some(Statement::Expression(_) | Statement::Return(_)) => return Some(block),
+            Some(_) => format!("{} must end with an expression or a return statement", what),
+            None => format!("empty {}", what),
+        };
+        self.errors.push(ParseError::Log(code, error, start.to(self.curr_span)));
+        Some(block)
+    }
+
+    fn parse_block_statement(&mut self) -> Block {
         let mut statements = vec![];
         self.next_token(); 
         
@@ -618,10 +1518,12 @@ impl Parser {
     fn parse_type_statement(&mut self) -> Option<Statement> {
         self.next_token(); // consume 'type'
         
-        let name = match self.parse_identifier() {
-            Some(ident) => ident,
-            None => return None,
-        };
+        let name = self.parse_identifier()?;
+        self.name(NameKind::Type, true);
+        if type_keyword(&name) != name {
+            self.errors.push(ParseError::Log(Code::MalformedType, format!("cannot declare a type named {}, which is a builtin type", name), self.curr_span));
+            return None;
+        }
         
         if !self.expect_peek(Token::Assign) {
             return None;
@@ -632,7 +1534,7 @@ impl Parser {
             self.next_token(); // consume =
             self.next_token(); // consume |
             let type_def = self.parse_union_type()?;
-            return Some(Statement::Type(name, type_def));
+            return Some(Statement::Type(name, type_def, Vec::new()));
         }
         
         self.next_token();
@@ -642,7 +1544,7 @@ impl Parser {
             _ => self.parse_type_alias()?,
         };
         
-        Some(Statement::Type(name, type_def))
+        Some(Statement::Type(name, type_def, Vec::new()))
     }
 
     fn parse_union_type(&mut self) -> Option<Type> {
@@ -651,6 +1553,7 @@ impl Parser {
         loop {
             if let Token::Identifier(_) = &self.curr {
                 let variant_name = self.curr.clone();
+                self.name(NameKind::Variant, true);
                 
                 // Check if variant has associated type (Of)
                 let associated_type = if self.peek_token_is(Token::Of) {
@@ -671,10 +1574,10 @@ impl Parser {
             } else if let Token::Vbar = &self.curr {
                 self.next_token(); // consume the Vbar
             } else {
-                self.errors.push(ParseError::Log(format!(
+                self.errors.push(ParseError::Log(Code::MalformedType, format!(
                     "Expected variant name, got {:?}",
                     self.curr
-                )));
+                ), self.curr_span));
                 return None;
             }
         }
@@ -693,12 +1596,13 @@ impl Parser {
             self.next_token();
             // Parse field name
             let field_name = if let Token::Identifier(_) = &self.curr {
+                self.name(NameKind::Field, true);
                 self.curr.clone()
             } else {
-                self.errors.push(ParseError::Log(format!(
+                self.errors.push(ParseError::Log(Code::MalformedType, format!(
                     "Expected field name, got {:?}",
                     self.curr
-                )));
+                ), self.curr_span));
                 return None;
             };
             
@@ -740,7 +1644,7 @@ impl Parser {
     }
 
     fn parse_type_annotation(&mut self) -> Option<Alias> {
-        match &self.curr {
+        match &type_keyword(&self.curr) {
             // Handle type variables
             // Handle lowercase primitive types
             Token::IntType => Some(Alias {
@@ -763,29 +1667,33 @@ impl Parser {
                 name: TypeConstructor::BuiltIn(Constructor::Bool),
                 parameters: Vec::new(),
             }),
-            Token::UnitType => Some(Alias {
+            Token::UnitType | Token::Unit => Some(Alias {
                 name: TypeConstructor::BuiltIn(Constructor::Unit),
                 parameters: Vec::new(),
             }),
+            Token::DynamicType => Some(Alias {
+                name: TypeConstructor::BuiltIn(Constructor::Dynamic),
+                parameters: Vec::new(),
+            }),
             // Handle result type with tuple parameters
-            Token::Result | Token::HashMap => {
-                let constructor = match &self.curr {
+            token @ (Token::Result | Token::HashMap) => {
+                let constructor = match token {
                     Token::Result => Constructor::Result,
                     Token::HashMap => Constructor::HashMap,
                     _ => unreachable!(),
                 };
                 // Expect * after result
                 if !self.expect_peek(Token::Product) {
-                    self.errors.push(ParseError::Log(
-                        "Expected * after result type".to_string()
+                    self.errors.push(ParseError::Log(Code::MalformedType, 
+                        "Expected * after result type".to_string(), self.curr_span
                     ));
                     return None;
                 }
                 
                 // Expect left parenthesis for tuple
                 if !self.expect_peek(Token::LeftParen) {
-                    self.errors.push(ParseError::Log(
-                        "Expected ( for result type parameters".to_string()
+                    self.errors.push(ParseError::Log(Code::MalformedType, 
+                        "Expected ( for result type parameters".to_string(), self.curr_span
                     ));
                     return None;
                 }
@@ -797,8 +1705,8 @@ impl Parser {
                 
                 // Expect comma between parameters
                 if !self.expect_peek(Token::Comma) {
-                    self.errors.push(ParseError::Log(
-                        "Expected , between result type parameters".to_string()
+                    self.errors.push(ParseError::Log(Code::MalformedType, 
+                        "Expected , between result type parameters".to_string(), self.curr_span
                     ));
                     return None;
                 }
@@ -810,8 +1718,8 @@ impl Parser {
                 
                 // Expect right parenthesis
                 if !self.expect_peek(Token::RightParen) {
-                    self.errors.push(ParseError::Log(
-                        "Expected ) after result type parameters".to_string()
+                    self.errors.push(ParseError::Log(Code::MalformedType, 
+                        "Expected ) after result type parameters".to_string(), self.curr_span
                     ));
                     return None;
                 }
@@ -822,8 +1730,8 @@ impl Parser {
                 })
             },
             // Handle product types for list, and option
-            Token::List | Token::Option => {
-                let constructor = match &self.curr {
+            token @ (Token::List | Token::Option) => {
+                let constructor = match token {
                     Token::List => Constructor::List,
                     Token::Option => Constructor::Option,
                     _ => unreachable!(),
@@ -831,8 +1739,8 @@ impl Parser {
                 
                 // Expect * after type constructor
                 if !self.expect_peek(Token::Product) {
-                    self.errors.push(ParseError::Log(
-                        "Expected * after type constructor".to_string()
+                    self.errors.push(ParseError::Log(Code::MalformedType, 
+                        "Expected * after type constructor".to_string(), self.curr_span
                     ));
                     return None;
                 }
@@ -847,6 +1755,7 @@ impl Parser {
             // Custom type identifiers can be any case
             Token::Identifier(_) => {
                 let name = self.curr.clone();
+                self.name(NameKind::Type, false);
                 // Check if this is a product type
                 if self.peek_token_is(Token::Product) {
                     self.next_token(); // move past identifier
@@ -864,16 +1773,16 @@ impl Parser {
                 }
             },
             Token::LeftBrace => {
-                self.errors.push(ParseError::Log(
-                    "Inline record types are not allowed. Define a named record type instead.".to_string()
+                self.errors.push(ParseError::Log(Code::MalformedType, 
+                    "Inline record types are not allowed. Define a named record type instead.".to_string(), self.curr_span
                 ));
                 None
             },
             _ => {
-                self.errors.push(ParseError::Log(format!(
+                self.errors.push(ParseError::Log(Code::MalformedType, format!(
                     "Expected type name, got {:?}",
                     self.curr
-                )));
+                ), self.curr_span));
                 None
             }
         }
@@ -885,6 +1794,16 @@ impl Parser {
         Some(Expression::OptionSome(Box::new(expr)))
     }
 
+    // Everything to the right is quoted, as with `Some`: `quote x + 1`. The
+    // source text is kept for the value to print as
+    fn parse_quote_expression(&mut self) -> Option<Expression> {
+        self.next_token(); // consume 'quote'
+        let start = self.curr_span.start;
+        let expr = self.parse_expression(Precedence::Lowest)?;
+        let source = self.lexer.source(start, self.curr_span.end);
+        Some(Expression::Quote(Rc::new(expr), Rc::from(source)))
+    }
+
     fn parse_ok_expression(&mut self) -> Option<Expression> {
         self.next_token(); // consume 'Ok'
         let expr = self.parse_expression(Precedence::Lowest)?;
@@ -898,3 +1817,109 @@ impl Parser {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_metadata() {
+        let program = Parser::new(Lexer::new("-- config\nlet x = 1;\nx + 1")).parse_program().with_source_id("main.opl");
+        assert_eq!(program.len(), 3);
+        assert_eq!(program.source_id.as_deref(), Some("main.opl"));
+        assert_eq!(program.comments, vec![(" config".to_string(), Span { start: 0, end: 9, line: 1, column: 1, ..Span::default() })]);
+        let spans: Vec<(usize, usize, usize)> = program.spanned().map(|(_, span)| (span.line, span.start, span.end)).collect();
+        assert_eq!(spans, vec![(1, 0, 9), (2, 10, 20), (3, 21, 26)]);
+        assert_eq!((program.span.start, program.span.end), (0, 26));
+        assert!(program.errors.is_empty());
+    }
+
+    #[test]
+    fn test_recovers_from_illegal_tokens() {
+        let program = Parser::new(Lexer::new("let x = 1 $;\nlet y = 2x;\nlet z = x;")).parse_program();
+        let errors: Vec<(Code, String, usize)> = program.errors.iter().map(|e| (e.code(), e.message(), e.span().line)).collect();
+        assert_eq!(errors, vec![
+            (Code::InvalidToken, "unknown character '$'".to_string(), 1),
+            (Code::InvalidNumber, "malformed number '2x'".to_string(), 2),
+        ]);
+        assert_eq!(program.len(), 3);
+    }
+
+    #[test]
+    fn test_soft_keywords() {
+        let program = Parser::new(Lexer::new("let string = \"s\";\nlet list: list * int = [1];\nlet result = list;")).parse_program();
+        assert!(program.errors.is_empty());
+        let Statement::Let(_, Expression::Annotated { expression, annotation }, _) = &program[1] else {
+            panic!("expected an annotated let, got {:?}", program[1]);
+        };
+        assert_eq!(annotation.to_string(), "list * int");
+        assert_eq!(**expression, Expression::Literal(Literal::List(vec![Expression::Literal(Literal::Integer(1))])));
+        assert!(matches!(&program[2], Statement::Let(Token::Identifier(name), Expression::Identifier(Token::Identifier(value)), _) if name == "result" && value == "list"));
+
+        let program = Parser::new(Lexer::new("type int = string;")).parse_program();
+        assert_eq!(program.errors[0].message(), "cannot declare a type named int, which is a builtin type");
+    }
+
+    #[test]
+    fn test_cond() {
+        let source = "let size = fn x -> cond {\n  | x > 10 -> \"big\"\n  | x > 5 -> \"mid\"\n  | else -> \"small\"\n};\nlet cond = true;";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let Statement::Let(_, Expression::Function { body, .. }, _) = &program[0] else {
+            panic!("expected a function, got {:?}", program[0]);
+        };
+        let Statement::Expression(Expression::If { alternative: Some(alternative), .. }) = &body[0] else {
+            panic!("expected an if, got {:?}", body);
+        };
+        assert!(matches!(&alternative[0], Statement::Expression(Expression::If { alternative: Some(_), .. })));
+
+        let program = Parser::new(Lexer::new("cond { | else -> 1 }")).parse_program();
+        assert_eq!(program.errors[0].code(), Code::MalformedCond);
+    }
+
+    #[test]
+    fn test_record_punning() {
+        let parse = |source| Parser::new(Lexer::new(source)).parse_program();
+        let punned = parse("let name = \"ada\";\nlet age = 36;\n{ name, age = 37, }");
+        assert!(punned.errors.is_empty(), "{:?}", punned.errors);
+        assert_eq!(punned[2], parse("{ name = name, age = 37 }")[0]);
+        assert_eq!(punned.identifiers.len(), 3);
+        // A name alone in braces is a block
+        assert!(matches!(parse("{ name }")[0], Statement::Expression(Expression::Block(_))));
+    }
+
+    #[test]
+    fn test_chained_comparisons() {
+        let program = Parser::new(Lexer::new("1 < 2 >= 3;\n(1 < 2) == (2 <= 3);")).parse_program();
+        let errors: Vec<(Code, String, usize)> = program.errors.iter().map(|e| (e.code(), e.message(), e.span().column)).collect();
+        assert_eq!(errors, vec![(
+            Code::ChainedComparison,
+            "comparisons cannot be chained, `a < b >= c` would compare a bool with c; compare each pair on its own".to_string(),
+            7,
+        )]);
+        assert_eq!(program.len(), 2);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let parse = |expression: String| Parser::new(Lexer::new(&format!("let x = {};\nlet y = 1;", expression))).parse_program();
+        // Too deep an expression is reported once, and parsing goes on after it
+        for expression in [
+            format!("{}{}", "[".repeat(50_000), "]".repeat(50_000)),
+            format!("{}1{}", "(".repeat(50_000), ")".repeat(50_000)),
+            vec!["1"; 100_000].join(" + "),
+            vec!["[1]"; 100_000].join(" ++ "),
+        ] {
+            let program = parse(expression);
+            assert_eq!(program.errors.iter().map(ParseError::code).collect::<Vec<_>>(), vec![Code::NestedTooDeeply]);
+            assert_eq!(program.len(), 1);
+        }
+        // Chains of operators that group to the right are read in a loop
+        let program = parse(format!("{} :: []", vec!["1"; 1000].join(" :: ")));
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let Statement::Let(_, Expression::Infix(Infix::Cons, _, tail, _), _) = &program[0] else {
+            panic!("expected a cons, got {:?}", program[0]);
+        };
+        assert!(matches!(**tail, Expression::Infix(Infix::Cons, ..)));
+    }
+}
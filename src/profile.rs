@@ -0,0 +1,151 @@
+// profile.rs
+//
+// Execution profiles, for `opl run --profile-out`: how often each named
+// function is called, and how often each `if` takes either branch. While a
+// profile is being recorded the evaluator counts into it, as do the
+// evaluators builtins start for callbacks, so it is kept per thread like the
+// step budget. An `if` is known by the file and position of the operator of
+// its condition, so one on a plain name or literal is not counted. `opl run
+// --profile-in` reads one back for the optimizer.
+
+use crate::ast::{Expression, Literal};
+use crate::diagnostics::json_escape;
+use crate::json;
+use crate::lexer::Span;
+use crate::source::{FileId, SourceMap};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Profile {
+    pub calls: BTreeMap<String, u64>,
+    // By the file, line and column of the condition: times taken, times not
+    pub branches: BTreeMap<(FileId, usize, usize), (u64, u64)>,
+}
+
+thread_local! {
+    static RECORDING: RefCell<Option<Profile>> = const { RefCell::new(None) };
+}
+
+/// Starts recording a profile on this thread, dropping any unfinished one.
+pub fn start() {
+    RECORDING.set(Some(Profile::default()));
+}
+
+/// Stops recording, and gives what was recorded since `start`.
+pub fn finish() -> Option<Profile> {
+    RECORDING.take()
+}
+
+pub(crate) fn call(name: &str) {
+    RECORDING.with_borrow_mut(|profile| {
+        if let Some(profile) = profile {
+            *profile.calls.entry(name.to_string()).or_insert(0) += 1;
+        }
+    });
+}
+
+pub(crate) fn branch(condition: Span, taken: bool) {
+    RECORDING.with_borrow_mut(|profile| {
+        if let Some(profile) = profile {
+            let (then, otherwise) = profile.branches.entry((condition.file, condition.line, condition.column)).or_insert((0, 0));
+            *if taken { then } else { otherwise } += 1;
+        }
+    });
+}
+
+impl Profile {
+    /// The profile as JSON, naming files by `sources`: `{"calls": {"name": count, ...},
+    /// "branches": [{"file": ..., "line": ..., "column": ..., "taken": ..., "not_taken": ...}, ...]}`
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        let calls: Vec<String> = self.calls.iter().map(|(name, count)| format!("\"{}\":{}", json_escape(name), count)).collect();
+        let branches: Vec<String> = self
+            .branches
+            .iter()
+            .map(|((file, line, column), (taken, not_taken))| {
+                format!(
+                    "{{\"file\":\"{}\",\"line\":{},\"column\":{},\"taken\":{},\"not_taken\":{}}}",
+                    json_escape(sources.name(*file)), line, column, taken, not_taken
+                )
+            })
+            .collect();
+        format!("{{\"calls\":{{{}}},\"branches\":[{}]}}", calls.join(","), branches.join(","))
+    }
+
+    /// A profile written by `to_json`. Branches in files `sources` does not
+    /// have are left out.
+    pub fn from_json(text: &str, sources: &SourceMap) -> Result<Profile, String> {
+        let count = |value: &Expression| match value {
+            Expression::Literal(Literal::Integer(count)) if *count >= 0 => Ok(*count as u64),
+            other => Err(format!("expected a count, found {:?}", other)),
+        };
+        let document = json::parse(text)?;
+        let Expression::Literal(Literal::Record(fields)) = &document else {
+            return Err("expected an object".to_string());
+        };
+        let mut profile = Profile::default();
+        for (key, value) in fields {
+            match (key.to_string().as_str(), value) {
+                ("calls", Expression::Literal(Literal::Record(calls))) => {
+                    for (name, calls) in calls {
+                        profile.calls.insert(name.to_string(), count(calls)?);
+                    }
+                }
+                ("branches", Expression::Literal(Literal::List(branches))) => {
+                    for branch in branches {
+                        let Expression::Literal(Literal::Record(branch)) = branch else {
+                            return Err("expected a branch object".to_string());
+                        };
+                        let field = |name: &str| match branch.iter().find(|(key, _)| key.to_string() == name) {
+                            Some((_, value)) => Ok(value),
+                            None => Err(format!("a branch has no {}", name)),
+                        };
+                        let Expression::Literal(Literal::String(file)) = field("file")? else {
+                            return Err("expected a file name".to_string());
+                        };
+                        let Some(file) = sources.find(file) else {
+                            continue;
+                        };
+                        let position = (file, count(field("line")?)? as usize, count(field("column")?)? as usize);
+                        profile.branches.insert(position, (count(field("taken")?)?, count(field("not_taken")?)?));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::source::SourceMap;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_profile() {
+        let mut sources = SourceMap::new();
+        let lib = sources.add("lib.opl", "let sign = fn n -> if n < 0 { 0 - 1 } else { 1 };".to_string());
+        let main = sources.add("a.opl", "let twice = fn n -> if n < 0 { 0 } else { n * 2 };\nmap(fn n -> twice(sign(n)), [3, -1, 4]);".to_string());
+        super::start();
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
+        evaluator.eval(&sources.parse(lib));
+        evaluator.eval(&sources.parse(main));
+        let profile = super::finish().unwrap();
+        assert_eq!(profile.calls.get("sign"), Some(&3));
+        assert_eq!(profile.calls.get("twice"), Some(&3));
+        // The conditions of the two ifs are at the same place of their files
+        assert_eq!(profile.branches.keys().collect::<Vec<_>>(), [&(lib, 1, 25), &(main, 1, 26)]);
+        assert_eq!(profile.branches.values().collect::<Vec<_>>(), [&(1, 2), &(1, 2)]);
+        assert_eq!(
+            profile.to_json(&sources),
+            r#"{"calls":{"sign":3,"twice":3},"branches":[{"file":"lib.opl","line":1,"column":25,"taken":1,"not_taken":2},{"file":"a.opl","line":1,"column":26,"taken":1,"not_taken":2}]}"#
+        );
+        assert_eq!(Profile::from_json(&profile.to_json(&sources), &sources), Ok(profile));
+        assert_eq!(super::finish(), None);
+    }
+}
@@ -0,0 +1,232 @@
+// python.rs
+//
+// The `opl` Python extension module, enabled by the `python` feature. Build
+// it with
+//
+//     cargo rustc --lib --release --features python,pyo3/extension-module --crate-type cdylib
+//
+// and copy target/release/libopl.so to `opl.so` on the Python path.
+//
+//     >>> import opl
+//     >>> opl.eval("let x = [1, 2, 3]; x;")
+//     [1, 2, 3]
+//     >>> engine = opl.Engine()
+//     >>> engine.register("shout", lambda s: s.upper())
+//     >>> engine.set("config", {"name": "opl", "retries": 3})
+//     >>> engine.eval("shout(config.name);")
+//     'OPL'
+//
+// None, bool, int, float and str map to their opl counterparts, lists and
+// tuples to lists, dicts with str keys to records and sets to sets. Back in
+// Python, records and maps become dicts and deques `collections.deque`s;
+// `Some(x)` and `Ok(x)` become `x` and `Err(e)` raises `OplError`.
+
+// The wrappers pyo3 generates for methods convert `PyErr` into itself
+#![allow(clippy::useless_conversion)]
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyFrozenSet, PyList, PyLong, PySet, PyString, PyTuple};
+
+use crate::diagnostics::Code;
+use crate::interop::Engine;
+use crate::object::Object;
+use crate::record::Record;
+use crate::set::Set;
+
+create_exception!(opl, OplError, PyException);
+
+// Conversions recurse, so values nested deeper than this are refused rather
+// than overflowing the stack of the Python thread
+const MAX_DEPTH: usize = 1000;
+
+/// An opl interpreter whose bindings persist between calls to `eval`.
+#[pyclass(unsendable, name = "Engine", module = "opl")]
+pub struct PyEngine {
+    engine: Engine,
+}
+
+#[pymethods]
+impl PyEngine {
+    #[new]
+    fn new() -> Self {
+        PyEngine { engine: Engine::new() }
+    }
+
+    fn eval(&mut self, py: Python<'_>, source: &str) -> PyResult<PyObject> {
+        eval_with(&mut self.engine, py, source)
+    }
+
+    fn set(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        self.engine.set_global(name, from_python(value, 0)?);
+        Ok(())
+    }
+
+    fn get(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        match self.engine.get_global::<Object>(name) {
+            Some(Ok(value)) => to_python(py, &value, 0),
+            Some(Err(error)) => Err(OplError::new_err(error.to_string())),
+            None => Err(PyKeyError::new_err(name.to_string())),
+        }
+    }
+
+    /// Makes a Python callable available to opl as `name`.
+    fn register(&mut self, name: &str, function: PyObject) {
+        let function_name = name.to_string();
+        self.engine.register(name, move |arguments| {
+            Python::with_gil(|py| {
+                let arguments = arguments.iter().map(|argument| to_python(py, argument, 0)).collect::<PyResult<Vec<_>>>()?;
+                let result = function.call1(py, PyTuple::new_bound(py, arguments))?;
+                from_python(result.bind(py), 0)
+            })
+            .unwrap_or_else(|error| Object::Error(Code::HostFunction, format!("host function '{}' failed: {}", function_name, error)))
+        });
+    }
+}
+
+/// Evaluates `source` in a fresh engine.
+#[pyfunction]
+fn eval(py: Python<'_>, source: &str) -> PyResult<PyObject> {
+    eval_with(&mut Engine::new(), py, source)
+}
+
+fn eval_with(engine: &mut Engine, py: Python<'_>, source: &str) -> PyResult<PyObject> {
+    match engine.eval(source) {
+        Ok(value) => to_python(py, &value, 0),
+        Err(diagnostics) => {
+            let message = diagnostics.iter().map(ToString::to_string).collect::<Vec<String>>().join("\n");
+            Err(OplError::new_err(message))
+        }
+    }
+}
+
+fn from_python(value: &Bound<'_, PyAny>, depth: usize) -> PyResult<Object> {
+    if depth > MAX_DEPTH {
+        return Err(PyTypeError::new_err("value is nested too deeply to convert to opl"));
+    }
+    let element = |item: Bound<'_, PyAny>| from_python(&item, depth + 1);
+    if value.is_none() {
+        Ok(Object::OptionNone)
+    } else if let Ok(value) = value.downcast::<PyBool>() {
+        Ok(Object::Boolean(value.is_true()))
+    } else if value.is_instance_of::<PyLong>() {
+        Ok(Object::Integer(value.extract()?))
+    } else if let Ok(value) = value.downcast::<PyFloat>() {
+        Ok(Object::Float(value.value()))
+    } else if let Ok(value) = value.downcast::<PyString>() {
+        Ok(Object::from(value.to_str()?))
+    } else if let Ok(list) = value.downcast::<PyList>() {
+        Ok(Object::List(list.iter().map(element).collect::<PyResult<_>>()?))
+    } else if let Ok(tuple) = value.downcast::<PyTuple>() {
+        Ok(Object::List(tuple.iter().map(element).collect::<PyResult<_>>()?))
+    } else if let Ok(dict) = value.downcast::<PyDict>() {
+        let mut fields = Vec::with_capacity(dict.len());
+        for (key, value) in dict.iter() {
+            let Ok(key) = key.downcast::<PyString>() else {
+                return Err(PyTypeError::new_err("only dicts with str keys convert to opl records"));
+            };
+            fields.push((key.to_str()?.to_string(), element(value)?));
+        }
+        Ok(Object::Record(Record::new(fields)))
+    } else if value.is_instance_of::<PySet>() || value.is_instance_of::<PyFrozenSet>() {
+        let elements = value.iter()?.map(|item| element(item?)).collect::<PyResult<Vec<_>>>()?;
+        Set::from_elements(elements).map(Object::Set).map_err(|error| PyTypeError::new_err(error.to_string()))
+    } else {
+        Err(PyTypeError::new_err(format!("cannot convert {} to an opl value", value.get_type().name()?)))
+    }
+}
+
+fn to_python(py: Python<'_>, value: &Object, depth: usize) -> PyResult<PyObject> {
+    if depth > MAX_DEPTH {
+        return Err(OplError::new_err("value is nested too deeply to convert to Python"));
+    }
+    let element = |item: &Object| to_python(py, item, depth + 1);
+    Ok(match value {
+        Object::Unit | Object::OptionNone => py.None(),
+        Object::Integer(value) => value.into_py(py),
+        Object::Float(value) => value.into_py(py),
+        Object::Boolean(value) => value.into_py(py),
+        Object::String(text) => text.as_str().into_py(py),
+        Object::List(elements) => PyList::new_bound(py, elements.iter().map(element).collect::<PyResult<Vec<_>>>()?).into_py(py),
+        Object::Record(fields) => {
+            let dict = PyDict::new_bound(py);
+            for (name, value) in fields.iter() {
+                dict.set_item(name, element(value)?)?;
+            }
+            dict.into_py(py)
+        }
+        Object::Map(map) => {
+            let dict = PyDict::new_bound(py);
+            for (key, value) in map.iter() {
+                dict.set_item(element(key)?, element(value)?)?;
+            }
+            dict.into_py(py)
+        }
+        Object::Set(set) => PySet::new_bound(py, &set.iter().map(element).collect::<PyResult<Vec<_>>>()?)?.into_py(py),
+        Object::Deque(deque) => {
+            let elements = deque.to_vec().iter().map(element).collect::<PyResult<Vec<_>>>()?;
+            py.import_bound("collections")?.getattr("deque")?.call1((elements,))?.unbind()
+        }
+        Object::OptionSome(inner) | Object::ResultOk(inner) => element(inner)?,
+        Object::ResultErr(inner) => return Err(OplError::new_err(element(inner)?)),
+        other => return Err(OplError::new_err(format!("cannot convert {} to a Python value", other))),
+    })
+}
+
+#[pymodule]
+fn opl(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("OplError", module.py().get_type_bound::<OplError>())?;
+    module.add_class::<PyEngine>()?;
+    module.add_function(wrap_pyfunction!(eval, module)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_module() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| -> PyResult<()> {
+            let module = PyModule::new_bound(py, "opl")?;
+            opl(&module)?;
+            let globals = PyDict::new_bound(py);
+            globals.set_item("opl", &module)?;
+            let run = |code: &str| py.run_bound(code, Some(&globals), None);
+
+            run("assert opl.eval('let x = [1, 2, 3]; x;') == [1, 2, 3]")?;
+            run("assert opl.eval('2.5;') == 2.5 and opl.eval('true;') is True")?;
+            run("assert opl.eval('{ name = \"opl\", tags = [\"a\"] };') == {'name': 'opl', 'tags': ['a']}")?;
+            run("assert opl.eval('Some(1);') == 1 and opl.eval('None;') is None")?;
+
+            run("engine = opl.Engine()")?;
+            run("engine.register('shout', lambda s: s.upper())")?;
+            run("engine.set('config', {'name': 'opl', 'retries': 3, 'tags': ('a', None), 'ids': {1, 2}})")?;
+            run("assert engine.eval('shout(config.name);') == 'OPL'")?;
+            run("assert engine.eval('config.retries + 1;') == 4")?;
+            run("assert engine.get('config') == {'name': 'opl', 'retries': 3, 'tags': ['a', None], 'ids': {1, 2}}")?;
+
+            // Failures surface as OplError, host functions that raise included
+            run("engine.register('fail', lambda: 1 / 0)")?;
+            run("
+for source in ['Err(\"no\");', 'let;', 'fail();']:
+    try:
+        engine.eval(source)
+        raise AssertionError(source)
+    except opl.OplError:
+        pass
+")?;
+            run("
+try:
+    engine.set('bad', {1: 2})
+    raise AssertionError('int keys')
+except TypeError:
+    pass
+")?;
+            Ok(())
+        })
+        .unwrap();
+    }
+}
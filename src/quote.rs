@@ -0,0 +1,84 @@
+// quote.rs
+//
+// Quoted code, the runtime counterpart of macros: `quote expr` is `expr`
+// unevaluated, as a value that can be passed around, compared and printed,
+// and `eval_quoted(q)` evaluates it. Quotes are not hygienic. The names in
+// quoted code are not bound where it is quoted but looked up where
+// `eval_quoted` is called, in the caller's scope as it is then, so
+//
+//     let area = quote w * h;
+//     let w = 3;
+//     let h = 4;
+//     eval_quoted(area)
+//
+// is 12, and a quote passed to a function reads that function's bindings
+// where they have the same names. Quoted code is neither type checked nor
+// optimized, as what its names stand for is only known once it runs. Two
+// quotes are equal when their code has the same structure, as
+// `opl diff --semantic` compares it, and a quote prints as its source.
+
+use crate::ast::Expression;
+use crate::diagnostics::Code;
+use crate::environment::Env;
+use crate::evaluator::Evaluator;
+use crate::object::{Native, Object};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// An expression quoted with `quote`, and its source text.
+#[derive(Clone, Debug)]
+pub struct Quoted {
+    pub expression: Rc<Expression>,
+    pub source: Rc<str>,
+}
+
+impl PartialEq for Quoted {
+    fn eq(&self, other: &Self) -> bool {
+        crate::diff::same(&self.expression, &other.expression)
+    }
+}
+
+impl fmt::Display for Quoted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quote {}", self.source)
+    }
+}
+
+/// `eval_quoted` as called from `env`, which the quoted code's names are
+/// looked up in.
+pub(crate) fn eval_quoted(env: Rc<RefCell<Env>>) -> Object {
+    let function = move |arguments: Vec<Object>| match arguments.as_slice() {
+        [Object::Quoted(quoted)] => match Evaluator::new(Rc::clone(&env)).eval_expression(&quoted.expression) {
+            Some(Object::Return(ref mut value)) => std::mem::take(&mut **value),
+            Some(value) => value,
+            None => Object::Unit,
+        },
+        [other] => Object::Error(Code::BuiltinArgument, format!("eval_quoted expects a quote, got {}", other.type_name())),
+        _ => Object::Error(Code::BuiltinArgument, "eval_quoted expects exactly one argument".to_string()),
+    };
+    Object::Native(Native { name: "eval_quoted".to_string(), function: Rc::new(function) })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_quote() {
+        let eval = |source: &str| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&Parser::new(Lexer::new(source)).parse_program());
+        let area = "let area = quote w * h;\nlet w = 3;\nlet h = 4;\n";
+        assert_eq!(eval(&format!("{}area", area)).map(|q| q.to_string()), Some("quote w * h".to_string()));
+        // Names are looked up where the quote is evaluated, not where it was made
+        assert_eq!(eval(&format!("{}eval_quoted(area)", area)), Some(Object::Integer(12)));
+        assert_eq!(eval(&format!("{}let wide = fn q -> {{ let w = 10; eval_quoted(q) }};\nwide(area)", area)), Some(Object::Integer(40)));
+        assert_eq!(eval(&format!("{}[(quote w*(h)) == area, (quote h * w) == area]", area)), Some(Object::List(vec![true.into(), false.into()])));
+        assert!(matches!(eval("eval_quoted(quote x)"), Some(Object::Error(..))));
+    }
+}
@@ -0,0 +1,246 @@
+// record.rs
+//
+// Records keep their field names apart from their values: the names, in
+// declaration order, form a shape shared by every record with the same
+// fields, and the values are a flat vector in that order. Shapes are
+// interned per thread while some record holds them, so one shape is always
+// the same allocation, and a field access can remember the index it found a
+// field at for the last shape it saw, see `FieldCache`.
+
+use crate::object::Object;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::Zip;
+use std::rc::{Rc, Weak};
+use std::slice;
+
+type Shape = Rc<[String]>;
+
+// Shapes by the hash of their names. The table only holds them weakly, so a
+// shape is freed with the last record of it, and entries of freed shapes are
+// swept out once the table has doubled since the last sweep.
+#[derive(Default)]
+struct Shapes {
+    table: HashMap<u64, Vec<Weak<[String]>>>,
+    entries: usize,
+    limit: usize,
+}
+
+thread_local! {
+    static SHAPES: RefCell<Shapes> = RefCell::new(Shapes::default());
+}
+
+fn shape(names: &[String]) -> Shape {
+    let mut hasher = DefaultHasher::new();
+    names.hash(&mut hasher);
+    let hash = hasher.finish();
+    SHAPES.with(|shapes| {
+        let mut shapes = shapes.borrow_mut();
+        let found = shapes.table.get(&hash).and_then(|entries| {
+            entries.iter().filter_map(Weak::upgrade).find(|shape| **shape == *names)
+        });
+        if let Some(shape) = found {
+            return shape;
+        }
+        let shape: Shape = names.into();
+        shapes.table.entry(hash).or_default().push(Rc::downgrade(&shape));
+        shapes.entries += 1;
+        if shapes.entries > shapes.limit {
+            shapes.table.retain(|_, entries| {
+                entries.retain(|entry| entry.strong_count() > 0);
+                !entries.is_empty()
+            });
+            shapes.entries = shapes.table.values().map(Vec::len).sum();
+            shapes.limit = (shapes.entries * 2).max(64);
+        }
+        shape
+    })
+}
+
+#[derive(Clone, Default)]
+pub struct Record {
+    shape: Shape,
+    values: Vec<Object>,
+}
+
+impl Record {
+    pub fn new(fields: Vec<(String, Object)>) -> Self {
+        let (names, values): (Vec<String>, Vec<Object>) = fields.into_iter().unzip();
+        Record { shape: shape(&names), values }
+    }
+
+    /// A record of the same shape holding `values` instead.
+    pub fn with_values(&self, values: Vec<Object>) -> Self {
+        debug_assert_eq!(values.len(), self.shape.len());
+        Record { shape: Rc::clone(&self.shape), values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Whether `other` has the same fields in the same order.
+    pub fn same_shape(&self, other: &Record) -> bool {
+        Rc::ptr_eq(&self.shape, &other.shape)
+    }
+
+    pub fn names(&self) -> slice::Iter<'_, String> {
+        self.shape.iter()
+    }
+
+    pub fn values(&self) -> &[Object] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [Object] {
+        &mut self.values
+    }
+
+    /// Fields in declaration order.
+    pub fn iter(&self) -> Zip<slice::Iter<'_, String>, slice::Iter<'_, Object>> {
+        self.shape.iter().zip(&self.values)
+    }
+
+    pub fn iter_mut(&mut self) -> Zip<slice::Iter<'_, String>, slice::IterMut<'_, Object>> {
+        self.shape.iter().zip(&mut self.values)
+    }
+
+    /// The field at `index` in declaration order.
+    pub fn get_index(&self, index: usize) -> Option<(&String, &Object)> {
+        Some((self.shape.get(index)?, self.values.get(index)?))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Object> {
+        self.shape.iter().position(|field| field == name).map(|index| &self.values[index])
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Object> {
+        self.shape.iter().position(|field| field == name).map(|index| &mut self.values[index])
+    }
+
+    /// `get`, looking first where `cache` last found a field in a record of
+    /// this shape.
+    pub fn get_cached(&self, name: &str, cache: &FieldCache) -> Option<&Object> {
+        if let Some((shape, index)) = &*cache.0.borrow() {
+            if Rc::ptr_eq(shape, &self.shape) {
+                return Some(&self.values[*index]);
+            }
+        }
+        let index = self.shape.iter().position(|field| field == name)?;
+        *cache.0.borrow_mut() = Some((Rc::clone(&self.shape), index));
+        Some(&self.values[index])
+    }
+
+    /// Adds a field after the others, which changes the record's shape. Each
+    /// call builds a shape, so build many fields with `new` instead.
+    pub fn push(&mut self, name: String, value: Object) {
+        let mut names = self.shape.to_vec();
+        names.push(name);
+        self.shape = shape(&names);
+        self.values.push(value);
+    }
+}
+
+impl From<Vec<(String, Object)>> for Record {
+    fn from(fields: Vec<(String, Object)>) -> Self {
+        Record::new(fields)
+    }
+}
+
+impl FromIterator<(String, Object)> for Record {
+    fn from_iter<I: IntoIterator<Item = (String, Object)>>(fields: I) -> Self {
+        Record::new(fields.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Record {
+    type Item = (String, Object);
+    type IntoIter = std::vec::IntoIter<(String, Object)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.shape.iter().cloned().zip(self.values).collect::<Vec<_>>().into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Record {
+    type Item = (&'a String, &'a Object);
+    type IntoIter = Zip<slice::Iter<'a, String>, slice::Iter<'a, Object>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Where a field access last found its field, as the shape it looked in and
+/// the field's index there. Accesses at one place in a program mostly see
+/// records of one shape. The cache keeps that shape alive, so its address is
+/// not reused by another. Copies of an expression share nothing, and every
+/// cache compares equal, so it does not affect comparing expressions.
+#[derive(Clone, Default)]
+pub struct FieldCache(RefCell<Option<(Shape, usize)>>);
+
+impl PartialEq for FieldCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for FieldCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FieldCache")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_share_shapes() {
+        let point = |x, y| Record::new(vec![("x".to_string(), Object::Integer(x)), ("y".to_string(), Object::Integer(y))]);
+        let (a, b) = (point(1, 2), point(3, 4));
+        assert!(a.same_shape(&b));
+        assert!(!a.same_shape(&Record::new(vec![("y".to_string(), Object::Integer(1)), ("x".to_string(), Object::Integer(2))])));
+
+        // A cached index is only used for the shape it was found in
+        let cache = FieldCache::default();
+        assert_eq!(a.get_cached("y", &cache), Some(&Object::Integer(2)));
+        assert_eq!(b.get_cached("y", &cache), Some(&Object::Integer(4)));
+        let other = Record::new(vec![("y".to_string(), Object::Integer(5))]);
+        assert_eq!(other.get_cached("y", &cache), Some(&Object::Integer(5)));
+        assert_eq!(other.get_cached("x", &FieldCache::default()), None);
+    }
+
+    #[test]
+    fn test_unused_shapes_are_dropped() {
+        let record = |name: &str| Record::new(vec![(name.to_string(), Object::Unit)]);
+        let first = record("dropped");
+        let shape = Rc::downgrade(&first.shape);
+        assert!(record("dropped").same_shape(&first));
+        drop(first);
+        assert!(shape.upgrade().is_none());
+
+        // Growing a record one field at a time leaves only its final shape
+        let mut wide = Record::default();
+        let before = SHAPES.with(|shapes| shapes.borrow().table.values().flatten().filter(|entry| entry.strong_count() > 0).count());
+        for i in 0..1000 {
+            wide.push(format!("field{}", i), Object::Integer(i));
+        }
+        let after = SHAPES.with(|shapes| shapes.borrow().table.values().flatten().filter(|entry| entry.strong_count() > 0).count());
+        assert_eq!(after, before + 1);
+        assert!(SHAPES.with(|shapes| shapes.borrow().entries) < 1000);
+    }
+}
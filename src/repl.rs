@@ -1,53 +1,599 @@
 // repl.rs
 
-use crate::{environment::Env, evaluator::Evaluator};
-use crate::lexer::Lexer;
-use crate::parser::Parser;
-use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+use crate::ast::{Expression, Macro, Program, Rule};
+use crate::diagnostics::Code;
+use crate::{environment::Env, evaluator::{self, Evaluator, Resume, Stop}};
+use crate::lexer::{Lexer, Span};
+use crate::object::Object;
+use crate::{handle, optimizer};
+use crate::parser::{Operators, Parser};
+use crate::style::{self, Style};
+use crate::typechecker::TypeChecker;
+use std::{cell::RefCell, collections::{BTreeSet, HashMap, HashSet}, fs, rc::Rc};
 
-pub fn start(parse: bool) {
+const PROMPT: &str = "$ ";
+const CONTINUATION: &str = ". ";
+const DEBUG_PROMPT: &str = "debug$ ";
+const HELP: &str = "\
+:help          Show this message
+:type <expr>   Show the inferred type of an expression without evaluating it
+:ast <expr>    Show the parsed syntax tree
+:env           List the bindings in the session
+:load <file>   Evaluate a .opl file into the session
+clear          Clear the screen
+exit           Leave the REPL, or exit(status) to leave with a status";
+const DEBUG_HELP: &str = "\
+<expr>               Evaluate an expression in the scope the run is stopped in
+:env                 List the bindings the scope can see
+:reverse-step        Go back to the operator or call before this one
+:step                Go on to the next operator or call
+:break [<line>]      Set a breakpoint on a line, or list them
+:reverse-continue    Go back to the last operator or call on a breakpoint
+:continue [<expr>]   Carry on; at the error, with the value of <expr> in place of the failed expression's
+:abort               Let the error stop the program, as does the end of input";
 
-    let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::new())));
-    loop {
-        print!("$ ");
+pub enum Line {
+    Input(String),
+    Interrupted,
+    Eof,
+}
+
+// Returns true while `input` has unclosed delimiters or an unterminated string
+pub fn is_incomplete(input: &str) -> bool {
+    let mut depth: i64 = 0;
+    let mut in_string = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_string {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '-' if chars.peek() == Some(&'-') => {
+                // Comments run to the end of the line
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    in_string || depth > 0
+}
+
+#[cfg(feature = "line-editor")]
+mod editor {
+    use super::Line;
+    use crate::completion;
+    use crate::environment::Env;
+    use crate::style;
+    use rustyline::completion::{Completer, Pair};
+    use rustyline::error::ReadlineError;
+    use rustyline::highlight::{CmdKind, Highlighter};
+    use rustyline::hint::Hinter;
+    use rustyline::history::DefaultHistory;
+    use rustyline::validate::Validator;
+    use rustyline::{Config, Context, Editor, Helper};
+    use std::{borrow::Cow, cell::RefCell, path::PathBuf, rc::Rc};
+
+    struct OplHelper {
+        env: Rc<RefCell<Env>>,
+    }
+
+    impl Completer for OplHelper {
+        type Candidate = Pair;
+
+        fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+            let (start, items) = completion::complete(line, pos, &self.env.borrow());
+            let pairs = items
+                .into_iter()
+                .map(|item| Pair { display: item.label.clone(), replacement: item.label })
+                .collect();
+            Ok((start, pairs))
+        }
+    }
+
+    impl Hinter for OplHelper {
+        type Hint = String;
+    }
+
+    // Echoes input highlighted as it is typed, when color is on
+    impl Highlighter for OplHelper {
+        fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+            if style::enabled() {
+                Cow::Owned(style::highlight(line))
+            } else {
+                Cow::Borrowed(line)
+            }
+        }
+
+        fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+            style::enabled()
+        }
+    }
+
+    impl Validator for OplHelper {}
+
+    impl Helper for OplHelper {}
+
+    pub struct LineEditor {
+        editor: Option<Editor<OplHelper, DefaultHistory>>,
+        history: Option<PathBuf>,
+    }
+
+    impl LineEditor {
+        pub fn new(env: Rc<RefCell<Env>>) -> Self {
+            let config = Config::builder().auto_add_history(false).bracketed_paste(true).build();
+            let editor = Editor::with_config(config).ok().map(|mut editor| {
+                editor.set_helper(Some(OplHelper { env }));
+                editor
+            });
+            let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".opl_history"));
+            let mut line_editor = LineEditor { editor, history };
+            if let (Some(editor), Some(path)) = (line_editor.editor.as_mut(), line_editor.history.as_ref()) {
+                let _ = editor.load_history(path);
+            }
+            line_editor
+        }
+
+        pub fn read(&mut self, prompt: &str) -> Line {
+            let Some(editor) = self.editor.as_mut() else {
+                return super::stdin::read(prompt);
+            };
+            match editor.readline(prompt) {
+                Ok(line) => Line::Input(line),
+                Err(ReadlineError::Interrupted) => Line::Interrupted,
+                Err(_) => Line::Eof,
+            }
+        }
+
+        pub fn add_history(&mut self, entry: &str) {
+            if let Some(editor) = self.editor.as_mut() {
+                let _ = editor.add_history_entry(entry);
+            }
+        }
+
+        pub fn save_history(&mut self) {
+            if let (Some(editor), Some(path)) = (self.editor.as_mut(), self.history.as_ref()) {
+                let _ = editor.save_history(path);
+            }
+        }
+    }
+}
+
+mod stdin {
+    use super::Line;
+    use std::io::{self, Write};
+
+    pub fn read(prompt: &str) -> Line {
+        print!("{}", prompt);
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        if let Err(err) = io::stdin().read_line(&mut input) {
-            println!("Error reading input: {}", err);
+        match io::stdin().read_line(&mut input) {
+            Ok(0) => Line::Eof,
+            Ok(_) => Line::Input(input.trim_end_matches(['\n', '\r']).to_string()),
+            Err(err) => {
+                println!("Error reading input: {}", err);
+                Line::Interrupted
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "line-editor"))]
+mod editor {
+    use super::Line;
+    use crate::environment::Env;
+    use std::{cell::RefCell, rc::Rc};
+
+    pub struct LineEditor;
+
+    impl LineEditor {
+        pub fn new(_env: Rc<RefCell<Env>>) -> Self {
+            LineEditor
+        }
+
+        pub fn read(&mut self, prompt: &str) -> Line {
+            super::stdin::read(prompt)
+        }
+
+        pub fn add_history(&mut self, _entry: &str) {}
+
+        pub fn save_history(&mut self) {}
+    }
+}
+
+struct Session {
+    evaluator: Evaluator,
+    checker: TypeChecker,
+    parse: bool,
+    // Operators, macros, rules and constants defined by earlier input
+    operators: Operators,
+    macros: Vec<Macro>,
+    rules: Vec<Rule>,
+    constants: HashMap<String, Expression>,
+    // Set once the input calls `exit`, which ends the session
+    exit: Option<i32>,
+}
+
+impl Session {
+    fn new(parse: bool) -> Self {
+        Session {
+            evaluator: Evaluator::new(Rc::new(RefCell::new(Env::new()))),
+            checker: TypeChecker::new(),
+            parse,
+            operators: Operators::new(),
+            macros: Vec::new(),
+            rules: Vec::new(),
+            constants: HashMap::new(),
+            exit: None,
+        }
+    }
+
+    fn parse(&mut self, input: &str, out: &mut Vec<String>) -> Option<Program> {
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::with_operators(lexer, &self.operators);
+        parser.macros = std::mem::take(&mut self.macros);
+        parser.rules = std::mem::take(&mut self.rules);
+        parser.constants = std::mem::take(&mut self.constants);
+        let program = parser.parse_program();
+        self.operators = std::mem::take(&mut parser.operators);
+        self.macros = std::mem::take(&mut parser.macros);
+        self.rules = std::mem::take(&mut parser.rules);
+        self.constants = std::mem::take(&mut parser.constants);
+        if !parser.errors.is_empty() {
+            out.push("Parser errors:".to_string());
+            for error in parser.errors {
+                out.push(format!("  {}", error));
+            }
+            return None;
+        }
+        Some(program)
+    }
+
+    fn eval_input(&mut self, input: &str, out: &mut Vec<String>) {
+        let Some(program) = self.parse(input, out) else {
+            return;
+        };
+        if self.parse {
+            out.push(format!("{:?}", program));
+            return;
+        }
+        // Keep the checker's bindings in step with the evaluator's; the
+        // evaluator remains the authority on errors.
+        self.checker.check_program(&program);
+        self.checker.errors.clear();
+        let program = optimizer::optimize(program);
+        evaluator::clear_interrupt();
+        match self.evaluator.eval(&program) {
+            Some(Object::Error(Code::Exit(status), _)) => self.exit = Some(status),
+            Some(object) => out.push(format!("# {}", object)),
+            None => {}
+        }
+    }
+
+    fn type_of(&mut self, input: &str, out: &mut Vec<String>) {
+        let Some(program) = self.parse(input, out) else {
+            return;
+        };
+        match self.checker.infer_program(&program) {
+            Ok(ty) => out.push(format!("{}", ty)),
+            Err(error) => out.push(format!("{}", error)),
+        }
+    }
+
+    fn ast(&mut self, input: &str, out: &mut Vec<String>) {
+        if let Some(program) = self.parse(input, out) {
+            out.push(format!("{:#?}", program));
+        }
+    }
+
+    fn print_env(&self, out: &mut Vec<String>) {
+        let env = self.evaluator.env.borrow();
+        let mut names: Vec<&String> = env.store.keys().collect();
+        names.sort();
+        for name in names {
+            let value = &env.store[name];
+            let ty = match self.checker.binding(name) {
+                Some(ty) => ty.to_string(),
+                None => value.type_name(),
+            };
+            out.push(format!("{} : {} = {}", name, ty, value));
+        }
+    }
+
+    fn load(&mut self, file: &str, out: &mut Vec<String>) {
+        match fs::read_to_string(file) {
+            Ok(content) => self.eval_input(&content, out),
+            Err(e) => out.push(format!("Error reading file '{}': {}", file, e)),
+        }
+    }
+
+    // Runs one complete input, a meta command or code. Returns false when the
+    // session should end.
+    fn handle(&mut self, input: &str, out: &mut Vec<String>) -> bool {
+        if input.starts_with(':') {
+            return self.meta_command(input, out);
+        }
+        self.eval_input(input, out);
+        self.exit.is_none()
+    }
+
+    // Returns false when the session should end
+    fn meta_command(&mut self, input: &str, out: &mut Vec<String>) -> bool {
+        let (command, argument) = match input.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (input, ""),
+        };
+        match command {
+            ":help" => out.push(HELP.to_string()),
+            ":type" => self.type_of(argument, out),
+            ":ast" => self.ast(argument, out),
+            ":env" => self.print_env(out),
+            ":load" if !argument.is_empty() => {
+                self.load(argument, out);
+                return self.exit.is_none();
+            }
+            ":load" => out.push("Usage: :load <file>".to_string()),
+            ":quit" => return false,
+            _ => out.push(format!("Unknown command '{}', try :help", command)),
+        }
+        true
+    }
+}
+
+/// Replays a transcript of a session: `>>> ` lines are inputs, `... ` lines
+/// continue the input above them, and the lines up to the next input are
+/// what the session must print for it, e.g.
+///
+/// ```text
+/// >>> let inc = fn x -> x + 1;
+/// >>> inc(1)
+/// # 2
+/// >>> :type inc
+/// fn int -> int
+/// ```
+///
+/// Blank lines after an output are not part of it. Output of `println` goes
+/// to stdout and is not compared. Fails at the first difference.
+pub fn replay(transcript: &str) -> Result<(), String> {
+    let mut session = Session::new(false);
+    let mut lines = transcript.lines().enumerate().peekable();
+    while let Some((number, line)) = lines.next() {
+        let Some(first) = line.strip_prefix(">>> ") else {
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Err(format!("line {}: expected an input starting with `>>> `, got `{}`", number + 1, line));
+        };
+        let mut input = first.to_string();
+        while let Some(more) = lines.peek().and_then(|(_, line)| line.strip_prefix("... ")) {
+            input.push('\n');
+            input.push_str(more);
+            lines.next();
+        }
+        let mut expected = Vec::new();
+        while let Some((_, line)) = lines.next_if(|(_, line)| !line.starts_with(">>> ")) {
+            expected.push(line);
+        }
+        while expected.last().is_some_and(|line| line.trim().is_empty()) {
+            expected.pop();
+        }
+
+        let mut out = Vec::new();
+        session.handle(input.trim(), &mut out);
+        let actual: Vec<&str> = out.iter().flat_map(|output| output.lines()).collect();
+        if actual != expected {
+            return Err(format!(
+                "line {}: `{}` printed\n{}\nbut the transcript expects\n{}",
+                number + 1,
+                input,
+                actual.join("\n"),
+                expected.join("\n")
+            ));
+        }
+    }
+    Ok(())
+}
+
+// A line of output as the terminal shows it: values highlighted like source
+// and errors painted as errors
+fn styled(line: &str) -> String {
+    match line.strip_prefix("# ") {
+        Some(error) if error.starts_with("error[") => format!("# {}", style::paint(error, Style::Error)),
+        Some(value) => format!("# {}", style::highlight(value)),
+        None => line.to_string(),
+    }
+}
+
+pub fn start(parse: bool) {
+
+    let mut session = Session::new(parse);
+    let mut editor = editor::LineEditor::new(Rc::clone(&session.evaluator.env));
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() { PROMPT } else { CONTINUATION };
+        let line = match editor.read(prompt) {
+            Line::Input(line) => line,
+            Line::Interrupted => {
+                buffer.clear();
+                continue;
+            }
+            Line::Eof => break,
+        };
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if is_incomplete(&buffer) {
             continue;
         }
+        let input = std::mem::take(&mut buffer);
 
-        let input = input.trim_matches(|c: char| c.is_whitespace() && c != '\n');
+        let input = input.trim();
         if input.is_empty() {
             continue;
         }
-        if input.trim() == "exit" {
+        editor.add_history(input);
+        if input == "exit" {
             break;
         }
-        if input.trim() == "clear" {
+        if input == "clear" {
             print!("\x1b[2J\x1b[H");
             continue;
         }
+        let mut out = Vec::new();
+        let running = session.handle(input, &mut out);
+        for line in out {
+            println!("{}", styled(&line));
+        }
+        if !running {
+            break;
+        }
+    }
+    editor.save_history();
+    handle::close_all();
+    if let Some(status) = session.exit {
+        std::process::exit(status);
+    }
+}
 
-        let lexer = Lexer::new(input);
-        let mut parser = Parser::new(lexer);
-        let program = parser.parse_program();
-        if !parser.errors.is_empty() {
-            println!("Parser errors:");
-            for error in parser.errors {
-                println!("Errors {:#?}", error);
+/// The debugger of `opl run --debug-on-error`, a REPL in the scope where
+/// the run is stopped, first at the error, see `evaluator::Debugger`.
+/// `location` names a span, e.g. `main.opl:3:7`, and `breakpoints` are the
+/// lines `:break` has set so far.
+pub fn debug(stop: &Stop, location: &dyn Fn(Span) -> String, breakpoints: &RefCell<BTreeSet<usize>>) -> Resume {
+    let arrow = style::paint("-->", Style::Location);
+    match (stop.moment, stop.span) {
+        (None, span) => {
+            eprintln!("{}", style::paint(&stop.error.to_string(), Style::Error));
+            if let Some(span) = span {
+                eprintln!("  {} near {}", arrow, location(span));
             }
-        } else {
-            // Swap this if-else
-            if parse {
-                println!("{:?}", program);
-            } else {
-                match evaluator.eval(&program) {
-                    Some(object) => println!("# {}", object),
-                    None => (),
-                };
+            eprintln!("Debugging in the scope of the error; :help lists the commands");
+        }
+        (Some(moment), Some(span)) => eprintln!("  {} moment {} at {}", arrow, moment, location(span)),
+        (Some(moment), None) => eprintln!("  {} moment {}", arrow, moment),
+    }
+    // Where the run is, the moment after the last one reached at the error
+    let now = stop.moment.unwrap_or(stop.earliest + stop.moments.len());
+    let env = Rc::clone(&stop.env);
+    let mut evaluator = Evaluator::new(Rc::clone(&env));
+    let mut editor = editor::LineEditor::new(Rc::clone(&env));
+    loop {
+        let line = match editor.read(DEBUG_PROMPT) {
+            Line::Input(line) => line,
+            Line::Interrupted => continue,
+            Line::Eof => return Resume::Abort,
+        };
+        let input = line.trim();
+        editor.add_history(input);
+        let (command, argument) = match input.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (input, ""),
+        };
+        match command {
+            "" => {}
+            ":help" => println!("{}", DEBUG_HELP),
+            ":env" => bindings(&env).iter().for_each(|binding| println!("{}", binding)),
+            ":abort" => return Resume::Abort,
+            ":reverse-step" if now > stop.earliest => return Resume::Travel(now - 1),
+            ":reverse-step" => println!("The run cannot go back any further"),
+            ":step" => match stop.moment {
+                Some(moment) => return Resume::Travel(moment + 1),
+                None => println!("The run is stopped at the error"),
+            },
+            ":break" if argument.is_empty() => breakpoints.borrow().iter().for_each(|line| println!("line {}", line)),
+            ":break" => match argument.parse::<usize>() {
+                Ok(line) => {
+                    breakpoints.borrow_mut().insert(line);
+                }
+                Err(_) => println!("Usage: :break <line>"),
+            },
+            ":reverse-continue" => {
+                let breakpoints = breakpoints.borrow();
+                match (stop.earliest..now).rev().find(|moment| breakpoints.contains(&stop.moments[moment - stop.earliest].line)) {
+                    Some(moment) => return Resume::Travel(moment),
+                    None => println!("No breakpoint was reached before this"),
+                }
             }
+            ":continue" if argument.is_empty() && stop.moment.is_none() => println!("Usage: :continue <expr>"),
+            ":continue" if argument.is_empty() => return Resume::Continue(None),
+            ":continue" if stop.moment.is_some() => println!("Only at the error can :continue take a value"),
+            ":continue" => match evaluate(&mut evaluator, argument) {
+                Ok(value) => return Resume::Continue(Some(value.unwrap_or(Object::Unit))),
+                Err(message) => println!("{}", styled(&message)),
+            },
+            _ if command.starts_with(':') => println!("Unknown command '{}', try :help", command),
+            _ => match evaluate(&mut evaluator, input) {
+                Ok(Some(value)) => println!("{}", styled(&format!("# {}", value))),
+                Ok(None) => {}
+                Err(message) => println!("{}", styled(&message)),
+            },
+        }
+    }
+}
+
+// Runs the input, or gives its parse errors or runtime error as a line
+fn evaluate(evaluator: &mut Evaluator, input: &str) -> Result<Option<Object>, String> {
+    let mut parser = Parser::new(Lexer::new(input));
+    let program = parser.parse_program();
+    if !parser.errors.is_empty() {
+        let errors: Vec<String> = parser.errors.iter().map(|error| error.to_string()).collect();
+        return Err(format!("Parser errors: {}", errors.join("; ")));
+    }
+    match evaluator.eval(&program) {
+        Some(error @ Object::Error(..)) => Err(format!("# {}", error)),
+        value => Ok(value),
+    }
+}
+
+// `name = value` for each binding `env` can see, innermost scope first
+fn bindings(env: &Rc<RefCell<Env>>) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut seen = HashSet::new();
+    let mut scope = Some(Rc::clone(env));
+    while let Some(current) = scope {
+        let current = current.borrow();
+        let mut names: Vec<&String> = current.store.keys().filter(|name| !seen.contains(*name)).collect();
+        names.sort();
+        for name in names {
+            lines.push(format!("{} = {}", name, current.store[name]));
+            seen.insert(name.clone());
         }
+        scope = current.outer.clone();
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete("let f = fn x -> {"));
+        assert!(is_incomplete("let s = \"abc"));
+        assert!(!is_incomplete("let f = fn x -> { x };"));
+        assert!(!is_incomplete("-- a comment with {"));
+        assert!(!is_incomplete("let s = \"{\";"));
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(replay(include_str!("../tests/session.repl")), Ok(()));
+        let error = replay(">>> 1 + 1\n# 3").unwrap_err();
+        assert_eq!(error, "line 1: `1 + 1` printed\n# 2\nbut the transcript expects\n# 3");
     }
 }
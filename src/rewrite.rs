@@ -0,0 +1,232 @@
+// rewrite.rs
+//
+// Rewrite rules, which the optimizer applies before its own rewrites, so a
+// library can teach it the algebra of its functions:
+//
+//   rule "map_map": map(f, map(g, xs)) => map(fn x -> f(g(x)), xs);
+//
+// In a pattern, a name stands for any expression, the same one wherever it
+// appears, but for the name of a called function, which stands for itself;
+// function literals and blocks only match as they are written. Expressions
+// are rewritten innermost first: one matching a pattern is replaced by the
+// rule's replacement, with what each name matched in its place, and the
+// result is rewritten again. The names a replacement binds, such as `x`
+// above, are renamed apart from the program's. Like the optimizer's own
+// rewrites, a rule is trusted to keep results, and unlike them it may change
+// how often an expression is evaluated; its other names are looked up where
+// the replacement ends up. A rule that undoes itself, or two undoing each
+// other, would rewrite forever, so rewriting stops after 64 nested rewrites,
+// leaving the expression as it then is, which the `rewrite_limit` lint
+// reports.
+
+use crate::ast::*;
+use crate::closure;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::diff;
+use crate::lexer::{Span, Token};
+use crate::macros::{children, Child};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const MAX_DEPTH: usize = 64;
+
+struct Rewriter<'a> {
+    rules: &'a [Rule],
+    // Replacements made, numbering the names they bind
+    count: usize,
+    limited: Vec<Diagnostic>,
+}
+
+/// Applies `program.rules` to the program, giving a `rewrite_limit`
+/// diagnostic for each statement where rewriting was stopped.
+pub fn rewrite(program: &mut Program) -> Vec<Diagnostic> {
+    if program.rules.is_empty() {
+        return Vec::new();
+    }
+    let mut rewriter = Rewriter { rules: &program.rules, count: 0, limited: Vec::new() };
+    for (index, statement) in program.statements.iter_mut().enumerate() {
+        let before = rewriter.limited.len();
+        rewriter.statement(statement);
+        // Located at the statement when the expression has no span of its own
+        if let Some(span) = program.spans.get(index) {
+            rewriter.limited[before..].iter_mut().filter(|diagnostic| diagnostic.span.is_none()).for_each(|diagnostic| diagnostic.span = Some(*span));
+        }
+    }
+    let (limited, count) = (rewriter.limited, rewriter.count);
+    // Replacements bring in function literals and names of their own
+    if count > 0 {
+        closure::annotate(program);
+    }
+    limited
+}
+
+impl Rewriter<'_> {
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => self.expression(expression, 0, None),
+            Statement::Assert(assertion) => self.expression(&mut assertion.condition, 0, None),
+            Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+        }
+    }
+
+    // `origin` is the span of what was written in place of an expression
+    // being rewritten, for the diagnostic
+    fn expression(&mut self, expression: &mut Expression, depth: usize, origin: Option<Span>) {
+        // Quoted code is a value, left as it was written
+        if let Expression::Quote(..) = expression {
+            return;
+        }
+        for child in children(expression) {
+            match child {
+                Child::Expression(child) => self.expression(child, depth, origin),
+                Child::Block(block) => block.iter_mut().for_each(|statement| self.statement(statement)),
+            }
+        }
+        let Some((rule, bindings)) = self.rules.iter().find_map(|rule| {
+            let mut bindings = Vec::new();
+            matches(&rule.pattern, expression, &mut bindings).then(|| (rule, bindings.into_iter().collect::<HashMap<_, _>>()))
+        }) else {
+            return;
+        };
+        if depth >= MAX_DEPTH {
+            let mut diagnostic = Diagnostic::new(Code::RewriteLimit, format!(
+                "rule {:?} still applies after {} nested rewrites, so it likely undoes itself or another rule",
+                rule.name, MAX_DEPTH
+            ));
+            diagnostic.span = origin;
+            self.limited.push(diagnostic.with_note(format!("rule {:?} is declared at line {}, column {}", rule.name, rule.span.line, rule.span.column)));
+            return;
+        }
+        self.count += 1;
+        let mut replacement = rule.replacement.clone();
+        self.substitute(&mut replacement, &bindings, &HashMap::new());
+        let origin = origin.or(expression.span());
+        *expression = replacement;
+        self.expression(expression, depth + 1, origin);
+    }
+
+    // Puts what the pattern's names matched in place of them, renaming the
+    // names bound in the replacement as it goes
+    fn substitute(&self, expression: &mut Expression, bindings: &HashMap<&str, Expression>, renamed: &HashMap<String, String>) {
+        match expression {
+            Expression::Identifier(Token::Identifier(name)) => {
+                if let Some(fresh) = renamed.get(name.as_str()) {
+                    *name = fresh.clone();
+                } else if let Some(bound) = bindings.get(name.as_str()) {
+                    *expression = bound.clone();
+                }
+            }
+            Expression::Function { parameters, body, captures } => {
+                let mut renamed = renamed.clone();
+                for parameter in parameters.iter_mut() {
+                    if let Token::Identifier(name) = parameter {
+                        let fresh = self.fresh(name);
+                        renamed.insert(std::mem::replace(name, fresh.clone()), fresh);
+                    }
+                }
+                *captures = None;
+                self.block(Rc::make_mut(body), bindings, renamed);
+            }
+            _ => {
+                for child in children(expression) {
+                    match child {
+                        Child::Expression(child) => self.substitute(child, bindings, renamed),
+                        Child::Block(block) => self.block(block, bindings, renamed.clone()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn block(&self, block: &mut Block, bindings: &HashMap<&str, Expression>, mut renamed: HashMap<String, String>) {
+        for statement in block.iter_mut() {
+            if let Statement::Let(Token::Identifier(name), ..) = statement {
+                let fresh = self.fresh(name);
+                renamed.insert(std::mem::replace(name, fresh.clone()), fresh);
+            }
+            match statement {
+                Statement::Let(_, expression, _) | Statement::Return(expression) | Statement::Expression(expression) => self.substitute(expression, bindings, &renamed),
+                Statement::Assert(assertion) => self.substitute(&mut assertion.condition, bindings, &renamed),
+                Statement::Type(..) | Statement::Comment(_) | Statement::Attribute(..) => {}
+            }
+        }
+    }
+
+    // e.g. `x#rule3` for `x` in the third replacement made, a name no
+    // program can bind
+    fn fresh(&self, name: &str) -> String {
+        format!("{}#rule{}", name, self.count)
+    }
+}
+
+// Whether `expression` matches `pattern`, adding what its names stand for to
+// `bindings`
+fn matches<'a>(pattern: &'a Expression, expression: &Expression, bindings: &mut Vec<(&'a str, Expression)>) -> bool {
+    let all = |patterns: &'a [Expression], expressions: &[Expression], bindings: &mut Vec<(&'a str, Expression)>| {
+        patterns.len() == expressions.len() && patterns.iter().zip(expressions).all(|(p, e)| matches(p, e, bindings))
+    };
+    match (pattern, expression) {
+        (Expression::Identifier(Token::Identifier(name)), _) => match bindings.iter().find(|(bound, _)| bound == name) {
+            Some((_, bound)) => diff::same(bound, expression),
+            None => {
+                bindings.push((name, expression.clone()));
+                true
+            }
+        },
+        (Expression::OptionSome(p), Expression::OptionSome(e))
+        | (Expression::ResultOk(p), Expression::ResultOk(e))
+        | (Expression::ResultErr(p), Expression::ResultErr(e)) => matches(p, e, bindings),
+        (Expression::Prefix(a, p), Expression::Prefix(b, e)) => a == b && matches(p, e, bindings),
+        (Expression::Infix(a, pl, pr, _), Expression::Infix(b, el, er, _)) => a == b && matches(pl, el, bindings) && matches(pr, er, bindings),
+        (Expression::Literal(Literal::List(p)), Expression::Literal(Literal::List(e))) => all(p, e, bindings),
+        (Expression::Literal(Literal::Record(p)), Expression::Literal(Literal::Record(e))) => {
+            p.len() == e.len() && p.iter().zip(e).all(|((a, p), (b, e))| a == b && matches(p, e, bindings))
+        }
+        (Expression::Call { function: f, arguments: p, .. }, Expression::Call { function: g, arguments: e, .. }) => {
+            let function = match &**f {
+                Expression::Identifier(_) => diff::same(f, g),
+                _ => matches(f, g, bindings),
+            };
+            function && all(p, e, bindings)
+        }
+        (Expression::BuiltIn { function: f, arguments: p, .. }, Expression::BuiltIn { function: g, arguments: e, .. }) => f == g && all(p, e, bindings),
+        (Expression::Field { record: p, field: a, optional: x, .. }, Expression::Field { record: e, field: b, optional: y, .. }) => {
+            a == b && x == y && matches(p, e, bindings)
+        }
+        (Expression::Range { start: ps, end: pe }, Expression::Range { start: es, end: ee }) => matches(ps, es, bindings) && matches(pe, ee, bindings),
+        (Expression::Annotated { expression: p, annotation: a }, Expression::Annotated { expression: e, annotation: b }) => {
+            a == b && matches(p, e, bindings)
+        }
+        _ => diff::same(pattern, expression),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::optimizer::optimize;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rewrite() {
+        let source = "rule \"map_map\": map(f, map(g, xs)) => map(fn x -> f(g(x)), xs);\nrule \"add_zero\": n + 0 => n;\n\
+                      let x = 10;\nlet twice = fn n -> n * 2;\nmap(fn n -> n + x, map(twice, [1, 2] ++ [3 + 0]))";
+        let mut program = Parser::new(Lexer::new(source)).parse_program();
+        assert!(super::rewrite(&mut program).is_empty());
+        // The replacement's `x` does not capture the program's
+        assert_eq!(format!("{:?}", program.statements[2]).matches("x#rule2").count(), 2);
+        let value = Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&optimize(program));
+        assert_eq!(value, Some(Object::List(vec![12.into(), 14.into(), 16.into()])));
+
+        let mut program = Parser::new(Lexer::new("rule \"swap\": a + b => b + a;\nlet f = fn a, b -> a + b;")).parse_program();
+        let limited = super::rewrite(&mut program);
+        assert_eq!(limited.iter().map(|diagnostic| (diagnostic.code, diagnostic.span.map(|span| span.line))).collect::<Vec<_>>(), [(Code::RewriteLimit, Some(2))]);
+        assert_eq!(Parser::new(Lexer::new("rule \"all\": x => 1;")).parse_program().errors.len(), 1);
+    }
+}
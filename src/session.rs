@@ -0,0 +1,176 @@
+// session.rs
+//
+// `Session`, the read-eval loop for notebook-style hosts such as a Jupyter
+// kernel. Source arrives a cell at a time and bindings carry over between
+// cells, as in the REPL. Each cell runs in a top level of its own inside the
+// previous cell's, so a cell may bind a name an earlier one did, and can be
+// run again. Each cell's result has its diagnostics, what it printed and the
+// value it shows. A cell can also be invalidated: the session then starts
+// over from a fresh engine and replays the cells still kept, so nothing the
+// cell bound survives.
+//
+// Printing builtins write through `print`, which a running cell captures.
+
+use crate::completion::{self, Completion};
+use crate::diagnostics::Diagnostic;
+use crate::evaluator;
+use crate::interop::{Engine, EngineConfig};
+use crate::object::Object;
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Set while a debugger runs a program again up to where it had got
+    static MUTED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Writes `text` to stdout, or to the output of the cell being run.
+pub(crate) fn print(text: &str) {
+    if MUTED.get() {
+        return;
+    }
+    let captured = CAPTURED.with_borrow_mut(|captured| captured.as_mut().map(|output| output.push_str(text)).is_some());
+    if !captured {
+        print!("{}", text);
+    }
+}
+
+/// Stops printing, or starts again, so what a program printed once is not
+/// printed a second time.
+pub(crate) fn mute(muted: bool) {
+    MUTED.set(muted);
+}
+
+pub(crate) fn muted() -> bool {
+    MUTED.get()
+}
+
+/// What running a cell gave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellResult {
+    pub id: usize,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Everything the cell printed.
+    pub stdout: String,
+    /// The value of the cell's last statement, None for `()` and on errors.
+    pub value: Option<Object>,
+}
+
+struct Cell {
+    source: String,
+    result: CellResult,
+}
+
+/// Evaluates cells of source one after another, keeping their bindings.
+pub struct Session {
+    engine: Engine,
+    config: EngineConfig,
+    cells: Vec<Cell>,
+    next: usize,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::with_config(EngineConfig::full())
+    }
+
+    /// A session whose cells are held to `config`, like an `Engine`.
+    pub fn with_config(config: EngineConfig) -> Self {
+        Session { engine: Engine::with_config(config.clone()), config, cells: Vec::new(), next: 1 }
+    }
+
+    /// Runs a new cell, numbered one more than the last.
+    pub fn submit(&mut self, source: &str) -> CellResult {
+        let result = self.run(self.next, source);
+        self.next += 1;
+        self.cells.push(Cell { source: source.to_string(), result: result.clone() });
+        result
+    }
+
+    /// Runs cell `id` again, on the bindings as they are now, or None when
+    /// there is no such cell.
+    pub fn rerun(&mut self, id: usize) -> Option<CellResult> {
+        let index = self.cells.iter().position(|cell| cell.result.id == id)?;
+        let source = self.cells[index].source.clone();
+        let result = self.run(id, &source);
+        self.cells[index].result = result.clone();
+        Some(result)
+    }
+
+    /// Forgets cell `id` and replays the others in order on a fresh engine,
+    /// updating their results. False when there is no such cell.
+    pub fn invalidate(&mut self, id: usize) -> bool {
+        let Some(index) = self.cells.iter().position(|cell| cell.result.id == id) else {
+            return false;
+        };
+        self.cells.remove(index);
+        self.engine = Engine::with_config(self.config.clone());
+        for index in 0..self.cells.len() {
+            let source = self.cells[index].source.clone();
+            self.cells[index].result = self.run(self.cells[index].result.id, &source);
+        }
+        true
+    }
+
+    /// The latest result of each cell kept, in the order they were submitted.
+    pub fn results(&self) -> Vec<&CellResult> {
+        self.cells.iter().map(|cell| &cell.result).collect()
+    }
+
+    /// Completions for the word ending at byte `cursor` of `source`, with
+    /// the names the cells have bound, as `completion::complete` gives them.
+    pub fn complete(&self, source: &str, cursor: usize) -> (usize, Vec<Completion>) {
+        completion::complete(source, cursor, &self.engine.env().borrow())
+    }
+
+    /// The engine the cells run on, e.g. to register host functions. They
+    /// are lost when a cell is invalidated.
+    pub fn engine(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    fn run(&mut self, id: usize, source: &str) -> CellResult {
+        let outer = CAPTURED.replace(Some(String::new()));
+        evaluator::clear_interrupt();
+        self.engine.nest_globals();
+        let outcome = self.engine.eval(source);
+        let stdout = CAPTURED.replace(outer).unwrap_or_default();
+        match outcome {
+            Ok(Object::Unit) => CellResult { id, diagnostics: Vec::new(), stdout, value: None },
+            Ok(value) => CellResult { id, diagnostics: Vec::new(), stdout, value: Some(value) },
+            Err(diagnostics) => CellResult { id, diagnostics, stdout, value: None },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Session;
+    use crate::object::Object;
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new();
+        let first = session.submit("let n = 20;\nprintln(\"set\");");
+        assert_eq!((first.id, first.stdout.as_str(), first.value), (1, "set\n", None));
+        let second = session.submit("let n = n + 1;\nn * 2");
+        assert_eq!(second.value, Some(Object::Integer(42)));
+        assert_eq!(session.rerun(2).unwrap().value, Some(Object::Integer(44)));
+
+        assert_eq!(session.submit("let down = fn k -> if k == 0 { n } else { down(k - 1) };\ndown(3)").value, Some(Object::Integer(22)));
+
+        let failed = session.submit("n / 0");
+        assert_eq!((failed.id, failed.diagnostics.len(), failed.value), (4, 1, None));
+
+        // Without the first cell, n is unbound when the second is replayed
+        assert!(session.invalidate(1));
+        assert_eq!(session.results().iter().map(|result| (result.id, result.diagnostics.len())).collect::<Vec<_>>(), [(2, 1), (3, 1), (4, 1)]);
+        assert!(!session.invalidate(1));
+    }
+}
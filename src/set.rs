@@ -0,0 +1,168 @@
+// set.rs
+//
+// Sets of values and the `set` module of builtins over them. A set is a
+// `Map` whose values are all unit, so it hashes its elements the same way
+// and iterates in the order they were first inserted: the result of
+// `set.union(a, b)` lists the elements of `a`, then those only in `b`.
+
+use crate::diagnostics::Code;
+use crate::map::Map;
+use crate::object::Object;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Set {
+    elements: Map,
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Set::default()
+    }
+
+    /// The distinct `elements`, in the order they first appear.
+    pub fn from_elements(elements: impl IntoIterator<Item = Object>) -> Result<Set, Object> {
+        let mut set = Set::new();
+        for element in elements {
+            set.insert(element)?;
+        }
+        Ok(set)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn contains(&self, element: &Object) -> bool {
+        self.elements.contains(element)
+    }
+
+    /// Adds `element` after the others unless it is already there. Fails
+    /// for unhashable elements.
+    pub fn insert(&mut self, element: Object) -> Result<(), Object> {
+        self.elements.insert(element, Object::Unit)
+    }
+
+    pub fn remove(&mut self, element: &Object) -> bool {
+        self.elements.remove(element).is_some()
+    }
+
+    /// Elements in the order they were first inserted.
+    pub fn iter(&self) -> impl Iterator<Item = &Object> {
+        self.elements.iter().map(|(element, _)| element)
+    }
+
+    // Empties the set, for dropping nested values one at a time
+    pub(crate) fn take_elements(&mut self) -> Vec<Object> {
+        self.elements.take_entries().into_iter().map(|(element, _)| element).collect()
+    }
+
+    pub fn union(&self, other: &Set) -> Set {
+        let mut union = self.clone();
+        for element in other.iter() {
+            // Elements of a set are hashable
+            let _ = union.insert(element.clone());
+        }
+        union
+    }
+
+    pub fn intersect(&self, other: &Set) -> Set {
+        self.filter(|element| other.contains(element))
+    }
+
+    pub fn difference(&self, other: &Set) -> Set {
+        self.filter(|element| !other.contains(element))
+    }
+
+    pub fn is_subset(&self, other: &Set) -> bool {
+        self.iter().all(|element| other.contains(element))
+    }
+
+    fn filter(&self, keep: impl Fn(&Object) -> bool) -> Set {
+        let mut set = Set::new();
+        for element in self.iter().filter(|element| keep(element)) {
+            let _ = set.insert(element.clone());
+        }
+        set
+    }
+}
+
+/// `set.from_list`, `set.to_list`, `set.insert`, `set.remove`,
+/// `set.contains`, `set.size`, `set.union`, `set.intersect`,
+/// `set.difference` and `set.subset`. The set comes first, and updates
+/// return a new set.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("from_list", |args| match args.as_slice() {
+            [Object::List(elements)] => Set::from_elements(elements.iter().cloned()).map_or_else(|error| error, Object::Set),
+            _ => argument_error("from_list", "a list"),
+        }),
+        function("to_list", |args| match args.as_slice() {
+            [Object::Set(set)] => Object::List(set.iter().cloned().collect()),
+            _ => argument_error("to_list", "a set"),
+        }),
+        function("insert", |args| match args.as_slice() {
+            [Object::Set(set), element] => {
+                let mut set = set.clone();
+                set.insert(element.clone()).map_or_else(|error| error, |_| Object::Set(set))
+            }
+            _ => argument_error("insert", "a set and an element"),
+        }),
+        function("remove", |args| match args.as_slice() {
+            [Object::Set(set), element] => {
+                let mut set = set.clone();
+                set.remove(element);
+                Object::Set(set)
+            }
+            _ => argument_error("remove", "a set and an element"),
+        }),
+        function("contains", |args| match args.as_slice() {
+            [Object::Set(set), element] => Object::Boolean(set.contains(element)),
+            _ => argument_error("contains", "a set and an element"),
+        }),
+        function("size", |args| match args.as_slice() {
+            [Object::Set(set)] => Object::Integer(set.len() as i64),
+            _ => argument_error("size", "a set"),
+        }),
+        function("union", |args| combine("union", &args, |a, b| Object::Set(a.union(b)))),
+        function("intersect", |args| combine("intersect", &args, |a, b| Object::Set(a.intersect(b)))),
+        function("difference", |args| combine("difference", &args, |a, b| Object::Set(a.difference(b)))),
+        function("subset", |args| combine("subset", &args, |a, b| Object::Boolean(a.is_subset(b)))),
+    ])
+}
+
+fn argument_error(name: &str, expected: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("set.{} expects {}", name, expected))
+}
+
+fn combine(name: &str, args: &[Object], operation: fn(&Set, &Set) -> Object) -> Object {
+    match args {
+        [Object::Set(a), Object::Set(b)] => operation(a, b),
+        _ => argument_error(name, "two sets"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+
+    #[test]
+    fn test_set_operations() {
+        let mut eval = crate::evaluator::test_eval();
+        let ints = |ints: &[i64]| Some(Object::List(ints.iter().map(|&n| Object::Integer(n)).collect()));
+
+        eval("let a = set.from_list([3, 1, 3, 2]);\nlet b = set.from_list([2, 4, 3]);");
+        assert_eq!(eval("set.to_list(a)"), ints(&[3, 1, 2]));
+        assert_eq!(eval("set.to_list(set.union(a, b))"), ints(&[3, 1, 2, 4]));
+        assert_eq!(eval("set.to_list(set.intersect(a, b))"), ints(&[3, 2]));
+        assert_eq!(eval("set.to_list(set.difference(a, b))"), ints(&[1]));
+        assert_eq!(eval("show(set.insert(a, 1))"), Some(Object::from("set {3, 1, 2}")));
+        let checks = "[set.subset(set.from_list([2, 3]), a), set.subset(b, a), 4 in b, set.contains(a, 4), a == set.from_list([1, 2, 3])]";
+        let expected = [true, false, true, false, true].map(Object::Boolean).to_vec();
+        assert_eq!(eval(checks), Some(Object::List(expected)));
+    }
+}
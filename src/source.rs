@@ -0,0 +1,109 @@
+// source.rs
+//
+// The files loaded in a session. Each gets a `FileId` that the lexer stamps
+// on every span it produces, so a span can be traced back to its file from
+// anywhere, and diagnostics from several files point at the right one.
+
+use crate::ast::Program;
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Lexer, Span};
+use crate::parser::Parser;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A file in a `SourceMap`. Spans of text lexed outside of one have the
+/// default id.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Default)]
+pub struct FileId(u32);
+
+struct SourceFile {
+    name: String,
+    text: String,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    pub fn add(&mut self, name: &str, text: String) -> FileId {
+        self.files.push(SourceFile { name: name.to_string(), text });
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    /// Reads the file at `path`, named by its path.
+    pub fn load(&mut self, path: &str) -> io::Result<FileId> {
+        let text = fs::read_to_string(path)?;
+        Ok(self.add(path, text))
+    }
+
+    /// The first file named `name`, if any.
+    pub fn find(&self, name: &str) -> Option<FileId> {
+        self.files.iter().position(|file| file.name == name).map(|index| FileId(index as u32))
+    }
+
+    pub fn name(&self, file: FileId) -> &str {
+        &self.files[file.0 as usize].name
+    }
+
+    pub fn text(&self, file: FileId) -> &str {
+        &self.files[file.0 as usize].text
+    }
+
+    pub fn lexer(&self, file: FileId) -> Lexer {
+        Lexer::new(self.text(file)).with_file(file)
+    }
+
+    /// Parses a file, with its name as the program's source id and its
+    /// directory as the one it includes files from.
+    pub fn parse(&self, file: FileId) -> Program {
+        let directory = Path::new(self.name(file)).parent().unwrap_or(Path::new(""));
+        Parser::new(self.lexer(file)).with_directory(directory).parse_program().with_source_id(self.name(file))
+    }
+
+    /// e.g. `lib.opl:3:14`
+    pub fn location(&self, span: Span) -> String {
+        format!("{}:{}:{}", self.name(span.file), span.line, span.column)
+    }
+
+    /// `Diagnostic::render`, pointing into the file of the diagnostic's span.
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.render(self.file_of(diagnostic))
+    }
+
+    pub fn to_json(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.to_json(self.file_of(diagnostic))
+    }
+
+    fn file_of(&self, diagnostic: &Diagnostic) -> &str {
+        diagnostic.span.map_or("", |span| self.name(span.file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Code;
+
+    #[test]
+    fn test_spans_resolve_to_their_file() {
+        let mut sources = SourceMap::new();
+        let main = sources.add("main.opl", "let x = 1;".to_string());
+        let lib = sources.add("lib.opl", "let y = 2;\nlet z = y +;".to_string());
+        assert_eq!(sources.parse(main).source_id.as_deref(), Some("main.opl"));
+        let program = sources.parse(lib);
+        let error = program.errors.first().unwrap();
+        assert_eq!(error.span().file, lib);
+        assert_eq!(sources.location(error.span()), "lib.opl:2:12");
+        assert_eq!((sources.find("lib.opl"), sources.find("other.opl")), (Some(lib), None));
+        let diagnostic = Diagnostic::from(error);
+        assert!(sources.render(&diagnostic).contains("--> lib.opl:2:12"));
+        assert_eq!(sources.render(&Diagnostic::new(Code::UnusedVariable, "unused variable 'x'".to_string())), "warning[W0001]: unused variable 'x' [unused_variable]");
+    }
+}
@@ -0,0 +1,90 @@
+// stdin.rs
+//
+// The `stdin` module, so a script can sit in a pipeline such as
+// `cat data | opl run filter.opl`. Nothing is read ahead of what a script
+// asks for, so `stdin.lines()` can follow input that never ends. Reading is
+// an `io` effect, which a `@pure` module cannot have.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::string::Str;
+use crate::trace;
+use std::io::{self, BufRead, Read};
+
+/// `stdin.read_all()` gives the rest of the input as a string,
+/// `stdin.read_line()` the next line without its line ending, or None at
+/// the end of the input, and `stdin.lines()` a function that gives the next
+/// line each time it is called, a lazy sequence of the lines.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("read_all", |args| match args.as_slice() {
+            [] => trace::read("stdin", || read_all(&mut io::stdin().lock())).map_or_else(input_error, |text| Object::String(Str::from(text))),
+            _ => argument_error("read_all"),
+        }),
+        function("read_line", read_line_builtin),
+        function("lines", |args| match args.as_slice() {
+            [] => Object::Builtin(read_line_builtin),
+            _ => argument_error("lines"),
+        }),
+    ])
+}
+
+fn read_line_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [] => trace::read("stdin", || read_line(&mut io::stdin().lock())).map_or_else(input_error, line),
+        _ => argument_error("read_line"),
+    }
+}
+
+/// The rest of `input`, which must be UTF-8.
+pub(crate) fn read_all(input: &mut impl Read) -> io::Result<String> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// The next line of `input` without its line ending, or None at its end.
+pub(crate) fn read_line(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let length = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(length);
+    Ok(Some(line))
+}
+
+/// A line read by `read_line` as an opl option.
+pub(crate) fn line(line: Option<String>) -> Object {
+    match line {
+        Some(line) => Object::OptionSome(Box::new(Object::String(Str::from(line)))),
+        None => Object::OptionNone,
+    }
+}
+
+fn argument_error(name: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("stdin.{} takes no arguments", name))
+}
+
+fn input_error(error: io::Error) -> Object {
+    Object::Error(Code::InputFailed, format!("reading stdin failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_all, read_line};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_stdin() {
+        let line = |text: &str| Some(text.to_string());
+        let mut input = Cursor::new("first\r\nsecond\n\nlast");
+        assert_eq!(read_line(&mut input).unwrap(), line("first"));
+        assert_eq!(read_line(&mut input).unwrap(), line("second"));
+        assert_eq!(read_line(&mut input).unwrap(), line(""));
+        assert_eq!(read_all(&mut input).unwrap(), "last");
+        assert_eq!(read_line(&mut input).unwrap(), None);
+        assert!(read_all(&mut Cursor::new(vec![0xff, 0xfe])).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// string.rs
+//
+// The string type behind `Object::String`. Strings are shared rather than
+// copied when values are passed around, and `++` builds a rope node in
+// constant time instead of copying both sides. A rope is flattened once,
+// the first time its contents are read.
+
+use std::cell::OnceCell;
+use std::fmt;
+use std::rc::Rc;
+
+// Concatenations shorter than this are copied straight away, which keeps
+// short strings flat and avoids rope nodes that cost more than they save
+const FLAT_LIMIT: usize = 64;
+
+#[derive(Clone)]
+pub struct Str(Rc<Node>);
+
+enum Node {
+    Flat(String),
+    Concat {
+        left: Str,
+        right: Str,
+        len: usize,
+        flat: OnceCell<String>,
+    },
+}
+
+impl Str {
+    pub fn len(&self) -> usize {
+        match &*self.0 {
+            Node::Flat(s) => s.len(),
+            Node::Concat { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &*self.0 {
+            Node::Flat(s) => s,
+            Node::Concat { flat, .. } => flat.get_or_init(|| self.flatten()),
+        }
+    }
+
+    /// Joins two strings. Amortized O(1): only short results are copied.
+    pub fn concat(&self, other: &Str) -> Str {
+        if other.is_empty() {
+            return self.clone();
+        }
+        if self.is_empty() {
+            return other.clone();
+        }
+        let len = self.len() + other.len();
+        if len < FLAT_LIMIT {
+            return Str::from(format!("{}{}", self.as_str(), other.as_str()));
+        }
+        Str(Rc::new(Node::Concat { left: self.clone(), right: other.clone(), len, flat: OnceCell::new() }))
+    }
+
+    /// Joins many strings with a single copy of their contents.
+    pub fn concat_all<'a>(parts: impl IntoIterator<Item = &'a Str> + Clone) -> Str {
+        let len = parts.clone().into_iter().map(Str::len).sum();
+        let mut out = String::with_capacity(len);
+        for part in parts {
+            out.push_str(part.as_str());
+        }
+        Str::from(out)
+    }
+
+    // Walks the rope with an explicit stack, since loops build very deep,
+    // left leaning chains
+    fn flatten(&self) -> String {
+        let mut out = String::with_capacity(self.len());
+        let mut stack = vec![self];
+        while let Some(s) = stack.pop() {
+            match &*s.0 {
+                Node::Flat(flat) => out.push_str(flat),
+                Node::Concat { left, right, flat, .. } => match flat.get() {
+                    Some(flat) => out.push_str(flat),
+                    None => {
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                },
+            }
+        }
+        out
+    }
+}
+
+impl Default for Str {
+    fn default() -> Self {
+        Str::from(String::new())
+    }
+}
+
+// Dropping a long chain of nodes recursively would overflow the stack, so
+// uniquely owned children are unlinked and dropped one at a time
+impl Drop for Node {
+    fn drop(&mut self) {
+        let Node::Concat { left, right, .. } = self else {
+            return;
+        };
+        let mut pending = vec![std::mem::take(left), std::mem::take(right)];
+        while let Some(s) = pending.pop() {
+            if let Ok(Node::Concat { left, right, .. }) = Rc::try_unwrap(s.0).as_mut() {
+                pending.push(std::mem::take(left));
+                pending.push(std::mem::take(right));
+            }
+        }
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Self {
+        Str(Rc::new(Node::Flat(s)))
+    }
+}
+
+impl From<&str> for Str {
+    fn from(s: &str) -> Self {
+        Str::from(s.to_string())
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || self.as_str() == other.as_str()
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concat_deep_rope() {
+        let piece = Str::from("<li>item</li>");
+        let mut s = Str::default();
+        for _ in 0..100_000 {
+            s = s.concat(&piece);
+        }
+        assert_eq!(s.len(), 13 * 100_000);
+        assert!(s.as_str().starts_with("<li>item</li><li>"));
+        assert_eq!(s.concat(&Str::default()), s);
+    }
+}
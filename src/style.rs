@@ -0,0 +1,127 @@
+// style.rs
+//
+// Colors for the terminal, in one place: diagnostics, REPL values and the
+// REPL's echo of input are painted here. Painting is off until a program
+// turns it on, as `opl` does with `--color`; `auto` paints only when stdout
+// and stderr are both terminals and NO_COLOR is unset or empty.
+
+use crate::lexer::{Lexer, Token};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// When to color output, as `--color` takes it.
+#[derive(PartialEq, Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Style {
+    Error,
+    Warning,
+    // Notes and help below a diagnostic
+    Note,
+    Location,
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stdout().is_terminal() && io::stderr().is_terminal()
+        }
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// `text` in `style`, or as it is while painting is off.
+pub fn paint(text: &str, style: Style) -> String {
+    if enabled() {
+        ansi(text, style)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `source` with its keywords, literals and comments painted.
+pub fn highlight(source: &str) -> String {
+    if enabled() {
+        highlighted(source)
+    } else {
+        source.to_string()
+    }
+}
+
+fn ansi(text: &str, style: Style) -> String {
+    let code = match style {
+        Style::Error => "1;31",
+        Style::Warning => "1;33",
+        Style::Note => "36",
+        Style::Location => "34",
+        Style::Keyword => "35",
+        Style::String => "32",
+        Style::Number => "33",
+        Style::Comment => "2",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+// Spans count chars, so the source is copied a char at a time, painting each
+// token that has a style and leaving the text between tokens as it is
+fn highlighted(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut lexer = Lexer::new(source);
+    let mut out = String::new();
+    let mut copied = 0;
+    loop {
+        let token = lexer.advance();
+        let (start, end) = (lexer.span.start.min(chars.len()), lexer.span.end.min(chars.len()));
+        if token == Token::End || end <= copied {
+            break;
+        }
+        let style = match token {
+            Token::StringLiteral(_) => Some(Style::String),
+            Token::IntegerLiteral(_) | Token::FloatLiteral(_) | Token::Boolean(_) => Some(Style::Number),
+            Token::Comment(_) => Some(Style::Comment),
+            Token::Let | Token::Fn | Token::Return | Token::If | Token::Else | Token::Type | Token::Match | Token::With | Token::Of => Some(Style::Keyword),
+            Token::Raise | Token::Quote | Token::Assert | Token::Requires | Token::Ensures | Token::In | Token::Use | Token::Std => Some(Style::Keyword),
+            _ => None,
+        };
+        let Some(style) = style else { continue };
+        out.extend(&chars[copied..start]);
+        out.push_str(&ansi(&chars[start..end].iter().collect::<String>(), style));
+        copied = end;
+    }
+    out.extend(&chars[copied..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            highlighted("let s = \"hi\"; -- é\nf(1.5)"),
+            "\x1b[35mlet\x1b[0m s = \x1b[32m\"hi\"\x1b[0m; \x1b[2m-- é\x1b[0m\nf(\x1b[33m1.5\x1b[0m)"
+        );
+        // Values print much like source, and what does not lex stays as it is
+        assert_eq!(highlighted("{ name = \"a\", n = 1 } #"), "{ name = \x1b[32m\"a\"\x1b[0m, n = \x1b[33m1\x1b[0m } #");
+        assert_eq!(paint("x", Style::Error), "x");
+    }
+}
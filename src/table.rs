@@ -0,0 +1,110 @@
+// table.rs
+//
+// The `table` module, aligned text tables of lists of records. Columns are
+// the fields of the records in the order they first appear, each as wide as
+// its widest cell; numbers align right and everything else left, and a
+// record without a field leaves its cell blank.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::session;
+use crate::string::Str;
+
+/// `table.print(rows)` prints the table of a list of records, and
+/// `table.render(rows)` gives it as a string.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("print", |args| match render(&args) {
+            Ok(table) => {
+                session::print(&table);
+                Object::Unit
+            }
+            Err(error) => error,
+        }),
+        function("render", |args| render(&args).map_or_else(|error| error, |table| Object::String(Str::from(table)))),
+    ])
+}
+
+// A cell as it is printed, with whether it aligns right
+fn cell(value: &Object) -> (String, bool) {
+    match value {
+        Object::String(s) => (s.to_string(), false),
+        Object::Integer(_) | Object::Float(_) => (value.to_string(), true),
+        other => (other.to_string(), false),
+    }
+}
+
+// The cells of a row as they are printed, each with whether it aligns right
+pub(crate) type Row = Vec<(String, bool)>;
+
+/// The columns of a list of records, and the cells of each row.
+pub(crate) fn grid(rows: &[Object]) -> Result<(Vec<&str>, Vec<Row>), Object> {
+    let mut columns: Vec<&str> = Vec::new();
+    for row in rows {
+        let Object::Record(fields) = row else {
+            return Err(Object::Error(Code::BuiltinArgument, format!("table expects a list of records, got {} in it", row.type_name())));
+        };
+        for (name, _) in fields {
+            if !columns.contains(&name.as_str()) {
+                columns.push(name);
+            }
+        }
+    }
+    let cells: Vec<Row> = rows
+        .iter()
+        .map(|row| {
+            let Object::Record(fields) = row else { unreachable!("checked above") };
+            let value = |column: &str| fields.get(column);
+            columns.iter().map(|column| value(column).map_or((String::new(), false), cell)).collect()
+        })
+        .collect();
+    Ok((columns, cells))
+}
+
+fn render(args: &[Object]) -> Result<String, Object> {
+    let [Object::List(rows)] = args else {
+        return Err(Object::Error(Code::BuiltinArgument, "table expects a list of records".to_string()));
+    };
+    let (columns, cells) = grid(rows)?;
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| cells.iter().map(|row| row[i].0.chars().count()).chain([column.chars().count()]).max().unwrap_or(0))
+        .collect();
+
+    let line = |cells: Row| {
+        let padded: Vec<String> = cells
+            .into_iter()
+            .zip(&widths)
+            .map(|((text, right), &width)| if right { format!("{:>width$}", text) } else { format!("{:<width$}", text) })
+            .collect();
+        format!("{}\n", padded.join(" | ").trim_end())
+    };
+    if columns.is_empty() {
+        return Ok(String::new());
+    }
+    let mut table = line(columns.iter().map(|column| (column.to_string(), false)).collect());
+    table.push_str(&widths.iter().map(|&width| "-".repeat(width)).collect::<Vec<_>>().join("-+-"));
+    table.push('\n');
+    for row in cells {
+        table.push_str(&line(row));
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+
+    #[test]
+    fn test_table() {
+        let mut eval = crate::evaluator::test_eval();
+
+        let rows = "[{ name = \"ada\", age = 36 }, { name = \"grace\", age = 105, lang = \"cobol\" }]";
+        let table = "name  | age | lang\n------+-----+------\nada   |  36 |\ngrace | 105 | cobol\n";
+        assert_eq!(eval(&format!("table.render({})", rows)), Some(Object::from(table)));
+        assert_eq!(eval("table.render([])"), Some(Object::from("")));
+        assert!(matches!(eval("table.render([1, 2])"), Some(Object::Error(..))));
+    }
+}
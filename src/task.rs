@@ -0,0 +1,102 @@
+// task.rs
+//
+// Tasks: `spawn(f)` runs `f()` apart from the program that spawned it, and
+// `await(task)` gives its outcome as a result. Like timers, tasks take turns
+// with the script: one runs when it is awaited, while the script sleeps, or
+// once the program is done, whichever comes first. A task that fails only
+// fails its own result, `Err` of the message, unless it was started with
+// `spawn_link`, whose errors stop the program as if the spawner had failed.
+// `exit` and Ctrl-C stop the program from any task.
+
+use crate::diagnostics::Code;
+use crate::list::call;
+use crate::object::{Native, Object};
+use crate::string::Str;
+use crate::timer;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+enum Task {
+    Pending(Object),
+    Running,
+    Done(Object),
+}
+
+/// `spawn(f)`, a task running `f()` whose errors stay in its result.
+pub fn spawn_builtin(args: Vec<Object>) -> Object {
+    spawn("spawn", args, false)
+}
+
+/// `spawn_link(f)`, a task running `f()` whose errors are the program's.
+pub fn spawn_link_builtin(args: Vec<Object>) -> Object {
+    spawn("spawn_link", args, true)
+}
+
+fn spawn(name: &str, args: Vec<Object>, linked: bool) -> Object {
+    let [function @ (Object::Function(..) | Object::Builtin(_) | Object::Native(_))] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, format!("{} expects a function of no arguments", name));
+    };
+    let task = Rc::new(RefCell::new(Task::Pending(function.clone())));
+    let scheduled = Rc::clone(&task);
+    let start = move |_: Vec<Object>| match run(&scheduled, linked) {
+        error @ Object::Error(..) => error,
+        _ => Object::Unit,
+    };
+    timer::schedule(Duration::ZERO, Object::Native(Native { name: "task".to_string(), function: Rc::new(start) }));
+    let outcome = move |_: Vec<Object>| run(&task, linked);
+    Object::Native(Native { name: "task".to_string(), function: Rc::new(outcome) })
+}
+
+/// `await(task)` runs the task if it has not run yet, and gives `Ok` of its
+/// value or `Err` of its error message.
+pub fn await_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [Object::Native(task)] if task.name == "task" => (task.function)(Vec::new()),
+        _ => Object::Error(Code::BuiltinArgument, "await expects a task".to_string()),
+    }
+}
+
+// The outcome of a task, running it the first time. Errors that are the
+// program's, rather than the task's, are the outcome as they are.
+fn run(task: &RefCell<Task>, linked: bool) -> Object {
+    let state = task.replace(Task::Running);
+    let function = match state {
+        Task::Pending(function) => function,
+        Task::Done(outcome) => {
+            *task.borrow_mut() = Task::Done(outcome.clone());
+            return outcome;
+        }
+        Task::Running => return Object::Error(Code::BuiltinArgument, "a task cannot await itself".to_string()),
+    };
+    let outcome = match call(&function, Vec::new()) {
+        error @ Object::Error(Code::Exit(_) | Code::Interrupted, _) => error,
+        error @ Object::Error(..) if linked => error,
+        Object::Error(_, ref mut message) => Object::ResultErr(Box::new(Object::String(Str::from(std::mem::take(message))))),
+        value => Object::ResultOk(Box::new(value)),
+    };
+    *task.borrow_mut() = Task::Done(outcome.clone());
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::object::Object;
+
+    #[test]
+    fn test_tasks() {
+        let mut eval = crate::evaluator::test_eval();
+        let err = |message: &str| Some(Object::ResultErr(Box::new(Object::from(message))));
+
+        eval("let ok = spawn(fn () -> 6 * 7);\nlet failed = spawn(fn () -> 1 / 0);");
+        assert_eq!(eval("await(ok)"), Some(Object::ResultOk(Box::new(Object::Integer(42)))));
+        assert_eq!(eval("await(failed)"), err("Cannot divide 1 by zero"));
+        assert_eq!(eval("await(failed)"), err("Cannot divide 1 by zero"));
+
+        // Unawaited, a failed task only fails itself, unless it is linked
+        assert_eq!(eval("spawn(fn () -> 1 / 0);\n1"), Some(Object::Integer(1)));
+        assert!(matches!(eval("spawn_link(fn () -> 1 / 0);\n1"), Some(Object::Error(..))));
+        assert!(matches!(eval("spawn(fn () -> exit(3));\n1"), Some(Object::Error(Code::Exit(3), _))));
+    }
+}
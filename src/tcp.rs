@@ -0,0 +1,139 @@
+// tcp.rs
+//
+// The `tcp` module, enabled by the `net` feature: enough TCP for toy servers
+// and clients. Listeners and connections are handles, so they close like
+// files do. Calls block until they are done, and using the module is a `net`
+// effect, which an engine only allows when its config does.
+
+use crate::diagnostics::Code;
+use crate::handle::{Handle, Resource};
+use crate::object::Object;
+use crate::stdin::{line, read_line};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// `tcp.listen(port)` listens on a port of localhost, 0 for any free one,
+/// which `tcp.port` tells. `tcp.accept` waits for a connection to a
+/// listener and `tcp.connect(host, port)` makes one. `tcp.read` gives what
+/// has arrived on a connection, `tcp.read_line` the next line, both None
+/// once the peer has closed it, and `tcp.write` sends a string.
+pub fn module() -> Object {
+    let function = |name: &str, function: fn(Vec<Object>) -> Object| (name.to_string(), Object::Builtin(function));
+    Object::record(vec![
+        function("listen", |args| match args.as_slice() {
+            [Object::Integer(port @ 0..=65535)] => match TcpListener::bind(("127.0.0.1", *port as u16)) {
+                Ok(listener) => {
+                    let address = listener.local_addr().map_or_else(|_| port.to_string(), |address| address.to_string());
+                    Object::Handle(Handle::open(format!("tcp listener on {}", address), Resource::Listener(listener)))
+                }
+                Err(error) => Object::Error(Code::IoFailed, format!("cannot listen on port {}: {}", port, error)),
+            },
+            _ => argument_error("listen", "a port from 0 to 65535"),
+        }),
+        function("port", |args| match args.as_slice() {
+            [Object::Handle(handle)] => {
+                let address = handle.with(|resource| match resource {
+                    Resource::Listener(listener) => listener.local_addr(),
+                    Resource::Connection(connection) => connection.get_ref().local_addr(),
+                    _ => Err(io::Error::other("it is not a tcp handle")),
+                });
+                address.map_or_else(|error| io_error(handle, error), |address| Object::Integer(i64::from(address.port())))
+            }
+            _ => argument_error("port", "a handle"),
+        }),
+        function("accept", |args| match args.as_slice() {
+            [Object::Handle(handle)] => {
+                let accepted = handle.with(|resource| match resource {
+                    Resource::Listener(listener) => listener.accept(),
+                    _ => Err(io::Error::other("it is not a listener")),
+                });
+                accepted.map_or_else(|error| io_error(handle, error), |(stream, peer)| connection(format!("tcp connection from {}", peer), stream))
+            }
+            _ => argument_error("accept", "a listener"),
+        }),
+        function("connect", |args| match args.as_slice() {
+            [Object::String(host), Object::Integer(port @ 0..=65535)] => match TcpStream::connect((host.as_str(), *port as u16)) {
+                Ok(stream) => connection(format!("tcp connection to {}:{}", host, port), stream),
+                Err(error) => Object::Error(Code::IoFailed, format!("cannot connect to {}:{}: {}", host, port, error)),
+            },
+            _ => argument_error("connect", "a host and a port from 0 to 65535"),
+        }),
+        function("read", |args| match args.as_slice() {
+            [Object::Handle(handle)] => {
+                let received = reader(handle, |reader| {
+                    let text = String::from_utf8_lossy(reader.fill_buf()?).into_owned();
+                    reader.consume(reader.buffer().len());
+                    Ok((!text.is_empty()).then_some(text))
+                });
+                received.map_or_else(|error| io_error(handle, error), line)
+            }
+            _ => argument_error("read", "a connection"),
+        }),
+        function("read_line", |args| match args.as_slice() {
+            [Object::Handle(handle)] => reader(handle, read_line).map_or_else(|error| io_error(handle, error), line),
+            _ => argument_error("read_line", "a connection"),
+        }),
+        function("write", |args| match args.as_slice() {
+            [Object::Handle(handle), Object::String(text)] => {
+                let written = handle.with(|resource| match resource {
+                    Resource::Connection(connection) => connection.get_mut().write_all(text.as_str().as_bytes()),
+                    _ => Err(io::Error::other("it is not a connection")),
+                });
+                written.map_or_else(|error| io_error(handle, error), |_| Object::Unit)
+            }
+            _ => argument_error("write", "a connection and a string"),
+        }),
+        function("close", |args| match args.as_slice() {
+            [Object::Handle(handle)] => handle.close().map_or_else(|error| io_error(handle, error), |_| Object::Unit),
+            _ => argument_error("close", "a handle"),
+        }),
+    ])
+}
+
+fn connection(description: String, stream: TcpStream) -> Object {
+    Object::Handle(Handle::open(description, Resource::Connection(BufReader::new(stream))))
+}
+
+fn reader<T>(handle: &Handle, read: impl FnOnce(&mut BufReader<TcpStream>) -> io::Result<T>) -> io::Result<T> {
+    handle.with(|resource| match resource {
+        Resource::Connection(connection) => read(connection),
+        _ => Err(io::Error::other("it is not a connection")),
+    })
+}
+
+fn argument_error(name: &str, expected: &str) -> Object {
+    Object::Error(Code::BuiltinArgument, format!("tcp.{} expects {}", name, expected))
+}
+
+fn io_error(handle: &Handle, error: io::Error) -> Object {
+    Object::Error(Code::IoFailed, format!("{} failed: {}", handle.description(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_tcp() {
+        let mut eval = crate::evaluator::test_eval();
+
+        eval("let server = tcp.listen(0);");
+        let Some(Object::Integer(port)) = eval("tcp.port(server)") else { panic!("expected a port") };
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port as u16)).unwrap();
+            stream.write_all(b"ping\n").unwrap();
+            let mut reply = String::new();
+            BufReader::new(stream).read_line(&mut reply).unwrap();
+            reply
+        });
+        eval("let peer = tcp.accept(server);");
+        assert_eq!(eval("tcp.read_line(peer)"), Some(Object::OptionSome(Box::new(Object::from("ping")))));
+        eval("tcp.write(peer, \"pong\\n\");\ntcp.close(peer);");
+        assert_eq!(client.join().unwrap(), "pong\n");
+        assert!(matches!(eval("tcp.read(peer)"), Some(Object::Error(..))));
+        assert!(matches!(eval("tcp.read(server)"), Some(Object::Error(..))));
+        eval("tcp.close(server);");
+    }
+}
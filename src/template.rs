@@ -0,0 +1,218 @@
+// template.rs
+//
+// The `template` module. `template.render` fills a text template from a
+// record:
+//
+//   {{ name }}                        a field, or a nested one like {{ user.name }}
+//   {{ for item in items }}..{{ end }}  repeat for each element of a list
+//   {{ if done }}..{{ else }}..{{ end }}  false, None, () and empty strings
+//                                     and lists are falsy
+//
+// Strings are inserted as they are, other values as they print.
+
+use crate::diagnostics::{self, Code};
+use crate::object::Object;
+use crate::string::Str;
+
+pub fn module() -> Object {
+    Object::record(vec![("render".to_string(), Object::Builtin(render_builtin))])
+}
+
+pub fn render_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(template), record @ Object::Record(_)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "template.render expects a template string and a record".to_string());
+    };
+    let result = parse(template.as_str()).and_then(|nodes| {
+        let mut out = String::new();
+        render(&nodes, &mut vec![("", record)], &mut out)?;
+        Ok(out)
+    });
+    match result {
+        Ok(text) => Object::ResultOk(Box::new(Object::String(Str::from(text)))),
+        Err(message) => Object::ResultErr(Box::new(Object::String(Str::from(message)))),
+    }
+}
+
+enum Node {
+    Text(String),
+    Value(Vec<String>),
+    For { name: String, list: Vec<String>, body: Vec<Node> },
+    If { condition: Vec<String>, then: Vec<Node>, otherwise: Vec<Node> },
+}
+
+// How a run of nodes ended
+enum Stop {
+    Eof,
+    End,
+    Else,
+}
+
+fn parse(template: &str) -> Result<Vec<Node>, String> {
+    let mut rest = template;
+    match parse_nodes(&mut rest)? {
+        (nodes, Stop::Eof) => Ok(nodes),
+        (_, Stop::End) => Err("'{{ end }}' without a matching 'for' or 'if'".to_string()),
+        (_, Stop::Else) => Err("'{{ else }}' outside of an 'if'".to_string()),
+    }
+}
+
+fn parse_nodes(rest: &mut &str) -> Result<(Vec<Node>, Stop), String> {
+    let mut nodes = Vec::new();
+    loop {
+        let Some(start) = rest.find("{{") else {
+            if !rest.is_empty() {
+                nodes.push(Node::Text(rest.to_string()));
+            }
+            *rest = "";
+            return Ok((nodes, Stop::Eof));
+        };
+        if start > 0 {
+            nodes.push(Node::Text(rest[..start].to_string()));
+        }
+        let Some(length) = rest[start..].find("}}") else {
+            return Err("unclosed '{{'".to_string());
+        };
+        let tag = rest[start + 2..start + length].trim();
+        *rest = &rest[start + length + 2..];
+
+        let words: Vec<&str> = tag.split_whitespace().collect();
+        match words.as_slice() {
+            ["end"] => return Ok((nodes, Stop::End)),
+            ["else"] => return Ok((nodes, Stop::Else)),
+            ["for", name, "in", list] => {
+                let (body, end) = parse_nodes(rest)?;
+                if !matches!(end, Stop::End) {
+                    return Err(format!("'{{{{ {} }}}}' is missing its '{{{{ end }}}}'", tag));
+                }
+                nodes.push(Node::For { name: name.to_string(), list: path(list)?, body });
+            }
+            ["if", condition] => {
+                let (then, mut end) = parse_nodes(rest)?;
+                let mut otherwise = Vec::new();
+                if matches!(end, Stop::Else) {
+                    (otherwise, end) = parse_nodes(rest)?;
+                }
+                if !matches!(end, Stop::End) {
+                    return Err(format!("'{{{{ {} }}}}' is missing its '{{{{ end }}}}'", tag));
+                }
+                nodes.push(Node::If { condition: path(condition)?, then, otherwise });
+            }
+            [value] => nodes.push(Node::Value(path(value)?)),
+            _ => return Err(format!("cannot read '{{{{ {} }}}}'", tag)),
+        }
+    }
+}
+
+fn path(text: &str) -> Result<Vec<String>, String> {
+    let parts: Vec<String> = text.split('.').map(str::to_string).collect();
+    if parts.iter().any(|part| part.is_empty() || !part.chars().all(|c| c.is_alphanumeric() || c == '_')) {
+        return Err(format!("'{}' is not a field name", text));
+    }
+    Ok(parts)
+}
+
+// Innermost first: loop variables, then the fields of the record
+fn lookup<'a>(path: &[String], scopes: &[(&str, &'a Object)]) -> Result<&'a Object, String> {
+    let (first, rest) = path.split_first().unwrap();
+    let mut value = None;
+    for (name, scope) in scopes.iter().rev() {
+        if name == first {
+            value = Some(*scope);
+            break;
+        }
+        if let (&"", Object::Record(fields)) = (name, scope) {
+            if let Some(field) = fields.get(first) {
+                value = Some(field);
+                break;
+            }
+        }
+    }
+    let mut value = value.ok_or_else(|| {
+        let names = scopes.iter().flat_map(|(name, scope)| match (name, scope) {
+            (&"", Object::Record(fields)) => fields.iter().map(|(field, _)| field.as_str()).collect(),
+            _ => vec![*name],
+        });
+        missing(first, names)
+    })?;
+    for part in rest {
+        let Object::Record(fields) = value else {
+            return Err(format!("cannot read field '{}' of {}", part, value.type_name()));
+        };
+        value = match fields.get(part) {
+            Some(field) => field,
+            None => return Err(missing(part, fields.iter().map(|(field, _)| field.as_str()))),
+        };
+    }
+    Ok(value)
+}
+
+fn missing<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    match diagnostics::closest(name, candidates) {
+        Some(candidate) => format!("no field '{}', did you mean `{}`?", name, candidate),
+        None => format!("no field '{}'", name),
+    }
+}
+
+fn render<'a>(nodes: &'a [Node], scopes: &mut Vec<(&'a str, &'a Object)>, out: &mut String) -> Result<(), String> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value(path) => match lookup(path, scopes)? {
+                Object::String(s) => out.push_str(s.as_str()),
+                value => out.push_str(&value.to_string()),
+            },
+            Node::For { name, list, body } => {
+                let value = lookup(list, scopes)?;
+                let Object::List(elements) = value else {
+                    return Err(format!("cannot loop over {}, which is {}", list.join("."), value.type_name()));
+                };
+                for element in elements {
+                    scopes.push((name, element));
+                    let result = render(body, scopes, out);
+                    scopes.pop();
+                    result?;
+                }
+            }
+            Node::If { condition, then, otherwise } => {
+                let truthy = match lookup(condition, scopes)? {
+                    Object::Boolean(value) => *value,
+                    Object::OptionNone | Object::Unit => false,
+                    Object::String(s) => !s.is_empty(),
+                    Object::List(elements) => !elements.is_empty(),
+                    _ => true,
+                };
+                render(if truthy { then } else { otherwise }, scopes, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, record: Object) -> Object {
+        render_builtin(vec![Object::from(template), record])
+    }
+
+    #[test]
+    fn test_render() {
+        let item = |name: &str, done: bool| {
+            Object::record(vec![("name".to_string(), Object::from(name)), ("done".to_string(), Object::Boolean(done))])
+        };
+        let record = Object::record(vec![
+            ("title".to_string(), Object::from("Todo")),
+            ("owner".to_string(), Object::record(vec![("name".to_string(), Object::from("ada"))])),
+            ("items".to_string(), Object::List(vec![item("write", true), item("test", false)])),
+        ]);
+        let template = "{{ title }} for {{owner.name}}:\n{{ for item in items }}- {{ item.name }}{{ if item.done }} (done){{ else }}{{ end }}\n{{ end }}";
+        let expected = "Todo for ada:\n- write (done)\n- test\n";
+        assert_eq!(render(template, record.clone()), Object::ResultOk(Box::new(Object::from(expected))));
+
+        let error = |message: &str| Object::ResultErr(Box::new(Object::from(message)));
+        assert_eq!(render("{{ titel }}", record.clone()), error("no field 'titel', did you mean `title`?"));
+        assert_eq!(render("{{ for x in title }}{{ end }}", record.clone()), error("cannot loop over title, which is string"));
+        assert_eq!(render("{{ if title }}", record), error("'{{ if title }}' is missing its '{{ end }}'"));
+    }
+}
@@ -0,0 +1,134 @@
+// timer.rs
+//
+// `sleep(ms)` and the `timer` module. There is one thread of evaluation, so
+// timers are cooperative: one set with `timer.after` fires while the script
+// sleeps past its deadline, or once the program is done, in deadline order.
+// Sleeping is interrupted by Ctrl-C like any other step, and both are `time`
+// effects.
+
+use crate::diagnostics::Code;
+use crate::evaluator;
+use crate::list::call;
+use crate::object::Object;
+use crate::trace;
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+struct Timer {
+    deadline: Instant,
+    // Breaks ties between equal deadlines, first set first
+    id: u64,
+    callback: Object,
+}
+
+thread_local! {
+    static TIMERS: RefCell<Vec<Timer>> = const { RefCell::new(Vec::new()) };
+    static NEXT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// `timer.after(ms, callback)` calls `callback()` once `ms` milliseconds
+/// have passed.
+pub fn module() -> Object {
+    Object::record(vec![("after".to_string(), Object::Builtin(after_builtin))])
+}
+
+fn after_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [Object::Integer(ms @ 0..), callback @ (Object::Function(..) | Object::Builtin(_) | Object::Native(_))] => {
+            schedule(Duration::from_millis(*ms as u64), callback.clone());
+            Object::Unit
+        }
+        _ => Object::Error(Code::BuiltinArgument, "timer.after expects a number of milliseconds and a function".to_string()),
+    }
+}
+
+/// Calls `callback()` after `delay`, like `timer.after`.
+pub(crate) fn schedule(delay: Duration, callback: Object) {
+    let id = NEXT.replace(NEXT.get() + 1);
+    TIMERS.with(|timers| timers.borrow_mut().push(Timer { deadline: trace::now() + delay, id, callback }));
+}
+
+/// `sleep(ms)` waits `ms` milliseconds, firing the timers due meanwhile.
+pub fn sleep_builtin(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+        [Object::Integer(ms @ 0..)] => run_until(Some(trace::now() + Duration::from_millis(*ms as u64))).unwrap_or(Object::Unit),
+        _ => Object::Error(Code::BuiltinArgument, "sleep expects a number of milliseconds, 0 or more".to_string()),
+    }
+}
+
+/// Fires the timers still pending when a program is done, waiting for
+/// each, and returns the first error one of them gives.
+pub fn run_pending() -> Option<Object> {
+    let error = run_until(None);
+    if evaluator::interrupted() {
+        evaluator::clear_interrupt();
+        return Some(Object::Error(Code::Interrupted, "interrupted while waiting for a timer".to_string()));
+    }
+    error
+}
+
+/// Drops the pending timers, as when a program stops with an error.
+pub fn cancel_all() {
+    TIMERS.with(|timers| timers.borrow_mut().clear());
+}
+
+// Fires the timers due by `until`, or all of them, then waits out the rest.
+// An interrupt cuts the wait short and is left for the evaluator to report.
+fn run_until(until: Option<Instant>) -> Option<Object> {
+    loop {
+        let next = TIMERS.with(|timers| {
+            let mut timers = timers.borrow_mut();
+            let due = (0..timers.len())
+                .filter(|&i| until.is_none_or(|until| timers[i].deadline <= until))
+                .min_by_key(|&i| (timers[i].deadline, timers[i].id))?;
+            Some(timers.swap_remove(due))
+        });
+        let Some(timer) = next else { break };
+        if !wait_until(timer.deadline) {
+            return None;
+        }
+        if let error @ Object::Error(..) = call(&timer.callback, Vec::new()) {
+            cancel_all();
+            return Some(error);
+        }
+    }
+    if let Some(until) = until {
+        wait_until(until);
+    }
+    None
+}
+
+// Sleeps in short slices so Ctrl-C is noticed; false when interrupted
+fn wait_until(deadline: Instant) -> bool {
+    loop {
+        if evaluator::interrupted() {
+            return false;
+        }
+        let now = trace::now();
+        if now >= deadline {
+            return true;
+        }
+        trace::sleep((deadline - now).min(Duration::from_millis(50)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Code;
+    use crate::object::Object;
+
+    #[test]
+    fn test_timers() {
+        let mut eval = crate::evaluator::test_eval();
+
+        // The actor records the order the timers fire in, which `exit` reports
+        eval("let order = actor(0, fn seen n -> if n == 0 { exit(seen) } else { seen * 10 + n });");
+        assert_eq!(eval("timer.after(30, fn () -> send(order, 3));\ntimer.after(0, fn () -> send(order, 1));\nsleep(10);\nsend(order, 2);"), Some(Object::Unit));
+        assert!(matches!(eval("send(order, 0)"), Some(Object::Error(Code::Exit(123), _))));
+
+        // Pending timers fire once the program is done, and their errors are
+        // the program's
+        assert!(matches!(eval("timer.after(1, fn () -> exit(7));\n1"), Some(Object::Error(Code::Exit(7), _))));
+        assert!(matches!(eval("sleep(-1)"), Some(Object::Error(..))));
+    }
+}
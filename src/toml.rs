@@ -0,0 +1,107 @@
+// toml.rs
+//
+// The `toml` module, enabled by the `toml` feature. `toml.parse` reads a TOML
+// document with the `toml` crate and maps it to records, lists and scalars.
+// Dates and times become strings in their TOML form.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::record::Record;
+use crate::string::Str;
+
+pub fn module() -> Object {
+    Object::record(vec![("parse".to_string(), Object::Builtin(parse_builtin))])
+}
+
+pub fn parse_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(text)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "toml.parse expects a string".to_string());
+    };
+    match parse(text.as_str()) {
+        Ok(value) => Object::ResultOk(Box::new(value)),
+        Err(message) => Object::ResultErr(Box::new(Object::String(Str::from(message)))),
+    }
+}
+
+fn parse(text: &str) -> Result<Object, String> {
+    text.parse::<::toml::Table>().map(table_object).map_err(|error| {
+        let line = error.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+        format!("line {}: {}", line, error.message().trim_end())
+    })
+}
+
+fn table_object(table: ::toml::Table) -> Object {
+    Object::Record(Record::new(table.into_iter().map(|(name, value)| (name, object(value))).collect()))
+}
+
+fn object(value: ::toml::Value) -> Object {
+    match value {
+        ::toml::Value::String(text) => Object::String(Str::from(text)),
+        ::toml::Value::Integer(value) => Object::Integer(value),
+        ::toml::Value::Float(value) => Object::Float(value),
+        ::toml::Value::Boolean(value) => Object::Boolean(value),
+        ::toml::Value::Array(values) => Object::List(values.into_iter().map(object).collect()),
+        ::toml::Value::Table(table) => table_object(table),
+        ::toml::Value::Datetime(datetime) => Object::String(Str::from(datetime.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# service config
+name = "opl"
+tags = ["a", 'b',]  # trailing comma
+limits = { cpu = 1.5, "mem-mb" = 0x200 }
+
+[server]
+port = 8_080
+enabled = true
+
+[[server.routes]]
+path = "/"
+
+[[server.routes]]
+path = "/api"
+"#;
+        let table = |fields: Vec<(&str, Object)>| {
+            Object::Record(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+        };
+        let expected = table(vec![
+            ("name", Object::from("opl")),
+            ("tags", Object::List(vec![Object::from("a"), Object::from("b")])),
+            ("limits", table(vec![("cpu", Object::Float(1.5)), ("mem-mb", Object::Integer(512))])),
+            ("server", table(vec![
+                ("port", Object::Integer(8080)),
+                ("enabled", Object::Boolean(true)),
+                ("routes", Object::List(vec![
+                    table(vec![("path", Object::from("/"))]),
+                    table(vec![("path", Object::from("/api"))]),
+                ])),
+            ])),
+        ]);
+        assert_eq!(parse_builtin(vec![Object::from(text)]), Object::ResultOk(Box::new(expected)));
+
+        let error = |message: &str| Object::ResultErr(Box::new(Object::from(message)));
+        assert_eq!(parse_builtin(vec![Object::from("a = 1\na = 2")]), error("line 2: duplicate key `a` in document root"));
+        let when = parse_builtin(vec![Object::from("when = 1979-05-27T07:32:00Z\nnote = \"\"\"\nmulti\nline\"\"\"")]);
+        assert_eq!(when, Object::ResultOk(Box::new(table(vec![("when", Object::from("1979-05-27T07:32:00Z")), ("note", Object::from("multi\nline"))]))));
+    }
+
+    #[test]
+    fn test_parse_wide_table() {
+        let text: String = (0..8000).map(|i| format!("key{} = {}\n", i, i)).collect();
+        let Object::ResultOk(table) = &parse_builtin(vec![Object::from(text.as_str())]) else {
+            panic!("expected a table");
+        };
+        let Object::Record(table) = &**table else {
+            panic!("expected a record");
+        };
+        assert_eq!(table.len(), 8000);
+        assert_eq!(table.get("key7999"), Some(&Object::Integer(7999)));
+    }
+}
@@ -0,0 +1,277 @@
+// trace.rs
+//
+// Record and replay, for `opl run --record` and `--replay`. While recording,
+// what a program takes in from outside is kept in the order it arrives: what
+// it reads from stdin, the files it opens for reading and what it reads from
+// them, and the clock that `sleep` and timers go by. A replay hands the same
+// back in the same order instead of reading it again, so the run repeats
+// exactly even with the inputs gone; replayed sleeps take no time. Builtins
+// are plain functions, so the trace is kept per thread like the profile.
+// Connections are not traced, and there is no random number or environment
+// builtin yet; any that come should read through here.
+//
+// A trace file is an opl-bin list of `[source, value]` pairs, a failed read
+// being `Err` of its message. A replay that asks for something other than
+// what comes next in the trace has diverged from it: reads then fail and the
+// clock is the real one. A recording can also be rewound, for a debugger
+// travelling back through a run, and hands back what it recorded until the
+// run catches up or goes another way.
+
+use crate::handle::Resource;
+use crate::object::Object;
+use crate::string::Str;
+use std::cell::RefCell;
+use std::io;
+use std::time::{Duration, Instant};
+
+struct Trace {
+    replaying: bool,
+    start: Instant,
+    events: Vec<Object>,
+    // The next event to replay, or the end of a recording
+    position: usize,
+    diverged: Option<String>,
+}
+
+thread_local! {
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// Starts recording the inputs of this thread, dropping any unfinished trace.
+pub fn record() {
+    TRACE.set(Some(Trace { replaying: false, start: Instant::now(), events: Vec::new(), position: 0, diverged: None }));
+}
+
+/// Starts replaying the trace in `bytes`, as `finish` gave it.
+pub fn replay(bytes: &[u8]) -> Result<(), String> {
+    let events = match Object::from_bytes(bytes).map_err(|e| e.to_string())? {
+        Object::List(ref mut events) if events.iter().all(|event| matches!(event, Object::List(pair) if matches!(pair.as_slice(), [Object::String(_), _]))) => std::mem::take(events),
+        _ => return Err("not a trace of inputs".to_string()),
+    };
+    TRACE.set(Some(Trace { replaying: true, start: Instant::now(), events, position: 0, diverged: None }));
+    Ok(())
+}
+
+/// Stops recording or replaying. A recording gives the trace to save; a
+/// replay fails when it diverged from its trace or left some of it unread.
+pub fn finish() -> Result<Option<Vec<u8>>, String> {
+    match TRACE.take() {
+        None => Ok(None),
+        Some(Trace { replaying: false, events, .. }) => Object::List(events).to_bytes().map(Some).map_err(|e| e.to_string()),
+        Some(Trace { diverged: Some(diverged), .. }) => Err(diverged),
+        Some(Trace { events, position, .. }) if position < events.len() => {
+            Err(format!("the program stopped after {} of the {} inputs recorded", position, events.len()))
+        }
+        Some(_) => Ok(None),
+    }
+}
+
+/// How far into the trace the run has read, to `rewind` to.
+pub(crate) fn position() -> usize {
+    TRACE.with_borrow(|trace| trace.as_ref().map_or(0, |trace| trace.position))
+}
+
+/// Goes back to `position` in the trace, so the inputs after it are handed
+/// back again.
+pub(crate) fn rewind(position: usize) {
+    TRACE.with_borrow_mut(|trace| {
+        if let Some(trace) = trace.as_mut().filter(|trace| position <= trace.events.len()) {
+            trace.position = position;
+        }
+    });
+}
+
+/// A value read through the trace.
+pub(crate) trait Input: Sized {
+    fn to_object(&self) -> Object;
+    fn from_object(object: Object) -> Option<Self>;
+}
+
+impl Input for String {
+    fn to_object(&self) -> Object {
+        Object::String(Str::from(self.as_str()))
+    }
+
+    fn from_object(object: Object) -> Option<Self> {
+        match object {
+            Object::String(ref text) => Some(text.as_str().to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Input for Option<String> {
+    fn to_object(&self) -> Object {
+        match self {
+            Some(text) => Object::OptionSome(Box::new(text.to_object())),
+            None => Object::OptionNone,
+        }
+    }
+
+    fn from_object(mut object: Object) -> Option<Self> {
+        match object {
+            Object::OptionSome(ref mut text) => String::from_object(std::mem::take(&mut **text)).map(Some),
+            Object::OptionNone => Some(None),
+            _ => None,
+        }
+    }
+}
+
+/// Reads from `source` with `live`, recording what it gives, or gives what
+/// was recorded in its place when replaying.
+pub(crate) fn read<T: Input>(source: &str, live: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    match replayed(source) {
+        Some(Ok(value)) => T::from_object(value).ok_or_else(|| io::Error::other(diverge(source))),
+        Some(Err(message)) => Err(io::Error::other(message)),
+        None => {
+            let result = live();
+            recorded(source, || match &result {
+                Ok(value) => value.to_object(),
+                Err(error) => Object::ResultErr(Box::new(Object::String(Str::from(error.to_string())))),
+            });
+            result
+        }
+    }
+}
+
+/// Opens a file for reading with `live`, recording whether it opened. A
+/// replayed file is never opened, its reads all coming from the trace.
+pub(crate) fn open(live: impl FnOnce() -> io::Result<Resource>) -> io::Result<Resource> {
+    match replayed("open") {
+        Some(Ok(Object::Unit)) => Ok(Resource::Replayed),
+        Some(Ok(_)) => Err(io::Error::other(diverge("open"))),
+        Some(Err(message)) => Err(io::Error::other(message)),
+        None => {
+            let result = live();
+            recorded("open", || match &result {
+                Ok(_) => Object::Unit,
+                Err(error) => Object::ResultErr(Box::new(Object::String(Str::from(error.to_string())))),
+            });
+            result
+        }
+    }
+}
+
+/// The time now, for `sleep` and timers.
+pub(crate) fn now() -> Instant {
+    let Some(start) = TRACE.with_borrow(|trace| trace.as_ref().map(|trace| trace.start)) else {
+        return Instant::now();
+    };
+    match replayed("time") {
+        Some(Ok(Object::Integer(micros @ 0..))) => start + Duration::from_micros(micros as u64),
+        Some(Ok(_)) => {
+            diverge("time");
+            Instant::now()
+        }
+        Some(Err(_)) => Instant::now(),
+        None => {
+            let now = Instant::now();
+            recorded("time", || Object::Integer((now - start).as_micros() as i64));
+            now
+        }
+    }
+}
+
+/// Waits for `duration`, or not at all when replaying.
+pub(crate) fn sleep(duration: Duration) {
+    if !TRACE.with_borrow(|trace| trace.as_ref().is_some_and(|trace| trace.position < trace.events.len() && trace.diverged.is_none())) {
+        std::thread::sleep(duration);
+    }
+}
+
+// The next value of a replay, Err of the message of a recorded failure or of
+// the divergence, or None when there is nothing to replay
+fn replayed(source: &str) -> Option<Result<Object, String>> {
+    TRACE.with_borrow_mut(|trace| {
+        let trace = trace.as_mut()?;
+        if let Some(diverged) = &trace.diverged {
+            return Some(Err(diverged.clone()));
+        }
+        if trace.events.get(trace.position).and_then(source_of) != Some(source) {
+            if trace.replaying {
+                return Some(Err(trace.diverge(source)));
+            }
+            // A rewound recording that goes another way records afresh
+            trace.events.truncate(trace.position);
+            return None;
+        }
+        trace.position += 1;
+        match &trace.events[trace.position - 1] {
+            Object::List(pair) => match &pair[1] {
+                Object::ResultErr(message) => Some(Err(String::from_object((**message).clone()).unwrap_or_default())),
+                value => Some(Ok(value.clone())),
+            },
+            _ => None,
+        }
+    })
+}
+
+fn recorded(source: &str, value: impl FnOnce() -> Object) {
+    TRACE.with_borrow_mut(|trace| {
+        if let Some(trace) = trace.as_mut().filter(|trace| !trace.replaying) {
+            trace.events.push(Object::List(vec![Object::from(source), value()]));
+            trace.position += 1;
+        }
+    });
+}
+
+fn diverge(source: &str) -> String {
+    TRACE.with_borrow_mut(|trace| trace.as_mut().map(|trace| trace.diverge(source)).unwrap_or_default())
+}
+
+fn source_of(event: &Object) -> Option<&str> {
+    match event {
+        Object::List(pair) => match pair.first() {
+            Some(Object::String(source)) => Some(source.as_str()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Trace {
+    // Marks the replay as diverged at the input it is on, giving why
+    fn diverge(&mut self, source: &str) -> String {
+        let message = match self.events.get(self.position).and_then(source_of) {
+            Some(recorded) => format!("the replay diverged from its trace at input {}: it read {} where {} was recorded", self.position + 1, source, recorded),
+            None => format!("the replay diverged from its trace after all {} inputs: it read {} more", self.position, source),
+        };
+        self.diverged.get_or_insert(message).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::Env;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_record_and_replay() {
+        let eval = |source: &str| Evaluator::new(Rc::new(RefCell::new(Env::new()))).eval(&Parser::new(Lexer::new(source)).parse_program());
+        let path = std::env::temp_dir().join(format!("opl-trace-{}.txt", std::process::id()));
+        let path = format!("{:?}", path.to_string_lossy());
+        std::fs::write(path.trim_matches('"'), "recorded\n").unwrap();
+        let source = format!("let input = file.open({}, \"r\");\nsleep(1);\nlet line = file.read_line(input);\nfile.close(input);\nline", path);
+        let line = Some(Object::OptionSome(Box::new(Object::from("recorded"))));
+
+        super::record();
+        assert_eq!(eval(&source), line);
+        let trace = super::finish().unwrap().unwrap();
+
+        // The replay reads the file as it was, even once it is gone
+        let _ = std::fs::remove_file(path.trim_matches('"'));
+        super::replay(&trace).unwrap();
+        assert_eq!(eval(&source), line);
+        assert_eq!(super::finish(), Ok(None));
+
+        super::replay(&trace).unwrap();
+        assert!(matches!(eval("sleep(1);\nsleep(1);\nstdin.read_line()"), Some(Object::Error(..))));
+        assert!(super::finish().unwrap_err().starts_with("the replay diverged from its trace at input 1"));
+        assert!(super::replay(b"not a trace").is_err());
+    }
+}
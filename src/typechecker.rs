@@ -0,0 +1,1668 @@
+// typechecker.rs
+//
+// Hindley-Milner style inference over the AST. Types are inferred without
+// evaluating anything; let bindings are generalized so polymorphic helpers
+// (e.g. `let id = fn x -> x;`) can be used at several types.
+//
+// Typing is gradual: a `dynamic` value is accepted wherever any type is
+// expected, and it reaches typed code through annotated lets
+// (`let port: int = config.port;`), which the evaluator checks at runtime.
+
+use crate::ast::*;
+use crate::builtin::{self, BUILTINS};
+use crate::diagnostics::{self, Code, Diagnostic};
+use crate::effects::Effects;
+use crate::lexer::{Span, Token};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Ty {
+    Int,
+    Float,
+    Bool,
+    String,
+    Char,
+    Unit,
+    List(Box<Ty>),
+    Option(Box<Ty>),
+    Result(Box<Ty>, Box<Ty>),
+    HashMap(Box<Ty>, Box<Ty>),
+    Function(Vec<Ty>, Box<Ty>),
+    Record(Vec<(String, Ty)>),
+    // User declared type, e.g. `cell` or `tree * int`
+    Named(String, Vec<Ty>),
+    Var(usize),
+    // Only known at runtime, e.g. a parsed document or a host value
+    Dynamic,
+}
+
+/// A type error, with the span of the innermost operator, call or let it was
+/// found in, if any. Derefs to what went wrong.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TypeError {
+    pub kind: Box<TypeErrorKind>,
+    pub span: Option<Span>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TypeErrorKind {
+    Mismatch { expected: Ty, actual: Ty },
+    // A variable unified with a type containing it, and the let being checked
+    InfiniteType { var: Ty, ty: Ty, binding: Option<String> },
+    // The unbound name and the closest name in scope, if any
+    Undefined(String, Option<String>),
+    // The missing field and the closest field the record has, if any
+    UnknownField(String, Option<String>),
+    Unsupported(String),
+    // An effectful builtin or host function used in a `@pure` module
+    Effect(String, Effects),
+}
+
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Ty,
+    // Effects of calling the binding, for functions
+    effects: Effects,
+}
+
+pub struct TypeChecker {
+    // Substitution, indexed by type variable
+    subst: Vec<Option<Ty>>,
+    scopes: Vec<HashMap<String, Scheme>>,
+    // Declared record types and aliases by name
+    records: HashMap<String, Vec<(String, Ty)>>,
+    aliases: HashMap<String, Ty>,
+    // User types deriving `ord`, which `<` and friends accept
+    ordered: Vec<String>,
+    // Return type of each enclosing function literal
+    returns: Vec<Ty>,
+    // Names of the enclosing lets, innermost last
+    bindings: Vec<String>,
+    // Effects performed by the code being checked, one set per enclosing
+    // function literal and one for the top level
+    performed: Vec<Effects>,
+    // Effects of the function literal checked last
+    latent: Effects,
+    // Effects of every tagged function referred to, at any depth
+    mentioned: Effects,
+    // Host functions and their effects
+    hosts: HashMap<String, Effects>,
+    // Set by a `@pure` attribute; effectful builtins are then rejected
+    pure: bool,
+    // Set by a `@gradual` attribute; see `check_program`
+    gradual: bool,
+    pub errors: Vec<TypeError>,
+    // The span of the innermost operator, call or let being checked
+    location: Option<Span>,
+    // Types of let bindings, operators and calls by span, when recording them
+    types: Option<Vec<(Span, Ty)>>,
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        TypeChecker {
+            subst: Vec::new(),
+            scopes: vec![HashMap::new()],
+            records: HashMap::new(),
+            aliases: HashMap::new(),
+            ordered: Vec::new(),
+            returns: Vec::new(),
+            bindings: Vec::new(),
+            performed: vec![Effects::NONE],
+            latent: Effects::NONE,
+            mentioned: Effects::NONE,
+            hosts: HashMap::new(),
+            pure: false,
+            gradual: false,
+            errors: Vec::new(),
+            location: None,
+            types: None,
+        }
+    }
+
+    /// Records the types of let bindings, operators and calls as they are
+    /// checked, see `types`.
+    pub fn with_types(mut self) -> Self {
+        self.types = Some(Vec::new());
+        self
+    }
+
+    /// The recorded types of the nodes at `span`s, as resolved so far.
+    pub fn types(&self) -> Vec<(Span, Ty)> {
+        self.types.iter().flatten().map(|(span, ty)| (*span, self.resolve(ty))).collect()
+    }
+
+    fn record(&mut self, span: Span, ty: &Ty) {
+        if let Some(types) = &mut self.types {
+            types.push((span, ty.clone()));
+        }
+    }
+
+    /// Declares a value provided by the host, of type `dynamic`. Calling it
+    /// has `effects`.
+    pub fn declare_host(&mut self, name: &str, effects: Effects) {
+        self.hosts.insert(name.to_string(), effects);
+    }
+
+    /// Effects of calling the top level binding `name`.
+    pub fn effects(&self, name: &str) -> Option<Effects> {
+        self.scopes[0].get(name).map(|scheme| scheme.effects)
+    }
+
+    /// Effects of the top level code checked so far.
+    pub fn performed(&self) -> Effects {
+        self.performed[0]
+    }
+
+    /// Effects of every builtin and host function the code refers to, called
+    /// or not. Unlike `performed`, this bounds what running it can do even
+    /// when effectful functions are passed around as values.
+    pub fn mentioned(&self) -> Effects {
+        self.mentioned
+    }
+
+    // Records the effects of the code being checked. Only builtins and host
+    // functions are tagged with effects, so only their uses are reported in a
+    // pure module.
+    fn perform(&mut self, name: &str, effects: Effects, tagged: bool) {
+        let current = self.performed.last_mut().unwrap();
+        *current = current.union(effects);
+        if tagged {
+            self.mentioned = self.mentioned.union(effects);
+        }
+        if tagged && self.pure && !effects.is_empty() {
+            self.errors.push(TypeError { kind: Box::new(TypeErrorKind::Effect(name.to_string(), effects)), span: self.location });
+        }
+    }
+
+    fn fresh(&mut self) -> Ty {
+        self.subst.push(None);
+        Ty::Var(self.subst.len() - 1)
+    }
+
+    // Follow the substitution until reaching a concrete type or an unbound variable.
+    fn prune(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Var(v) => match &self.subst[*v] {
+                Some(bound) => self.prune(bound),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    /// Fully applies the current substitution to `ty`.
+    pub fn resolve(&self, ty: &Ty) -> Ty {
+        match self.prune(ty) {
+            Ty::List(t) => Ty::List(Box::new(self.resolve(&t))),
+            Ty::Option(t) => Ty::Option(Box::new(self.resolve(&t))),
+            Ty::Result(a, b) => Ty::Result(Box::new(self.resolve(&a)), Box::new(self.resolve(&b))),
+            Ty::HashMap(k, v) => Ty::HashMap(Box::new(self.resolve(&k)), Box::new(self.resolve(&v))),
+            Ty::Function(params, ret) => Ty::Function(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(&ret)),
+            ),
+            Ty::Record(fields) => Ty::Record(fields.iter().map(|(n, t)| (n.clone(), self.resolve(t))).collect()),
+            Ty::Named(name, params) => Ty::Named(name, params.iter().map(|p| self.resolve(p)).collect()),
+            other => other,
+        }
+    }
+
+    fn occurs(&self, var: usize, ty: &Ty) -> bool {
+        match self.prune(ty) {
+            Ty::Var(v) => v == var,
+            Ty::List(t) | Ty::Option(t) => self.occurs(var, &t),
+            Ty::Result(a, b) | Ty::HashMap(a, b) => self.occurs(var, &a) || self.occurs(var, &b),
+            Ty::Function(params, ret) => params.iter().any(|p| self.occurs(var, p)) || self.occurs(var, &ret),
+            Ty::Record(fields) => fields.iter().any(|(_, t)| self.occurs(var, t)),
+            Ty::Named(_, params) => params.iter().any(|p| self.occurs(var, p)),
+            _ => false,
+        }
+    }
+
+    fn mismatch(&self, expected: &Ty, actual: &Ty) -> TypeError {
+        TypeErrorKind::Mismatch {
+            expected: self.resolve(expected),
+            actual: self.resolve(actual),
+        }
+        .into()
+    }
+
+    fn infinite(&self, var: usize, ty: &Ty) -> TypeError {
+        TypeErrorKind::InfiniteType {
+            var: Ty::Var(var),
+            ty: self.resolve(ty),
+            binding: self.bindings.last().cloned(),
+        }
+        .into()
+    }
+
+    /// Unifies two types. A mismatch is reported between the whole types, not
+    /// just the parts that differ, so the diff shows where they disagree.
+    pub fn unify(&mut self, expected: &Ty, actual: &Ty) -> Result<(), TypeError> {
+        self.unify_parts(expected, actual).map_err(|error| match *error.kind {
+            TypeErrorKind::Mismatch { .. } => self.mismatch(expected, actual),
+            _ => error,
+        })
+    }
+
+    fn unify_parts(&mut self, expected: &Ty, actual: &Ty) -> Result<(), TypeError> {
+        let (a, b) = (self.prune(expected), self.prune(actual));
+        match (&a, &b) {
+            (Ty::Var(x), Ty::Var(y)) if x == y => Ok(()),
+            (Ty::Var(x), other) | (other, Ty::Var(x)) => {
+                if self.occurs(*x, other) {
+                    return Err(self.infinite(*x, other));
+                }
+                self.subst[*x] = Some(other.clone());
+                Ok(())
+            }
+            (Ty::Dynamic, _) | (_, Ty::Dynamic) => Ok(()),
+            (Ty::List(x), Ty::List(y)) | (Ty::Option(x), Ty::Option(y)) => self.unify_parts(x, y),
+            (Ty::Result(x1, x2), Ty::Result(y1, y2)) | (Ty::HashMap(x1, x2), Ty::HashMap(y1, y2)) => {
+                self.unify_parts(x1, y1)?;
+                self.unify_parts(x2, y2)
+            }
+            (Ty::Function(xp, xr), Ty::Function(yp, yr)) => {
+                if xp.len() != yp.len() {
+                    return Err(self.mismatch(&a, &b));
+                }
+                for (x, y) in xp.iter().zip(yp.iter()) {
+                    self.unify_parts(x, y)?;
+                }
+                self.unify_parts(xr, yr)
+            }
+            (Ty::Record(xf), Ty::Record(yf)) => {
+                if xf.len() != yf.len() {
+                    return Err(self.mismatch(&a, &b));
+                }
+                for (name, x) in xf {
+                    match yf.iter().find(|(n, _)| n == name) {
+                        Some((_, y)) => self.unify_parts(x, y)?,
+                        None => return Err(self.mismatch(&a, &b)),
+                    }
+                }
+                Ok(())
+            }
+            (Ty::Named(name, params), Ty::Record(_)) | (Ty::Record(_), Ty::Named(name, params))
+                if params.is_empty() && self.records.contains_key(name) =>
+            {
+                let record = Ty::Record(self.records[name].clone());
+                if matches!(a, Ty::Named(..)) {
+                    self.unify_parts(&record, &b)
+                } else {
+                    self.unify_parts(&a, &record)
+                }
+            }
+            (Ty::Named(x, xp), Ty::Named(y, yp)) if x == y && xp.len() == yp.len() => {
+                for (x, y) in xp.iter().zip(yp.iter()) {
+                    self.unify_parts(x, y)?;
+                }
+                Ok(())
+            }
+            _ if a == b => Ok(()),
+            _ => Err(self.mismatch(&a, &b)),
+        }
+    }
+
+    fn free_vars(&self, ty: &Ty, out: &mut Vec<usize>) {
+        match self.prune(ty) {
+            Ty::Var(v) if !out.contains(&v) => out.push(v),
+            Ty::List(t) | Ty::Option(t) => self.free_vars(&t, out),
+            Ty::Result(a, b) | Ty::HashMap(a, b) => {
+                self.free_vars(&a, out);
+                self.free_vars(&b, out);
+            }
+            Ty::Function(params, ret) => {
+                for p in &params {
+                    self.free_vars(p, out);
+                }
+                self.free_vars(&ret, out);
+            }
+            Ty::Record(fields) => {
+                for (_, t) in &fields {
+                    self.free_vars(t, out);
+                }
+            }
+            Ty::Named(_, params) => {
+                for p in &params {
+                    self.free_vars(p, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn generalize(&self, ty: &Ty) -> Scheme {
+        let mut env_vars = Vec::new();
+        for scope in &self.scopes {
+            for scheme in scope.values() {
+                let mut vars = Vec::new();
+                self.free_vars(&scheme.ty, &mut vars);
+                env_vars.extend(vars.into_iter().filter(|v| !scheme.vars.contains(v)));
+            }
+        }
+        let mut vars = Vec::new();
+        self.free_vars(ty, &mut vars);
+        vars.retain(|v| !env_vars.contains(v));
+        Scheme { vars, ty: self.resolve(ty), effects: Effects::NONE }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Ty {
+        let mapping: HashMap<usize, Ty> = scheme.vars.iter().map(|v| (*v, self.fresh())).collect();
+        substitute(&scheme.ty, &mapping)
+    }
+
+    fn bind(&mut self, name: &str, scheme: Scheme) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), scheme);
+    }
+
+    fn bind_mono(&mut self, name: &str, ty: Ty) {
+        self.bind(name, Scheme { vars: Vec::new(), ty, effects: Effects::NONE });
+    }
+
+    fn scheme(&self, name: &str) -> Option<&Scheme> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<Ty> {
+        let scheme = self.scheme(name).cloned()?;
+        Some(self.instantiate(&scheme))
+    }
+
+    /// Declared type of a binding in the outermost scope.
+    pub fn binding(&self, name: &str) -> Option<Ty> {
+        self.scopes[0].get(name).map(|scheme| self.resolve(&scheme.ty))
+    }
+
+    /// Checks a whole program, keeping its bindings for later calls. Errors are
+    /// collected in `self.errors`; a binding whose right hand side fails to
+    /// check is given an unconstrained type so checking can continue.
+    ///
+    /// In a `@gradual` module such a binding is `dynamic` instead, and only
+    /// errors in annotated lets are reported, so untyped code can be
+    /// annotated a binding at a time.
+    pub fn check_program(&mut self, program: &[Statement]) -> Option<Ty> {
+        let attribute = |expected: &str| {
+            program.iter().any(|statement| {
+                matches!(statement, Statement::Attribute(Token::Identifier(name), arguments) if name == expected && arguments.is_empty())
+            })
+        };
+        self.pure |= attribute("pure");
+        self.gradual |= attribute("gradual");
+        let mut last = None;
+        for statement in program {
+            match self.infer_statement(statement) {
+                Ok(ty) => last = ty,
+                Err(error) => {
+                    let annotated = matches!(statement, Statement::Let(_, Expression::Annotated { .. }, _));
+                    if let Statement::Let(Token::Identifier(name), _, _) = statement {
+                        let ty = if self.gradual { Ty::Dynamic } else { self.fresh() };
+                        self.bind_mono(name, ty);
+                    }
+                    if annotated || !self.gradual {
+                        self.errors.push(error);
+                    }
+                    last = None;
+                }
+            }
+        }
+        last.map(|ty| self.resolve(&ty))
+    }
+
+    /// Infers the type of `program` without keeping any of its bindings.
+    pub fn infer_program(&mut self, program: &[Statement]) -> Result<Ty, TypeError> {
+        self.scopes.push(HashMap::new());
+        let result = self.infer_block(program);
+        self.scopes.pop();
+        result.map(|ty| self.resolve(&ty))
+    }
+
+    fn infer_block(&mut self, program: &[Statement]) -> Result<Ty, TypeError> {
+        let mut last = Ty::Unit;
+        for statement in program {
+            last = self.infer_statement(statement)?.unwrap_or(Ty::Unit);
+        }
+        Ok(last)
+    }
+
+    fn infer_statement(&mut self, statement: &Statement) -> Result<Option<Ty>, TypeError> {
+        match statement {
+            Statement::Let(identifier, expression, span) => {
+                let name = identifier.to_string();
+                self.bindings.push(name.clone());
+                let outer = self.location.replace(*span);
+                let ty = self.infer_let(&name, expression).map_err(|error| error.within(Some(*span)));
+                self.location = outer;
+                self.bindings.pop();
+                let ty = ty?;
+                self.record(*span, &ty);
+                let mut scheme = self.generalize(&ty);
+                scheme.effects = match expression {
+                    Expression::Function { .. } => self.latent,
+                    Expression::Identifier(identifier) => self.scheme(&identifier.to_string()).map_or(Effects::NONE, |s| s.effects),
+                    _ => Effects::NONE,
+                };
+                self.bind(&name, scheme);
+                Ok(None)
+            }
+            Statement::Return(expression) => {
+                let ty = self.infer_expression(expression)?;
+                if let Some(ret) = self.returns.last().cloned() {
+                    self.unify(&ret, &ty)?;
+                }
+                Ok(Some(ty))
+            }
+            Statement::Expression(expression) => self.infer_expression(expression).map(Some),
+            Statement::Type(identifier, declaration, derives) => {
+                self.declare_type(&identifier.to_string(), declaration);
+                self.derive(&identifier.to_string(), derives);
+                Ok(None)
+            }
+            Statement::Assert(assertion) => {
+                let condition = if assertion.kind == AssertionKind::Ensures {
+                    // `result` is the return value of the enclosing function
+                    let ret = self.returns.last().cloned().unwrap_or_else(|| self.fresh());
+                    self.scopes.push(HashMap::new());
+                    self.bind_mono("result", ret);
+                    let condition = self.infer_expression(&assertion.condition);
+                    self.scopes.pop();
+                    condition?
+                } else {
+                    self.infer_expression(&assertion.condition)?
+                };
+                self.unify(&Ty::Bool, &condition)?;
+                Ok(None)
+            }
+            Statement::Comment(_) | Statement::Attribute(..) => Ok(None),
+        }
+    }
+
+    fn infer_let(&mut self, name: &str, expression: &Expression) -> Result<Ty, TypeError> {
+        let Expression::Function { .. } = expression else {
+            return self.infer_expression(expression);
+        };
+        // Allow recursive references to the binding inside its own body
+        let placeholder = self.fresh();
+        self.bind_mono(name, placeholder.clone());
+        let ty = self.infer_expression(expression);
+        self.scopes.last_mut().unwrap().remove(name);
+        let ty = ty?;
+        self.unify(&placeholder, &ty)?;
+        Ok(ty)
+    }
+
+    fn declare_type(&mut self, name: &str, declaration: &Type) {
+        match declaration {
+            Type::Alias(alias) => {
+                let ty = self.alias_to_ty(alias);
+                self.aliases.insert(name.to_string(), ty);
+            }
+            Type::Record(fields) => {
+                let fields = fields.iter().map(|(f, a)| (f.to_string(), self.alias_to_ty(a))).collect();
+                self.records.insert(name.to_string(), fields);
+            }
+            Type::Union(variants) => {
+                let union = Ty::Named(name.to_string(), Vec::new());
+                for (variant, payload) in variants {
+                    let ty = match payload {
+                        Some(alias) => Ty::Function(vec![self.alias_to_ty(alias)], Box::new(union.clone())),
+                        None => union.clone(),
+                    };
+                    self.bind_mono(&variant.to_string(), ty);
+                }
+            }
+        }
+    }
+
+    // The helpers bound by `@derive(...)`, see `derive.rs`
+    fn derive(&mut self, name: &str, derives: &[Identifier]) {
+        if derives.iter().any(|derive| derive.to_string() == "ord") {
+            self.ordered.push(name.to_string());
+        }
+        if derives.iter().any(|derive| derive.to_string() == "enum") {
+            let union = Ty::Named(name.to_string(), Vec::new());
+            let helpers = Ty::Record(vec![
+                ("to_int".to_string(), Ty::Function(vec![union.clone()], Box::new(Ty::Int))),
+                ("from_int".to_string(), Ty::Function(vec![Ty::Int], Box::new(Ty::Option(Box::new(union.clone()))))),
+                ("values".to_string(), Ty::Function(Vec::new(), Box::new(Ty::List(Box::new(union))))),
+            ]);
+            self.bind_mono(name, helpers);
+        }
+    }
+
+    fn alias_to_ty(&mut self, alias: &Alias) -> Ty {
+        let params: Vec<Ty> = alias.parameters.iter().map(|p| self.alias_to_ty(p)).collect();
+        let param = |i: usize| params.get(i).cloned().unwrap_or(Ty::Unit);
+        match &alias.name {
+            TypeConstructor::BuiltIn(constructor) => match constructor {
+                Constructor::Int => Ty::Int,
+                Constructor::Float => Ty::Float,
+                Constructor::String => Ty::String,
+                Constructor::Char => Ty::Char,
+                Constructor::Bool => Ty::Bool,
+                Constructor::Unit => Ty::Unit,
+                Constructor::Dynamic => Ty::Dynamic,
+                Constructor::List => Ty::List(Box::new(param(0))),
+                Constructor::Option => Ty::Option(Box::new(param(0))),
+                Constructor::Result => Ty::Result(Box::new(param(0)), Box::new(param(1))),
+                Constructor::HashMap => Ty::HashMap(Box::new(param(0)), Box::new(param(1))),
+            },
+            TypeConstructor::Custom(name) => {
+                let name = name.to_string();
+                match self.aliases.get(&name) {
+                    Some(ty) if params.is_empty() => ty.clone(),
+                    _ => Ty::Named(name, params.clone()),
+                }
+            }
+        }
+    }
+
+    pub fn infer_expression(&mut self, expression: &Expression) -> Result<Ty, TypeError> {
+        let span = expression.span();
+        let outer = self.location;
+        self.location = span.or(outer);
+        let ty = self.infer_node(expression).map_err(|error| error.within(span));
+        self.location = outer;
+        let ty = ty?;
+        if let Some(span) = span {
+            self.record(span, &ty);
+        }
+        Ok(ty)
+    }
+
+    fn infer_node(&mut self, expression: &Expression) -> Result<Ty, TypeError> {
+        match expression {
+            Expression::Identifier(identifier) => self.infer_identifier(identifier),
+            Expression::Literal(literal) => self.infer_literal(literal),
+            Expression::OptionSome(inner) => Ok(Ty::Option(Box::new(self.infer_expression(inner)?))),
+            Expression::OptionNone => Ok(Ty::Option(Box::new(self.fresh()))),
+            Expression::ResultOk(inner) => {
+                let ok = self.infer_expression(inner)?;
+                Ok(Ty::Result(Box::new(ok), Box::new(self.fresh())))
+            }
+            Expression::ResultErr(inner) => {
+                let err = self.infer_expression(inner)?;
+                Ok(Ty::Result(Box::new(self.fresh()), Box::new(err)))
+            }
+            Expression::Prefix(prefix, right) => self.infer_prefix(prefix, right),
+            Expression::Infix(..) => self.infer_infix(expression),
+            Expression::Block(program) => {
+                self.scopes.push(HashMap::new());
+                let result = self.infer_block(program);
+                self.scopes.pop();
+                result
+            }
+            Expression::If { condition, consequence, alternative } => {
+                let condition = self.infer_expression(condition)?;
+                self.unify(&Ty::Bool, &condition)?;
+                self.scopes.push(HashMap::new());
+                let then = self.infer_block(consequence);
+                self.scopes.pop();
+                let then = then?;
+                match alternative {
+                    Some(alternative) => {
+                        self.scopes.push(HashMap::new());
+                        let otherwise = self.infer_block(alternative);
+                        self.scopes.pop();
+                        self.unify(&then, &otherwise?)?;
+                        Ok(then)
+                    }
+                    None => Ok(Ty::Unit),
+                }
+            }
+            Expression::Function { parameters, body, .. } => self.infer_function(parameters, body),
+            // What is quoted is not checked, its names being bound only where
+            // it is evaluated
+            Expression::Quote(..) => Ok(Ty::Named("quoted".to_string(), Vec::new())),
+            Expression::Call { .. } => self.infer_call(expression, None),
+            Expression::Match { expr, arms } => self.infer_match(expr, arms),
+            Expression::BuiltIn { .. } => self.infer_call(expression, None),
+            Expression::Field { record, field, optional: false, .. } => {
+                let record = self.infer_expression(record)?;
+                self.field_type(&record, field)
+            }
+            // The field of a record or an optional record, or the value of a
+            // string key of a map, as an option
+            Expression::Field { record, field, optional: true, .. } => {
+                let record = match self.infer_expression(record).map(|record| self.resolve(&record))? {
+                    Ty::Option(record) => *record,
+                    record => record,
+                };
+                if let Ty::HashMap(key, value) = self.resolve(&record) {
+                    self.unify(&Ty::String, &key)?;
+                    return Ok(Ty::Option(value));
+                }
+                match self.field_type(&record, field).map(|ty| self.resolve(&ty))? {
+                    ty @ (Ty::Option(_) | Ty::Var(_) | Ty::Dynamic) => Ok(ty),
+                    ty => Ok(Ty::Option(Box::new(ty))),
+                }
+            }
+            Expression::Range { start, end } => {
+                let start = self.infer_expression(start)?;
+                self.unify(&Ty::Int, &start)?;
+                let end = self.infer_expression(end)?;
+                self.unify(&Ty::Int, &end)?;
+                Ok(Ty::List(Box::new(Ty::Int)))
+            }
+            Expression::Annotated { expression, annotation } => {
+                let ty = self.infer_expression(expression)?;
+                let annotation = self.alias_to_ty(annotation);
+                self.unify(&annotation, &ty)?;
+                Ok(annotation)
+            }
+        }
+    }
+
+    // The type of `field` in a value of type `record`
+    fn field_type(&mut self, record: &Ty, field: &Identifier) -> Result<Ty, TypeError> {
+        let fields = match self.resolve(record) {
+            Ty::Record(fields) => fields,
+            Ty::Named(name, params) if params.is_empty() && self.records.contains_key(&name) => {
+                self.records[&name].clone()
+            }
+            // Without row types the record is unknown here, e.g. a parameter
+            Ty::Var(_) => return Ok(self.fresh()),
+            Ty::Dynamic => return Ok(Ty::Dynamic),
+            other => return Err(self.mismatch(&Ty::Record(Vec::new()), &other)),
+        };
+        let name = field.to_string();
+        match fields.iter().find(|(field, _)| *field == name) {
+            Some((_, ty)) => Ok(ty.clone()),
+            None => {
+                let suggestion = diagnostics::closest(&name, fields.iter().map(|(field, _)| field.as_str()));
+                Err(TypeErrorKind::UnknownField(name, suggestion.map(str::to_string)).into())
+            }
+        }
+    }
+
+    fn infer_identifier(&mut self, identifier: &Identifier) -> Result<Ty, TypeError> {
+        let name = identifier.to_string();
+        if let Some(ty) = self.lookup(&name) {
+            return Ok(ty);
+        }
+        if let Some(ty) = self.named_builtin_type(&name) {
+            self.perform(&name, builtin::effects(&name), true);
+            return Ok(ty);
+        }
+        if let Some(effects) = self.hosts.get(&name).copied() {
+            self.perform(&name, effects, true);
+            return Ok(Ty::Dynamic);
+        }
+        let candidates = self.scopes.iter().flat_map(|scope| scope.keys().map(String::as_str));
+        let suggestion = diagnostics::closest(&name, candidates.chain(BUILTINS)).map(str::to_string);
+        Err(TypeErrorKind::Undefined(name, suggestion).into())
+    }
+
+    fn infer_literal(&mut self, literal: &Literal) -> Result<Ty, TypeError> {
+        match literal {
+            Literal::Integer(_) => Ok(Ty::Int),
+            Literal::Float(_) => Ok(Ty::Float),
+            Literal::String(_) => Ok(Ty::String),
+            Literal::Boolean(_) => Ok(Ty::Bool),
+            Literal::Char(_) => Ok(Ty::Char),
+            Literal::Unit => Ok(Ty::Unit),
+            Literal::List(elements) => {
+                let element = self.fresh();
+                for expression in elements {
+                    let ty = self.infer_expression(expression)?;
+                    self.unify(&element, &ty)?;
+                }
+                Ok(Ty::List(Box::new(element)))
+            }
+            Literal::Record(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, expression)| Ok((name.to_string(), self.infer_expression(expression)?)))
+                    .collect::<Result<Vec<(String, Ty)>, TypeError>>()?;
+                Ok(Ty::Record(fields))
+            }
+            Literal::HashMap(entries) => {
+                let (key, value) = (self.fresh(), self.fresh());
+                for (k, v) in entries {
+                    let k = self.infer_expression(k)?;
+                    self.unify(&key, &k)?;
+                    let v = self.infer_expression(v)?;
+                    self.unify(&value, &v)?;
+                }
+                Ok(Ty::HashMap(Box::new(key), Box::new(value)))
+            }
+        }
+    }
+
+    fn infer_function(&mut self, parameters: &[Identifier], body: &[Statement]) -> Result<Ty, TypeError> {
+        self.scopes.push(HashMap::new());
+        let mut params = Vec::new();
+        for parameter in parameters {
+            let ty = self.fresh();
+            self.bind_mono(&parameter.to_string(), ty.clone());
+            params.push(ty);
+        }
+        let ret = self.fresh();
+        self.returns.push(ret.clone());
+        self.performed.push(Effects::NONE);
+        let body = self.infer_block(body);
+        self.latent = self.performed.pop().unwrap();
+        self.returns.pop();
+        self.scopes.pop();
+        self.unify(&ret, &body?)?;
+        Ok(Ty::Function(params, Box::new(ret)))
+    }
+
+    fn apply(&mut self, function: Ty, arguments: Vec<Ty>) -> Result<Ty, TypeError> {
+        if self.prune(&function) == Ty::Dynamic {
+            return Ok(Ty::Dynamic);
+        }
+        let ret = self.fresh();
+        let expected = Ty::Function(arguments, Box::new(ret.clone()));
+        self.unify(&function, &expected)?;
+        Ok(ret)
+    }
+
+    fn builtin_type(&mut self, function: &Token) -> Result<Ty, TypeError> {
+        let (a, b) = (self.fresh(), self.fresh());
+        let list = |t: &Ty| Ty::List(Box::new(t.clone()));
+        let func = |params: Vec<Ty>, ret: Ty| Ty::Function(params, Box::new(ret));
+        match function {
+            Token::Map => Ok(func(vec![func(vec![a.clone()], b.clone()), list(&a)], list(&b))),
+            Token::Filter => Ok(func(vec![func(vec![a.clone()], Ty::Bool), list(&a)], list(&a))),
+            Token::Fold => Ok(func(vec![func(vec![b.clone(), a.clone()], b.clone()), b.clone(), list(&a)], b)),
+            Token::Any | Token::All => Ok(func(vec![func(vec![a.clone()], Ty::Bool), list(&a)], Ty::Bool)),
+            Token::Println => Ok(func(vec![Ty::String], Ty::Unit)),
+            Token::ConcatAll => Ok(func(vec![list(&Ty::String)], Ty::String)),
+            // With the source text and line, see `parser::dbg`
+            Token::Dbg => Ok(func(vec![a.clone(), Ty::String, Ty::Int], a)),
+            other => Err(TypeErrorKind::Unsupported(format!("builtin {:?}", other)).into()),
+        }
+    }
+
+    /// Type of a builtin that is an ordinary identifier, see `builtin::lookup`.
+    pub fn named_builtin_type(&mut self, name: &str) -> Option<Ty> {
+        let a = self.fresh();
+        match name {
+            "hash" => Some(Ty::Function(vec![a], Box::new(Ty::Int))),
+            "divmod" => Some(Ty::Function(vec![Ty::Int, Ty::Int], Box::new(Ty::List(Box::new(Ty::Int))))),
+            "bit_and" | "bit_or" | "bit_xor" | "div_euclid" | "rem_euclid" => Some(Ty::Function(vec![Ty::Int, Ty::Int], Box::new(Ty::Int))),
+            "bit_not" | "popcount" | "leading_zeros" => Some(Ty::Function(vec![Ty::Int], Box::new(Ty::Int))),
+            "to_char_code" => Some(Ty::Function(vec![Ty::String], Box::new(Ty::Result(Box::new(Ty::Int), Box::new(Ty::String))))),
+            "from_char_code" => Some(Ty::Function(vec![Ty::Int], Box::new(Ty::Result(Box::new(Ty::String), Box::new(Ty::String))))),
+            "utf8_bytes" => Some(Ty::Function(vec![Ty::String], Box::new(Ty::List(Box::new(Ty::Int))))),
+            "from_utf8" => {
+                let bytes = Ty::List(Box::new(Ty::Int));
+                Some(Ty::Function(vec![bytes], Box::new(Ty::Result(Box::new(Ty::String), Box::new(Ty::String)))))
+            }
+            // Encoders take a string or a list of bytes
+            "base64_encode" | "hex_encode" => Some(Ty::Function(vec![a], Box::new(Ty::String))),
+            "base64_decode" | "hex_decode" => {
+                let bytes = Ty::List(Box::new(Ty::Int));
+                Some(Ty::Function(vec![Ty::String], Box::new(Ty::Result(Box::new(bytes), Box::new(Ty::String)))))
+            }
+            "show" => Some(Ty::Function(vec![a], Box::new(Ty::String))),
+            "eval_quoted" => Some(Ty::Function(vec![Ty::Named("quoted".to_string(), Vec::new())], Box::new(Ty::Dynamic))),
+            // The values are a list or a record
+            "format" => Some(Ty::Function(vec![Ty::String, a], Box::new(Ty::String))),
+            // Never returns, so fits wherever a value is expected
+            "exit" => Some(Ty::Function(vec![Ty::Int], Box::new(a))),
+            // An address is typed by the messages it takes
+            "actor" => {
+                let message = self.fresh();
+                let handler = Ty::Function(vec![a.clone(), message.clone()], Box::new(a.clone()));
+                Some(Ty::Function(vec![a, handler], Box::new(Ty::Named("actor".to_string(), vec![message]))))
+            }
+            "sleep" => Some(Ty::Function(vec![Ty::Int], Box::new(Ty::Unit))),
+            "spawn" | "spawn_link" => Some(Ty::Function(vec![Ty::Function(Vec::new(), Box::new(a.clone()))], Box::new(Ty::Named("task".to_string(), vec![a])))),
+            "await" => Some(Ty::Function(vec![Ty::Named("task".to_string(), vec![a.clone()])], Box::new(Ty::Result(Box::new(a), Box::new(Ty::String))))),
+            "send" => Some(Ty::Function(vec![Ty::Named("actor".to_string(), vec![a.clone()]), a], Box::new(Ty::Unit))),
+            "sort_by" => {
+                let list = Ty::List(Box::new(a.clone()));
+                let comparator = Ty::Function(vec![a.clone(), a], Box::new(Ty::Int));
+                Some(Ty::Function(vec![list.clone(), comparator], Box::new(list)))
+            }
+            "group_by" | "count_by" => {
+                let (list, k) = (Ty::List(Box::new(a.clone())), self.fresh());
+                let key = Ty::Function(vec![a.clone()], Box::new(k.clone()));
+                let value = if name == "group_by" { list.clone() } else { Ty::Int };
+                Some(Ty::Function(vec![list, key], Box::new(Ty::HashMap(Box::new(k), Box::new(value)))))
+            }
+            "sum_by" => {
+                let n = self.fresh();
+                Some(Ty::Function(vec![Ty::List(Box::new(a.clone())), Ty::Function(vec![a], Box::new(n.clone()))], Box::new(n)))
+            }
+            "min_by" | "max_by" => {
+                let key = Ty::Function(vec![a.clone()], Box::new(self.fresh()));
+                Some(Ty::Function(vec![Ty::List(Box::new(a.clone())), key], Box::new(Ty::Option(Box::new(a)))))
+            }
+            "partition" => {
+                let list = Ty::List(Box::new(a.clone()));
+                let predicate = Ty::Function(vec![a], Box::new(Ty::Bool));
+                Some(Ty::Function(vec![list.clone(), predicate], Box::new(Ty::List(Box::new(list)))))
+            }
+            "chunks" | "windows" => {
+                let list = Ty::List(Box::new(a));
+                Some(Ty::Function(vec![list.clone(), Ty::Int], Box::new(Ty::List(Box::new(list)))))
+            }
+            "intersperse" => {
+                let list = Ty::List(Box::new(a.clone()));
+                Some(Ty::Function(vec![list.clone(), a], Box::new(list)))
+            }
+            "transpose" => {
+                let rows = Ty::List(Box::new(Ty::List(Box::new(a))));
+                Some(Ty::Function(vec![rows.clone()], Box::new(rows)))
+            }
+            "sort_by_key" => {
+                let list = Ty::List(Box::new(a.clone()));
+                let key = Ty::Function(vec![a], Box::new(self.fresh()));
+                Some(Ty::Function(vec![list.clone(), key], Box::new(list)))
+            }
+            "float" => {
+                let test = Ty::Function(vec![Ty::Float], Box::new(Ty::Bool));
+                Some(Ty::Record(vec![
+                    ("parse".to_string(), Ty::Function(vec![Ty::String], Box::new(Ty::Option(Box::new(Ty::Float))))),
+                    ("is_nan".to_string(), test.clone()),
+                    ("is_finite".to_string(), test),
+                    ("nan".to_string(), Ty::Float),
+                    ("infinity".to_string(), Ty::Float),
+                ]))
+            }
+            "hashmap" => {
+                let (k, v) = (a, self.fresh());
+                let map = Ty::HashMap(Box::new(k.clone()), Box::new(v.clone()));
+                let entries = Ty::List(Box::new(Ty::Record(vec![("key".to_string(), k.clone()), ("value".to_string(), v.clone())])));
+                let function = |parameters: Vec<Ty>, result: Ty| Ty::Function(parameters, Box::new(result));
+                Some(Ty::Record(vec![
+                    ("get".to_string(), function(vec![map.clone(), k.clone()], Ty::Option(Box::new(v.clone())))),
+                    ("insert".to_string(), function(vec![map.clone(), k.clone(), v.clone()], map.clone())),
+                    ("remove".to_string(), function(vec![map.clone(), k.clone()], map.clone())),
+                    ("contains".to_string(), function(vec![map.clone(), k.clone()], Ty::Bool)),
+                    ("size".to_string(), function(vec![map.clone()], Ty::Int)),
+                    ("keys".to_string(), function(vec![map.clone()], Ty::List(Box::new(k)))),
+                    ("values".to_string(), function(vec![map.clone()], Ty::List(Box::new(v)))),
+                    ("entries".to_string(), function(vec![map.clone()], entries.clone())),
+                    ("from_entries".to_string(), function(vec![entries], map)),
+                ]))
+            }
+            "set" => {
+                let set = Ty::Named("set".to_string(), vec![a.clone()]);
+                let function = |parameters: Vec<Ty>, result: Ty| Ty::Function(parameters, Box::new(result));
+                let combine = function(vec![set.clone(), set.clone()], set.clone());
+                Some(Ty::Record(vec![
+                    ("from_list".to_string(), function(vec![Ty::List(Box::new(a.clone()))], set.clone())),
+                    ("to_list".to_string(), function(vec![set.clone()], Ty::List(Box::new(a.clone())))),
+                    ("insert".to_string(), function(vec![set.clone(), a.clone()], set.clone())),
+                    ("remove".to_string(), function(vec![set.clone(), a.clone()], set.clone())),
+                    ("contains".to_string(), function(vec![set.clone(), a], Ty::Bool)),
+                    ("size".to_string(), function(vec![set.clone()], Ty::Int)),
+                    ("union".to_string(), combine.clone()),
+                    ("intersect".to_string(), combine.clone()),
+                    ("difference".to_string(), combine),
+                    ("subset".to_string(), function(vec![set.clone(), set], Ty::Bool)),
+                ]))
+            }
+            "deque" => {
+                let deque = Ty::Named("deque".to_string(), vec![a.clone()]);
+                let function = |parameters: Vec<Ty>, result: Ty| Ty::Function(parameters, Box::new(result));
+                let popped = Ty::Option(Box::new(Ty::Record(vec![("value".to_string(), a.clone()), ("rest".to_string(), deque.clone())])));
+                Some(Ty::Record(vec![
+                    ("empty".to_string(), deque.clone()),
+                    ("from_list".to_string(), function(vec![Ty::List(Box::new(a.clone()))], deque.clone())),
+                    ("to_list".to_string(), function(vec![deque.clone()], Ty::List(Box::new(a.clone())))),
+                    ("size".to_string(), function(vec![deque.clone()], Ty::Int)),
+                    ("push_front".to_string(), function(vec![deque.clone(), a.clone()], deque.clone())),
+                    ("push_back".to_string(), function(vec![deque.clone(), a], deque.clone())),
+                    ("pop_front".to_string(), function(vec![deque.clone()], popped.clone())),
+                    ("pop_back".to_string(), function(vec![deque], popped)),
+                ]))
+            }
+            "table" => {
+                let rows = vec![Ty::List(Box::new(a))];
+                Some(Ty::Record(vec![
+                    ("print".to_string(), Ty::Function(rows.clone(), Box::new(Ty::Unit))),
+                    ("render".to_string(), Ty::Function(rows, Box::new(Ty::String))),
+                ]))
+            }
+            "stdin" => {
+                let line = Ty::Function(vec![], Box::new(Ty::Option(Box::new(Ty::String))));
+                Some(Ty::Record(vec![
+                    ("read_all".to_string(), Ty::Function(vec![], Box::new(Ty::String))),
+                    ("read_line".to_string(), line.clone()),
+                    ("lines".to_string(), Ty::Function(vec![], Box::new(line))),
+                ]))
+            }
+            "file" => {
+                let handle = Ty::Named("handle".to_string(), Vec::new());
+                let function = |parameters: Vec<Ty>, result: Ty| Ty::Function(parameters, Box::new(result));
+                Some(Ty::Record(vec![
+                    ("open".to_string(), function(vec![Ty::String, Ty::String], handle.clone())),
+                    ("read_line".to_string(), function(vec![handle.clone()], Ty::Option(Box::new(Ty::String)))),
+                    ("read_all".to_string(), function(vec![handle.clone()], Ty::String)),
+                    ("write".to_string(), function(vec![handle.clone(), Ty::String], Ty::Unit)),
+                    ("close".to_string(), function(vec![handle], Ty::Unit)),
+                ]))
+            }
+            "timer" => {
+                let callback = Ty::Function(Vec::new(), Box::new(a));
+                Some(Ty::Record(vec![("after".to_string(), Ty::Function(vec![Ty::Int, callback], Box::new(Ty::Unit)))]))
+            }
+            "template" => {
+                let result = Ty::Result(Box::new(Ty::String), Box::new(Ty::String));
+                let render = Ty::Function(vec![Ty::String, a], Box::new(result));
+                Some(Ty::Record(vec![("render".to_string(), render)]))
+            }
+            #[cfg(feature = "csv")]
+            "csv" => {
+                let options = Ty::Record(vec![("headers".to_string(), Ty::Bool)]);
+                let rows = Ty::List(Box::new(a));
+                let parse = Ty::Function(vec![Ty::String, options], Box::new(Ty::Result(Box::new(rows), Box::new(Ty::String))));
+                let encode = Ty::Function(vec![Ty::List(Box::new(self.fresh()))], Box::new(Ty::String));
+                Some(Ty::Record(vec![("parse".to_string(), parse), ("encode".to_string(), encode)]))
+            }
+            // The shape of a document is only known at runtime
+            #[cfg(any(feature = "toml", feature = "yaml"))]
+            "toml" | "yaml" => {
+                let parse = Ty::Function(vec![Ty::String], Box::new(Ty::Result(Box::new(Ty::Dynamic), Box::new(Ty::String))));
+                Some(Ty::Record(vec![("parse".to_string(), parse)]))
+            }
+            #[cfg(feature = "net")]
+            "tcp" => {
+                let handle = Ty::Named("handle".to_string(), Vec::new());
+                let function = |parameters: Vec<Ty>, result: Ty| Ty::Function(parameters, Box::new(result));
+                let received = Ty::Option(Box::new(Ty::String));
+                Some(Ty::Record(vec![
+                    ("listen".to_string(), function(vec![Ty::Int], handle.clone())),
+                    ("port".to_string(), function(vec![handle.clone()], Ty::Int)),
+                    ("accept".to_string(), function(vec![handle.clone()], handle.clone())),
+                    ("connect".to_string(), function(vec![Ty::String, Ty::Int], handle.clone())),
+                    ("read".to_string(), function(vec![handle.clone()], received.clone())),
+                    ("read_line".to_string(), function(vec![handle.clone()], received)),
+                    ("write".to_string(), function(vec![handle.clone(), Ty::String], Ty::Unit)),
+                    ("close".to_string(), function(vec![handle], Ty::Unit)),
+                ]))
+            }
+            _ => None,
+        }
+    }
+
+    fn infer_prefix(&mut self, prefix: &Prefix, right: &Expression) -> Result<Ty, TypeError> {
+        let right = self.infer_expression(right)?;
+        match prefix {
+            Prefix::Bang => {
+                self.unify(&Ty::Bool, &right)?;
+                Ok(Ty::Bool)
+            }
+            Prefix::Plus | Prefix::Minus => {
+                self.expect_numeric(&right)?;
+                Ok(right)
+            }
+        }
+    }
+
+    // Arithmetic is overloaded over int and float; an unresolved operand is accepted.
+    fn expect_numeric(&self, ty: &Ty) -> Result<(), TypeError> {
+        match self.prune(ty) {
+            Ty::Int | Ty::Float | Ty::Var(_) | Ty::Dynamic => Ok(()),
+            other => Err(self.mismatch(&Ty::Int, &other)),
+        }
+    }
+
+    // Whether `ty` is a user type deriving `ord`; records are of a record
+    // type when they have its fields
+    fn is_ordered(&self, ty: &Ty) -> bool {
+        match self.prune(ty) {
+            Ty::Named(name, _) => self.ordered.contains(&name),
+            Ty::Record(fields) => self.ordered.iter().filter_map(|name| self.records.get(name)).any(|declared| {
+                declared.len() == fields.len() && fields.iter().all(|(field, _)| declared.iter().any(|(name, _)| name == field))
+            }),
+            _ => false,
+        }
+    }
+
+    // A call, or with `piped` the stage `f(a)` of `x |> f(a)`, which takes x
+    // as its last argument
+    fn infer_call(&mut self, call: &Expression, piped: Option<Ty>) -> Result<Ty, TypeError> {
+        let (function, arguments) = match call {
+            Expression::Call { function, arguments, .. } => {
+                if let Expression::Identifier(identifier) = &**function {
+                    let name = identifier.to_string();
+                    if let Some(effects) = self.scheme(&name).map(|scheme| scheme.effects) {
+                        self.perform(&name, effects, false);
+                    }
+                }
+                (self.infer_expression(function)?, arguments)
+            }
+            Expression::BuiltIn { function, arguments, .. } => {
+                if let Token::Println | Token::Dbg = function {
+                    let name = if *function == Token::Dbg { "dbg" } else { "println" };
+                    self.perform(name, builtin::effects(name), true);
+                }
+                (self.builtin_type(function)?, arguments)
+            }
+            _ => return self.infer_expression(call),
+        };
+        let mut arguments = arguments
+            .iter()
+            .map(|argument| self.infer_expression(argument))
+            .collect::<Result<Vec<Ty>, TypeError>>()?;
+        arguments.extend(piped);
+        self.apply(function, arguments)
+    }
+
+    // Operators associate to the left, so a long chain like `1 + 1 + ...` nests
+    // down its left side; that side is walked in a loop rather than recursion.
+    // `::` and `++` associate to the right, and their chains are walked down
+    // the right side instead.
+    fn infer_infix(&mut self, expression: &Expression) -> Result<Ty, TypeError> {
+        if let Expression::Infix(infix @ (Infix::Cons | Infix::Concat), _, right, _) = expression {
+            if matches!(&**right, Expression::Infix(next, ..) if next == infix) {
+                return self.infer_right_chain(infix, expression);
+            }
+        }
+        let mut chain = Vec::new();
+        let mut leftmost = expression;
+        while let Expression::Infix(infix, left, right, span) = leftmost {
+            chain.push((infix, right, span));
+            leftmost = left;
+        }
+        let mut left = self.infer_expression(leftmost)?;
+        while let Some((infix, right, span)) = chain.pop() {
+            left = match (infix, &**right) {
+                (Infix::Pipe, Expression::Call { .. } | Expression::BuiltIn { .. }) => self.infer_call(right, Some(left))?,
+                _ => {
+                    let right = self.infer_expression(right)?;
+                    self.combine(infix, left, right)?
+                }
+            };
+            // The outermost operator is recorded by `infer_expression`
+            if !chain.is_empty() {
+                self.record(*span, &left);
+            }
+        }
+        Ok(left)
+    }
+
+    fn infer_right_chain(&mut self, chained: &Infix, expression: &Expression) -> Result<Ty, TypeError> {
+        let mut chain = Vec::new();
+        let mut rightmost = expression;
+        while let Expression::Infix(infix, left, right, span) = rightmost {
+            if infix != chained {
+                break;
+            }
+            chain.push((infix, left, span));
+            rightmost = right;
+        }
+        let mut right = self.infer_expression(rightmost)?;
+        while let Some((infix, left, span)) = chain.pop() {
+            let left = self.infer_expression(left)?;
+            right = self.combine(infix, left, right)?;
+            if !chain.is_empty() {
+                self.record(*span, &right);
+            }
+        }
+        Ok(right)
+    }
+
+    fn combine(&mut self, infix: &Infix, left: Ty, right: Ty) -> Result<Ty, TypeError> {
+        match infix {
+            Infix::Plus | Infix::Minus | Infix::Product | Infix::ForwardSlash | Infix::Modulo => {
+                self.unify(&left, &right)?;
+                self.expect_numeric(&left)?;
+                Ok(left)
+            }
+            Infix::Equal | Infix::DoesNotEqual => {
+                self.unify(&left, &right)?;
+                Ok(Ty::Bool)
+            }
+            Infix::GreaterThan | Infix::LessThan | Infix::GTOrEqual | Infix::LTOrEqual => {
+                self.unify(&left, &right)?;
+                if !self.is_ordered(&left) {
+                    self.expect_numeric(&left)?;
+                }
+                Ok(Ty::Bool)
+            }
+            Infix::ShiftLeft | Infix::ShiftRight => {
+                self.unify(&Ty::Int, &left)?;
+                self.unify(&Ty::Int, &right)?;
+                Ok(Ty::Int)
+            }
+            // Strings and lists
+            Infix::Concat => {
+                self.unify(&left, &right)?;
+                match self.prune(&left) {
+                    Ty::String | Ty::List(_) | Ty::Var(_) | Ty::Dynamic => Ok(left),
+                    other => Err(self.mismatch(&Ty::String, &other)),
+                }
+            }
+            Infix::Cons => {
+                self.unify(&Ty::List(Box::new(left)), &right)?;
+                Ok(right)
+            }
+            Infix::Pipe => self.apply(right, vec![left]),
+            // `x ?? default` is the value of an option or a result, else the default
+            Infix::Coalesce => match self.prune(&left) {
+                Ty::Dynamic => Ok(right),
+                Ty::Result(value, _) => {
+                    self.unify(&value, &right)?;
+                    Ok(right)
+                }
+                _ => {
+                    self.unify(&Ty::Option(Box::new(right.clone())), &left)?;
+                    Ok(right)
+                }
+            },
+            // `x in xs`, `"sub" in s`, `"field" in record`, `key in map` and `x in set`
+            Infix::In => {
+                match self.prune(&right) {
+                    Ty::Dynamic => {}
+                    Ty::String | Ty::Record(_) => self.unify(&Ty::String, &left)?,
+                    Ty::HashMap(key, _) => self.unify(&key, &left)?,
+                    Ty::Named(name, elements) if name == "set" => self.unify(&elements[0], &left)?,
+                    _ => self.unify(&Ty::List(Box::new(left)), &right)?,
+                }
+                Ok(Ty::Bool)
+            }
+            Infix::Caret | Infix::Ampersand => Err(TypeErrorKind::Unsupported(format!("operator {:?}", infix)).into()),
+        }
+    }
+
+    fn infer_match(&mut self, expr: &Expression, arms: &[(Pattern, Block)]) -> Result<Ty, TypeError> {
+        let scrutinee = self.infer_expression(expr)?;
+        let result = self.fresh();
+        for (pattern, body) in arms {
+            self.scopes.push(HashMap::new());
+            let arm = self.infer_pattern(pattern, &scrutinee).and_then(|_| self.infer_block(body));
+            self.scopes.pop();
+            self.unify(&result, &arm?)?;
+        }
+        Ok(result)
+    }
+
+    fn infer_pattern(&mut self, pattern: &Pattern, expected: &Ty) -> Result<(), TypeError> {
+        match pattern {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Identifier(identifier) => {
+                self.bind_mono(&identifier.to_string(), expected.clone());
+                Ok(())
+            }
+            Pattern::Literal(literal) => {
+                let ty = self.infer_literal(literal)?;
+                self.unify(expected, &ty)
+            }
+            Pattern::Empty => {
+                let element = self.fresh();
+                self.unify(expected, &Ty::List(Box::new(element)))
+            }
+            Pattern::Infix(Infix::Cons, head, tail) => {
+                let element = self.fresh();
+                let list = Ty::List(Box::new(element.clone()));
+                self.unify(expected, &list)?;
+                self.infer_pattern(head, &element)?;
+                self.infer_pattern(tail, &list)
+            }
+            Pattern::Infix(infix, _, _) => Err(TypeErrorKind::Unsupported(format!("pattern operator {:?}", infix)).into()),
+            Pattern::Variant(name, payload) => {
+                let payload_ty = match name {
+                    Token::Some | Token::None => {
+                        let inner = self.fresh();
+                        self.unify(expected, &Ty::Option(Box::new(inner.clone())))?;
+                        inner
+                    }
+                    Token::Ok | Token::Err => {
+                        let (ok, err) = (self.fresh(), self.fresh());
+                        self.unify(expected, &Ty::Result(Box::new(ok.clone()), Box::new(err.clone())))?;
+                        if *name == Token::Ok { ok } else { err }
+                    }
+                    _ => match self.infer_identifier(name)? {
+                        Ty::Function(mut params, union) if params.len() == 1 => {
+                            self.unify(expected, &union)?;
+                            params.remove(0)
+                        }
+                        union => {
+                            self.unify(expected, &union)?;
+                            Ty::Unit
+                        }
+                    },
+                };
+                match payload {
+                    Some(payload) => self.infer_pattern(payload, &payload_ty),
+                    None => Ok(()),
+                }
+            }
+            Pattern::Record(fields) => {
+                let mut tys = Vec::new();
+                for (name, pattern) in fields {
+                    let ty = self.fresh();
+                    self.infer_pattern(pattern, &ty)?;
+                    tys.push((name.to_string(), ty));
+                }
+                self.unify(expected, &Ty::Record(tys))
+            }
+        }
+    }
+}
+
+fn substitute(ty: &Ty, mapping: &HashMap<usize, Ty>) -> Ty {
+    match ty {
+        Ty::Var(v) => mapping.get(v).cloned().unwrap_or(Ty::Var(*v)),
+        Ty::List(t) => Ty::List(Box::new(substitute(t, mapping))),
+        Ty::Option(t) => Ty::Option(Box::new(substitute(t, mapping))),
+        Ty::Result(a, b) => Ty::Result(Box::new(substitute(a, mapping)), Box::new(substitute(b, mapping))),
+        Ty::HashMap(k, v) => Ty::HashMap(Box::new(substitute(k, mapping)), Box::new(substitute(v, mapping))),
+        Ty::Function(params, ret) => Ty::Function(
+            params.iter().map(|p| substitute(p, mapping)).collect(),
+            Box::new(substitute(ret, mapping)),
+        ),
+        Ty::Record(fields) => Ty::Record(fields.iter().map(|(n, t)| (n.clone(), substitute(t, mapping))).collect()),
+        Ty::Named(name, params) => Ty::Named(name.clone(), params.iter().map(|p| substitute(p, mapping)).collect()),
+        other => other.clone(),
+    }
+}
+
+// Type variables are printed as 'a, 'b, ... in order of first appearance.
+struct Printer {
+    names: Vec<usize>,
+}
+
+impl Printer {
+    fn var(&mut self, v: usize) -> String {
+        let index = match self.names.iter().position(|n| *n == v) {
+            Some(index) => index,
+            None => {
+                self.names.push(v);
+                self.names.len() - 1
+            }
+        };
+        let letter = (b'a' + (index % 26) as u8) as char;
+        if index < 26 {
+            format!("'{}", letter)
+        } else {
+            format!("'{}{}", letter, index / 26)
+        }
+    }
+
+    // Parameters of `*` constructors are wrapped when they are themselves compound
+    fn atom(&mut self, ty: &Ty) -> String {
+        match ty {
+            Ty::List(_) | Ty::Option(_) | Ty::Function(..) => format!("({})", self.print(ty)),
+            Ty::Named(_, params) if !params.is_empty() => format!("({})", self.print(ty)),
+            _ => self.print(ty),
+        }
+    }
+
+    fn print(&mut self, ty: &Ty) -> String {
+        match ty {
+            Ty::Int => "int".to_string(),
+            Ty::Float => "float".to_string(),
+            Ty::Bool => "bool".to_string(),
+            Ty::String => "string".to_string(),
+            Ty::Char => "char".to_string(),
+            Ty::Unit => "unit".to_string(),
+            Ty::List(t) => format!("list * {}", self.atom(t)),
+            Ty::Option(t) => format!("option * {}", self.atom(t)),
+            Ty::Result(a, b) => format!("result * ({}, {})", self.print(a), self.print(b)),
+            Ty::HashMap(k, v) => format!("hashmap * ({}, {})", self.print(k), self.print(v)),
+            Ty::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| self.print(p)).collect();
+                let params = if params.is_empty() { "()".to_string() } else { params.join(", ") };
+                format!("fn {} -> {}", params, self.print(ret))
+            }
+            Ty::Record(fields) => {
+                let fields: Vec<String> = fields.iter().map(|(n, t)| format!("{}: {}", n, self.print(t))).collect();
+                format!("{{ {} }}", fields.join(", "))
+            }
+            Ty::Named(name, params) => match params.first() {
+                Some(param) => format!("{} * {}", name, self.atom(param)),
+                None => name.clone(),
+            },
+            Ty::Var(v) => self.var(*v),
+            Ty::Dynamic => "dynamic".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Printer { names: Vec::new() }.print(self))
+    }
+}
+
+// Prints two types with the same names for the variables they share
+fn print_pair(a: &Ty, b: &Ty) -> (String, String) {
+    print_pair_with(&mut Printer { names: Vec::new() }, a, b)
+}
+
+fn print_pair_with(printer: &mut Printer, a: &Ty, b: &Ty) -> (String, String) {
+    (printer.print(a), printer.print(b))
+}
+
+// Prints two types side by side, marking the parts where they disagree.
+// Variables and `dynamic` agree with anything.
+struct Differ {
+    printer: Printer,
+    // Printed text and marked ranges, in chars, of each type
+    sides: [(String, Vec<(usize, usize)>); 2],
+}
+
+impl Differ {
+    fn new(a: &Ty, b: &Ty) -> Self {
+        // Name the variables in the same order as the message does
+        let mut printer = Printer { names: Vec::new() };
+        print_pair_with(&mut printer, a, b);
+        Differ { printer, sides: Default::default() }
+    }
+
+    fn text(&mut self, text: &str) {
+        for (out, _) in &mut self.sides {
+            out.push_str(text);
+        }
+    }
+
+    fn whole(&mut self, printed: [String; 2], mark: bool) {
+        for ((out, marks), printed) in self.sides.iter_mut().zip(printed) {
+            let start = out.chars().count();
+            out.push_str(&printed);
+            if mark {
+                marks.push((start, start + printed.chars().count()));
+            }
+        }
+    }
+
+    fn walk(&mut self, a: &Ty, b: &Ty) {
+        match (a, b) {
+            (Ty::Var(_) | Ty::Dynamic, _) | (_, Ty::Var(_) | Ty::Dynamic) => {
+                let printed = [self.printer.print(a), self.printer.print(b)];
+                self.whole(printed, false);
+            }
+            (Ty::List(x), Ty::List(y)) => {
+                self.text("list * ");
+                self.atom(x, y);
+            }
+            (Ty::Option(x), Ty::Option(y)) => {
+                self.text("option * ");
+                self.atom(x, y);
+            }
+            (Ty::Result(x1, x2), Ty::Result(y1, y2)) | (Ty::HashMap(x1, x2), Ty::HashMap(y1, y2)) => {
+                self.text(if matches!(a, Ty::Result(..)) { "result * (" } else { "hashmap * (" });
+                self.walk(x1, y1);
+                self.text(", ");
+                self.walk(x2, y2);
+                self.text(")");
+            }
+            (Ty::Function(xp, xr), Ty::Function(yp, yr)) if xp.len() == yp.len() => {
+                self.text("fn ");
+                for (i, (x, y)) in xp.iter().zip(yp).enumerate() {
+                    if i > 0 {
+                        self.text(", ");
+                    }
+                    self.walk(x, y);
+                }
+                self.text(" -> ");
+                self.walk(xr, yr);
+            }
+            (Ty::Record(xf), Ty::Record(yf)) if xf.iter().map(|(n, _)| n).eq(yf.iter().map(|(n, _)| n)) => {
+                self.text("{ ");
+                for (i, ((name, x), (_, y))) in xf.iter().zip(yf).enumerate() {
+                    if i > 0 {
+                        self.text(", ");
+                    }
+                    self.text(&format!("{}: ", name));
+                    self.walk(x, y);
+                }
+                self.text(" }");
+            }
+            (Ty::Named(x, xp), Ty::Named(y, yp)) if x == y && xp.len() == yp.len() => {
+                self.text(x);
+                if let (Some(x), Some(y)) = (xp.first(), yp.first()) {
+                    self.text(" * ");
+                    self.atom(x, y);
+                }
+            }
+            _ => {
+                let printed = [self.printer.print(a), self.printer.print(b)];
+                self.whole(printed, a != b);
+            }
+        }
+    }
+
+    // A parameter of a `*` constructor, see `Printer::atom`
+    fn atom(&mut self, a: &Ty, b: &Ty) {
+        let printed = [self.printer.atom(a), self.printer.atom(b)];
+        let wrapped = [printed[0].starts_with('('), printed[1].starts_with('(')];
+        if wrapped[0] != wrapped[1] {
+            let agree = matches!(a, Ty::Var(_) | Ty::Dynamic) || matches!(b, Ty::Var(_) | Ty::Dynamic);
+            return self.whole(printed, !agree);
+        }
+        if wrapped[0] {
+            self.text("(");
+        }
+        self.walk(a, b);
+        if wrapped[0] {
+            self.text(")");
+        }
+    }
+}
+
+impl TypeErrorKind {
+    pub fn code(&self) -> Code {
+        match self {
+            TypeErrorKind::Mismatch { .. } => Code::TypeMismatch,
+            TypeErrorKind::InfiniteType { .. } => Code::InfiniteType,
+            TypeErrorKind::Undefined(..) => Code::UnboundType,
+            TypeErrorKind::UnknownField(..) => Code::UnknownField,
+            TypeErrorKind::Unsupported(_) => Code::UnsupportedInference,
+            TypeErrorKind::Effect(..) => Code::EffectNotAllowed,
+        }
+    }
+}
+
+impl TypeErrorKind {
+    pub fn message(&self) -> String {
+        match self {
+            TypeErrorKind::Mismatch { expected, actual } => {
+                let (expected, actual) = print_pair(expected, actual);
+                format!("Type mismatch: expected {}, got {}", expected, actual)
+            }
+            TypeErrorKind::InfiniteType { var, ty, binding } => {
+                let (var, ty) = print_pair(var, ty);
+                match binding {
+                    Some(binding) => format!("Infinite type in let {}: {} would have to be {}, which contains it", binding, var, ty),
+                    None => format!("Infinite type: {} would have to be {}, which contains it", var, ty),
+                }
+            }
+            TypeErrorKind::Undefined(name, _) => format!("Undefined variable: {:?}", name),
+            TypeErrorKind::UnknownField(name, _) => format!("No field '{}' in record", name),
+            TypeErrorKind::Unsupported(what) => format!("Type inference not supported for {}", what),
+            TypeErrorKind::Effect(name, effects) => format!("`{}` performs {}, which a @pure module cannot do", name, effects),
+        }
+    }
+
+    /// For a mismatch between compound types, the two types aligned with the
+    /// parts that differ underlined:
+    ///
+    /// ```text
+    /// expected: list * int
+    ///                  ^^^
+    ///   actual: list * string
+    ///                  ^^^^^^
+    /// ```
+    pub fn diff(&self) -> Option<String> {
+        let TypeErrorKind::Mismatch { expected, actual } = self else {
+            return None;
+        };
+        let mut differ = Differ::new(expected, actual);
+        differ.walk(expected, actual);
+        let [(expected, x), (actual, y)] = differ.sides;
+        let whole = |printed: &str, marks: &[(usize, usize)]| marks == [(0, printed.chars().count())];
+        if x.is_empty() || (whole(&expected, &x) && whole(&actual, &y)) {
+            return None;
+        }
+        let underline = |marks: &[(usize, usize)]| {
+            let mut line = " ".repeat(10);
+            for (start, end) in marks {
+                line.push_str(&" ".repeat(start + 10 - line.len()));
+                line.push_str(&"^".repeat(end - start));
+            }
+            line
+        };
+        Some(format!("expected: {}\n{}\n  actual: {}\n{}", expected, underline(&x), actual, underline(&y)))
+    }
+
+}
+
+impl TypeError {
+    // Locates the error at `span` unless it already is, deeper in
+    fn within(mut self, span: Option<Span>) -> Self {
+        self.span = self.span.or(span);
+        self
+    }
+}
+
+impl From<TypeErrorKind> for TypeError {
+    fn from(kind: TypeErrorKind) -> Self {
+        TypeError { kind: Box::new(kind), span: None }
+    }
+}
+
+impl std::ops::Deref for TypeError {
+    type Target = TypeErrorKind;
+
+    fn deref(&self) -> &TypeErrorKind {
+        &self.kind
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(error: &TypeError) -> Self {
+        let mut diagnostic = Diagnostic::new(error.code(), error.message());
+        if let Some(span) = error.span {
+            diagnostic = diagnostic.with_span(span);
+        }
+        if let Some(diff) = error.diff() {
+            diagnostic = diagnostic.with_note(diff);
+        }
+        match &*error.kind {
+            TypeErrorKind::Undefined(_, Some(suggestion)) | TypeErrorKind::UnknownField(_, Some(suggestion)) => {
+                diagnostic.with_suggestion(format!("did you mean `{}`?", suggestion))
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code(), self.message())?;
+        match &*self.kind {
+            TypeErrorKind::Undefined(_, Some(suggestion)) | TypeErrorKind::UnknownField(_, Some(suggestion)) => write!(f, ", did you mean `{}`?", suggestion),
+            _ => Ok(()),
+        }?;
+        match self.diff() {
+            Some(diff) => write!(f, "\n{}", diff),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn infer(source: &str) -> Result<String, TypeErrorKind> {
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.parse_program();
+        TypeChecker::new().infer_program(&program).map(|ty| ty.to_string()).map_err(|error| *error.kind)
+    }
+
+    #[test]
+    fn test_infer_long_chain() {
+        let chain = vec!["1"; 3000].join(" + ");
+        assert_eq!(infer(&chain), Ok("int".to_string()));
+        assert!(infer(&format!("{} + \"a\"", chain)).is_err());
+        let chain = vec!["1"; 3000].join(" :: ");
+        assert_eq!(infer(&format!("{} :: []", chain)), Ok("list * int".to_string()));
+        assert!(infer(&format!("{} :: [\"a\"]", chain)).is_err());
+    }
+
+    #[test]
+    fn test_infer_literals_and_functions() {
+        assert_eq!(infer("[1, 2, 3]"), Ok("list * int".to_string()));
+        assert_eq!(infer("let add = fn x, y -> x + y; add(1, 2)"), Ok("int".to_string()));
+        assert_eq!(infer("map(fn x -> x > 1;, [1, 2])"), Ok("list * bool".to_string()));
+        assert_eq!(infer("fn x -> x;"), Ok("fn 'a -> 'a".to_string()));
+        assert_eq!(infer("0 :: [1] ++ [2]"), Ok("list * int".to_string()));
+        assert_eq!(infer("let has = fn x, xs -> x in xs; has(1, [2])"), Ok("bool".to_string()));
+        assert_eq!(infer("let user = Some({ name = \"ada\" }); user?.name"), Ok("option * string".to_string()));
+        assert_eq!(infer("let user = Some({ name = \"ada\" }); user?.name ?? \"nobody\""), Ok("string".to_string()));
+        assert_eq!(infer("let m = { \"a\": 1 }; m?.a"), Ok("option * int".to_string()));
+        assert!(infer("let m = { 1: 1 }; m?.a").is_err());
+        assert_eq!(infer("let ports = { \"web\": 80 }; hashmap.get(ports, \"web\")"), Ok("option * int".to_string()));
+        assert!(infer("let ports = { \"web\": 80 }; 1 in ports").is_err());
+        assert_eq!(infer("set.union(set.from_list([1]), set.from_list([2]))"), Ok("set * int".to_string()));
+        assert_eq!(infer("deque.pop_front(deque.push_back(deque.empty, \"a\"))?.value"), Ok("option * string".to_string()));
+        assert_eq!(infer("table.render([{ id = 1 }])"), Ok("string".to_string()));
+        assert_eq!(infer("[1, 2] |> map(\\x -> x > 1)"), Ok("list * bool".to_string()));
+        assert!(infer("[1, 2] |> map(\\x -> x ++ \"a\")").is_err());
+    }
+
+    #[test]
+    fn test_infer_let_polymorphism() {
+        assert_eq!(infer("let id = fn x -> x; id(1); id(\"s\")"), Ok("string".to_string()));
+    }
+
+    #[test]
+    fn test_infer_mismatch() {
+        assert_eq!(
+            infer("1 + \"a\""),
+            Err(TypeErrorKind::Mismatch { expected: Ty::Int, actual: Ty::String })
+        );
+        assert_eq!(
+            infer("\"a\" ++ [\"b\"]"),
+            Err(TypeErrorKind::Mismatch { expected: Ty::String, actual: Ty::List(Box::new(Ty::String)) })
+        );
+        assert_eq!(infer("y"), Err(TypeErrorKind::Undefined("y".to_string(), None)));
+        assert_eq!(
+            infer("let count = 1; coutn + 1"),
+            Err(TypeErrorKind::Undefined("coutn".to_string(), Some("count".to_string())))
+        );
+
+        // At the innermost operator, call or let
+        let program = Parser::new(Lexer::new("let n = 1;\nlet s = [n, 2 + (n ++ \"a\")];\nlet t = m;")).parse_program();
+        let mut checker = TypeChecker::new();
+        checker.check_program(&program);
+        let spans: Vec<_> = checker.errors.iter().map(|error| error.span.map(|span| (span.line, span.column))).collect();
+        assert_eq!(spans, [Some((2, 20)), Some((3, 5))]);
+        assert_eq!(Diagnostic::from(&checker.errors[0]).span, checker.errors[0].span);
+    }
+
+    #[test]
+    fn test_gradual() {
+        let source = "@gradual\nlet settings: dynamic = { port = 80 };\nlet legacy = if true { 1 } else { \"one\" };\nlet port: int = settings.port;\nlet name: string = 1;";
+        let program = Parser::new(Lexer::new(source)).parse_program();
+        let mut checker = TypeChecker::new();
+        checker.check_program(&program);
+        assert_eq!(checker.binding("legacy"), Some(Ty::Dynamic));
+        assert_eq!(checker.binding("port"), Some(Ty::Int));
+        let kinds: Vec<&TypeErrorKind> = checker.errors.iter().map(|error| &*error.kind).collect();
+        assert_eq!(kinds, [&TypeErrorKind::Mismatch { expected: Ty::String, actual: Ty::Int }]);
+    }
+
+    #[test]
+    fn test_infinite_type_and_diff() {
+        let error = infer("let apply_self = fn f -> f(f);").unwrap_err();
+        assert_eq!(error.message(), "Infinite type in let apply_self: 'a would have to be fn 'a -> 'b, which contains it");
+
+        let error = infer("let add = fn x, y -> x + y; add(1, [2])").unwrap_err();
+        assert_eq!(error.message(), "Type mismatch: expected fn int, int -> int, got fn int, list * int -> 'a");
+        let diff = "expected: fn int, int -> int\n                  ^^^\n  actual: fn int, list * int -> 'a\n                  ^^^^^^^^^^";
+        assert_eq!(error.diff().as_deref(), Some(diff));
+        assert_eq!(infer("1 + \"a\"").unwrap_err().diff(), None);
+    }
+}
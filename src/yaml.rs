@@ -0,0 +1,107 @@
+// yaml.rs
+//
+// The `yaml` module, enabled by the `yaml` feature. `yaml.parse` reads a
+// single YAML document with the `serde_yaml` crate and maps it to records,
+// lists and scalars. Tags are dropped and keys must be scalars.
+
+use crate::diagnostics::Code;
+use crate::object::Object;
+use crate::record::Record;
+use crate::string::Str;
+use serde_yaml::Value;
+
+pub fn module() -> Object {
+    Object::record(vec![("parse".to_string(), Object::Builtin(parse_builtin))])
+}
+
+pub fn parse_builtin(args: Vec<Object>) -> Object {
+    let [Object::String(text)] = args.as_slice() else {
+        return Object::Error(Code::BuiltinArgument, "yaml.parse expects a string".to_string());
+    };
+    match parse(text.as_str()) {
+        Ok(value) => Object::ResultOk(Box::new(value)),
+        Err(message) => Object::ResultErr(Box::new(Object::String(Str::from(message)))),
+    }
+}
+
+fn parse(text: &str) -> Result<Object, String> {
+    let value: Value = serde_yaml::from_str(text).map_err(|error| error.to_string())?;
+    object(value)
+}
+
+fn object(value: Value) -> Result<Object, String> {
+    Ok(match value {
+        Value::Null => Object::OptionNone,
+        Value::Bool(value) => Object::Boolean(value),
+        Value::Number(number) => match (number.as_i64(), number.is_u64()) {
+            (Some(value), _) => Object::Integer(value),
+            (None, true) => return Err(format!("integer out of range: {}", number)),
+            (None, false) => Object::Float(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(text) => Object::String(Str::from(text)),
+        Value::Sequence(values) => Object::List(values.into_iter().map(object).collect::<Result<_, _>>()?),
+        Value::Mapping(mapping) => {
+            let fields = mapping.into_iter().map(|(key, value)| Ok((key_name(key)?, object(value)?))).collect::<Result<_, String>>()?;
+            Object::Record(Record::new(fields))
+        }
+        Value::Tagged(tagged) => object(tagged.value)?,
+    })
+}
+
+fn key_name(key: Value) -> Result<String, String> {
+    match key {
+        Value::String(name) => Ok(name),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Null => Ok("~".to_string()),
+        _ => Err("keys must be strings, numbers or booleans".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "
+name: opl # the language
+version: 0.4
+tags: [fast, 'pure']
+owner: { name: ada, admin: true }
+servers:
+  - host: a.example
+    port: 8080
+  - host: b.example
+    port: ~
+notes: |
+  line one
+  line two
+";
+        let record = |fields: Vec<(&str, Object)>| {
+            Object::Record(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+        };
+        let expected = record(vec![
+            ("name", Object::from("opl")),
+            ("version", Object::Float(0.4)),
+            ("tags", Object::List(vec![Object::from("fast"), Object::from("pure")])),
+            ("owner", record(vec![("name", Object::from("ada")), ("admin", Object::Boolean(true))])),
+            ("servers", Object::List(vec![
+                record(vec![("host", Object::from("a.example")), ("port", Object::Integer(8080))]),
+                record(vec![("host", Object::from("b.example")), ("port", Object::OptionNone)]),
+            ])),
+            ("notes", Object::from("line one\nline two\n")),
+        ]);
+        assert_eq!(parse(text), Ok(expected));
+
+        let list = parse("- 1\n-\n  - a\n- x: 1\n  y: 2\n").unwrap();
+        assert_eq!(list, Object::List(vec![
+            Object::Integer(1),
+            Object::List(vec![Object::from("a")]),
+            record(vec![("x", Object::Integer(1)), ("y", Object::Integer(2))]),
+        ]));
+        assert_eq!(parse("a: 1\na: 2\n"), Err("duplicate entry with key \"a\"".to_string()));
+        assert_eq!(parse("a: [1\n"), Err("did not find expected ',' or ']' at line 2 column 1, while parsing a flow sequence at line 1 column 4".to_string()));
+        assert_eq!(parse("a: &x 1\nb: *x\n"), Ok(record(vec![("a", Object::Integer(1)), ("b", Object::Integer(1))])));
+    }
+}